@@ -0,0 +1,882 @@
+use async_trait::async_trait;
+use bson::{oid::ObjectId, Document};
+use mongodb_cursor_pagination::FindResult;
+use serde::{de::DeserializeOwned, Serialize};
+use std::time::Duration;
+
+use crate::base::{
+    BaseService, DeleteManyResponse, DeleteResponse, GroupCount, UpdateManyResponse, UpsertKey,
+    UpsertResponse,
+};
+use crate::change_stream::ChangeStream;
+use crate::error::ServiceError;
+use crate::filter::Filter;
+use crate::history::HistoryRecord;
+use crate::id::ID;
+use crate::node::Node;
+use crate::projection::Projection;
+use crate::search::SearchQuery;
+
+/// Runs a blocking service call on tokio's blocking thread pool.
+///
+/// The MongoDB driver is synchronous, so this keeps it from stalling the async executor.
+pub async fn run_blocking<F, R>(f: F) -> Result<R, ServiceError>
+where
+    F: FnOnce() -> Result<R, ServiceError> + Send + 'static,
+    R: Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| ServiceError::Unknown(format!("Blocking service call failed: {}", e)))?
+}
+
+/// An owned copy of a nested embedded path, to move into a blocking call.
+fn owned_path(path: &[(&str, ID)]) -> Vec<(String, ID)> {
+    path.iter()
+        .map(|(field, id)| ((*field).to_owned(), id.clone()))
+        .collect()
+}
+
+fn borrowed_path(path: &[(String, ID)]) -> Vec<(&str, ID)> {
+    path.iter()
+        .map(|(field, id)| (field.as_str(), id.clone()))
+        .collect()
+}
+
+/// An awaitable adapter over a blocking `BaseService`.
+///
+/// This is not async I/O: the driver is blocking, and every operation is provided by default and
+/// runs the blocking service's implementation on tokio's blocking pool, holding one of its threads
+/// until the driver returns. Implementors only hand out the blocking service, so overrides like
+/// `id_parameter`, `default_sort` or `default_filter` apply the same way.
+#[async_trait]
+pub trait AsyncBaseService: Send + Sync {
+    type Service: for<'a> BaseService<'a> + Clone + Send + Sync + 'static;
+
+    fn service(&self) -> &Self::Service;
+
+    async fn find<T>(
+        &self,
+        filter: Option<Document>,
+        sort: Option<Document>,
+        limit: Option<i32>,
+        after: Option<String>,
+        before: Option<String>,
+        skip: Option<i32>,
+    ) -> Result<FindResult<T>, ServiceError>
+    where
+        T: DeserializeOwned + Send + 'static,
+    {
+        let service = self.service().clone();
+        run_blocking(move || service.find(filter, sort, limit, after, before, skip)).await
+    }
+
+    async fn find_where<T>(
+        &self,
+        filter: Filter,
+        sort: Option<Document>,
+        limit: Option<i32>,
+        after: Option<String>,
+        before: Option<String>,
+        skip: Option<i32>,
+    ) -> Result<FindResult<T>, ServiceError>
+    where
+        T: DeserializeOwned + Send + 'static,
+    {
+        let service = self.service().clone();
+        run_blocking(move || service.find_where(filter, sort, limit, after, before, skip)).await
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn find_projected<T>(
+        &self,
+        filter: Option<Document>,
+        projection: Option<Projection>,
+        sort: Option<Document>,
+        limit: Option<i32>,
+        after: Option<String>,
+        before: Option<String>,
+        skip: Option<i32>,
+    ) -> Result<FindResult<T>, ServiceError>
+    where
+        T: DeserializeOwned + Send + 'static,
+    {
+        let service = self.service().clone();
+        run_blocking(move || {
+            service.find_projected(filter, projection, sort, limit, after, before, skip)
+        })
+        .await
+    }
+
+    async fn get_embedded_by_id<U>(
+        &self,
+        id: ID,
+        field: &str,
+        limit: Option<i32>,
+        skip: Option<i32>,
+    ) -> Result<Vec<U>, ServiceError>
+    where
+        U: DeserializeOwned + Send + 'static,
+    {
+        let service = self.service().clone();
+        let field = field.to_owned();
+        run_blocking(move || service.get_embedded_by_id(id, &field, limit, skip)).await
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn find_embedded<U>(
+        &self,
+        id: ID,
+        field: &str,
+        filter: Option<Document>,
+        sort: Option<Document>,
+        limit: Option<i32>,
+        after: Option<String>,
+        before: Option<String>,
+        skip: Option<i32>,
+    ) -> Result<FindResult<U>, ServiceError>
+    where
+        U: DeserializeOwned + Send + 'static,
+    {
+        let service = self.service().clone();
+        let field = field.to_owned();
+        run_blocking(move || {
+            service.find_embedded(id, &field, filter, sort, limit, after, before, skip)
+        })
+        .await
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn search<T>(
+        &self,
+        search_term: String,
+        fields: Vec<String>,
+        sort: Option<Document>,
+        limit: Option<i32>,
+        after: Option<String>,
+        before: Option<String>,
+        skip: Option<i32>,
+    ) -> Result<FindResult<T>, ServiceError>
+    where
+        T: DeserializeOwned + Send + 'static,
+    {
+        let service = self.service().clone();
+        run_blocking(move || service.search(search_term, fields, sort, limit, after, before, skip))
+            .await
+    }
+
+    async fn search_with<T>(
+        &self,
+        query: SearchQuery,
+        sort: Option<Document>,
+        limit: Option<i32>,
+        after: Option<String>,
+        before: Option<String>,
+        skip: Option<i32>,
+    ) -> Result<FindResult<T>, ServiceError>
+    where
+        T: DeserializeOwned + Send + 'static,
+    {
+        let service = self.service().clone();
+        run_blocking(move || service.search_with(query, sort, limit, after, before, skip)).await
+    }
+
+    async fn text_search<T>(
+        &self,
+        query: SearchQuery,
+        sort: Option<Document>,
+        limit: Option<i32>,
+        after: Option<String>,
+        before: Option<String>,
+        skip: Option<i32>,
+    ) -> Result<FindResult<T>, ServiceError>
+    where
+        T: DeserializeOwned + Send + 'static,
+    {
+        let service = self.service().clone();
+        run_blocking(move || service.text_search(query, sort, limit, after, before, skip)).await
+    }
+
+    async fn aggregate<T>(
+        &self,
+        pipeline: Vec<Document>,
+        sort: Option<Document>,
+        limit: Option<i32>,
+        after: Option<String>,
+        before: Option<String>,
+        skip: Option<i32>,
+    ) -> Result<FindResult<T>, ServiceError>
+    where
+        T: DeserializeOwned + Send + 'static,
+    {
+        let service = self.service().clone();
+        run_blocking(move || service.aggregate(pipeline, sort, limit, after, before, skip)).await
+    }
+
+    /// Opens the change stream on the blocking pool. Polling it blocks too, so `try_next` should
+    /// be run with `run_blocking`.
+    async fn watch<T>(
+        &self,
+        pipeline: Vec<Document>,
+        resume_after: Option<Document>,
+    ) -> Result<ChangeStream<T>, ServiceError>
+    where
+        T: DeserializeOwned + Send + 'static,
+    {
+        let service = self.service().clone();
+        run_blocking(move || service.watch(pipeline, resume_after)).await
+    }
+
+    async fn count(&self, filter: Option<Document>) -> Result<i64, ServiceError> {
+        let service = self.service().clone();
+        run_blocking(move || service.count(filter)).await
+    }
+
+    async fn count_by(
+        &self,
+        field: &str,
+        filter: Option<Document>,
+    ) -> Result<Vec<GroupCount>, ServiceError> {
+        let service = self.service().clone();
+        let field = field.to_owned();
+        run_blocking(move || service.count_by(&field, filter)).await
+    }
+
+    async fn find_one<T>(&self, filter: Document) -> Result<Option<T>, ServiceError>
+    where
+        T: DeserializeOwned + Send + 'static,
+    {
+        let service = self.service().clone();
+        run_blocking(move || service.find_one(filter)).await
+    }
+
+    async fn find_one_projected<T>(
+        &self,
+        filter: Document,
+        projection: Option<Projection>,
+    ) -> Result<Option<T>, ServiceError>
+    where
+        T: DeserializeOwned + Send + 'static,
+    {
+        let service = self.service().clone();
+        run_blocking(move || service.find_one_projected(filter, projection)).await
+    }
+
+    async fn find_one_where<T>(&self, filter: Filter) -> Result<Option<T>, ServiceError>
+    where
+        T: DeserializeOwned + Send + 'static,
+    {
+        let service = self.service().clone();
+        run_blocking(move || service.find_one_where(filter)).await
+    }
+
+    async fn find_one_by_object_id<T>(
+        &self,
+        field: &str,
+        value: ObjectId,
+    ) -> Result<Option<T>, ServiceError>
+    where
+        T: DeserializeOwned + Send + 'static,
+    {
+        let service = self.service().clone();
+        let field = field.to_owned();
+        run_blocking(move || service.find_one_by_object_id(&field, value)).await
+    }
+
+    async fn find_one_by_id<T>(&self, id: ID) -> Result<Option<T>, ServiceError>
+    where
+        T: DeserializeOwned + Send + 'static,
+    {
+        let service = self.service().clone();
+        run_blocking(move || service.find_one_by_id(id)).await
+    }
+
+    async fn find_one_by_id_projected<T>(
+        &self,
+        id: ID,
+        projection: Option<Projection>,
+    ) -> Result<Option<T>, ServiceError>
+    where
+        T: DeserializeOwned + Send + 'static,
+    {
+        let service = self.service().clone();
+        run_blocking(move || service.find_one_by_id_projected(id, projection)).await
+    }
+
+    async fn find_many_by_ids<T>(&self, ids: Vec<ID>) -> Result<Vec<Option<T>>, ServiceError>
+    where
+        T: DeserializeOwned + Send + 'static,
+    {
+        let service = self.service().clone();
+        run_blocking(move || service.find_many_by_ids(ids)).await
+    }
+
+    async fn find_one_by_string_value<T>(
+        &self,
+        field: &str,
+        value: &str,
+    ) -> Result<Option<T>, ServiceError>
+    where
+        T: DeserializeOwned + Send + 'static,
+    {
+        let service = self.service().clone();
+        let field = field.to_owned();
+        let value = value.to_owned();
+        run_blocking(move || service.find_one_by_string_value(&field, &value)).await
+    }
+
+    async fn find_one_by_i64<T>(&self, field: &str, value: i64) -> Result<Option<T>, ServiceError>
+    where
+        T: DeserializeOwned + Send + 'static,
+    {
+        let service = self.service().clone();
+        let field = field.to_owned();
+        run_blocking(move || service.find_one_by_i64(&field, value)).await
+    }
+
+    async fn insert_embedded<T>(
+        &self,
+        id: ID,
+        field_path: &str,
+        new_items: Vec<T>,
+        user_id: Option<ID>,
+    ) -> Result<Vec<ID>, ServiceError>
+    where
+        T: Serialize + Send + 'static,
+    {
+        let service = self.service().clone();
+        let field_path = field_path.to_owned();
+        run_blocking(move || service.insert_embedded(id, &field_path, new_items, user_id)).await
+    }
+
+    async fn upsert_embedded<T, U>(
+        &self,
+        id: ID,
+        field_path: &str,
+        new_items: Vec<T>,
+        user_id: Option<ID>,
+        parent: Option<U>,
+    ) -> Result<Vec<ID>, ServiceError>
+    where
+        T: Serialize + Send + 'static,
+        U: Serialize + Send + 'static,
+    {
+        let service = self.service().clone();
+        let field_path = field_path.to_owned();
+        run_blocking(move || service.upsert_embedded(id, &field_path, new_items, user_id, parent))
+            .await
+    }
+
+    async fn insert_one<T>(&self, new_item: T, user_id: Option<ID>) -> Result<ID, ServiceError>
+    where
+        T: Serialize + Send + 'static,
+    {
+        let service = self.service().clone();
+        run_blocking(move || service.insert_one(new_item, user_id)).await
+    }
+
+    async fn insert_many<T>(
+        &self,
+        new_items: Vec<T>,
+        user_id: Option<ID>,
+    ) -> Result<Vec<ID>, ServiceError>
+    where
+        T: Serialize + Send + 'static,
+    {
+        let service = self.service().clone();
+        run_blocking(move || service.insert_many(new_items, user_id)).await
+    }
+
+    async fn upsert_one<T>(
+        &self,
+        key: UpsertKey,
+        item: T,
+        user_id: Option<ID>,
+    ) -> Result<UpsertResponse, ServiceError>
+    where
+        T: Serialize + Send + 'static,
+    {
+        let service = self.service().clone();
+        run_blocking(move || service.upsert_one(key, item, user_id)).await
+    }
+
+    async fn upsert_many<T>(
+        &self,
+        items: Vec<(UpsertKey, T)>,
+        user_id: Option<ID>,
+    ) -> Result<Vec<Result<UpsertResponse, ServiceError>>, ServiceError>
+    where
+        T: Serialize + Send + 'static,
+    {
+        let service = self.service().clone();
+        run_blocking(move || Ok(service.upsert_many(items, user_id))).await
+    }
+
+    async fn delete_one_by_id(&self, id: ID) -> Result<DeleteResponse, ServiceError> {
+        let service = self.service().clone();
+        run_blocking(move || service.delete_one_by_id(id)).await
+    }
+
+    async fn delete_one_by_id_with_user(
+        &self,
+        id: ID,
+        user_id: Option<ID>,
+    ) -> Result<DeleteResponse, ServiceError> {
+        let service = self.service().clone();
+        run_blocking(move || service.delete_one_by_id_with_user(id, user_id)).await
+    }
+
+    async fn delete_one_by_query(&self, filter: Document) -> Result<bool, ServiceError> {
+        let service = self.service().clone();
+        run_blocking(move || service.delete_one_by_query(filter)).await
+    }
+
+    async fn delete_one_where(&self, filter: Filter) -> Result<bool, ServiceError> {
+        let service = self.service().clone();
+        run_blocking(move || service.delete_one_where(filter)).await
+    }
+
+    async fn update_many<T>(
+        &self,
+        filter: Option<Document>,
+        update_item: T,
+        user_id: Option<ID>,
+    ) -> Result<UpdateManyResponse, ServiceError>
+    where
+        T: Serialize + Send + 'static,
+    {
+        let service = self.service().clone();
+        run_blocking(move || service.update_many(filter, update_item, user_id)).await
+    }
+
+    async fn update_many_with_doc(
+        &self,
+        filter: Option<Document>,
+        update_doc: Document,
+        user_id: Option<ID>,
+    ) -> Result<UpdateManyResponse, ServiceError> {
+        let service = self.service().clone();
+        run_blocking(move || service.update_many_with_doc(filter, update_doc, user_id)).await
+    }
+
+    async fn delete_many(
+        &self,
+        filter: Option<Document>,
+    ) -> Result<DeleteManyResponse, ServiceError> {
+        let service = self.service().clone();
+        run_blocking(move || service.delete_many(filter)).await
+    }
+
+    async fn delete_embedded(
+        &self,
+        id: ID,
+        field_path: &str,
+        embedded_id: ID,
+    ) -> Result<DeleteResponse, ServiceError> {
+        let service = self.service().clone();
+        let field_path = field_path.to_owned();
+        run_blocking(move || service.delete_embedded(id, &field_path, embedded_id)).await
+    }
+
+    async fn delete_embedded_with_user(
+        &self,
+        id: ID,
+        field_path: &str,
+        embedded_id: ID,
+        user_id: Option<ID>,
+    ) -> Result<DeleteResponse, ServiceError> {
+        let service = self.service().clone();
+        let field_path = field_path.to_owned();
+        run_blocking(move || {
+            service.delete_embedded_with_user(id, &field_path, embedded_id, user_id)
+        })
+        .await
+    }
+
+    async fn update_embedded<T, U>(
+        &self,
+        id: ID,
+        field_path: &str,
+        embedded_id: ID,
+        update_item: T,
+        user_id: Option<ID>,
+    ) -> Result<U, ServiceError>
+    where
+        T: Serialize + Send + 'static,
+        U: DeserializeOwned + Send + 'static,
+    {
+        let service = self.service().clone();
+        let field_path = field_path.to_owned();
+        run_blocking(move || {
+            service.update_embedded(id, &field_path, embedded_id, update_item, user_id)
+        })
+        .await
+    }
+
+    async fn find_and_update_embedded<T, U>(
+        &self,
+        id: ID,
+        field_path: &str,
+        embedded_id: ID,
+        update_item: T,
+        user_id: Option<ID>,
+    ) -> Result<U, ServiceError>
+    where
+        T: Serialize + Send + 'static,
+        U: DeserializeOwned + Send + 'static,
+    {
+        let service = self.service().clone();
+        let field_path = field_path.to_owned();
+        run_blocking(move || {
+            service.find_and_update_embedded(id, &field_path, embedded_id, update_item, user_id)
+        })
+        .await
+    }
+
+    async fn find_and_update_embedded_item<T, U>(
+        &self,
+        id: ID,
+        field_path: &str,
+        embedded_id: ID,
+        update_item: T,
+        user_id: Option<ID>,
+    ) -> Result<U, ServiceError>
+    where
+        T: Serialize + Send + 'static,
+        U: DeserializeOwned + Send + 'static,
+    {
+        let service = self.service().clone();
+        let field_path = field_path.to_owned();
+        run_blocking(move || {
+            service.find_and_update_embedded_item(
+                id,
+                &field_path,
+                embedded_id,
+                update_item,
+                user_id,
+            )
+        })
+        .await
+    }
+
+    async fn insert_nested_embedded<T>(
+        &self,
+        id: ID,
+        path: &[(&str, ID)],
+        field: &str,
+        new_items: Vec<T>,
+        user_id: Option<ID>,
+    ) -> Result<Vec<ID>, ServiceError>
+    where
+        T: Serialize + Send + 'static,
+    {
+        let service = self.service().clone();
+        let path = owned_path(path);
+        let field = field.to_owned();
+        run_blocking(move || {
+            service.insert_nested_embedded(id, &borrowed_path(&path), &field, new_items, user_id)
+        })
+        .await
+    }
+
+    async fn update_nested_embedded<T, U>(
+        &self,
+        id: ID,
+        path: &[(&str, ID)],
+        update_item: T,
+        user_id: Option<ID>,
+    ) -> Result<U, ServiceError>
+    where
+        T: Serialize + Send + 'static,
+        U: DeserializeOwned + Send + 'static,
+    {
+        let service = self.service().clone();
+        let path = owned_path(path);
+        run_blocking(move || {
+            service.update_nested_embedded(id, &borrowed_path(&path), update_item, user_id)
+        })
+        .await
+    }
+
+    async fn delete_nested_embedded(
+        &self,
+        id: ID,
+        path: &[(&str, ID)],
+        user_id: Option<ID>,
+    ) -> Result<DeleteResponse, ServiceError> {
+        let service = self.service().clone();
+        let path = owned_path(path);
+        run_blocking(move || service.delete_nested_embedded(id, &borrowed_path(&path), user_id))
+            .await
+    }
+
+    async fn update_one<T, U>(
+        &self,
+        id: ID,
+        update_item: T,
+        user_id: Option<ID>,
+    ) -> Result<U, ServiceError>
+    where
+        T: Serialize + Send + 'static,
+        U: DeserializeOwned + Node + Send + 'static,
+    {
+        let service = self.service().clone();
+        run_blocking(move || service.update_one(id, update_item, user_id)).await
+    }
+
+    async fn update_one_with_doc<U>(&self, id: ID, update_doc: Document) -> Result<U, ServiceError>
+    where
+        U: DeserializeOwned + Send + 'static,
+    {
+        let service = self.service().clone();
+        run_blocking(move || service.update_one_with_doc(id, update_doc)).await
+    }
+
+    async fn update_one_versioned<T, U>(
+        &self,
+        id: ID,
+        expected_version: i64,
+        update_item: T,
+        user_id: Option<ID>,
+    ) -> Result<U, ServiceError>
+    where
+        T: Serialize + Send + 'static,
+        U: DeserializeOwned + Node + Send + 'static,
+    {
+        let service = self.service().clone();
+        run_blocking(move || {
+            service.update_one_versioned(id, expected_version, update_item, user_id)
+        })
+        .await
+    }
+
+    async fn update_embedded_versioned<T, U>(
+        &self,
+        id: ID,
+        field_path: &str,
+        embedded_id: ID,
+        expected_version: i64,
+        update_item: T,
+        user_id: Option<ID>,
+    ) -> Result<U, ServiceError>
+    where
+        T: Serialize + Send + 'static,
+        U: DeserializeOwned + Send + 'static,
+    {
+        let service = self.service().clone();
+        let field_path = field_path.to_owned();
+        run_blocking(move || {
+            service.update_embedded_versioned(
+                id,
+                &field_path,
+                embedded_id,
+                expected_version,
+                update_item,
+                user_id,
+            )
+        })
+        .await
+    }
+
+    async fn soft_delete_by_id(
+        &self,
+        id: ID,
+        user_id: Option<ID>,
+    ) -> Result<DeleteResponse, ServiceError> {
+        let service = self.service().clone();
+        run_blocking(move || service.soft_delete_by_id(id, user_id)).await
+    }
+
+    async fn soft_delete_embedded(
+        &self,
+        id: ID,
+        field_path: &str,
+        embedded_id: ID,
+        user_id: Option<ID>,
+    ) -> Result<DeleteResponse, ServiceError> {
+        let service = self.service().clone();
+        let field_path = field_path.to_owned();
+        run_blocking(move || service.soft_delete_embedded(id, &field_path, embedded_id, user_id))
+            .await
+    }
+
+    async fn restore_by_id(&self, id: ID) -> Result<bool, ServiceError> {
+        let service = self.service().clone();
+        run_blocking(move || service.restore_by_id(id)).await
+    }
+
+    async fn restore_embedded(
+        &self,
+        id: ID,
+        field_path: &str,
+        embedded_id: ID,
+    ) -> Result<bool, ServiceError> {
+        let service = self.service().clone();
+        let field_path = field_path.to_owned();
+        run_blocking(move || service.restore_embedded(id, &field_path, embedded_id)).await
+    }
+
+    async fn find_deleted<T>(
+        &self,
+        filter: Option<Document>,
+        sort: Option<Document>,
+        limit: Option<i32>,
+        after: Option<String>,
+        before: Option<String>,
+        skip: Option<i32>,
+    ) -> Result<FindResult<T>, ServiceError>
+    where
+        T: DeserializeOwned + Send + 'static,
+    {
+        let service = self.service().clone();
+        run_blocking(move || service.find_deleted(filter, sort, limit, after, before, skip)).await
+    }
+
+    async fn find_deleted_embedded<U>(
+        &self,
+        id: ID,
+        field: &str,
+        limit: Option<i32>,
+        skip: Option<i32>,
+    ) -> Result<Vec<U>, ServiceError>
+    where
+        U: DeserializeOwned + Send + 'static,
+    {
+        let service = self.service().clone();
+        let field = field.to_owned();
+        run_blocking(move || service.find_deleted_embedded(id, &field, limit, skip)).await
+    }
+
+    async fn purge_deleted_older_than(&self, age: Duration) -> Result<i64, ServiceError> {
+        let service = self.service().clone();
+        run_blocking(move || service.purge_deleted_older_than(age)).await
+    }
+
+    async fn purge_deleted_embedded_older_than(
+        &self,
+        field_path: &str,
+        age: Duration,
+    ) -> Result<i64, ServiceError> {
+        let service = self.service().clone();
+        let field_path = field_path.to_owned();
+        run_blocking(move || service.purge_deleted_embedded_older_than(&field_path, age)).await
+    }
+    async fn find_history(
+        &self,
+        id: ID,
+        limit: Option<i32>,
+        after: Option<String>,
+        before: Option<String>,
+        skip: Option<i32>,
+    ) -> Result<FindResult<HistoryRecord>, ServiceError> {
+        let service = self.service().clone();
+        run_blocking(move || service.find_history(id, limit, after, before, skip)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockCollection;
+    use crate::mongo::{AsyncMongoService, MongoService};
+    use crate::AsyncDataSources;
+    use bson::doc;
+    use serde::Deserialize;
+
+    #[derive(Debug, Deserialize)]
+    struct Pet {
+        name: String,
+    }
+
+    #[tokio::test]
+    async fn test_async_service_round_trip() {
+        let mut data_sources = AsyncDataSources::new();
+        data_sources.create_mock_service("pets", None);
+        let service = data_sources.get_mongo_service("pets").unwrap();
+
+        let id = service
+            .insert_one(doc! { "_id": "rex", "name": "Rex" }, None)
+            .await
+            .unwrap();
+        let pet: Option<Pet> = service.find_one_by_id(id.clone()).await.unwrap();
+        assert_eq!(pet.unwrap().name, "Rex");
+
+        let pets: FindResult<Pet> = service
+            .find(None, None, None, None, None, None)
+            .await
+            .unwrap();
+        assert_eq!(pets.total_count, 1);
+
+        assert!(service
+            .delete_one_by_query(doc! { "_id": "rex" })
+            .await
+            .unwrap());
+        let pet: Option<Pet> = service.find_one_by_id(id).await.unwrap();
+        assert!(pet.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_async_service_returns_errors() {
+        let mut data_sources = AsyncDataSources::new();
+        data_sources.create_mock_service("pets", None);
+        let service = data_sources.get_mongo_service("pets").unwrap();
+
+        let missing: Result<Document, ServiceError> = service
+            .update_one_with_doc(ID::with_string("rex"), doc! { "$set": { "name": "Rex" } })
+            .await;
+        match missing {
+            Err(ServiceError::NotFound(_)) => {}
+            other => panic!("expected NotFound, got {:?}", other),
+        }
+
+        service
+            .insert_one(doc! { "_id": "rex", "name": "Rex" }, None)
+            .await
+            .unwrap();
+        match service
+            .insert_one(doc! { "_id": "rex", "name": "Rex" }, None)
+            .await
+        {
+            Err(ServiceError::MongoError(_)) => {}
+            other => panic!("expected a duplicate key error, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_async_service_keeps_the_service_configuration() {
+        let collection = MockCollection::with_documents("pets", vec![doc! { "_id": "rex" }]);
+        let service: AsyncMongoService = MongoService::with_data_source(collection.clone(), None)
+            .with_soft_delete()
+            .into();
+
+        service
+            .delete_one_by_id(ID::with_string("rex"))
+            .await
+            .unwrap();
+        let pet: Option<Document> = service
+            .find_one_by_id(ID::with_string("rex"))
+            .await
+            .unwrap();
+        assert!(pet.is_none());
+        let stored = collection.documents();
+        assert!(stored[0]
+            .get_document("node")
+            .unwrap()
+            .contains_key("date_deleted"));
+    }
+
+    #[tokio::test]
+    async fn test_async_calls_can_run_concurrently() {
+        let mut data_sources = AsyncDataSources::new();
+        let collection = data_sources.create_mock_service("pets", None);
+        let service = data_sources.get_mongo_service("pets").unwrap();
+
+        let (rex, fido) = tokio::join!(
+            service.insert_one(doc! { "_id": "rex" }, None),
+            service.insert_one(doc! { "_id": "fido" }, None),
+        );
+        assert_eq!(rex.unwrap(), ID::with_string("rex"));
+        assert_eq!(fido.unwrap(), ID::with_string("fido"));
+        assert_eq!(collection.documents().len(), 2);
+    }
+}
@@ -1,16 +1,34 @@
 use bson::{doc, oid::ObjectId, Bson, Document};
 
 use log::{debug, warn};
-use mongodb::options::{FindOneOptions, FindOptions, InsertManyOptions, UpdateOptions};
+use mongodb::options::{
+    FindOneAndUpdateOptions, FindOneOptions, FindOptions, InsertManyOptions, ReturnDocument,
+    UpdateOptions,
+};
 use mongodb::Collection;
-use mongodb_cursor_pagination::{CursorDirections, FindResult, PaginatedCursor};
+use mongodb_cursor_pagination::{CursorDirections, FindResult};
 use serde::{Deserialize, Serialize};
-use std::time::SystemTime;
-use voca_rs::case::snake_case;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, SystemTime};
 
+use crate::cache::{CacheStats, ItemCache};
+use crate::change_stream::{on_document_key, on_full_document, ChangeStream};
+use crate::cursor::PageQuery;
+use crate::data_source::DataSource;
 use crate::error::ServiceError;
+use crate::filter::Filter;
+use crate::history::{HistoryOperation, HistoryRecord};
+use crate::hooks::ServiceHooks;
 use crate::id::ID;
+use crate::index::IndexSpec;
 use crate::node::Node;
+use crate::projection::{hidden_within, hide, is_hidden, Projection};
+use crate::relation::Relation;
+use crate::search::{
+    all_of, text_search_pipeline, SearchMode, SearchQuery, TermMatch, TEXT_SCORE_FIELD,
+};
+use crate::tenant::TenantScope;
+use crate::validation::{FieldError, ValidationScope, Validator};
 
 #[derive(Serialize, Deserialize)]
 pub struct DeleteResponse {
@@ -35,7 +53,104 @@ impl From<DeleteResponse> for DeleteResponseGQL {
     }
 }
 
+/// The outcome of `update_many`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UpdateManyResponse {
+    pub matched_count: i64,
+    pub modified_count: i64,
+}
+
+#[cfg(feature = "graphql")]
+#[derive(Serialize, Deserialize, juniper::GraphQLObject)]
+pub struct UpdateManyResponseGQL {
+    matched_count: i32,
+    modified_count: i32,
+}
+
+#[cfg(feature = "graphql")]
+impl From<UpdateManyResponse> for UpdateManyResponseGQL {
+    fn from(r: UpdateManyResponse) -> UpdateManyResponseGQL {
+        UpdateManyResponseGQL {
+            matched_count: r.matched_count as i32,
+            modified_count: r.modified_count as i32,
+        }
+    }
+}
+
+/// The outcome of `delete_many`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DeleteManyResponse {
+    pub deleted_count: i64,
+}
+
+#[cfg(feature = "graphql")]
+#[derive(Serialize, Deserialize, juniper::GraphQLObject)]
+pub struct DeleteManyResponseGQL {
+    deleted_count: i32,
+}
+
+#[cfg(feature = "graphql")]
+impl From<DeleteManyResponse> for DeleteManyResponseGQL {
+    fn from(r: DeleteManyResponse) -> DeleteManyResponseGQL {
+        DeleteManyResponseGQL {
+            deleted_count: r.deleted_count as i32,
+        }
+    }
+}
+
+/// What `upsert_one` matches the stored item by.
+#[derive(Clone, Debug, PartialEq)]
+pub enum UpsertKey {
+    Id(ID),
+    /// A natural key, eg. `{ "sku": "A-1" }`, which should be backed by a unique index.
+    Filter(Document),
+}
+
+/// The outcome of `upsert_one`, and of each item of `upsert_many`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UpsertResponse {
+    pub id: ID,
+    pub inserted: bool,
+}
+
+#[cfg(feature = "graphql")]
+#[derive(Serialize, Deserialize, juniper::GraphQLObject)]
+pub struct UpsertResponseGQL {
+    id: ID,
+    inserted: bool,
+}
+
+#[cfg(feature = "graphql")]
+impl From<UpsertResponse> for UpsertResponseGQL {
+    fn from(r: UpsertResponse) -> UpsertResponseGQL {
+        UpsertResponseGQL {
+            id: r.id,
+            inserted: r.inserted,
+        }
+    }
+}
+
+/// How the `total_count` of the pages returned by `find` and `search` is worked out.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CountMode {
+    /// Counts the matching items with `count_documents`.
+    Exact,
+    /// Takes the count from the collection's metadata when nothing filters the query, ie. no
+    /// filter, no `default_filter` and no soft delete, and counts exactly otherwise. Cheap on
+    /// large collections, but it can be off after unclean shutdowns or during chunk migrations.
+    Estimated,
+}
+
+/// The number of items sharing a value, as returned by `count_by`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GroupCount {
+    pub value: Bson,
+    pub count: i64,
+}
+
 const DEFAULT_LIMIT: i64 = 25;
+const DATE_DELETED: &str = "node.date_deleted";
+const VERSION: &str = "node.version";
 
 #[cfg(not(any(test, feature = "test")))]
 fn now() -> SystemTime {
@@ -96,12 +211,778 @@ pub mod mock_time {
 #[cfg(any(test, feature = "test"))]
 pub use mock_time::now;
 
+fn timestamp() -> u64 {
+    now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("Unable to retrieve time")
+        .as_secs()
+}
+
+fn date_deleted(item: &Document) -> Option<i64> {
+    match item
+        .get_document("node")
+        .map(|node| node.get("date_deleted"))
+    {
+        Ok(Some(Bson::I64(date))) => Some(*date),
+        Ok(Some(Bson::I32(date))) => Some(i64::from(*date)),
+        _ => None,
+    }
+}
+
+fn is_deleted(item: &Bson) -> bool {
+    match item {
+        Bson::Document(d) => date_deleted(d).is_some(),
+        _ => false,
+    }
+}
+
+/// Finds an embedded item within its parent document.
+fn embedded_item<'d>(
+    parent: &'d Document,
+    field_path: &str,
+    id_parameter: &str,
+    embedded_id: &Bson,
+) -> Option<&'d Document> {
+    parent
+        .get_array(field_path)
+        .ok()?
+        .iter()
+        .find_map(|item| match item {
+            Bson::Document(d) if d.get(id_parameter) == Some(embedded_id) => Some(d),
+            _ => None,
+        })
+}
+
+/// Follows a path of `(field, id)` pairs down nested embedded arrays.
+fn nested_item<'d>(
+    parent: &'d Document,
+    path: &[(&str, ID)],
+    id_parameter: &str,
+) -> Option<&'d Document> {
+    path.iter().try_fold(parent, |item, (field, embedded_id)| {
+        embedded_item(item, field, id_parameter, &embedded_id.to_bson())
+    })
+}
+
+/// `nested_item`, unless the item or any embedded item along `path` is in the trash.
+fn live_nested_item<'d>(
+    parent: &'d Document,
+    path: &[(&str, ID)],
+    id_parameter: &str,
+) -> Option<&'d Document> {
+    path.iter().try_fold(parent, |item, (field, embedded_id)| {
+        embedded_item(item, field, id_parameter, &embedded_id.to_bson())
+            .filter(|item| date_deleted(item).is_none())
+    })
+}
+
+/// The dotted path of the arrays along `path`, eg. `sections.items`, as used by validation,
+/// hooks and history.
+fn nested_field_path(path: &[(&str, ID)]) -> String {
+    path.iter()
+        .map(|(field, _)| *field)
+        .collect::<Vec<&str>>()
+        .join(".")
+}
+
+/// The update paths of the embedded items along `path`, eg. `sections.$[e0]` and
+/// `sections.$[e0].items.$[e1]`, along with the `arrayFilters` that pick them out.
+fn nested_positions(path: &[(&str, ID)], id_parameter: &str) -> (Vec<String>, Vec<Document>) {
+    let mut positions: Vec<String> = Vec::new();
+    let mut array_filters = Vec::new();
+    for (level, (field, embedded_id)) in path.iter().enumerate() {
+        let position = match positions.last() {
+            Some(parent) => format!("{}.{}.$[e{}]", parent, field, level),
+            None => format!("{}.$[e{}]", field, level),
+        };
+        positions.push(position);
+        array_filters.push(doc! { format!("e{}.{}", level, id_parameter): embedded_id.to_bson() });
+    }
+    (positions, array_filters)
+}
+
+/// Stamps `node.date_modified` on the item and each of the embedded items at `positions`, and
+/// bumps the version of the item itself so that a versioned update of it notices the change.
+/// The embedded item being written bumps its own version, the ones above it keep theirs.
+fn with_level_stamps(mut update: Document, positions: &[String]) -> Document {
+    let now = timestamp();
+    let mut stamp = doc! { "node.date_modified": now };
+    for position in positions.iter() {
+        stamp.insert(format!("{}.node.date_modified", position), now);
+    }
+    match update.get_document_mut("$set") {
+        Ok(set_doc) => set_doc.extend(stamp),
+        Err(_) => {
+            update.insert("$set", stamp);
+        }
+    }
+    with_version_bump(update, VERSION)
+}
+
+fn array_filter_options(array_filters: Vec<Document>) -> Option<UpdateOptions> {
+    Some(
+        UpdateOptions::builder()
+            .array_filters(if array_filters.is_empty() {
+                None
+            } else {
+                Some(array_filters)
+            })
+            .build(),
+    )
+}
+
+/// History records for newly added embedded items, `ids` lines up with `items`.
+fn embedded_insert_records(
+    id: &ID,
+    field_path: &str,
+    ids: &[ID],
+    items: &[Document],
+    user_id: &Option<ID>,
+    timestamp: u64,
+) -> Vec<HistoryRecord> {
+    ids.iter()
+        .zip(items.iter())
+        .map(|(embedded_id, item)| {
+            HistoryRecord::new(id.clone(), HistoryOperation::Insert, timestamp as i64)
+                .embedded(field_path, embedded_id.clone())
+                .diff(None, Some(item))
+                .user(user_id.clone())
+        })
+        .collect()
+}
+
+/// The ids of embedded items about to be written, one per item so they stay aligned with them.
+fn embedded_ids(items: &[Document]) -> Result<Vec<ID>, ServiceError> {
+    items
+        .iter()
+        .map(|item| match item.get("_id") {
+            Some(id) => stored_id(id),
+            None => Err(ServiceError::ParseError(
+                "Embedded item is missing its _id".into(),
+            )),
+        })
+        .collect()
+}
+
+/// The ID of a document the data source reported back, refusing an `_id` that can't be an ID
+/// instead of panicking.
+fn stored_id(value: &Bson) -> Result<ID, ServiceError> {
+    ID::try_with_bson(value).ok_or_else(|| {
+        warn!("Unsupported id type {:?}", value);
+        ServiceError::ParseError(format!("Unsupported id type {:?}", value))
+    })
+}
+
+/// Keeps the embedded items that are (or are not) in the trash and applies skip/limit.
+fn filter_embedded<'a, U>(
+    embedded: &[Bson],
+    deleted: bool,
+    limit: i64,
+    skip: Option<i32>,
+) -> Result<Vec<U>, ServiceError>
+where
+    U: serde::Deserialize<'a>,
+{
+    let items: Vec<Bson> = embedded
+        .iter()
+        .filter(|item| is_deleted(item) == deleted)
+        .skip(skip.unwrap_or(0).max(0) as usize)
+        .take(limit.max(0) as usize)
+        .cloned()
+        .collect();
+    Ok(bson::from_bson(Bson::Array(items))?)
+}
+
+/// Builds a partial update of an item along with its node details and version bump. The
+/// `array_path` targets an embedded item, eg. `comments.$`.
+fn node_update<T>(
+    update_item: &T,
+    array_path: Option<&str>,
+    user_id: Option<ID>,
+) -> Result<Document, ServiceError>
+where
+    T: serde::Serialize,
+{
+    let prefix = array_path.map_or(String::new(), |path| format!("{}.", path));
+    if let Bson::Document(document) = bson::to_bson(update_item)? {
+        let mut set_doc = Document::new();
+        for (key, value) in document.iter() {
+            set_doc.insert(format!("{}{}", prefix, key), value.clone());
+        }
+        set_doc.insert(format!("{}node.date_modified", prefix), timestamp());
+        if let Some(uid) = user_id {
+            set_doc.insert(format!("{}node.updated_by_id", prefix), uid.to_bson());
+        }
+        Ok(doc! { "$set": set_doc, "$inc": { format!("{}{}", prefix, VERSION): 1_i64 } })
+    } else {
+        Err("Invalid update document".into())
+    }
+}
+
+/// Adds the version bump to an update document made of update operators.
+fn with_version_bump(mut update: Document, path: &str) -> Document {
+    if update.is_empty() || !update.keys().all(|key| key.starts_with('$')) {
+        // replacement documents can't be combined with operators
+        return update;
+    }
+    match update.get_document_mut("$inc") {
+        Ok(inc) => {
+            inc.insert(path, 1_i64);
+        }
+        Err(_) => {
+            update.insert("$inc", doc! { path: 1_i64 });
+        }
+    }
+    update
+}
+
+/// Adds the node details `update_one` stamps to an update document made of update operators.
+fn with_node_stamp(mut update: Document, user_id: Option<ID>) -> Document {
+    if update.is_empty() || !update.keys().all(|key| key.starts_with('$')) {
+        return update;
+    }
+    let mut stamp = doc! { "node.date_modified": timestamp() };
+    if let Some(uid) = user_id {
+        stamp.insert("node.updated_by_id", uid.to_bson());
+    }
+    match update.get_document_mut("$set") {
+        Ok(set_doc) => set_doc.extend(stamp),
+        Err(_) => {
+            update.insert("$set", stamp);
+        }
+    }
+    with_version_bump(update, VERSION)
+}
+
+/// Items written before versioning was added have no version and count as version 0.
+fn version_condition(expected_version: i64) -> Bson {
+    if expected_version == 0 {
+        Bson::Null
+    } else {
+        Bson::I64(expected_version)
+    }
+}
+
+fn current_version(item: &Document) -> i64 {
+    match item.get_document("node").map(|node| node.get("version")) {
+        Ok(Some(Bson::I64(version))) => *version,
+        Ok(Some(Bson::I32(version))) => i64::from(*version),
+        _ => 0,
+    }
+}
+
+/// Runs a paginated find, with an estimated `total_count` where the `CountMode` allows it.
+fn find_page(
+    coll: &dyn DataSource,
+    count_mode: CountMode,
+    filter: Option<Document>,
+    options: FindOptions,
+    cursor: Option<String>,
+    direction: CursorDirections,
+) -> Result<FindResult<Document>, ServiceError> {
+    let is_filtered = filter.as_ref().is_some_and(|f| !f.is_empty());
+    if count_mode == CountMode::Exact || is_filtered {
+        return coll.find_page(filter, options, cursor, direction);
+    }
+    let page = PageQuery::new(
+        options.sort.clone(),
+        options.limit,
+        options.skip,
+        cursor,
+        direction,
+    )?;
+    let total_count = coll.estimated_document_count()?;
+    let found = if total_count > 0 {
+        let mut options = options;
+        options.sort = Some(page.query_sort());
+        options.skip = Some(page.skip());
+        options.limit = Some(page.limit());
+        coll.find(Some(page.filter(None)), Some(options))?
+    } else {
+        Vec::new()
+    };
+    page.page(found, total_count)
+}
+
+fn deserialize_page<'a, T>(page: FindResult<Document>) -> Result<FindResult<T>, ServiceError>
+where
+    T: serde::Deserialize<'a>,
+{
+    let mut items: Vec<T> = Vec::with_capacity(page.items.len());
+    for item_doc in page.items {
+        items.push(bson::from_bson(bson::Bson::Document(item_doc))?);
+    }
+    Ok(FindResult {
+        page_info: page.page_info,
+        edges: page.edges,
+        total_count: page.total_count,
+        items,
+    })
+}
+
+/// Restricts a filter to the items of the service's tenant, if it has one.
+fn scoped<'a, S>(service: &S, filter: Document) -> Document
+where
+    S: BaseService<'a> + ?Sized,
+{
+    match service.tenant_scope() {
+        Some(scope) => scope.apply(filter),
+        None => filter,
+    }
+}
+
+/// Keeps an update from moving items out of the service's tenant, if it has one.
+fn scoped_update<'a, S>(service: &S, update: Document) -> Result<Document, ServiceError>
+where
+    S: BaseService<'a> + ?Sized,
+{
+    match service.tenant_scope() {
+        Some(scope) => scope.restrict(update),
+        None => Ok(update),
+    }
+}
+
+/// Adds the exclusion of soft deleted items to a filter, unless soft delete is disabled or the
+/// filter already says something about `node.date_deleted`. The filter is `scoped` as well.
+pub(crate) fn exclude_deleted<'a, S>(service: &S, mut filter: Document) -> Document
+where
+    S: BaseService<'a> + ?Sized,
+{
+    if service.uses_soft_delete() && !filter.contains_key(DATE_DELETED) {
+        filter.insert(DATE_DELETED, Bson::Null);
+    }
+    scoped(service, filter)
+}
+
+/// Adds the condition matching the embedded item `embedded_id` of `field_path` to a filter,
+/// leaving the item out once it is in the trash.
+fn with_live_embedded<'a, S>(
+    service: &S,
+    mut filter: Document,
+    field_path: &str,
+    embedded_id: &ID,
+) -> Document
+where
+    S: BaseService<'a> + ?Sized,
+{
+    if service.uses_soft_delete() {
+        filter.insert(
+            field_path,
+            doc! {
+                "$elemMatch": {
+                    service.id_parameter(): embedded_id.to_bson(),
+                    DATE_DELETED: Bson::Null,
+                }
+            },
+        );
+    } else {
+        filter.insert(
+            format!("{}.{}", field_path, service.id_parameter()),
+            embedded_id.to_bson(),
+        );
+    }
+    filter
+}
+
+/// Writes history records, stamped with the service's tenant. The change has already been
+/// made when this runs, so a failure is returned to the caller rather than undone.
+fn record_history<'a, S>(service: &S, records: Vec<HistoryRecord>) -> Result<(), ServiceError>
+where
+    S: BaseService<'a> + ?Sized,
+{
+    let history = match service.history_data_source() {
+        Some(history) if !records.is_empty() => history,
+        _ => return Ok(()),
+    };
+    let hidden = service.hidden_fields();
+    let tenant = service.tenant_scope().and_then(TenantScope::tenant);
+    let documents = records
+        .into_iter()
+        .map(|record| record.tenant(tenant.cloned()).hiding(&hidden).to_document())
+        .collect::<Result<Vec<Document>, ServiceError>>()?;
+    history.insert_many(documents, None)?;
+    Ok(())
+}
+
+/// Reads the item a write is about to change, but only when history is being kept.
+fn history_snapshot<'a, S>(service: &S, filter: &Document) -> Result<Option<Document>, ServiceError>
+where
+    S: BaseService<'a> + ?Sized,
+{
+    match service.history_data_source() {
+        Some(_) => service.data_source().find_one(Some(filter.clone()), None),
+        None => Ok(None),
+    }
+}
+
+/// Records the history of newly inserted items and runs their `after_insert` hooks.
+fn finish_insert<'a, S>(
+    service: &S,
+    inserted: Vec<(ID, Document)>,
+    user_id: &Option<ID>,
+    timestamp: u64,
+) -> Result<(), ServiceError>
+where
+    S: BaseService<'a> + ?Sized,
+{
+    if service.history_data_source().is_some() {
+        record_history(
+            service,
+            inserted
+                .iter()
+                .map(|(id, document)| {
+                    HistoryRecord::new(id.clone(), HistoryOperation::Insert, timestamp as i64)
+                        .diff(None, Some(document))
+                        .user(user_id.clone())
+                })
+                .collect(),
+        )?;
+    }
+    if let Some(hooks) = service.hooks() {
+        for (id, document) in inserted.iter() {
+            hooks.after_insert(id, document, user_id.as_ref())?;
+        }
+    }
+    Ok(())
+}
+
+/// Records the history of newly added embedded items and runs their
+/// `after_insert_embedded` hooks, `ids` lines up with `items`.
+fn finish_insert_embedded<'a, S>(
+    service: &S,
+    id: &ID,
+    field_path: &str,
+    ids: &[ID],
+    items: &[Document],
+    user_id: &Option<ID>,
+    timestamp: u64,
+) -> Result<(), ServiceError>
+where
+    S: BaseService<'a> + ?Sized,
+{
+    if service.history_data_source().is_some() {
+        record_history(
+            service,
+            embedded_insert_records(id, field_path, ids, items, user_id, timestamp),
+        )?;
+    }
+    if let Some(hooks) = service.hooks() {
+        for (embedded_id, item) in ids.iter().zip(items.iter()) {
+            hooks.after_insert_embedded(id, field_path, embedded_id, item, user_id.as_ref())?;
+        }
+    }
+    Ok(())
+}
+
+/// Runs the validator, failing with `ServiceError::ValidationError` when anything is wrong.
+fn validate<'a, S>(
+    service: &S,
+    document: &Document,
+    scope: ValidationScope,
+    field_path: Option<&str>,
+) -> Result<(), ServiceError>
+where
+    S: BaseService<'a> + ?Sized,
+{
+    let errors = match service.validator() {
+        Some(validator) => validator.validate(document, scope, field_path),
+        None => return Ok(()),
+    };
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(ServiceError::ValidationError(errors))
+    }
+}
+
+/// Validates a batch of new items, the field paths of their errors are prefixed with the
+/// index of the item, eg. `2.title`.
+fn validate_all<'a, S>(
+    service: &S,
+    documents: &[Document],
+    field_path: Option<&str>,
+) -> Result<(), ServiceError>
+where
+    S: BaseService<'a> + ?Sized,
+{
+    let validator = match service.validator() {
+        Some(validator) => validator,
+        None => return Ok(()),
+    };
+    let mut errors = Vec::new();
+    for (index, document) in documents.iter().enumerate() {
+        for error in validator.validate(document, ValidationScope::Full, field_path) {
+            errors.push(FieldError {
+                field: format!("{}.{}", index, error.field),
+                message: error.message,
+            });
+        }
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(ServiceError::ValidationError(errors))
+    }
+}
+
+/// Validates the fields of a partial update.
+fn validate_update<'a, S, T>(
+    service: &S,
+    update_item: &T,
+    field_path: Option<&str>,
+) -> Result<(), ServiceError>
+where
+    S: BaseService<'a> + ?Sized,
+    T: serde::Serialize,
+{
+    if service.validator().is_none() {
+        return Ok(());
+    }
+    match bson::to_bson(update_item)? {
+        Bson::Document(document) => {
+            validate(service, &document, ValidationScope::Partial, field_path)
+        }
+        _ => Err("Invalid update document".into()),
+    }
+}
+
+/// The projection document of a read: `projection` (or every field) without the
+/// `hidden_fields` it doesn't ask for, keeping the fields of `sort`. Sorting by a hidden field
+/// the projection doesn't ask for is refused.
+fn read_projection<'a, S>(
+    service: &S,
+    projection: Option<&Projection>,
+    sort: Option<&Document>,
+) -> Result<Option<Document>, ServiceError>
+where
+    S: BaseService<'a> + ?Sized,
+{
+    projection
+        .cloned()
+        .unwrap_or_default()
+        .resolve(&service.hidden_fields(), sort)
+}
+
+/// Deserializes an item a write read back, without its hidden fields.
+fn visible<'a, S, U>(service: &S, mut document: Document) -> Result<U, ServiceError>
+where
+    S: BaseService<'a> + ?Sized,
+    U: serde::Deserialize<'a>,
+{
+    hide(&mut document, &service.hidden_fields());
+    Ok(bson::from_bson(Bson::Document(document))?)
+}
+
+/// The embedded items of `field`, without the hidden fields within them. A hidden `field`
+/// has no items to show.
+fn visible_embedded<'a, S>(service: &S, field: &str, embedded: &[Bson]) -> Vec<Bson>
+where
+    S: BaseService<'a> + ?Sized,
+{
+    let hidden = service.hidden_fields();
+    if is_hidden(&hidden, field) {
+        return Vec::new();
+    }
+    let within = hidden_within(&hidden, field);
+    embedded
+        .iter()
+        .cloned()
+        .map(|mut item| {
+            if let Bson::Document(document) = &mut item {
+                hide(document, &within);
+            }
+            item
+        })
+        .collect()
+}
+
+/// Leaves the hidden fields out of a pipeline's input, right after the stages that have to
+/// come first.
+fn without_hidden<'a, S>(service: &S, mut pipeline: Vec<Document>) -> Vec<Document>
+where
+    S: BaseService<'a> + ?Sized,
+{
+    let hidden = service.hidden_fields();
+    if hidden.is_empty() {
+        return pipeline;
+    }
+    let mut projection = Document::new();
+    for field in hidden {
+        projection.insert(field, 0);
+    }
+    let first = match pipeline.first() {
+        Some(stage) if stage.contains_key("$match") || stage.contains_key("$geoNear") => 1,
+        _ => 0,
+    };
+    pipeline.insert(first, doc! { "$project": projection });
+    pipeline
+}
+
+/// Pages through the output of an aggregation pipeline, see `BaseService::aggregate`.
+#[allow(clippy::too_many_arguments)]
+fn aggregate_page<'a, S>(
+    service: &S,
+    pipeline: Vec<Document>,
+    sort: Option<Document>,
+    limit: Option<i32>,
+    after: Option<String>,
+    before: Option<String>,
+    skip: Option<i32>,
+) -> Result<FindResult<Document>, ServiceError>
+where
+    S: BaseService<'a> + ?Sized,
+{
+    let coll = service.data_source();
+    let is_previous_query = before.is_some() && after.is_none();
+    let (cursor, direction) = if is_previous_query {
+        (before, CursorDirections::Previous)
+    } else {
+        (after, CursorDirections::Next)
+    };
+    let page = PageQuery::new(
+        Some(sort.unwrap_or_else(|| service.default_sort())),
+        Some(limit.map_or(service.default_limit(), i64::from)),
+        skip.map(i64::from),
+        cursor,
+        direction,
+    )?;
+    let mut pipeline = pipeline;
+    if service.uses_soft_delete() || service.tenant_scope().is_some() {
+        // join a leading $match rather than going first, as stages like $text must
+        match pipeline
+            .first_mut()
+            .and_then(|stage| stage.get_mut("$match"))
+        {
+            Some(Bson::Document(filter)) => *filter = exclude_deleted(service, filter.clone()),
+            _ => pipeline.insert(
+                0,
+                doc! { "$match": exclude_deleted(service, Document::new()) },
+            ),
+        }
+    }
+
+    let mut count_pipeline = pipeline.clone();
+    count_pipeline.push(doc! { "$count": "total_count" });
+    let total_count = match coll.aggregate(count_pipeline, None)?.first() {
+        Some(counted) => match counted.get("total_count") {
+            Some(Bson::I32(count)) => i64::from(*count),
+            Some(Bson::I64(count)) => *count,
+            _ => 0,
+        },
+        None => 0,
+    };
+    let found = if total_count > 0 {
+        let filter = page.filter(None);
+        if !filter.is_empty() {
+            pipeline.push(doc! { "$match": filter });
+        }
+        pipeline.push(doc! { "$sort": page.query_sort() });
+        if page.skip() > 0 {
+            pipeline.push(doc! { "$skip": page.skip() });
+        }
+        pipeline.push(doc! { "$limit": page.limit() });
+        coll.aggregate(pipeline, None)?
+    } else {
+        Vec::new()
+    };
+    page.page(found, total_count)
+}
+
+/// Drops an item from the cache, after it was written to.
+fn forget_cached<'a, S>(service: &S, id: &ID)
+where
+    S: BaseService<'a> + ?Sized,
+{
+    if let Some(cache) = service.cache() {
+        cache.invalidate(id);
+    }
+}
+
+/// Empties the cache, after a write that may have touched any item.
+fn forget_all_cached<'a, S>(service: &S)
+where
+    S: BaseService<'a> + ?Sized,
+{
+    if let Some(cache) = service.cache() {
+        cache.clear();
+    }
+}
+
+/// The filter of a bulk write, falling back to the `default_filter` like `find` does.
+fn bulk_filter<'a, S>(service: &S, filter: Option<Document>) -> Document
+where
+    S: BaseService<'a> + ?Sized,
+{
+    let filter = match filter {
+        Some(f) => f,
+        None => service.default_filter().cloned().unwrap_or_default(),
+    };
+    exclude_deleted(service, filter)
+}
+
+/// Reads the items a bulk write is about to change, but only when history or hooks need them.
+fn bulk_targets<'a, S>(
+    service: &S,
+    filter: &Document,
+) -> Result<Option<Vec<Document>>, ServiceError>
+where
+    S: BaseService<'a> + ?Sized,
+{
+    if service.history_data_source().is_none() && service.hooks().is_none() {
+        return Ok(None);
+    }
+    Ok(Some(
+        service.data_source().find(Some(filter.clone()), None)?,
+    ))
+}
+
+/// Narrows a bulk write down to the items with the given stored ids, so that history and hooks
+/// see exactly the items that get written.
+fn bulk_target_filter<'a, S>(service: &S, ids: Vec<Bson>) -> Document
+where
+    S: BaseService<'a> + ?Sized,
+{
+    exclude_deleted(service, doc! { service.id_parameter(): { "$in": ids } })
+}
+
+/// The stored ids of items, in the shape `bulk_target_filter` takes them.
+fn stored_ids<'a, S>(service: &S, items: &[Document]) -> Vec<Bson>
+where
+    S: BaseService<'a> + ?Sized,
+{
+    items
+        .iter()
+        .filter_map(|item| item.get(service.id_parameter()).cloned())
+        .collect()
+}
+
+/// Pairs items up with their ids, skipping the ones whose stored id can't be an `ID` (they are
+/// still written, but history and hooks can't refer to them).
+fn with_ids<'a, 'd, S>(service: &S, items: &'d [Document]) -> Vec<(ID, &'d Document)>
+where
+    S: BaseService<'a> + ?Sized,
+{
+    items
+        .iter()
+        .filter_map(|item| {
+            let id = item.get(service.id_parameter())?;
+            match ID::try_with_bson(id) {
+                Some(id) => Some((id, item)),
+                None => {
+                    warn!("Skipping the history and hooks of an item with id {:?}", id);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
 pub trait BaseService<'a> {
     fn new(collection: &Collection, default_sort: Option<Document>) -> Self;
     fn id_parameter(&self) -> &'static str {
         "_id"
     }
-    fn data_source(&self) -> &Collection;
+    fn data_source(&self) -> &dyn DataSource;
     fn default_sort(&self) -> Document {
         doc! { self.id_parameter(): 1 }
     }
@@ -114,6 +995,65 @@ pub trait BaseService<'a> {
     fn generate_id(&self) -> Option<String> {
         None
     }
+    /// When enabled, deletes only mark items with `node.date_deleted` and reads skip them.
+    fn uses_soft_delete(&self) -> bool {
+        false
+    }
+
+    /// How the `total_count` of `find` and `search` pages is worked out, see `CountMode`.
+    fn count_mode(&self) -> CountMode {
+        CountMode::Exact
+    }
+
+    /// The relations to other services that `DataSources::populate` can fill in.
+    fn relations(&self) -> Vec<Relation> {
+        Vec::new()
+    }
+
+    /// The indexes the service's queries rely on, created by `DataSources::ensure_indexes`.
+    fn indexes(&self) -> Vec<IndexSpec> {
+        Vec::new()
+    }
+
+    /// How `search` matches, see `SearchMode`.
+    fn search_mode(&self) -> SearchMode {
+        SearchMode::Regex
+    }
+
+    /// Hooks that run around every write, see `ServiceHooks`.
+    fn hooks(&self) -> Option<&dyn ServiceHooks> {
+        None
+    }
+
+    /// Checks items before they are written, see `Validator`.
+    fn validator(&self) -> Option<&dyn Validator> {
+        None
+    }
+
+    /// The tenant every query and write is scoped to, see `TenantScope`.
+    fn tenant_scope(&self) -> Option<&TenantScope> {
+        None
+    }
+
+    /// Fields left out of every read unless its `Projection` asks for them, eg. password hashes.
+    fn hidden_fields(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// Caches the items read by `find_one_by_id`, see `ItemCache`.
+    fn cache(&self) -> Option<&ItemCache> {
+        None
+    }
+
+    /// The hit and miss counters of the cache, `None` when nothing is cached.
+    fn cache_stats(&self) -> Option<CacheStats> {
+        self.cache().map(ItemCache::stats)
+    }
+
+    /// The companion collection that change history is written to, `None` keeps no history.
+    fn history_data_source(&self) -> Option<&dyn DataSource> {
+        None
+    }
 
     fn find<T>(
         &self,
@@ -124,10 +1064,30 @@ pub trait BaseService<'a> {
         before: Option<String>,
         skip: Option<i32>,
     ) -> Result<FindResult<T>, ServiceError>
+    where
+        T: serde::Deserialize<'a>,
+    {
+        self.find_projected(filter, None, sort, limit, after, before, skip)
+    }
+
+    /// `find` that only returns the fields of `projection`, see `Projection`.
+    #[allow(clippy::too_many_arguments)]
+    fn find_projected<T>(
+        &self,
+        filter: Option<Document>,
+        projection: Option<Projection>,
+        sort: Option<Document>,
+        limit: Option<i32>,
+        after: Option<String>,
+        before: Option<String>,
+        skip: Option<i32>,
+    ) -> Result<FindResult<T>, ServiceError>
     where
         T: serde::Deserialize<'a>,
     {
         let coll = self.data_source();
+        // TODO: make this not something arbitrary for testing purposes
+        let sort = sort.unwrap_or_else(|| self.default_sort());
         // build the options object
         let find_options = FindOptions::builder()
             .limit(if let Some(l) = limit {
@@ -136,25 +1096,33 @@ pub trait BaseService<'a> {
                 self.default_limit()
             })
             .skip(if let Some(s) = skip { s as i64 } else { 0 })
-            // TODO: make this not something arbitrary for testing purposes
-            .sort(if let Some(s) = sort {
-                s
-            } else {
-                self.default_sort()
-            })
+            .projection(read_projection(self, projection.as_ref(), Some(&sort))?)
+            .sort(sort)
             .build();
         let is_previous_query = before.is_some() && after.is_none();
-        let query_cursor = if is_previous_query {
-            PaginatedCursor::new(Some(find_options), before, Some(CursorDirections::Previous))
+        let (cursor, direction) = if is_previous_query {
+            (before, CursorDirections::Previous)
         } else {
-            PaginatedCursor::new(Some(find_options), after, None)
+            (after, CursorDirections::Next)
+        };
+        let filter = match filter {
+            Some(f) => Some(f),
+            None => self.default_filter().cloned(),
         };
-        let find_results: FindResult<T> = if let Some(f) = filter {
-            query_cursor.find(&coll, Some(&f))?
+        let filter = if self.uses_soft_delete() || self.tenant_scope().is_some() {
+            Some(exclude_deleted(self, filter.unwrap_or_default()))
         } else {
-            query_cursor.find(&coll, self.default_filter())?
+            filter
         };
-        Ok(find_results)
+        let page = find_page(
+            coll,
+            self.count_mode(),
+            filter,
+            find_options,
+            cursor,
+            direction,
+        )?;
+        deserialize_page(page)
     }
 
     fn get_embedded_by_id<U>(
@@ -168,22 +1136,37 @@ pub trait BaseService<'a> {
         U: serde::Deserialize<'a>,
     {
         let coll = self.data_source();
-        let find_options = FindOneOptions::builder()
-            .projection(Some(doc! {
+        // soft deleted items have to be filtered out before they can be sliced
+        let projection = if self.uses_soft_delete() {
+            doc! { field: 1 }
+        } else {
+            doc! {
                 field: {
                     "$slice": [ skip.unwrap_or(0), limit.unwrap_or(self.default_limit() as i32) ]
                 }
-            }))
+            }
+        };
+        let find_options = FindOneOptions::builder()
+            .projection(Some(projection))
             .build();
-        let query = Some(doc! { self.id_parameter(): id.to_bson() });
+        let query = Some(exclude_deleted(
+            self,
+            doc! { self.id_parameter(): id.to_bson() },
+        ));
         let find_result = coll.find_one(query, Some(find_options))?;
         match find_result {
             Some(result) => {
                 let embedded_result = result.get_array(field);
                 match embedded_result {
+                    Ok(embedded) if self.uses_soft_delete() => filter_embedded(
+                        &visible_embedded(self, field, embedded),
+                        false,
+                        limit.map_or(self.default_limit(), i64::from),
+                        skip,
+                    ),
                     Ok(embedded) => {
-                        let docs = bson::from_bson(bson::Bson::Array(embedded.clone()))?;
-                        Ok(docs)
+                        let embedded = visible_embedded(self, field, embedded);
+                        Ok(bson::from_bson(Bson::Array(embedded))?)
                     }
                     Err(e) => Err(ServiceError::ParseError(e.to_string())),
                 }
@@ -192,58 +1175,264 @@ pub trait BaseService<'a> {
         }
     }
 
-    fn search<T>(
+    /// Pages through the embedded items of `field` in the item `id` with the same limit, skip
+    /// and cursors as `find`. The `filter` and `sort` (or the default sort) name fields of the
+    /// embedded items, eg. `{ "author_id": .. }` and `{ "node.date_created": -1 }`, and
+    /// `total_count` counts the embedded items matching the filter. Soft deleted embedded items
+    /// are left out.
+    #[allow(clippy::too_many_arguments)]
+    fn find_embedded<U>(
         &self,
-        search_term: String,
-        fields: Vec<String>,
+        id: ID,
+        field: &str,
+        filter: Option<Document>,
         sort: Option<Document>,
         limit: Option<i32>,
         after: Option<String>,
         before: Option<String>,
         skip: Option<i32>,
-    ) -> Result<FindResult<T>, ServiceError>
+    ) -> Result<FindResult<U>, ServiceError>
     where
-        T: serde::Deserialize<'a>,
+        U: serde::Deserialize<'a>,
     {
-        let coll = self.data_source();
-        // build the options object
-        let find_options = FindOptions::builder()
+        let mut filter = filter.unwrap_or_default();
+        if self.uses_soft_delete() && !filter.contains_key(DATE_DELETED) {
+            filter.insert(DATE_DELETED, Bson::Null);
+        }
+        let mut pipeline = vec![
+            doc! { "$match": exclude_deleted(self, doc! { self.id_parameter(): id.to_bson() }) },
+            doc! { "$unwind": format!("${}", field) },
+            doc! { "$replaceRoot": { "newRoot": format!("${}", field) } },
+        ];
+        if !filter.is_empty() {
+            pipeline.push(doc! { "$match": filter });
+        }
+        self.aggregate(pipeline, sort, limit, after, before, skip)
+    }
+
+    /// Searches `fields` for the literal `search_term`, see `search_with` for more options.
+    fn search<T>(
+        &self,
+        search_term: String,
+        fields: Vec<String>,
+        sort: Option<Document>,
+        limit: Option<i32>,
+        after: Option<String>,
+        before: Option<String>,
+        skip: Option<i32>,
+    ) -> Result<FindResult<T>, ServiceError>
+    where
+        T: serde::Deserialize<'a>,
+    {
+        let query = SearchQuery::new(&search_term, fields);
+        self.search_with(query, sort, limit, after, before, skip)
+    }
+
+    /// Searches with the service's `SearchMode`, only returning items that also match the
+    /// query's filter and the `default_filter`.
+    fn search_with<T>(
+        &self,
+        query: SearchQuery,
+        sort: Option<Document>,
+        limit: Option<i32>,
+        after: Option<String>,
+        before: Option<String>,
+        skip: Option<i32>,
+    ) -> Result<FindResult<T>, ServiceError>
+    where
+        T: serde::Deserialize<'a>,
+    {
+        if let SearchMode::Text { .. } = self.search_mode() {
+            return self.text_search(query, sort, limit, after, before, skip);
+        }
+        let coll = self.data_source();
+        // TODO: make this not something arbitrary for testing purposes
+        let sort = sort.unwrap_or_else(|| self.default_sort());
+        // build the options object
+        let find_options = FindOptions::builder()
             .limit(if let Some(l) = limit {
                 l as i64
             } else {
                 self.default_limit()
             })
             .skip(if let Some(s) = skip { s as i64 } else { 0 })
-            // TODO: make this not something arbitrary for testing purposes
-            .sort(if let Some(s) = sort {
-                s
-            } else {
-                self.default_sort()
-            })
+            .projection(read_projection(
+                self,
+                query.projection.as_ref(),
+                Some(&sort),
+            )?)
+            .sort(sort)
             .build();
         let is_previous_query = before.is_some() && after.is_none();
-        let query_cursor = if is_previous_query {
-            PaginatedCursor::new(Some(find_options), before, Some(CursorDirections::Previous))
+        let (cursor, direction) = if is_previous_query {
+            (before, CursorDirections::Previous)
         } else {
-            PaginatedCursor::new(Some(find_options), after, None)
+            (after, CursorDirections::Next)
         };
-        let mut filter = doc! { "$or": [] };
-        let or_array = filter.get_array_mut("$or").unwrap();
-        for field in fields.iter().map(|f| snake_case(&f)) {
-            or_array.push(Bson::Document(
-                doc! { field: Bson::RegExp(search_term.clone(), "i".to_string()) },
-            ));
+        let filter = all_of(vec![
+            query.term_filter(),
+            self.default_filter().cloned().unwrap_or_default(),
+            query.filter.unwrap_or_default(),
+        ]);
+        let filter = exclude_deleted(self, filter);
+        let page = find_page(
+            coll,
+            self.count_mode(),
+            Some(filter),
+            find_options,
+            cursor,
+            direction,
+        )?;
+        deserialize_page(page)
+    }
+
+    /// Searches with `$text`, which needs a text index on the collection, using the language of
+    /// the service's `SearchMode::Text`. The fields of the query are left to the index and its
+    /// matching has to be `TermMatch::Literal`, its filter and the `default_filter` still apply.
+    /// Items are ranked by their relevance in `TEXT_SCORE_FIELD`, which is removed before they
+    /// are returned, and unless another `sort` is given the most relevant items come first.
+    fn text_search<T>(
+        &self,
+        query: SearchQuery,
+        sort: Option<Document>,
+        limit: Option<i32>,
+        after: Option<String>,
+        before: Option<String>,
+        skip: Option<i32>,
+    ) -> Result<FindResult<T>, ServiceError>
+    where
+        T: serde::Deserialize<'a>,
+    {
+        if query.matching != TermMatch::Literal {
+            return Err("Text search only matches terms with TermMatch::Literal".into());
+        }
+        let language = match self.search_mode() {
+            SearchMode::Text { language } => language,
+            SearchMode::Regex => None,
+        };
+        let filter = all_of(vec![
+            self.default_filter().cloned().unwrap_or_default(),
+            query.filter.unwrap_or_default(),
+        ]);
+        let mut pipeline = text_search_pipeline(&query.term, language.as_deref(), filter);
+        let sort = sort.unwrap_or_else(|| doc! { TEXT_SCORE_FIELD: -1 });
+        let projection = query.projection.unwrap_or_default();
+        pipeline.extend(projection.stages(&self.hidden_fields(), Some(&sort))?);
+        // the projection stages already leave out the hidden fields it doesn't reveal
+        let mut page = aggregate_page(self, pipeline, Some(sort), limit, after, before, skip)?;
+        for item in page.items.iter_mut() {
+            item.remove(TEXT_SCORE_FIELD);
+        }
+        deserialize_page(page)
+    }
+
+    /// Runs an aggregation pipeline and pages through its output with the same limit, skip and
+    /// cursors as `find`. The `sort` (or the default sort) is applied to the documents the
+    /// pipeline returns, so it has to name fields of its output. The pipeline doesn't see the
+    /// `hidden_fields`.
+    fn aggregate<T>(
+        &self,
+        pipeline: Vec<Document>,
+        sort: Option<Document>,
+        limit: Option<i32>,
+        after: Option<String>,
+        before: Option<String>,
+        skip: Option<i32>,
+    ) -> Result<FindResult<T>, ServiceError>
+    where
+        T: serde::Deserialize<'a>,
+    {
+        let pipeline = without_hidden(self, pipeline);
+        deserialize_page(aggregate_page(
+            self, pipeline, sort, limit, after, before, skip,
+        )?)
+    }
+
+    /// Opens a change stream on the collection. Changes to items outside the `default_filter`
+    /// or the tenant are skipped. Deletes only carry the deleted item's `documentKey`, so they
+    /// always come through without a `default_filter`, and with a tenant scope only when the
+    /// tenant field is part of the shard key (and thus of the `documentKey`). `pipeline` can
+    /// narrow the events down further, eg. `{ "$match": { "operationType": "insert" } }`.
+    /// Pass the `resume_token` of the last event handled to pick up where a subscriber left off.
+    /// MongoDB only offers change streams on replica sets and sharded clusters.
+    fn watch<T>(
+        &self,
+        pipeline: Vec<Document>,
+        resume_after: Option<Document>,
+    ) -> Result<ChangeStream<T>, ServiceError>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        let mut stages = Vec::new();
+        let filter = scoped(self, self.default_filter().cloned().unwrap_or_default());
+        if !filter.is_empty() {
+            // deletes only carry the documentKey, which holds the tenant field when it is part
+            // of the shard key
+            let mut deletes = match self.tenant_scope() {
+                Some(scope) => on_document_key(&scope.apply(Document::new())),
+                None => Document::new(),
+            };
+            deletes.insert("operationType", "delete");
+            stages.push(doc! { "$match": { "$or": [deletes, on_full_document(&filter)] } });
         }
-        let find_results: FindResult<T> = query_cursor.find(&coll, Some(&filter))?;
-        Ok(find_results)
+        stages.extend(pipeline);
+        let events = self.data_source().watch(stages, resume_after.clone())?;
+        Ok(ChangeStream::new(events, resume_after).hiding(self.hidden_fields()))
+    }
+
+    /// Counts the items matching `filter`, or the `default_filter` when it is `None`.
+    fn count(&self, filter: Option<Document>) -> Result<i64, ServiceError> {
+        let filter = bulk_filter(self, filter);
+        self.data_source().count_documents(Some(filter), None)
+    }
+
+    /// Counts the items matching `filter` (or the `default_filter`) per value of `field`, most
+    /// common first. Items missing the field are counted under `Bson::Null`.
+    fn count_by(
+        &self,
+        field: &str,
+        filter: Option<Document>,
+    ) -> Result<Vec<GroupCount>, ServiceError> {
+        let pipeline = vec![
+            doc! { "$match": bulk_filter(self, filter) },
+            doc! { "$group": { "_id": format!("${}", field), "count": { "$sum": 1 } } },
+            doc! { "$sort": { "count": -1, "_id": 1 } },
+        ];
+        let groups = self.data_source().aggregate(pipeline, None)?;
+        Ok(groups
+            .into_iter()
+            .map(|group| GroupCount {
+                value: group.get("_id").cloned().unwrap_or(Bson::Null),
+                count: match group.get("count") {
+                    Some(Bson::I32(count)) => i64::from(*count),
+                    Some(Bson::I64(count)) => *count,
+                    _ => 0,
+                },
+            })
+            .collect())
     }
 
     fn find_one<T>(&self, filter: Document) -> Result<Option<T>, ServiceError>
+    where
+        T: serde::Deserialize<'a>,
+    {
+        self.find_one_projected(filter, None)
+    }
+
+    /// `find_one` that only returns the fields of `projection`, see `Projection`.
+    fn find_one_projected<T>(
+        &self,
+        filter: Document,
+        projection: Option<Projection>,
+    ) -> Result<Option<T>, ServiceError>
     where
         T: serde::Deserialize<'a>,
     {
         let coll = self.data_source();
-        let find_result = coll.find_one(filter, None)?;
+        let find_options = FindOneOptions::builder()
+            .projection(read_projection(self, projection.as_ref(), None)?)
+            .build();
+        let find_result = coll.find_one(Some(exclude_deleted(self, filter)), Some(find_options))?;
         match find_result {
             Some(item_doc) => {
                 let doc = bson::from_bson(bson::Bson::Document(item_doc))?;
@@ -253,6 +1442,30 @@ pub trait BaseService<'a> {
         }
     }
 
+    /// `find` with a typed `Filter` instead of a filter document.
+    fn find_where<T>(
+        &self,
+        filter: Filter,
+        sort: Option<Document>,
+        limit: Option<i32>,
+        after: Option<String>,
+        before: Option<String>,
+        skip: Option<i32>,
+    ) -> Result<FindResult<T>, ServiceError>
+    where
+        T: serde::Deserialize<'a>,
+    {
+        self.find(Some(filter.to_document()), sort, limit, after, before, skip)
+    }
+
+    /// `find_one` with a typed `Filter` instead of a filter document.
+    fn find_one_where<T>(&self, filter: Filter) -> Result<Option<T>, ServiceError>
+    where
+        T: serde::Deserialize<'a>,
+    {
+        self.find_one(filter.to_document())
+    }
+
     fn find_one_by_object_id<T>(
         &self,
         field: &str,
@@ -261,27 +1474,118 @@ pub trait BaseService<'a> {
     where
         T: serde::Deserialize<'a>,
     {
-        let coll = self.data_source();
-        let query = Some(doc! { field => value });
-        let find_result = coll.find_one(query, None)?;
-        match find_result {
-            Some(item_doc) => {
-                let doc = bson::from_bson(bson::Bson::Document(item_doc))?;
-                Ok(Some(doc))
-            }
-            None => Ok(None),
-        }
+        self.find_one(doc! { field => value })
     }
 
     fn find_one_by_id<T>(&self, id: ID) -> Result<Option<T>, ServiceError>
     where
         T: serde::Deserialize<'a>,
     {
-        match id {
-            ID::String(s) => self.find_one_by_string_value(self.id_parameter(), &s),
-            ID::I64(i) => self.find_one_by_i64(self.id_parameter(), i),
-            ID::ObjectId(o) => self.find_one_by_object_id(self.id_parameter(), o),
+        self.find_one_by_id_projected(id, None)
+    }
+
+    /// `find_one_by_id` that only returns the fields of `projection`. Projected reads skip the
+    /// cache.
+    fn find_one_by_id_projected<T>(
+        &self,
+        id: ID,
+        projection: Option<Projection>,
+    ) -> Result<Option<T>, ServiceError>
+    where
+        T: serde::Deserialize<'a>,
+    {
+        if let (Some(cache), None) = (self.cache(), &projection) {
+            let generation = cache.generation();
+            let found = match cache.get(&id) {
+                // the cache is shared by every tenant of the service
+                Some(item_doc) => match self.tenant_scope() {
+                    Some(scope) if !scope.owns(&item_doc) => None,
+                    _ => Some(item_doc),
+                },
+                None => {
+                    let query = exclude_deleted(self, doc! { self.id_parameter(): id.to_bson() });
+                    let found = self.data_source().find_one(Some(query), None)?;
+                    if let Some(item_doc) = &found {
+                        cache.insert(id, item_doc.clone(), generation);
+                    }
+                    found
+                }
+            };
+            return match found {
+                Some(mut item_doc) => {
+                    // the cache keeps whole items
+                    hide(&mut item_doc, &self.hidden_fields());
+                    Ok(Some(bson::from_bson(bson::Bson::Document(item_doc))?))
+                }
+                None => Ok(None),
+            };
+        }
+        self.find_one_projected(doc! { self.id_parameter(): id.to_bson() }, projection)
+    }
+
+    /// Reads the items of `ids` with a single `$in` query, eg. for the related items of a page
+    /// in GraphQL resolvers. The items come back in the order of `ids`, with `None` for those
+    /// that don't exist (or are soft deleted). Cached items are taken from the cache.
+    fn find_many_by_ids<T>(&self, ids: Vec<ID>) -> Result<Vec<Option<T>>, ServiceError>
+    where
+        T: serde::Deserialize<'a>,
+    {
+        let mut found: HashMap<ID, Document> = HashMap::new();
+        let mut missing: Vec<ID> = Vec::new();
+        let mut seen: HashSet<&ID> = HashSet::new();
+        for id in ids.iter() {
+            if !seen.insert(id) {
+                continue;
+            }
+            match self.cache().and_then(|cache| cache.get(id)) {
+                // the cache is shared by every tenant of the service
+                Some(item_doc) => match self.tenant_scope() {
+                    Some(scope) if !scope.owns(&item_doc) => {}
+                    _ => {
+                        found.insert(id.clone(), item_doc);
+                    }
+                },
+                None => missing.push(id.clone()),
+            }
+        }
+        if !missing.is_empty() {
+            let generation = self.cache().map(ItemCache::generation);
+            let values: Vec<Bson> = missing.iter().map(ID::to_bson).collect();
+            let filter = exclude_deleted(self, doc! { self.id_parameter(): { "$in": values } });
+            // the cache keeps whole items
+            let projection = match self.cache() {
+                Some(_) => None,
+                None => read_projection(self, None, None)?,
+            };
+            let find_options = FindOptions::builder().projection(projection).build();
+            let documents = self.data_source().find(Some(filter), Some(find_options))?;
+            for id in missing {
+                let id_bson = id.to_bson();
+                let item_doc = documents
+                    .iter()
+                    .find(|item_doc| item_doc.get(self.id_parameter()) == Some(&id_bson));
+                if let Some(item_doc) = item_doc {
+                    if let (Some(cache), Some(generation)) = (self.cache(), generation) {
+                        cache.insert(id.clone(), item_doc.clone(), generation);
+                    }
+                    found.insert(id, item_doc.clone());
+                }
+            }
+        }
+        let hidden = self.hidden_fields();
+        let mut items = Vec::with_capacity(ids.len());
+        for id in ids.iter() {
+            let item = match found.get(id) {
+                Some(item_doc) => {
+                    let mut item_doc = item_doc.clone();
+                    hide(&mut item_doc, &hidden);
+                    Some(bson::from_bson(Bson::Document(item_doc))?)
+                }
+                None => None,
+            };
+            items.push(item);
         }
+        Ok(items)
     }
 
     fn find_one_by_string_value<T>(
@@ -292,32 +1596,14 @@ pub trait BaseService<'a> {
     where
         T: serde::Deserialize<'a>,
     {
-        let coll = self.data_source();
-        let query = Some(doc! { field => value });
-        let find_result = coll.find_one(query, None)?;
-        match find_result {
-            Some(item_doc) => {
-                let doc = bson::from_bson(bson::Bson::Document(item_doc))?;
-                Ok(Some(doc))
-            }
-            None => Ok(None),
-        }
+        self.find_one(doc! { field => value })
     }
 
     fn find_one_by_i64<T>(&self, field: &str, value: i64) -> Result<Option<T>, ServiceError>
     where
         T: serde::Deserialize<'a>,
     {
-        let coll = self.data_source();
-        let query = Some(doc! { field => value });
-        let find_result = coll.find_one(query, None)?;
-        match find_result {
-            Some(item_doc) => {
-                let doc = bson::from_bson(bson::Bson::Document(item_doc))?;
-                Ok(Some(doc))
-            }
-            None => Ok(None),
-        }
+        self.find_one(doc! { field => value })
     }
 
     fn insert_embedded<T>(
@@ -332,43 +1618,29 @@ pub trait BaseService<'a> {
     {
         // get the item
         let coll = self.data_source();
-        let query = doc! { self.id_parameter(): id.to_bson() };
-        let find_result = coll.find_one(Some(query.clone()), None).unwrap();
-        let mut inserted_ids: Vec<ID> = Vec::new();
-        let timestamp = now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .expect("Unable to retrieve time")
-            .as_secs();
+        let query = exclude_deleted(self, doc! { self.id_parameter(): id.to_bson() });
+        let find_result = coll.find_one(Some(query.clone()), None)?;
+        let timestamp = timestamp();
         match find_result {
             None => Err(ServiceError::NotFound("Unable to find item".into())),
             Some(_item) => {
                 // insert it
-                let serialized_members = new_items.iter().fold(Vec::new(), |mut acc, item| {
+                let mut serialized_members = new_items.iter().fold(Vec::new(), |mut acc, item| {
                     match bson::to_bson(&item) {
                         Ok(serialized_member) => {
                             if let bson::Bson::Document(mut document) = serialized_member {
                                 let mut node_details = Document::new();
                                 node_details.insert("date_created", timestamp);
                                 node_details.insert("date_modified", timestamp);
+                                node_details.insert("version", 1_i64);
                                 if let Some(uid) = &user_id {
                                     node_details.insert("created_by_id", uid.to_bson());
                                     node_details.insert("updated_by_id", uid.to_bson());
                                 }
-                                let fallback_id = uuid::Uuid::new_v4().to_hyphenated().to_string();
-                                if let Some(insert_id) = document.get("_id") {
-                                    match insert_id {
-                                        Bson::Null => {
-                                            document.insert("_id", &fallback_id);
-                                            inserted_ids.push(ID::String(fallback_id));
-                                        }
-                                        _ => {
-                                            let i: ID = ID::with_bson(insert_id);
-                                            inserted_ids.push(i);
-                                        }
-                                    }
-                                } else {
-                                    document.insert("_id", &fallback_id);
-                                    inserted_ids.push(ID::String(fallback_id));
+                                if document.get("_id").map_or(true, |id| *id == Bson::Null) {
+                                    let fallback_id =
+                                        uuid::Uuid::new_v4().to_hyphenated().to_string();
+                                    document.insert("_id", fallback_id);
                                 }
                                 document.insert("node", node_details);
                                 acc.push(document);
@@ -379,8 +1651,26 @@ pub trait BaseService<'a> {
                     acc
                 });
 
+                validate_all(self, &serialized_members, Some(field_path))?;
+                if let Some(hooks) = self.hooks() {
+                    for item in serialized_members.iter_mut() {
+                        hooks.before_insert_embedded(&id, field_path, item, user_id.as_ref())?;
+                    }
+                }
+                let inserted_ids = embedded_ids(&serialized_members)?;
+                let inserted = serialized_members.clone();
                 let update_doc = doc! { "$push": { field_path: { "$each": serialized_members } } };
                 let _result = coll.update_one(query, update_doc, None)?;
+                forget_cached(self, &id);
+                finish_insert_embedded(
+                    self,
+                    &id,
+                    field_path,
+                    &inserted_ids,
+                    &inserted,
+                    &user_id,
+                    timestamp,
+                )?;
                 Ok(inserted_ids)
             }
         }
@@ -400,30 +1690,37 @@ pub trait BaseService<'a> {
     {
         // get the item
         let coll = self.data_source();
-        let query = doc! { self.id_parameter(): id.to_bson() };
-        let mut inserted_ids: Vec<ID> = Vec::new();
-        let timestamp = now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .expect("Unable to retrieve time")
-            .as_secs();
+        let query = exclude_deleted(self, doc! { self.id_parameter(): id.to_bson() });
+        if self.uses_soft_delete() {
+            // upserting would otherwise try to insert a second item with the trashed item's id
+            let trashed = scoped(
+                self,
+                doc! {
+                    self.id_parameter(): id.to_bson(),
+                    DATE_DELETED: { "$ne": Bson::Null },
+                },
+            );
+            if coll.find_one(Some(trashed), None)?.is_some() {
+                return Err(ServiceError::Trashed(id));
+            }
+        }
+        let timestamp = timestamp();
         // insert it
-        let serialized_members = new_items.iter().fold(Vec::new(), |mut acc, item| {
+        let mut serialized_members = new_items.iter().fold(Vec::new(), |mut acc, item| {
             match bson::to_bson(&item) {
                 Ok(serialized_member) => {
                     if let bson::Bson::Document(mut document) = serialized_member {
                         let mut node_details = Document::new();
                         node_details.insert("date_created", timestamp);
                         node_details.insert("date_modified", timestamp);
+                        node_details.insert("version", 1_i64);
                         if let Some(uid) = &user_id {
                             node_details.insert("created_by_id", uid.to_bson());
                             node_details.insert("updated_by_id", uid.to_bson());
                         }
-                        if let Some(insert_id) = document.get("_id") {
-                            inserted_ids.push(ID::with_bson(insert_id));
-                        } else {
+                        if !document.contains_key("_id") {
                             let insert_id = uuid::Uuid::new_v4().to_hyphenated().to_string();
-                            document.insert("_id", &insert_id);
-                            inserted_ids.push(ID::with_string(insert_id));
+                            document.insert("_id", insert_id);
                         }
                         document.insert("node", node_details);
                         acc.push(document);
@@ -434,22 +1731,50 @@ pub trait BaseService<'a> {
             acc
         });
 
+        validate_all(self, &serialized_members, Some(field_path))?;
+        if let Some(hooks) = self.hooks() {
+            for item in serialized_members.iter_mut() {
+                hooks.before_insert_embedded(&id, field_path, item, user_id.as_ref())?;
+            }
+        }
+        let inserted_ids = embedded_ids(&serialized_members)?;
+        let inserted = serialized_members.clone();
         let mut update_doc = doc! { "$push": { field_path: { "$each": serialized_members } } };
-        if parent.is_some() {
-            let serialized_parent = bson::to_bson(&parent)?;
-            update_doc.insert("$setOnInsert", serialized_parent);
+        let mut on_insert = match parent {
+            Some(parent) => bson::to_bson(&parent)?,
+            None => Bson::Document(Document::new()),
+        };
+        if let Some(scope) = self.tenant_scope() {
+            // a new item has to belong to the tenant even without a parent to insert
+            match &mut on_insert {
+                Bson::Document(parent) => scope.stamp(parent)?,
+                _ => return Err("Invalid parent document".into()),
+            }
+        }
+        if on_insert != Bson::Document(Document::new()) {
+            update_doc.insert("$setOnInsert", on_insert);
         }
         let _result = coll.update_one(
             query,
             update_doc,
-            UpdateOptions {
+            Some(UpdateOptions {
                 array_filters: None,
                 bypass_document_validation: None,
                 collation: None,
                 hint: None,
                 upsert: Some(true),
                 write_concern: None,
-            },
+            }),
+        )?;
+        forget_cached(self, &id);
+        finish_insert_embedded(
+            self,
+            &id,
+            field_path,
+            &inserted_ids,
+            &inserted,
+            &user_id,
+            timestamp,
         )?;
         Ok(inserted_ids)
     }
@@ -460,15 +1785,13 @@ pub trait BaseService<'a> {
     {
         let coll = self.data_source();
         let serialized_member = bson::to_bson(&new_item)?;
-        let timestamp = now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .expect("Unable to retrieve time")
-            .as_secs();
+        let timestamp = timestamp();
 
         if let bson::Bson::Document(mut document) = serialized_member {
             let mut node_details = Document::new();
             node_details.insert("date_created", timestamp);
             node_details.insert("date_modified", timestamp);
+            node_details.insert("version", 1_i64);
             if let Some(uid) = &user_id {
                 node_details.insert("created_by_id", uid.to_bson());
                 node_details.insert("updated_by_id", uid.to_bson());
@@ -483,8 +1806,17 @@ pub trait BaseService<'a> {
                     _ => debug!("id has value {}", temp_id),
                 }
             }
+            validate(self, &document, ValidationScope::Full, None)?;
+            if let Some(hooks) = self.hooks() {
+                hooks.before_insert(&mut document, user_id.as_ref())?;
+            }
+            if let Some(scope) = self.tenant_scope() {
+                scope.stamp(&mut document)?;
+            }
+            let inserted = document.clone();
             let result = coll.insert_one(document, None)?; // Insert into a MongoDB collection
-            let id = ID::with_bson(&result.inserted_id);
+            let id = stored_id(&result.inserted_id)?;
+            finish_insert(self, vec![(id.clone(), inserted)], &user_id, timestamp)?;
             Ok(id)
         } else {
             warn!("Error converting the BSON object into a MongoDB document");
@@ -503,17 +1835,15 @@ pub trait BaseService<'a> {
         T: serde::Serialize,
     {
         let coll = self.data_source();
-        let timestamp = now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .expect("Unable to retrieve time")
-            .as_secs();
-        let serialized_members = new_items.iter().fold(Vec::new(), |mut acc, item| {
+        let timestamp = timestamp();
+        let mut serialized_members = new_items.iter().fold(Vec::new(), |mut acc, item| {
             match bson::to_bson(&item) {
                 Ok(serialized_member) => {
                     if let bson::Bson::Document(mut document) = serialized_member {
                         let mut node_details = Document::new();
                         node_details.insert("date_created", timestamp);
                         node_details.insert("date_modified", timestamp);
+                        node_details.insert("version", 1_i64);
                         if let Some(uid) = &user_id {
                             node_details.insert("created_by_id", uid.to_bson());
                             node_details.insert("updated_by_id", uid.to_bson());
@@ -527,186 +1857,3352 @@ pub trait BaseService<'a> {
             acc
         });
 
+        validate_all(self, &serialized_members, None)?;
+        if let Some(hooks) = self.hooks() {
+            for document in serialized_members.iter_mut() {
+                hooks.before_insert(document, user_id.as_ref())?;
+            }
+        }
+        if let Some(scope) = self.tenant_scope() {
+            for document in serialized_members.iter_mut() {
+                scope.stamp(document)?;
+            }
+        }
+        let inserted = serialized_members.clone();
         let result = coll.insert_many(
             serialized_members,
-            InsertManyOptions {
+            Some(InsertManyOptions {
                 bypass_document_validation: None,
                 // dont stop if there's a failure on one item
                 ordered: Some(false),
                 write_concern: None,
-            },
+            }),
         )?;
-        let ids: Vec<ID> = result
+        let ids = result
             .inserted_ids
             .values()
-            .map(|i| ID::with_bson(i))
+            .map(stored_id)
+            .collect::<Result<Vec<ID>, ServiceError>>()?;
+        let inserted = result
+            .inserted_ids
+            .iter()
+            .filter_map(|(index, inserted_id)| {
+                let document = inserted.get(*index)?.clone();
+                Some((ID::try_with_bson(inserted_id)?, document))
+            })
             .collect();
+        finish_insert(self, inserted, &user_id, timestamp)?;
 
         Ok(ids)
     }
 
-    fn delete_one_by_id(&self, id: ID) -> Result<DeleteResponse, ServiceError> {
-        let coll = self.data_source();
-        let filter = doc! { self.id_parameter(): id.to_bson() };
-        let result = coll.delete_one(filter, None);
-        match result {
-            Ok(r) => Ok(DeleteResponse {
-                id,
-                success: r.deleted_count == 1,
-            }),
-            Err(e) => Err(e.into()),
-        }
-    }
-
-    fn delete_one_by_query(&self, filter: Document) -> Result<bool, ServiceError> {
-        let coll = self.data_source();
-        let result = coll.delete_one(filter, None);
-        match result {
-            Ok(r) => Ok(r.deleted_count == 1),
-            Err(e) => Err(e.into()),
-        }
-    }
-
-    fn delete_embedded(
-        &self,
-        id: ID,
-        field_path: &str,
-        embedded_id: ID,
-    ) -> Result<DeleteResponse, ServiceError> {
-        let coll = self.data_source();
-        let query = doc! { self.id_parameter(): &id.to_bson() };
-        let update_doc =
-            doc! { "$pull": { field_path: { self.id_parameter(): &embedded_id.to_bson()} } };
-        let _result = coll.update_one(query, update_doc, None)?;
-        Ok(DeleteResponse {
-            id: embedded_id,
-            success: true,
-        })
-    }
-
-    fn update_embedded<T, U>(
+    /// Inserts `item`, or updates the stored item matching `key` with its fields, in one atomic
+    /// write. `date_created` and `created_by_id` are only set on insert, while `date_modified`,
+    /// `updated_by_id` and the version change on every write.
+    ///
+    /// When the service has hooks, the stored item is looked up first to pick between the
+    /// `before_insert` and `before_update` hooks, and the `after_*` hook of what the write
+    /// turned out to be runs afterwards. An item in the trash isn't brought back by an upsert:
+    /// it fails with `ServiceError::Trashed` until the item is restored or purged.
+    fn upsert_one<T>(
         &self,
-        id: ID,
-        field_path: &str,
-        embedded_id: ID,
-        update_item: T,
+        key: UpsertKey,
+        item: T,
         user_id: Option<ID>,
-    ) -> Result<U, ServiceError>
+    ) -> Result<UpsertResponse, ServiceError>
     where
         T: serde::Serialize,
-        U: serde::Deserialize<'a>,
     {
         let coll = self.data_source();
-        let search_embedded = doc! {
-            self.id_parameter(): &id.to_bson(),
-            format!("{}.{}", field_path, self.id_parameter()): &embedded_id.to_bson(),
-        };
-        let serialized_member = bson::to_bson(&update_item)?;
-        let timestamp = now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .expect("Unable to retrieve time")
-            .as_secs();
-        if let bson::Bson::Document(document) = serialized_member {
-            let array_path = format!("{}.$", field_path);
-            let mut update_doc = Document::new();
-            for key in document.keys() {
-                let value = document.get(key);
-                if let Some(v) = value {
-                    update_doc.insert(format!("{}.{}", array_path, key), v.clone());
-                }
-            }
-            update_doc.insert(format!("{}.node.date_modified", array_path), timestamp);
-            if let Some(uid) = user_id {
-                update_doc.insert(format!("{}.node.updated_by_id", array_path), uid.to_bson());
-            }
-
-            let update = doc! { "$set": update_doc };
-            let search = doc! { self.id_parameter(): &id.to_bson() };
-            match coll.update_one(search_embedded, update, None) {
-                Ok(_res) => match coll.find_one(Some(search), None) {
-                    Ok(res) => match res {
-                        Some(doc) => {
-                            let item: U = bson::from_bson(bson::Bson::Document(doc))?;
-                            Ok(item)
+        let mut set_doc = match bson::to_bson(&item)? {
+            Bson::Document(document) => document,
+            _ => return Err("Invalid upsert document".into()),
+        };
+        // the node details are managed here and the id can't be changed
+        set_doc.remove("node");
+        let item_id = set_doc
+            .remove(self.id_parameter())
+            .filter(|id| *id != Bson::Null);
+        let (filter, insert_id) = match key {
+            UpsertKey::Id(id) => (doc! { self.id_parameter(): id.to_bson() }, id.to_bson()),
+            UpsertKey::Filter(filter) => {
+                let insert_id = item_id.unwrap_or_else(|| {
+                    Bson::ObjectId(ObjectId::new().expect("Unable to generate an ObjectId"))
+                });
+                (filter, insert_id)
+            }
+        };
+        let stored = if self.uses_soft_delete() || self.hooks().is_some() {
+            coll.find_one(Some(scoped(self, filter.clone())), None)?
+        } else {
+            None
+        };
+        let existing = match stored
+            .as_ref()
+            .and_then(|stored| stored.get(self.id_parameter()))
+        {
+            Some(id) => Some(stored_id(id)?),
+            None => None,
+        };
+        if let (Some(stored), Some(id)) = (&stored, &existing) {
+            if date_deleted(stored).is_some() {
+                return Err(ServiceError::Trashed(id.clone()));
+            }
+        }
+        validate(self, &set_doc, ValidationScope::Full, None)?;
+        if let (Some(hooks), None) = (self.hooks(), &existing) {
+            let mut document = set_doc.clone();
+            document.insert(self.id_parameter(), insert_id.clone());
+            hooks.before_insert(&mut document, user_id.as_ref())?;
+            document.remove(self.id_parameter());
+            document.remove("node");
+            set_doc = document;
+        }
+        let timestamp = timestamp();
+        let mut on_insert = doc! { "node.date_created": timestamp };
+        set_doc.insert("node.date_modified", timestamp);
+        if let Some(uid) = &user_id {
+            on_insert.insert("node.created_by_id", uid.to_bson());
+            set_doc.insert("node.updated_by_id", uid.to_bson());
+        }
+        if let Some(scope) = self.tenant_scope() {
+            scope.stamp(&mut set_doc)?;
+        }
+        if !filter.contains_key(self.id_parameter()) {
+            on_insert.insert(self.id_parameter(), insert_id.clone());
+        }
+        let mut update =
+            with_version_bump(doc! { "$set": set_doc, "$setOnInsert": on_insert }, VERSION);
+        if let (Some(hooks), Some(id)) = (self.hooks(), &existing) {
+            hooks.before_update(id, &mut update, user_id.as_ref())?;
+        }
+        let find_options = FindOneAndUpdateOptions::builder()
+            .upsert(Some(true))
+            .return_document(Some(ReturnDocument::Before))
+            .build();
+        let before = coll.find_one_and_update(
+            exclude_deleted(self, filter),
+            scoped_update(self, update)?,
+            Some(find_options),
+        )?;
+        let id = match before
+            .as_ref()
+            .and_then(|before| before.get(self.id_parameter()))
+        {
+            Some(id) => stored_id(id)?,
+            None => stored_id(&insert_id)?,
+        };
+        forget_cached(self, &id);
+        if self.history_data_source().is_some() || self.hooks().is_some() {
+            let search = scoped(self, doc! { self.id_parameter(): id.to_bson() });
+            if let Some(after) = coll.find_one(Some(search), None)? {
+                match &before {
+                    None => finish_insert(self, vec![(id.clone(), after)], &user_id, timestamp)?,
+                    Some(before) => {
+                        if let Some(hooks) = self.hooks() {
+                            hooks.after_update(&id, &after, user_id.as_ref())?;
                         }
-                        None => Err(ServiceError::NotFound("Unable to find item".to_owned())),
-                    },
-                    Err(t) => {
-                        warn!("Search failed");
-                        Err(ServiceError::from(t))
+                        record_history(
+                            self,
+                            vec![HistoryRecord::new(
+                                id.clone(),
+                                HistoryOperation::Update,
+                                timestamp as i64,
+                            )
+                            .diff(Some(before), Some(&after))
+                            .user(user_id)],
+                        )?;
                     }
-                },
-                Err(e) => Err(ServiceError::from(e)),
+                }
             }
-        } else {
-            Err("Unable to update document".into())
         }
+        Ok(UpsertResponse {
+            id,
+            inserted: before.is_none(),
+        })
     }
 
-    fn update_one<T, U>(
+    /// `upsert_one` for each of `items`, with the result of each, so that one failing item
+    /// doesn't keep the others from being written.
+    fn upsert_many<T>(
         &self,
-        id: ID,
-        update_item: T,
+        items: Vec<(UpsertKey, T)>,
         user_id: Option<ID>,
-    ) -> Result<U, ServiceError>
+    ) -> Vec<Result<UpsertResponse, ServiceError>>
     where
         T: serde::Serialize,
-        U: serde::Deserialize<'a> + Node,
     {
-        let coll = self.data_source();
-        let search = doc! { self.id_parameter(): id.to_bson() };
-        let serialized_member = bson::to_bson(&update_item)?;
-        let timestamp = now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .expect("Unable to retrieve time")
-            .as_secs();
-        if let bson::Bson::Document(mut document) = serialized_member {
-            document.insert("node.date_modified", timestamp);
-            if let Some(uid) = user_id {
-                document.insert("node.updated_by_id", uid.to_bson());
-            }
-            match coll.update_one(search.clone(), doc! {"$set": document}, None) {
-                Ok(_res) => match coll.find_one(Some(search), None) {
-                    Ok(res) => match res {
-                        Some(doc) => {
-                            let item: U = bson::from_bson(bson::Bson::Document(doc))?;
-                            Ok(item)
-                        }
-                        None => Err(ServiceError::NotFound("Unable to find item".to_owned())),
-                    },
-                    Err(t) => {
-                        warn!("Search failed");
-                        Err(ServiceError::from(t))
-                    }
-                },
-                Err(e) => Err(ServiceError::from(e)),
-            }
-        } else {
-            Err("Invalid update document".into())
-        }
+        items
+            .into_iter()
+            .map(|(key, item)| self.upsert_one(key, item, user_id.clone()))
+            .collect()
     }
 
-    fn update_one_with_doc<U>(&self, id: ID, update_doc: Document) -> Result<U, ServiceError>
-    where
-        U: serde::Deserialize<'a>,
-    {
-        let coll = self.data_source();
-        let search = doc! { self.id_parameter(): id.to_bson() };
-        match coll.update_one(search.clone(), update_doc, None) {
-            Ok(_res) => match coll.find_one(Some(search), None) {
+    fn delete_one_by_id(&self, id: ID) -> Result<DeleteResponse, ServiceError> {
+        self.delete_one_by_id_with_user(id, None)
+    }
+
+    /// `delete_one_by_id` on behalf of a user, who is recorded as `node.deleted_by_id` when soft
+    /// deleting and is passed on to the hooks and history.
+    fn delete_one_by_id_with_user(
+        &self,
+        id: ID,
+        user_id: Option<ID>,
+    ) -> Result<DeleteResponse, ServiceError> {
+        if self.uses_soft_delete() {
+            return self.soft_delete_by_id(id, user_id);
+        }
+        let coll = self.data_source();
+        if let Some(hooks) = self.hooks() {
+            hooks.before_delete(&id, user_id.as_ref())?;
+        }
+        let filter = scoped(self, doc! { self.id_parameter(): id.to_bson() });
+        let before = history_snapshot(self, &filter)?;
+        let result = coll.delete_one(filter, None);
+        forget_cached(self, &id);
+        match result {
+            Ok(r) => {
+                if let (Some(before), 1) = (before, r.deleted_count) {
+                    record_history(
+                        self,
+                        vec![HistoryRecord::new(
+                            id.clone(),
+                            HistoryOperation::Delete,
+                            timestamp() as i64,
+                        )
+                        .diff(Some(&before), None)
+                        .user(user_id.clone())],
+                    )?;
+                }
+                if let (Some(hooks), 1) = (self.hooks(), r.deleted_count) {
+                    hooks.after_delete(&id, user_id.as_ref())?;
+                }
+                Ok(DeleteResponse {
+                    id,
+                    success: r.deleted_count == 1,
+                })
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn delete_one_by_query(&self, filter: Document) -> Result<bool, ServiceError> {
+        let coll = self.data_source();
+        let filter = exclude_deleted(self, filter);
+        let before = if self.history_data_source().is_some() || self.hooks().is_some() {
+            coll.find_one(Some(filter.clone()), None)?
+        } else {
+            None
+        };
+        let target = before
+            .as_ref()
+            .and_then(|item| item.get(self.id_parameter()))
+            .and_then(ID::try_with_bson);
+        // narrow the query down to the item that history and hooks are told about
+        let filter = match &target {
+            Some(id) => exclude_deleted(self, doc! { self.id_parameter(): id.to_bson() }),
+            None => filter,
+        };
+        if let (Some(hooks), Some(id)) = (self.hooks(), &target) {
+            hooks.before_delete(id, None)?;
+        }
+        let deleted = if self.uses_soft_delete() {
+            let update = doc! {
+                "$set": { DATE_DELETED: timestamp() },
+                "$inc": { VERSION: 1_i64 },
+            };
+            coll.update_one(filter, update, None)?.modified_count == 1
+        } else {
+            coll.delete_one(filter, None)?.deleted_count == 1
+        };
+        match (deleted, &target) {
+            (true, Some(id)) => forget_cached(self, id),
+            (true, None) => forget_all_cached(self),
+            _ => {}
+        }
+        if let (true, Some(id)) = (deleted, target) {
+            let record = if self.uses_soft_delete() {
+                HistoryRecord::new(id.clone(), HistoryOperation::SoftDelete, timestamp() as i64)
+            } else {
+                HistoryRecord::new(id.clone(), HistoryOperation::Delete, timestamp() as i64)
+                    .diff(before.as_ref(), None)
+            };
+            record_history(self, vec![record])?;
+            if let Some(hooks) = self.hooks() {
+                hooks.after_delete(&id, None)?;
+            }
+        }
+        Ok(deleted)
+    }
+
+    /// `delete_one_by_query` with a typed `Filter` instead of a filter document.
+    fn delete_one_where(&self, filter: Filter) -> Result<bool, ServiceError> {
+        self.delete_one_by_query(filter.to_document())
+    }
+
+    /// Applies a partial update to every item matching `filter` (or the `default_filter`),
+    /// stamping their node details the same way `update_one` does.
+    fn update_many<T>(
+        &self,
+        filter: Option<Document>,
+        update_item: T,
+        user_id: Option<ID>,
+    ) -> Result<UpdateManyResponse, ServiceError>
+    where
+        T: serde::Serialize,
+    {
+        validate_update(self, &update_item, None)?;
+        let update = node_update(&update_item, None, user_id.clone())?;
+        self.apply_update_many(filter, update, user_id)
+    }
+
+    /// Like `update_many`, with an update document made of update operators.
+    fn update_many_with_doc(
+        &self,
+        filter: Option<Document>,
+        update_doc: Document,
+        user_id: Option<ID>,
+    ) -> Result<UpdateManyResponse, ServiceError> {
+        if let Ok(set_doc) = update_doc.get_document("$set") {
+            validate(self, set_doc, ValidationScope::Partial, None)?;
+        }
+        let update = with_node_stamp(update_doc, user_id.clone());
+        self.apply_update_many(filter, update, user_id)
+    }
+
+    /// Runs a stamped update over every matching item, along with its hooks and history. Each
+    /// item's `before_update` hook gets its own copy of the update, items whose hooks leave
+    /// the same update behind are written together.
+    fn apply_update_many(
+        &self,
+        filter: Option<Document>,
+        update: Document,
+        user_id: Option<ID>,
+    ) -> Result<UpdateManyResponse, ServiceError> {
+        let coll = self.data_source();
+        let filter = bulk_filter(self, filter);
+        let items = bulk_targets(self, &filter)?;
+        let targets = items.as_ref().map(|items| with_ids(self, items));
+        let writes = match (&items, &targets, self.hooks()) {
+            (Some(items), Some(targets), Some(hooks)) => {
+                let mut writes: Vec<(Document, Vec<Bson>)> = Vec::new();
+                for (id, item) in targets.iter() {
+                    let mut item_update = update.clone();
+                    hooks.before_update(id, &mut item_update, user_id.as_ref())?;
+                    let item_id = item.get(self.id_parameter()).cloned().unwrap_or(Bson::Null);
+                    match writes.iter_mut().find(|(other, _)| *other == item_update) {
+                        Some((_, ids)) => ids.push(item_id),
+                        None => writes.push((item_update, vec![item_id])),
+                    }
+                }
+                // items whose id can't be an `ID` have no hooks to run, they get the update as is
+                let unhooked: Vec<Bson> = stored_ids(self, items)
+                    .into_iter()
+                    .filter(|id| ID::try_with_bson(id).is_none())
+                    .collect();
+                if !unhooked.is_empty() {
+                    writes.push((update, unhooked));
+                }
+                writes
+                    .into_iter()
+                    .map(|(update, ids)| (bulk_target_filter(self, ids), update))
+                    .collect()
+            }
+            (Some(items), _, _) => {
+                vec![(bulk_target_filter(self, stored_ids(self, items)), update)]
+            }
+            _ => vec![(filter, update)],
+        };
+        let mut response = UpdateManyResponse {
+            matched_count: 0,
+            modified_count: 0,
+        };
+        for (filter, update) in writes {
+            let result = coll.update_many(filter, scoped_update(self, update)?, None)?;
+            response.matched_count += result.matched_count;
+            response.modified_count += result.modified_count;
+        }
+        forget_all_cached(self);
+        if let (Some(items), Some(targets)) = (&items, targets) {
+            let filter = bulk_target_filter(self, stored_ids(self, items));
+            let updated = coll.find(Some(filter), None)?;
+            let updated = with_ids(self, &updated);
+            record_history(
+                self,
+                updated
+                    .iter()
+                    .map(|(id, item)| {
+                        let before = targets
+                            .iter()
+                            .find(|(target, _)| target == id)
+                            .map(|(_, before)| *before);
+                        HistoryRecord::new(id.clone(), HistoryOperation::Update, timestamp() as i64)
+                            .diff(before, Some(item))
+                            .user(user_id.clone())
+                    })
+                    .collect(),
+            )?;
+            if let Some(hooks) = self.hooks() {
+                for (id, item) in updated.iter() {
+                    hooks.after_update(id, item, user_id.as_ref())?;
+                }
+            }
+        }
+        Ok(response)
+    }
+
+    /// Deletes every item matching `filter` (or the `default_filter`), which only moves them to
+    /// the trash when soft delete is enabled.
+    fn delete_many(&self, filter: Option<Document>) -> Result<DeleteManyResponse, ServiceError> {
+        let coll = self.data_source();
+        let filter = bulk_filter(self, filter);
+        let items = bulk_targets(self, &filter)?;
+        let filter = match &items {
+            Some(items) => bulk_target_filter(self, stored_ids(self, items)),
+            None => filter,
+        };
+        let targets = items.as_ref().map(|items| with_ids(self, items));
+        if let (Some(hooks), Some(targets)) = (self.hooks(), &targets) {
+            for (id, _) in targets.iter() {
+                hooks.before_delete(id, None)?;
+            }
+        }
+        let deleted_count = if self.uses_soft_delete() {
+            let update = doc! {
+                "$set": { DATE_DELETED: timestamp() },
+                "$inc": { VERSION: 1_i64 },
+            };
+            coll.update_many(filter, update, None)?.modified_count
+        } else {
+            coll.delete_many(filter, None)?.deleted_count
+        };
+        forget_all_cached(self);
+        if let Some(targets) = targets {
+            record_history(
+                self,
+                targets
+                    .iter()
+                    .map(|(id, before)| {
+                        if self.uses_soft_delete() {
+                            HistoryRecord::new(
+                                id.clone(),
+                                HistoryOperation::SoftDelete,
+                                timestamp() as i64,
+                            )
+                        } else {
+                            HistoryRecord::new(
+                                id.clone(),
+                                HistoryOperation::Delete,
+                                timestamp() as i64,
+                            )
+                            .diff(Some(*before), None)
+                        }
+                    })
+                    .collect(),
+            )?;
+            if let Some(hooks) = self.hooks() {
+                for (id, _) in targets.iter() {
+                    hooks.after_delete(id, None)?;
+                }
+            }
+        }
+        Ok(DeleteManyResponse { deleted_count })
+    }
+
+    fn delete_embedded(
+        &self,
+        id: ID,
+        field_path: &str,
+        embedded_id: ID,
+    ) -> Result<DeleteResponse, ServiceError> {
+        self.delete_embedded_with_user(id, field_path, embedded_id, None)
+    }
+
+    /// `delete_embedded` on behalf of a user, see `delete_one_by_id_with_user`.
+    fn delete_embedded_with_user(
+        &self,
+        id: ID,
+        field_path: &str,
+        embedded_id: ID,
+        user_id: Option<ID>,
+    ) -> Result<DeleteResponse, ServiceError> {
+        if self.uses_soft_delete() {
+            return self.soft_delete_embedded(id, field_path, embedded_id, user_id);
+        }
+        let coll = self.data_source();
+        if let Some(hooks) = self.hooks() {
+            hooks.before_delete_embedded(&id, field_path, &embedded_id, user_id.as_ref())?;
+        }
+        let query = exclude_deleted(self, doc! { self.id_parameter(): &id.to_bson() });
+        let before = history_snapshot(self, &query)?;
+        let update_doc =
+            doc! { "$pull": { field_path: { self.id_parameter(): &embedded_id.to_bson()} } };
+        let result = coll.update_one(query, update_doc, None)?;
+        forget_cached(self, &id);
+        let removed = before.as_ref().and_then(|parent| {
+            embedded_item(
+                parent,
+                field_path,
+                self.id_parameter(),
+                &embedded_id.to_bson(),
+            )
+        });
+        if let (Some(item), 1) = (removed, result.modified_count) {
+            record_history(
+                self,
+                vec![
+                    HistoryRecord::new(id.clone(), HistoryOperation::Delete, timestamp() as i64)
+                        .embedded(field_path, embedded_id.clone())
+                        .diff(Some(item), None)
+                        .user(user_id.clone()),
+                ],
+            )?;
+        }
+        if let (Some(hooks), 1) = (self.hooks(), result.modified_count) {
+            hooks.after_delete_embedded(&id, field_path, &embedded_id, user_id.as_ref())?;
+        }
+        Ok(DeleteResponse {
+            id: embedded_id,
+            success: result.modified_count == 1,
+        })
+    }
+
+    fn update_embedded<T, U>(
+        &self,
+        id: ID,
+        field_path: &str,
+        embedded_id: ID,
+        update_item: T,
+        user_id: Option<ID>,
+    ) -> Result<U, ServiceError>
+    where
+        T: serde::Serialize,
+        U: serde::Deserialize<'a>,
+    {
+        let coll = self.data_source();
+        let search_embedded = with_live_embedded(
+            self,
+            exclude_deleted(self, doc! { self.id_parameter(): &id.to_bson() }),
+            field_path,
+            &embedded_id,
+        );
+        let array_path = format!("{}.$", field_path);
+        validate_update(self, &update_item, Some(field_path))?;
+        let mut update = node_update(&update_item, Some(&array_path), user_id.clone())?;
+        if let Some(hooks) = self.hooks() {
+            hooks.before_update_embedded(
+                &id,
+                field_path,
+                &embedded_id,
+                &mut update,
+                user_id.as_ref(),
+            )?;
+        }
+        let search = exclude_deleted(self, doc! { self.id_parameter(): &id.to_bson() });
+        let before = history_snapshot(self, &search)?;
+        let result = coll.update_one(search_embedded, scoped_update(self, update)?, None);
+        forget_cached(self, &id);
+        match result {
+            Ok(_res) => match coll.find_one(Some(search), None) {
+                Ok(res) => match res {
+                    Some(doc) => {
+                        if let Some(hooks) = self.hooks() {
+                            hooks.after_update_embedded(
+                                &id,
+                                field_path,
+                                &embedded_id,
+                                &doc,
+                                user_id.as_ref(),
+                            )?;
+                        }
+                        if let Some(before) = before {
+                            let embedded_bson = embedded_id.to_bson();
+                            record_history(
+                                self,
+                                vec![HistoryRecord::new(
+                                    id,
+                                    HistoryOperation::Update,
+                                    timestamp() as i64,
+                                )
+                                .embedded(field_path, embedded_id)
+                                .diff(
+                                    embedded_item(
+                                        &before,
+                                        field_path,
+                                        self.id_parameter(),
+                                        &embedded_bson,
+                                    ),
+                                    embedded_item(
+                                        &doc,
+                                        field_path,
+                                        self.id_parameter(),
+                                        &embedded_bson,
+                                    ),
+                                )
+                                .user(user_id)],
+                            )?;
+                        }
+                        visible(self, doc)
+                    }
+                    None => Err(ServiceError::NotFound("Unable to find item".to_owned())),
+                },
+                Err(t) => {
+                    warn!("Search failed");
+                    Err(t)
+                }
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Like `update_embedded`, but updates and reads back the item in one atomic
+    /// find-and-modify, so the item returned is the one this update produced even while others
+    /// write to it. Fails with `NotFound` when the item or its embedded item doesn't exist.
+    fn find_and_update_embedded<T, U>(
+        &self,
+        id: ID,
+        field_path: &str,
+        embedded_id: ID,
+        update_item: T,
+        user_id: Option<ID>,
+    ) -> Result<U, ServiceError>
+    where
+        T: serde::Serialize,
+        U: serde::Deserialize<'a>,
+    {
+        let coll = self.data_source();
+        let search_embedded = with_live_embedded(
+            self,
+            exclude_deleted(self, doc! { self.id_parameter(): &id.to_bson() }),
+            field_path,
+            &embedded_id,
+        );
+        let array_path = format!("{}.$", field_path);
+        validate_update(self, &update_item, Some(field_path))?;
+        let mut update = node_update(&update_item, Some(&array_path), user_id.clone())?;
+        if let Some(hooks) = self.hooks() {
+            hooks.before_update_embedded(
+                &id,
+                field_path,
+                &embedded_id,
+                &mut update,
+                user_id.as_ref(),
+            )?;
+        }
+        let find_options = FindOneAndUpdateOptions::builder()
+            .return_document(Some(ReturnDocument::After))
+            .build();
+        let before = history_snapshot(self, &search_embedded)?;
+        let doc = coll
+            .find_one_and_update(
+                search_embedded,
+                scoped_update(self, update)?,
+                Some(find_options),
+            )?
+            .ok_or_else(|| ServiceError::NotFound("Unable to find embedded item".to_owned()))?;
+        forget_cached(self, &id);
+        if let Some(hooks) = self.hooks() {
+            hooks.after_update_embedded(&id, field_path, &embedded_id, &doc, user_id.as_ref())?;
+        }
+        if let Some(before) = before {
+            let embedded_bson = embedded_id.to_bson();
+            record_history(
+                self,
+                vec![
+                    HistoryRecord::new(id, HistoryOperation::Update, timestamp() as i64)
+                        .embedded(field_path, embedded_id)
+                        .diff(
+                            embedded_item(&before, field_path, self.id_parameter(), &embedded_bson),
+                            embedded_item(&doc, field_path, self.id_parameter(), &embedded_bson),
+                        )
+                        .user(user_id),
+                ],
+            )?;
+        }
+        visible(self, doc)
+    }
+
+    /// `find_and_update_embedded` returning only the updated embedded item.
+    fn find_and_update_embedded_item<T, U>(
+        &self,
+        id: ID,
+        field_path: &str,
+        embedded_id: ID,
+        update_item: T,
+        user_id: Option<ID>,
+    ) -> Result<U, ServiceError>
+    where
+        T: serde::Serialize,
+        U: serde::Deserialize<'a>,
+    {
+        let embedded_bson = embedded_id.to_bson();
+        let parent: Document =
+            self.find_and_update_embedded(id, field_path, embedded_id, update_item, user_id)?;
+        match embedded_item(&parent, field_path, self.id_parameter(), &embedded_bson) {
+            Some(item) => Ok(bson::from_bson(Bson::Document(item.clone()))?),
+            None => Err(ServiceError::NotFound(
+                "Unable to find embedded item".to_owned(),
+            )),
+        }
+    }
+
+    /// Like `insert_embedded`, but into the `field` array of an embedded item that can sit
+    /// at any depth, eg. `&[("sections", section_id)]` and `"items"`. The `path` is made of
+    /// `(field, id)` pairs and the item and every embedded item along it get their
+    /// `node.date_modified` stamped, while only the item's version is bumped. Fails with
+    /// `NotFound` when the path leads nowhere.
+    fn insert_nested_embedded<T>(
+        &self,
+        id: ID,
+        path: &[(&str, ID)],
+        field: &str,
+        new_items: Vec<T>,
+        user_id: Option<ID>,
+    ) -> Result<Vec<ID>, ServiceError>
+    where
+        T: serde::Serialize,
+    {
+        let coll = self.data_source();
+        let query = exclude_deleted(self, doc! { self.id_parameter(): id.to_bson() });
+        let parent = coll
+            .find_one(Some(query.clone()), None)?
+            .ok_or_else(|| ServiceError::NotFound("Unable to find item".into()))?;
+        if live_nested_item(&parent, path, self.id_parameter()).is_none() {
+            return Err(ServiceError::NotFound(
+                "Unable to find embedded item".into(),
+            ));
+        }
+        let field_path = if path.is_empty() {
+            field.to_owned()
+        } else {
+            format!("{}.{}", nested_field_path(path), field)
+        };
+        let timestamp = timestamp();
+        let mut items = Vec::new();
+        for item in new_items.iter() {
+            match bson::to_bson(item)? {
+                Bson::Document(mut document) => {
+                    let mut node_details = doc! {
+                        "date_created": timestamp,
+                        "date_modified": timestamp,
+                        "version": 1_i64,
+                    };
+                    if let Some(uid) = &user_id {
+                        node_details.insert("created_by_id", uid.to_bson());
+                        node_details.insert("updated_by_id", uid.to_bson());
+                    }
+                    if document.get("_id").map_or(true, |id| *id == Bson::Null) {
+                        let fallback_id = uuid::Uuid::new_v4().to_hyphenated().to_string();
+                        document.insert("_id", fallback_id);
+                    }
+                    document.insert("node", node_details);
+                    items.push(document);
+                }
+                _ => warn!("Unable to insert item"),
+            }
+        }
+        validate_all(self, &items, Some(&field_path))?;
+        if let Some(hooks) = self.hooks() {
+            for item in items.iter_mut() {
+                hooks.before_insert_embedded(&id, &field_path, item, user_id.as_ref())?;
+            }
+        }
+        let inserted_ids = embedded_ids(&items)?;
+        let (positions, array_filters) = nested_positions(path, self.id_parameter());
+        let target = match positions.last() {
+            Some(position) => format!("{}.{}", position, field),
+            None => field.to_owned(),
+        };
+        let update = with_level_stamps(
+            doc! { "$push": { target: { "$each": items.clone() } } },
+            &positions,
+        );
+        coll.update_one(query, update, array_filter_options(array_filters))?;
+        forget_cached(self, &id);
+        finish_insert_embedded(
+            self,
+            &id,
+            &field_path,
+            &inserted_ids,
+            &items,
+            &user_id,
+            timestamp,
+        )?;
+        Ok(inserted_ids)
+    }
+
+    /// Like `update_embedded`, for the embedded item at the end of `path` however deep it is,
+    /// see `insert_nested_embedded`. Returns the updated item.
+    fn update_nested_embedded<T, U>(
+        &self,
+        id: ID,
+        path: &[(&str, ID)],
+        update_item: T,
+        user_id: Option<ID>,
+    ) -> Result<U, ServiceError>
+    where
+        T: serde::Serialize,
+        U: serde::Deserialize<'a>,
+    {
+        let coll = self.data_source();
+        let embedded_id = match path.last() {
+            Some((_, embedded_id)) => embedded_id.clone(),
+            None => return Err("An embedded path needs at least one level".into()),
+        };
+        let search = exclude_deleted(self, doc! { self.id_parameter(): id.to_bson() });
+        let before = coll
+            .find_one(Some(search.clone()), None)?
+            .ok_or_else(|| ServiceError::NotFound("Unable to find item".into()))?;
+        if live_nested_item(&before, path, self.id_parameter()).is_none() {
+            return Err(ServiceError::NotFound(
+                "Unable to find embedded item".into(),
+            ));
+        }
+        let field_path = nested_field_path(path);
+        validate_update(self, &update_item, Some(&field_path))?;
+        let (positions, array_filters) = nested_positions(path, self.id_parameter());
+        let (parents, item_position) = positions.split_at(positions.len() - 1);
+        let update = node_update(&update_item, Some(&item_position[0]), user_id.clone())?;
+        let mut update = with_level_stamps(update, parents);
+        if let Some(hooks) = self.hooks() {
+            hooks.before_update_embedded(
+                &id,
+                &field_path,
+                &embedded_id,
+                &mut update,
+                user_id.as_ref(),
+            )?;
+        }
+        coll.update_one(
+            search.clone(),
+            scoped_update(self, update)?,
+            array_filter_options(array_filters),
+        )?;
+        forget_cached(self, &id);
+        let doc = coll
+            .find_one(Some(search), None)?
+            .ok_or_else(|| ServiceError::NotFound("Unable to find item".into()))?;
+        if let Some(hooks) = self.hooks() {
+            hooks.after_update_embedded(&id, &field_path, &embedded_id, &doc, user_id.as_ref())?;
+        }
+        record_history(
+            self,
+            vec![
+                HistoryRecord::new(id, HistoryOperation::Update, timestamp() as i64)
+                    .embedded(&field_path, embedded_id)
+                    .diff(
+                        nested_item(&before, path, self.id_parameter()),
+                        nested_item(&doc, path, self.id_parameter()),
+                    )
+                    .user(user_id),
+            ],
+        )?;
+        visible(self, doc)
+    }
+
+    /// Like `delete_embedded`, for the embedded item at the end of `path` however deep it is,
+    /// see `insert_nested_embedded`. With soft delete the item is stamped rather than removed.
+    fn delete_nested_embedded(
+        &self,
+        id: ID,
+        path: &[(&str, ID)],
+        user_id: Option<ID>,
+    ) -> Result<DeleteResponse, ServiceError> {
+        let coll = self.data_source();
+        let (field, embedded_id) = match path.last() {
+            Some((field, embedded_id)) => (*field, embedded_id.clone()),
+            None => return Err("An embedded path needs at least one level".into()),
+        };
+        let query = exclude_deleted(self, doc! { self.id_parameter(): id.to_bson() });
+        let before = coll
+            .find_one(Some(query.clone()), None)?
+            .ok_or_else(|| ServiceError::NotFound("Unable to find item".into()))?;
+        let removed = live_nested_item(&before, path, self.id_parameter())
+            .ok_or_else(|| ServiceError::NotFound("Unable to find embedded item".into()))?;
+        let field_path = nested_field_path(path);
+        if let Some(hooks) = self.hooks() {
+            hooks.before_delete_embedded(&id, &field_path, &embedded_id, user_id.as_ref())?;
+        }
+        let (positions, mut array_filters) = nested_positions(path, self.id_parameter());
+        let (parents, item_position) = positions.split_at(positions.len() - 1);
+        let (update, operation) = if self.uses_soft_delete() {
+            let mut set_doc =
+                doc! { format!("{}.{}", item_position[0], DATE_DELETED): timestamp() };
+            if let Some(uid) = &user_id {
+                set_doc.insert(
+                    format!("{}.node.deleted_by_id", item_position[0]),
+                    uid.to_bson(),
+                );
+            }
+            let update = doc! {
+                "$set": set_doc,
+                "$inc": { format!("{}.{}", item_position[0], VERSION): 1_i64 },
+            };
+            (update, HistoryOperation::SoftDelete)
+        } else {
+            // the item is pulled from its array, so only its parents are picked out
+            array_filters.pop();
+            let array = match parents.last() {
+                Some(parent) => format!("{}.{}", parent, field),
+                None => field.to_owned(),
+            };
+            let update =
+                doc! { "$pull": { array: { self.id_parameter(): embedded_id.to_bson() } } };
+            (update, HistoryOperation::Delete)
+        };
+        let update = with_level_stamps(update, parents);
+        let result = coll.update_one(query, update, array_filter_options(array_filters))?;
+        forget_cached(self, &id);
+        if result.modified_count == 1 {
+            if let Some(hooks) = self.hooks() {
+                hooks.after_delete_embedded(&id, &field_path, &embedded_id, user_id.as_ref())?;
+            }
+            let record = HistoryRecord::new(id, operation, timestamp() as i64)
+                .embedded(&field_path, embedded_id.clone())
+                .user(user_id);
+            record_history(
+                self,
+                vec![match operation {
+                    HistoryOperation::Delete => record.diff(Some(removed), None),
+                    _ => record,
+                }],
+            )?;
+        }
+        Ok(DeleteResponse {
+            id: embedded_id,
+            success: result.modified_count == 1,
+        })
+    }
+
+    fn update_one<T, U>(
+        &self,
+        id: ID,
+        update_item: T,
+        user_id: Option<ID>,
+    ) -> Result<U, ServiceError>
+    where
+        T: serde::Serialize,
+        U: serde::Deserialize<'a> + Node,
+    {
+        let coll = self.data_source();
+        let search = exclude_deleted(self, doc! { self.id_parameter(): id.to_bson() });
+        validate_update(self, &update_item, None)?;
+        let mut update = node_update(&update_item, None, user_id.clone())?;
+        if let Some(hooks) = self.hooks() {
+            hooks.before_update(&id, &mut update, user_id.as_ref())?;
+        }
+        let before = history_snapshot(self, &search)?;
+        let result = coll.update_one(search.clone(), scoped_update(self, update)?, None);
+        forget_cached(self, &id);
+        match result {
+            Ok(_res) => match coll.find_one(Some(search), None) {
                 Ok(res) => match res {
                     Some(doc) => {
-                        let item: U = bson::from_bson(bson::Bson::Document(doc))?;
-                        Ok(item)
+                        if let Some(hooks) = self.hooks() {
+                            hooks.after_update(&id, &doc, user_id.as_ref())?;
+                        }
+                        if let Some(before) = before {
+                            record_history(
+                                self,
+                                vec![HistoryRecord::new(
+                                    id,
+                                    HistoryOperation::Update,
+                                    timestamp() as i64,
+                                )
+                                .diff(Some(&before), Some(&doc))
+                                .user(user_id)],
+                            )?;
+                        }
+                        visible(self, doc)
                     }
                     None => Err(ServiceError::NotFound("Unable to find item".to_owned())),
                 },
                 Err(t) => {
                     warn!("Search failed");
-                    Err(ServiceError::from(t))
+                    Err(t)
                 }
             },
-            Err(e) => Err(ServiceError::from(e)),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn update_one_with_doc<U>(&self, id: ID, update_doc: Document) -> Result<U, ServiceError>
+    where
+        U: serde::Deserialize<'a>,
+    {
+        let coll = self.data_source();
+        let search = exclude_deleted(self, doc! { self.id_parameter(): id.to_bson() });
+        if !update_doc.keys().any(|key| key.starts_with('$')) {
+            validate(self, &update_doc, ValidationScope::Full, None)?;
+        } else if let Ok(set_doc) = update_doc.get_document("$set") {
+            validate(self, set_doc, ValidationScope::Partial, None)?;
+        }
+        let mut update_doc = with_version_bump(update_doc, VERSION);
+        if let Some(hooks) = self.hooks() {
+            hooks.before_update(&id, &mut update_doc, None)?;
         }
+        let before = history_snapshot(self, &search)?;
+        let result = coll.update_one(search.clone(), scoped_update(self, update_doc)?, None);
+        forget_cached(self, &id);
+        match result {
+            Ok(_res) => match coll.find_one(Some(search), None) {
+                Ok(res) => match res {
+                    Some(doc) => {
+                        if let Some(hooks) = self.hooks() {
+                            hooks.after_update(&id, &doc, None)?;
+                        }
+                        if let Some(before) = before {
+                            record_history(
+                                self,
+                                vec![HistoryRecord::new(
+                                    id,
+                                    HistoryOperation::Update,
+                                    timestamp() as i64,
+                                )
+                                .diff(Some(&before), Some(&doc))],
+                            )?;
+                        }
+                        visible(self, doc)
+                    }
+                    None => Err(ServiceError::NotFound("Unable to find item".to_owned())),
+                },
+                Err(t) => {
+                    warn!("Search failed");
+                    Err(t)
+                }
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Like `update_one`, but only applies the update while the stored item is still at
+    /// `expected_version`. Otherwise it fails with `ServiceError::Conflict` carrying the version
+    /// the item is at now.
+    fn update_one_versioned<T, U>(
+        &self,
+        id: ID,
+        expected_version: i64,
+        update_item: T,
+        user_id: Option<ID>,
+    ) -> Result<U, ServiceError>
+    where
+        T: serde::Serialize,
+        U: serde::Deserialize<'a> + Node,
+    {
+        let coll = self.data_source();
+        let search = exclude_deleted(self, doc! { self.id_parameter(): id.to_bson() });
+        let mut search_versioned = search.clone();
+        search_versioned.insert(VERSION, version_condition(expected_version));
+        validate_update(self, &update_item, None)?;
+        let mut update = node_update(&update_item, None, user_id.clone())?;
+        if let Some(hooks) = self.hooks() {
+            hooks.before_update(&id, &mut update, user_id.as_ref())?;
+        }
+        let before = history_snapshot(self, &search)?;
+        let result = coll.update_one(search_versioned, scoped_update(self, update)?, None)?;
+        forget_cached(self, &id);
+        match coll.find_one(Some(search), None)? {
+            Some(doc) if result.matched_count == 0 => {
+                Err(ServiceError::Conflict(current_version(&doc)))
+            }
+            Some(doc) => {
+                if let Some(hooks) = self.hooks() {
+                    hooks.after_update(&id, &doc, user_id.as_ref())?;
+                }
+                if let Some(before) = before {
+                    record_history(
+                        self,
+                        vec![
+                            HistoryRecord::new(id, HistoryOperation::Update, timestamp() as i64)
+                                .diff(Some(&before), Some(&doc))
+                                .user(user_id),
+                        ],
+                    )?;
+                }
+                visible(self, doc)
+            }
+            None => Err(ServiceError::NotFound("Unable to find item".to_owned())),
+        }
+    }
+
+    /// Like `update_embedded`, but only applies the update while the embedded item is still at
+    /// `expected_version`.
+    fn update_embedded_versioned<T, U>(
+        &self,
+        id: ID,
+        field_path: &str,
+        embedded_id: ID,
+        expected_version: i64,
+        update_item: T,
+        user_id: Option<ID>,
+    ) -> Result<U, ServiceError>
+    where
+        T: serde::Serialize,
+        U: serde::Deserialize<'a>,
+    {
+        let coll = self.data_source();
+        let mut conditions = doc! {
+            self.id_parameter(): &embedded_id.to_bson(),
+            VERSION: version_condition(expected_version),
+        };
+        if self.uses_soft_delete() {
+            conditions.insert(DATE_DELETED, Bson::Null);
+        }
+        let search_embedded = exclude_deleted(
+            self,
+            doc! {
+                self.id_parameter(): &id.to_bson(),
+                field_path: { "$elemMatch": conditions },
+            },
+        );
+        let array_path = format!("{}.$", field_path);
+        validate_update(self, &update_item, Some(field_path))?;
+        let mut update = node_update(&update_item, Some(&array_path), user_id.clone())?;
+        if let Some(hooks) = self.hooks() {
+            hooks.before_update_embedded(
+                &id,
+                field_path,
+                &embedded_id,
+                &mut update,
+                user_id.as_ref(),
+            )?;
+        }
+        let search = exclude_deleted(self, doc! { self.id_parameter(): &id.to_bson() });
+        let before = history_snapshot(self, &search)?;
+        let result = coll.update_one(search_embedded, scoped_update(self, update)?, None)?;
+        forget_cached(self, &id);
+        let embedded_bson = embedded_id.to_bson();
+        match coll.find_one(Some(search), None)? {
+            Some(doc) if result.matched_count == 0 => {
+                match embedded_item(&doc, field_path, self.id_parameter(), &embedded_bson) {
+                    Some(item) if date_deleted(item).is_none() => {
+                        Err(ServiceError::Conflict(current_version(item)))
+                    }
+                    _ => Err(ServiceError::NotFound(
+                        "Unable to find embedded item".to_owned(),
+                    )),
+                }
+            }
+            Some(doc) => {
+                if let Some(hooks) = self.hooks() {
+                    hooks.after_update_embedded(
+                        &id,
+                        field_path,
+                        &embedded_id,
+                        &doc,
+                        user_id.as_ref(),
+                    )?;
+                }
+                if let Some(before) = before {
+                    record_history(
+                        self,
+                        vec![
+                            HistoryRecord::new(id, HistoryOperation::Update, timestamp() as i64)
+                                .embedded(field_path, embedded_id)
+                                .diff(
+                                    embedded_item(
+                                        &before,
+                                        field_path,
+                                        self.id_parameter(),
+                                        &embedded_bson,
+                                    ),
+                                    embedded_item(
+                                        &doc,
+                                        field_path,
+                                        self.id_parameter(),
+                                        &embedded_bson,
+                                    ),
+                                )
+                                .user(user_id),
+                        ],
+                    )?;
+                }
+                visible(self, doc)
+            }
+            None => Err(ServiceError::NotFound("Unable to find item".to_owned())),
+        }
+    }
+
+    /// Moves an item to the trash by stamping `node.date_deleted` and `node.deleted_by_id`.
+    fn soft_delete_by_id(
+        &self,
+        id: ID,
+        user_id: Option<ID>,
+    ) -> Result<DeleteResponse, ServiceError> {
+        let coll = self.data_source();
+        if let Some(hooks) = self.hooks() {
+            hooks.before_delete(&id, user_id.as_ref())?;
+        }
+        let query = scoped(
+            self,
+            doc! { self.id_parameter(): id.to_bson(), DATE_DELETED: Bson::Null },
+        );
+        let mut update_doc = doc! { DATE_DELETED: timestamp() };
+        if let Some(uid) = &user_id {
+            update_doc.insert("node.deleted_by_id", uid.to_bson());
+        }
+        let update = doc! { "$set": update_doc, "$inc": { VERSION: 1_i64 } };
+        let result = coll.update_one(query, update, None)?;
+        forget_cached(self, &id);
+        if result.matched_count == 1 {
+            if let Some(hooks) = self.hooks() {
+                hooks.after_delete(&id, user_id.as_ref())?;
+            }
+            record_history(
+                self,
+                vec![HistoryRecord::new(
+                    id.clone(),
+                    HistoryOperation::SoftDelete,
+                    timestamp() as i64,
+                )
+                .user(user_id)],
+            )?;
+        }
+        Ok(DeleteResponse {
+            id,
+            success: result.matched_count == 1,
+        })
+    }
+
+    fn soft_delete_embedded(
+        &self,
+        id: ID,
+        field_path: &str,
+        embedded_id: ID,
+        user_id: Option<ID>,
+    ) -> Result<DeleteResponse, ServiceError> {
+        let coll = self.data_source();
+        if let Some(hooks) = self.hooks() {
+            hooks.before_delete_embedded(&id, field_path, &embedded_id, user_id.as_ref())?;
+        }
+        let query = with_live_embedded(
+            self,
+            exclude_deleted(self, doc! { self.id_parameter(): &id.to_bson() }),
+            field_path,
+            &embedded_id,
+        );
+        let array_path = format!("{}.$", field_path);
+        let mut update_doc = doc! { format!("{}.{}", array_path, DATE_DELETED): timestamp() };
+        if let Some(uid) = &user_id {
+            update_doc.insert(format!("{}.node.deleted_by_id", array_path), uid.to_bson());
+        }
+        let update = doc! {
+            "$set": update_doc,
+            "$inc": { format!("{}.{}", array_path, VERSION): 1_i64 },
+        };
+        let result = coll.update_one(query, update, None)?;
+        forget_cached(self, &id);
+        if result.matched_count == 1 {
+            if let Some(hooks) = self.hooks() {
+                hooks.after_delete_embedded(&id, field_path, &embedded_id, user_id.as_ref())?;
+            }
+            record_history(
+                self,
+                vec![
+                    HistoryRecord::new(id, HistoryOperation::SoftDelete, timestamp() as i64)
+                        .embedded(field_path, embedded_id.clone())
+                        .user(user_id),
+                ],
+            )?;
+        }
+        Ok(DeleteResponse {
+            id: embedded_id,
+            success: result.matched_count == 1,
+        })
+    }
+
+    /// Takes a soft deleted item back out of the trash, returning whether anything was restored.
+    fn restore_by_id(&self, id: ID) -> Result<bool, ServiceError> {
+        let coll = self.data_source();
+        let query = scoped(
+            self,
+            doc! {
+                self.id_parameter(): id.to_bson(),
+                DATE_DELETED: { "$ne": Bson::Null },
+            },
+        );
+        let mut update = doc! {
+            "$unset": { DATE_DELETED: "", "node.deleted_by_id": "" },
+            "$inc": { VERSION: 1_i64 },
+        };
+        if let Some(hooks) = self.hooks() {
+            hooks.before_update(&id, &mut update, None)?;
+        }
+        let result = coll.update_one(query, update, None)?;
+        forget_cached(self, &id);
+        if result.modified_count == 1 {
+            if let Some(hooks) = self.hooks() {
+                let search = scoped(self, doc! { self.id_parameter(): id.to_bson() });
+                if let Some(doc) = coll.find_one(Some(search), None)? {
+                    hooks.after_update(&id, &doc, None)?;
+                }
+            }
+            record_history(
+                self,
+                vec![HistoryRecord::new(
+                    id,
+                    HistoryOperation::Restore,
+                    timestamp() as i64,
+                )],
+            )?;
+        }
+        Ok(result.modified_count == 1)
+    }
+
+    fn restore_embedded(
+        &self,
+        id: ID,
+        field_path: &str,
+        embedded_id: ID,
+    ) -> Result<bool, ServiceError> {
+        let coll = self.data_source();
+        let query = scoped(
+            self,
+            doc! {
+                self.id_parameter(): &id.to_bson(),
+                field_path: {
+                    "$elemMatch": {
+                        self.id_parameter(): &embedded_id.to_bson(),
+                        DATE_DELETED: { "$ne": Bson::Null },
+                    }
+                },
+            },
+        );
+        let array_path = format!("{}.$", field_path);
+        let mut update = doc! {
+            "$unset": {
+                format!("{}.{}", array_path, DATE_DELETED): "",
+                format!("{}.node.deleted_by_id", array_path): "",
+            },
+            "$inc": { format!("{}.{}", array_path, VERSION): 1_i64 },
+        };
+        if let Some(hooks) = self.hooks() {
+            hooks.before_update_embedded(&id, field_path, &embedded_id, &mut update, None)?;
+        }
+        let result = coll.update_one(query, update, None)?;
+        forget_cached(self, &id);
+        if result.modified_count == 1 {
+            if let Some(hooks) = self.hooks() {
+                let search = scoped(self, doc! { self.id_parameter(): id.to_bson() });
+                if let Some(doc) = coll.find_one(Some(search), None)? {
+                    hooks.after_update_embedded(&id, field_path, &embedded_id, &doc, None)?;
+                }
+            }
+            record_history(
+                self,
+                vec![
+                    HistoryRecord::new(id, HistoryOperation::Restore, timestamp() as i64)
+                        .embedded(field_path, embedded_id),
+                ],
+            )?;
+        }
+        Ok(result.modified_count == 1)
+    }
+
+    /// Pages through the trash, with the same arguments as `find`.
+    fn find_deleted<T>(
+        &self,
+        filter: Option<Document>,
+        sort: Option<Document>,
+        limit: Option<i32>,
+        after: Option<String>,
+        before: Option<String>,
+        skip: Option<i32>,
+    ) -> Result<FindResult<T>, ServiceError>
+    where
+        T: serde::Deserialize<'a>,
+    {
+        let mut filter = filter.unwrap_or_default();
+        filter.insert(DATE_DELETED, doc! { "$ne": Bson::Null });
+        self.find(Some(filter), sort, limit, after, before, skip)
+    }
+
+    fn find_deleted_embedded<U>(
+        &self,
+        id: ID,
+        field: &str,
+        limit: Option<i32>,
+        skip: Option<i32>,
+    ) -> Result<Vec<U>, ServiceError>
+    where
+        U: serde::Deserialize<'a>,
+    {
+        let coll = self.data_source();
+        let find_options = FindOneOptions::builder()
+            .projection(Some(doc! { field: 1 }))
+            .build();
+        let query = Some(scoped(self, doc! { self.id_parameter(): id.to_bson() }));
+        match coll.find_one(query, Some(find_options))? {
+            Some(result) => match result.get_array(field) {
+                Ok(embedded) => filter_embedded(
+                    &visible_embedded(self, field, embedded),
+                    true,
+                    limit.map_or(self.default_limit(), i64::from),
+                    skip,
+                ),
+                Err(e) => Err(ServiceError::ParseError(e.to_string())),
+            },
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Permanently removes the items that were soft deleted more than `age` ago, returning how
+    /// many were removed.
+    fn purge_deleted_older_than(&self, age: Duration) -> Result<i64, ServiceError> {
+        let coll = self.data_source();
+        let cutoff = timestamp().saturating_sub(age.as_secs());
+        let query = scoped(self, doc! { DATE_DELETED: { "$lte": cutoff } });
+        let purged = if self.history_data_source().is_some() || self.hooks().is_some() {
+            coll.find(Some(query.clone()), None)?
+        } else {
+            Vec::new()
+        };
+        let purged = with_ids(self, &purged);
+        if let Some(hooks) = self.hooks() {
+            for (id, _) in purged.iter() {
+                hooks.before_delete(id, None)?;
+            }
+        }
+        let result = coll.delete_many(query, None)?;
+        forget_all_cached(self);
+        record_history(
+            self,
+            purged
+                .iter()
+                .map(|(id, before)| {
+                    HistoryRecord::new(id.clone(), HistoryOperation::Delete, timestamp() as i64)
+                        .diff(Some(before), None)
+                })
+                .collect(),
+        )?;
+        if let Some(hooks) = self.hooks() {
+            for (id, _) in purged.iter() {
+                hooks.after_delete(id, None)?;
+            }
+        }
+        Ok(result.deleted_count)
+    }
+
+    /// Permanently removes the embedded items that were soft deleted more than `age` ago,
+    /// returning how many parent documents were changed.
+    fn purge_deleted_embedded_older_than(
+        &self,
+        field_path: &str,
+        age: Duration,
+    ) -> Result<i64, ServiceError> {
+        let coll = self.data_source();
+        let cutoff = timestamp().saturating_sub(age.as_secs());
+        let query = scoped(
+            self,
+            doc! { format!("{}.{}", field_path, DATE_DELETED): { "$lte": cutoff } },
+        );
+        let parents = if self.history_data_source().is_some() || self.hooks().is_some() {
+            coll.find(Some(query.clone()), None)?
+        } else {
+            Vec::new()
+        };
+        // the parent id, embedded id and item of everything that is about to be removed
+        let mut purged: Vec<(ID, ID, &Document)> = Vec::new();
+        for parent in parents.iter() {
+            let id = match parent.get(self.id_parameter()).and_then(ID::try_with_bson) {
+                Some(id) => id,
+                None => continue,
+            };
+            let items = parent.get_array(field_path).map(|items| items.iter());
+            for item in items.into_iter().flatten() {
+                if let Bson::Document(item) = item {
+                    if let (Some(date), Some(embedded_id)) = (
+                        date_deleted(item),
+                        item.get(self.id_parameter()).and_then(ID::try_with_bson),
+                    ) {
+                        if date <= cutoff as i64 {
+                            purged.push((id.clone(), embedded_id, item));
+                        }
+                    }
+                }
+            }
+        }
+        if let Some(hooks) = self.hooks() {
+            for (id, embedded_id, _) in purged.iter() {
+                hooks.before_delete_embedded(id, field_path, embedded_id, None)?;
+            }
+        }
+        let update = doc! { "$pull": { field_path: { DATE_DELETED: { "$lte": cutoff } } } };
+        let result = coll.update_many(query, update, None)?;
+        forget_all_cached(self);
+        record_history(
+            self,
+            purged
+                .iter()
+                .map(|(id, embedded_id, item)| {
+                    HistoryRecord::new(id.clone(), HistoryOperation::Delete, timestamp() as i64)
+                        .embedded(field_path, embedded_id.clone())
+                        .diff(Some(item), None)
+                })
+                .collect(),
+        )?;
+        if let Some(hooks) = self.hooks() {
+            for (id, embedded_id, _) in purged.iter() {
+                hooks.after_delete_embedded(id, field_path, embedded_id, None)?;
+            }
+        }
+        Ok(result.modified_count)
+    }
+
+    /// Pages through the change history of an item, newest first. Changes to its embedded items
+    /// are included.
+    fn find_history(
+        &self,
+        id: ID,
+        limit: Option<i32>,
+        after: Option<String>,
+        before: Option<String>,
+        skip: Option<i32>,
+    ) -> Result<FindResult<HistoryRecord>, ServiceError> {
+        let history = match self.history_data_source() {
+            Some(history) => history,
+            None => {
+                return Err(ServiceError::ConnectionError(format!(
+                    "History is not enabled for collection {}",
+                    self.data_source().name()
+                )))
+            }
+        };
+        let find_options = FindOptions::builder()
+            .limit(limit.map_or(self.default_limit(), i64::from))
+            .skip(skip.map_or(0, i64::from))
+            .sort(doc! { "timestamp": -1, "_id": -1 })
+            .build();
+        let is_previous_query = before.is_some() && after.is_none();
+        let (cursor, direction) = if is_previous_query {
+            (before, CursorDirections::Previous)
+        } else {
+            (after, CursorDirections::Next)
+        };
+        let mut filter = doc! { "target_id": id.to_bson() };
+        if let Some(scope) = self.tenant_scope() {
+            // like the items themselves, a scope without a tenant sees no history
+            let tenant = match scope.tenant() {
+                Some(tenant) => tenant.clone(),
+                None => Bson::Document(doc! { "$in": [] }),
+            };
+            filter.insert("tenant", tenant);
+        }
+        let page = history.find_page(Some(filter), find_options, cursor, direction)?;
+        deserialize_page(page)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::change_stream::ChangeOperation;
+    use crate::mock::MockCollection;
+    use crate::mongo::MongoService;
+    use crate::node::NodeDetails;
+
+    #[derive(Debug, Deserialize)]
+    struct Item {
+        #[serde(default)]
+        node: NodeDetails,
+    }
+
+    impl Node for Item {
+        fn node(&self) -> &NodeDetails {
+            &self.node
+        }
+    }
+
+    fn trash_service(documents: Vec<Document>) -> (MongoService, MockCollection) {
+        let collection = MockCollection::with_documents("items", documents);
+        let service = MongoService::with_data_source(collection.clone(), None).with_soft_delete();
+        (service, collection)
+    }
+
+    fn comments(document: &Document) -> Vec<&str> {
+        document
+            .get_array("comments")
+            .unwrap()
+            .iter()
+            .filter_map(|comment| comment.as_document())
+            .filter_map(|comment| comment.get_str("_id").ok())
+            .collect()
+    }
+
+    #[test]
+    fn test_soft_delete_moves_items_to_the_trash() {
+        let (service, collection) =
+            trash_service(vec![doc! { "_id": 1_i64 }, doc! { "_id": 2_i64 }]);
+        let deleted = service
+            .delete_one_by_id_with_user(ID::I64(1), Some(ID::with_string("admin")))
+            .unwrap();
+        assert!(deleted.success);
+        assert_eq!(collection.documents().len(), 2);
+
+        let live: FindResult<Document> = service.find(None, None, None, None, None, None).unwrap();
+        assert_eq!(live.total_count, 1);
+        let missing: Option<Document> = service.find_one_by_id(ID::I64(1)).unwrap();
+        assert!(missing.is_none());
+
+        let trash: FindResult<Item> = service
+            .find_deleted(None, None, None, None, None, None)
+            .unwrap();
+        assert_eq!(trash.total_count, 1);
+        assert!(trash.items[0].node().is_deleted());
+        assert_eq!(
+            trash.items[0].node().deleted_by_id(),
+            &Some(ID::with_string("admin"))
+        );
+
+        let again = service.delete_one_by_id(ID::I64(1)).unwrap();
+        assert!(!again.success);
+    }
+
+    #[test]
+    fn test_restore_and_purge() {
+        let (service, collection) =
+            trash_service(vec![doc! { "_id": 1_i64 }, doc! { "_id": 2_i64 }]);
+        service.delete_one_by_id(ID::I64(1)).unwrap();
+        assert!(service.restore_by_id(ID::I64(1)).unwrap());
+        assert!(!service.restore_by_id(ID::I64(1)).unwrap());
+        let restored: Option<Item> = service.find_one_by_id(ID::I64(1)).unwrap();
+        assert!(!restored.unwrap().node().is_deleted());
+
+        service.delete_one_by_id(ID::I64(2)).unwrap();
+        let day = Duration::from_secs(24 * 60 * 60);
+        assert_eq!(service.purge_deleted_older_than(day).unwrap(), 0);
+        let purged = service
+            .purge_deleted_older_than(Duration::from_secs(0))
+            .unwrap();
+        assert_eq!(purged, 1);
+        let stored = collection.documents();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].get_i64("_id"), Ok(1));
+    }
+
+    #[test]
+    fn test_soft_delete_embedded_items() {
+        let (service, collection) = trash_service(vec![
+            doc! { "_id": 1_i64, "comments": [{ "_id": "a" }, { "_id": "b" }] },
+        ]);
+        let deleted = service
+            .delete_embedded_with_user(
+                ID::I64(1),
+                "comments",
+                ID::with_string("a"),
+                Some(ID::with_string("admin")),
+            )
+            .unwrap();
+        assert!(deleted.success);
+        let live: Vec<Document> = service
+            .get_embedded_by_id(ID::I64(1), "comments", None, None)
+            .unwrap();
+        assert_eq!(live.len(), 1);
+        let trash: Vec<Item> = service
+            .find_deleted_embedded(ID::I64(1), "comments", None, None)
+            .unwrap();
+        assert_eq!(
+            trash[0].node().deleted_by_id(),
+            &Some(ID::with_string("admin"))
+        );
+        let again = service
+            .delete_embedded(ID::I64(1), "comments", ID::with_string("a"))
+            .unwrap();
+        assert!(!again.success);
+
+        assert!(service
+            .restore_embedded(ID::I64(1), "comments", ID::with_string("a"))
+            .unwrap());
+        service
+            .delete_embedded(ID::I64(1), "comments", ID::with_string("b"))
+            .unwrap();
+        let purged = service
+            .purge_deleted_embedded_older_than("comments", Duration::from_secs(0))
+            .unwrap();
+        assert_eq!(purged, 1);
+        assert_eq!(comments(&collection.documents()[0]), vec!["a"]);
+    }
+
+    #[test]
+    fn test_delete_embedded_reports_whether_anything_was_removed() {
+        let collection = MockCollection::with_documents(
+            "items",
+            vec![doc! { "_id": 1_i64, "comments": [{ "_id": "a" }] }],
+        );
+        let service = MongoService::with_data_source(collection.clone(), None);
+        let missing = service
+            .delete_embedded(ID::I64(1), "comments", ID::with_string("b"))
+            .unwrap();
+        assert!(!missing.success);
+        let removed = service
+            .delete_embedded(ID::I64(1), "comments", ID::with_string("a"))
+            .unwrap();
+        assert!(removed.success);
+        assert!(comments(&collection.documents()[0]).is_empty());
+    }
+
+    #[test]
+    fn test_writes_skip_trashed_items() {
+        let (service, collection) = trash_service(vec![
+            doc! { "_id": 1_i64, "title": "kept", "comments": [{ "_id": "a" }] },
+        ]);
+        service.delete_one_by_id(ID::I64(1)).unwrap();
+
+        let updated: Result<Item, ServiceError> =
+            service.update_one(ID::I64(1), doc! { "title": "changed" }, None);
+        assert!(matches!(updated, Err(ServiceError::NotFound(_))));
+        let updated: Result<Document, ServiceError> =
+            service.update_one_with_doc(ID::I64(1), doc! { "$set": { "title": "changed" } });
+        assert!(matches!(updated, Err(ServiceError::NotFound(_))));
+        let inserted =
+            service.insert_embedded(ID::I64(1), "comments", vec![doc! { "_id": "b" }], None);
+        assert!(matches!(inserted, Err(ServiceError::NotFound(_))));
+        let upserted = service.upsert_embedded(
+            ID::I64(1),
+            "comments",
+            vec![doc! { "_id": "b" }],
+            None,
+            Some(doc! { "title": "new" }),
+        );
+        assert!(matches!(upserted, Err(ServiceError::Trashed(ID::I64(1)))));
+        let found: Result<Document, ServiceError> = service.find_and_update_embedded(
+            ID::I64(1),
+            "comments",
+            ID::with_string("a"),
+            doc! { "text": "changed" },
+            None,
+        );
+        assert!(matches!(found, Err(ServiceError::NotFound(_))));
+        let nested =
+            service.insert_nested_embedded(ID::I64(1), &[], "comments", vec![doc! {}], None);
+        assert!(matches!(nested, Err(ServiceError::NotFound(_))));
+
+        let stored = &collection.documents()[0];
+        assert_eq!(stored.get_str("title"), Ok("kept"));
+        assert_eq!(comments(stored), vec!["a"]);
+    }
+
+    #[test]
+    fn test_writes_skip_trashed_embedded_items() {
+        let (service, collection) =
+            trash_service(vec![doc! { "_id": 1_i64, "comments": [{ "_id": "a" }] }]);
+        service
+            .delete_embedded(ID::I64(1), "comments", ID::with_string("a"))
+            .unwrap();
+
+        let _: Document = service
+            .update_embedded(
+                ID::I64(1),
+                "comments",
+                ID::with_string("a"),
+                doc! { "text": "changed" },
+                None,
+            )
+            .unwrap();
+        let found: Result<Document, ServiceError> = service.find_and_update_embedded(
+            ID::I64(1),
+            "comments",
+            ID::with_string("a"),
+            doc! { "text": "changed" },
+            None,
+        );
+        assert!(matches!(found, Err(ServiceError::NotFound(_))));
+        let versioned: Result<Document, ServiceError> = service.update_embedded_versioned(
+            ID::I64(1),
+            "comments",
+            ID::with_string("a"),
+            1,
+            doc! { "text": "changed" },
+            None,
+        );
+        assert!(matches!(versioned, Err(ServiceError::NotFound(_))));
+        let nested: Result<Document, ServiceError> = service.update_nested_embedded(
+            ID::I64(1),
+            &[("comments", ID::with_string("a"))],
+            doc! { "text": "changed" },
+            None,
+        );
+        assert!(matches!(nested, Err(ServiceError::NotFound(_))));
+
+        let stored = collection.documents()[0].get_array("comments").unwrap()[0].clone();
+        assert!(stored.as_document().unwrap().get("text").is_none());
+    }
+
+    #[test]
+    fn test_writes_bump_the_version() {
+        let collection = MockCollection::new("items");
+        let service = MongoService::with_data_source(collection.clone(), None);
+        let id = service.insert_one(doc! { "_id": 1_i64 }, None).unwrap();
+        assert_eq!(current_version(&collection.documents()[0]), 1);
+        let item: Item = service
+            .update_one(id.clone(), doc! { "views": 1 }, None)
+            .unwrap();
+        assert_eq!(item.node().version(), 2);
+        let item: Document = service
+            .update_one_with_doc(id.clone(), doc! { "$inc": { "views": 1 } })
+            .unwrap();
+        assert_eq!(current_version(&item), 3);
+
+        let ids = service
+            .insert_embedded(id.clone(), "comments", vec![doc! {}], None)
+            .unwrap();
+        let item: Document = service
+            .update_embedded(id, "comments", ids[0].clone(), doc! { "text": "a" }, None)
+            .unwrap();
+        let comment = item.get_array("comments").unwrap()[0]
+            .as_document()
+            .unwrap();
+        assert_eq!(current_version(comment), 2);
+    }
+
+    #[test]
+    fn test_update_one_versioned_fails_with_the_current_version() {
+        let collection = MockCollection::with_documents(
+            "items",
+            vec![
+                doc! { "_id": 1_i64, "node": { "version": 2_i64 } },
+                doc! { "_id": 2_i64 },
+            ],
+        );
+        let service = MongoService::with_data_source(collection, None);
+        let item: Item = service
+            .update_one_versioned(ID::I64(1), 2, doc! { "views": 2 }, None)
+            .unwrap();
+        assert_eq!(item.node().version(), 3);
+        let stale: Result<Item, ServiceError> =
+            service.update_one_versioned(ID::I64(1), 2, doc! { "views": 3 }, None);
+        assert!(matches!(stale, Err(ServiceError::Conflict(3))));
+        let missing: Result<Item, ServiceError> =
+            service.update_one_versioned(ID::I64(9), 1, doc! { "views": 3 }, None);
+        assert!(matches!(missing, Err(ServiceError::NotFound(_))));
+
+        // items written before versioning are at version 0
+        let legacy: Item = service
+            .update_one_versioned(ID::I64(2), 0, doc! { "views": 1 }, None)
+            .unwrap();
+        assert_eq!(legacy.node().version(), 1);
+    }
+
+    #[test]
+    fn test_update_embedded_versioned_fails_with_the_current_version() {
+        let collection = MockCollection::with_documents(
+            "items",
+            vec![doc! { "_id": 1_i64, "comments": [{ "_id": "a", "node": { "version": 1_i64 } }] }],
+        );
+        let service = MongoService::with_data_source(collection, None);
+        let a = ID::with_string("a");
+        let _: Document = service
+            .update_embedded_versioned(
+                ID::I64(1),
+                "comments",
+                a.clone(),
+                1,
+                doc! { "text": "b" },
+                None,
+            )
+            .unwrap();
+        let stale: Result<Document, ServiceError> = service.update_embedded_versioned(
+            ID::I64(1),
+            "comments",
+            a,
+            1,
+            doc! { "text": "lost" },
+            None,
+        );
+        assert!(matches!(stale, Err(ServiceError::Conflict(2))));
+        let missing: Result<Document, ServiceError> = service.update_embedded_versioned(
+            ID::I64(1),
+            "comments",
+            ID::with_string("b"),
+            1,
+            doc! { "text": "lost" },
+            None,
+        );
+        assert!(matches!(missing, Err(ServiceError::NotFound(_))));
+    }
+
+    #[test]
+    fn test_update_many_stamps_every_matching_item() {
+        let collection = MockCollection::with_documents(
+            "items",
+            vec![
+                doc! { "_id": 1_i64, "views": 10 },
+                doc! { "_id": 2_i64, "views": 30 },
+                doc! { "_id": 3_i64, "views": 50 },
+            ],
+        );
+        let service = MongoService::with_data_source(collection.clone(), None);
+        let admin = Some(ID::with_string("admin"));
+        let result = service
+            .update_many(
+                Some(doc! { "views": { "$gte": 30 } }),
+                doc! { "title": "Popular" },
+                admin.clone(),
+            )
+            .unwrap();
+        assert_eq!((result.matched_count, result.modified_count), (2, 2));
+        let result = service
+            .update_many_with_doc(None, doc! { "$inc": { "views": 1 } }, None)
+            .unwrap();
+        assert_eq!(result.modified_count, 3);
+
+        let documents = collection.documents();
+        assert!(documents[0].get("title").is_none());
+        assert_eq!(documents[2].get_str("title"), Ok("Popular"));
+        assert_eq!(documents[2].get_i32("views"), Ok(51));
+        assert_eq!(current_version(&documents[2]), 2);
+        let node = documents[2].get_document("node").unwrap();
+        assert_eq!(node.get_str("updated_by_id"), Ok("admin"));
+    }
+
+    #[test]
+    fn test_delete_many_moves_items_to_the_trash_with_soft_delete() {
+        let documents = vec![
+            doc! { "_id": 1_i64, "views": 10 },
+            doc! { "_id": 2_i64, "views": 30 },
+        ];
+        let collection = MockCollection::with_documents("items", documents.clone());
+        let service = MongoService::with_data_source(collection.clone(), None);
+        let result = service
+            .delete_many(Some(doc! { "views": { "$lt": 30 } }))
+            .unwrap();
+        assert_eq!(result.deleted_count, 1);
+        assert_eq!(collection.documents().len(), 1);
+
+        let (service, collection) = trash_service(documents);
+        assert_eq!(service.delete_many(None).unwrap().deleted_count, 2);
+        assert_eq!(collection.documents().len(), 2);
+        assert_eq!(service.count(None).unwrap(), 0);
+        assert_eq!(service.delete_many(None).unwrap().deleted_count, 0);
+    }
+
+    struct OwnerHooks;
+
+    impl ServiceHooks for OwnerHooks {
+        fn before_update(
+            &self,
+            id: &ID,
+            update: &mut Document,
+            _user_id: Option<&ID>,
+        ) -> Result<(), ServiceError> {
+            let mut set = update.get_document("$set").cloned().unwrap_or_default();
+            if *id != ID::I64(3) {
+                set.insert("owner", id.to_bson());
+            }
+            update.insert("$set", set);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_bulk_update_hooks_change_only_their_own_item() {
+        let collection = MockCollection::with_documents(
+            "items",
+            vec![
+                doc! { "_id": 1_i64 },
+                doc! { "_id": 2_i64 },
+                doc! { "_id": 3_i64 },
+            ],
+        );
+        let service =
+            MongoService::with_data_source(collection.clone(), None).with_hooks(OwnerHooks);
+        let result = service
+            .update_many(None, doc! { "title": "Updated" }, None)
+            .unwrap();
+        assert_eq!((result.matched_count, result.modified_count), (3, 3));
+        let documents = collection.documents();
+        assert_eq!(documents[0].get_i64("owner"), Ok(1));
+        assert_eq!(documents[1].get_i64("owner"), Ok(2));
+        assert!(documents[2].get("owner").is_none());
+        assert!(documents
+            .iter()
+            .all(|item| item.get_str("title") == Ok("Updated")));
+    }
+
+    #[test]
+    fn test_bulk_writes_skip_the_history_of_unsupported_ids() {
+        let collection = MockCollection::with_documents(
+            "items",
+            vec![doc! { "_id": 1.5 }, doc! { "_id": 2_i64 }],
+        );
+        let history = MockCollection::new("items_history");
+        let service =
+            MongoService::with_data_source(collection.clone(), None).with_history(history.clone());
+        let result = service
+            .update_many(None, doc! { "title": "Updated" }, None)
+            .unwrap();
+        assert_eq!(result.modified_count, 2);
+        assert_eq!(history.documents().len(), 1);
+
+        assert_eq!(service.delete_many(None).unwrap().deleted_count, 2);
+        assert!(collection.documents().is_empty());
+        assert_eq!(history.documents().len(), 2);
+    }
+
+    #[test]
+    fn test_embedded_inserts_refuse_unsupported_ids() {
+        let collection = MockCollection::with_documents("items", vec![doc! { "_id": 1_i64 }]);
+        let service = MongoService::with_data_source(collection.clone(), None);
+        let inserted =
+            service.insert_embedded(ID::I64(1), "comments", vec![doc! { "_id": 1.5 }], None);
+        assert!(matches!(inserted, Err(ServiceError::ParseError(_))));
+        let upserted = service.upsert_embedded(
+            ID::I64(1),
+            "comments",
+            vec![doc! { "_id": 1.5 }],
+            None,
+            None::<Document>,
+        );
+        assert!(matches!(upserted, Err(ServiceError::ParseError(_))));
+        assert!(collection.documents()[0].get("comments").is_none());
+
+        let ids = service
+            .insert_embedded(
+                ID::I64(1),
+                "comments",
+                vec![doc! { "_id": "a" }, doc! {}],
+                None,
+            )
+            .unwrap();
+        assert_eq!(ids.len(), 2);
+        assert_eq!(ids[0], ID::with_string("a"));
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct CategoryTotal {
+        #[serde(rename = "_id")]
+        category: String,
+        views: i32,
+        posts: i32,
+    }
+
+    #[test]
+    fn test_aggregate_pages_with_cursors() {
+        let categories = ["news", "sport", "news", "tech", "sport", "news"];
+        let collection = MockCollection::with_documents(
+            "items",
+            categories
+                .iter()
+                .enumerate()
+                .map(|(i, category)| doc! { "_id": i as i64, "views": i as i32 * 10, "category": *category })
+                .collect(),
+        );
+        let service = MongoService::with_data_source(collection, None);
+        let pipeline = vec![doc! {
+            "$group": {
+                "_id": "$category",
+                "views": { "$sum": "$views" },
+                "posts": { "$sum": 1 },
+            }
+        }];
+        let sort = Some(doc! { "views": -1 });
+        let first: FindResult<CategoryTotal> = service
+            .aggregate(pipeline.clone(), sort.clone(), Some(2), None, None, None)
+            .unwrap();
+        assert_eq!(first.total_count, 3);
+        assert!(first.page_info.has_next_page);
+        let totals: Vec<(&str, i32, i32)> = first
+            .items
+            .iter()
+            .map(|t| (t.category.as_str(), t.views, t.posts))
+            .collect();
+        assert_eq!(totals, vec![("news", 70, 3), ("sport", 50, 2)]);
+
+        let second: FindResult<CategoryTotal> = service
+            .aggregate(
+                pipeline.clone(),
+                sort.clone(),
+                Some(2),
+                first.page_info.next_cursor,
+                None,
+                None,
+            )
+            .unwrap();
+        assert_eq!(second.items.len(), 1);
+        assert_eq!(second.items[0].category, "tech");
+        assert!(!second.page_info.has_next_page);
+        let back: FindResult<CategoryTotal> = service
+            .aggregate(
+                pipeline,
+                sort,
+                Some(2),
+                None,
+                second.page_info.start_cursor,
+                None,
+            )
+            .unwrap();
+        assert_eq!(back.items[0].category, "news");
+    }
+
+    #[test]
+    fn test_typed_filters() {
+        let collection = MockCollection::with_documents("posts", posts());
+        let service = MongoService::with_data_source(collection, None);
+        let filter = Filter::range("views", 20..=40)
+            .and(Filter::regex("title", "^post [24]$", "i").or(Filter::eq("_id", 3_i64)));
+        let found: FindResult<Document> = service
+            .find_where(filter, Some(doc! { "_id": 1 }), None, None, None, None)
+            .unwrap();
+        assert_eq!(page_ids(&found), vec![2, 3, 4]);
+
+        let post: Option<Document> = service
+            .find_one_where(Filter::gt("views", 10).and(Filter::lt("views", 30)))
+            .unwrap();
+        assert_eq!(post.unwrap().get_i64("_id"), Ok(2));
+
+        let excluded = Filter::is_in("_id", vec![1_i64, 2, 3]).not();
+        assert!(service
+            .delete_one_where(excluded.and(Filter::gte("views", 50)))
+            .unwrap());
+        assert!(service
+            .find_one_by_id::<Document>(ID::I64(5))
+            .unwrap()
+            .is_none());
+        assert!(!service
+            .delete_one_where(Filter::eq("title", "Post 5"))
+            .unwrap());
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct Hit {
+        #[serde(rename = "_id")]
+        id: ID,
+        score: Option<i32>,
+    }
+
+    fn hit_ids(found: FindResult<Hit>) -> Vec<ID> {
+        found.items.into_iter().map(|hit| hit.id).collect()
+    }
+
+    #[test]
+    fn test_text_search_ranks_by_relevance() {
+        let collection = MockCollection::with_documents(
+            "posts",
+            vec![
+                doc! { "_id": 1_i64, "title": "Rust ownership", "body": "Rust and the borrow checker" },
+                doc! { "_id": 2_i64, "title": "Rust async", "score": 7 },
+                doc! { "_id": 3_i64, "title": "Go channels" },
+                doc! { "_id": 4_i64, "title": "Rust and Go" },
+                doc! { "_id": 5_i64, "title": "Rust async", "node": { "date_deleted": 10_u64 } },
+            ],
+        );
+        let service = MongoService::with_data_source(collection, None)
+            .with_soft_delete()
+            .with_text_search(Some("english"));
+
+        let first: FindResult<Hit> = service
+            .search("rust".to_owned(), vec![], None, Some(2), None, None, None)
+            .unwrap();
+        assert_eq!(first.total_count, 3);
+        assert!(first.page_info.has_next_page);
+        let next_cursor = first.page_info.next_cursor.clone();
+        assert_eq!(hit_ids(first), vec![ID::I64(1), ID::I64(4)]);
+        let second: FindResult<Hit> = service
+            .search(
+                "rust".to_owned(),
+                vec![],
+                None,
+                Some(2),
+                next_cursor,
+                None,
+                None,
+            )
+            .unwrap();
+        assert!(!second.page_info.has_next_page);
+        // the relevance is not mixed up with the item's own fields
+        assert_eq!(second.items[0].score, Some(7));
+        assert_eq!(hit_ids(second), vec![ID::I64(2)]);
+
+        let found: FindResult<Document> = service
+            .text_search(
+                SearchQuery::new("rust -go", vec![]),
+                None,
+                None,
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+        assert!(found
+            .items
+            .iter()
+            .all(|item| !item.contains_key(TEXT_SCORE_FIELD)));
+        let found: FindResult<Hit> = service
+            .text_search(
+                SearchQuery::new("\"rust async\"", vec![]).with_filter(Filter::ne("_id", 5_i64)),
+                None,
+                None,
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+        assert_eq!(hit_ids(found), vec![ID::I64(2)]);
+    }
+
+    #[test]
+    fn test_text_search_refuses_term_matching() {
+        let collection = MockCollection::with_documents("posts", vec![doc! { "title": "Rust" }]);
+        let service = MongoService::with_data_source(collection, None).with_text_search(None);
+        let query = SearchQuery::new("rust", vec![]).with_matching(TermMatch::AllWords);
+        let found: Result<FindResult<Document>, ServiceError> =
+            service.search_with(query, None, None, None, None, None);
+        assert!(found.is_err());
+    }
+
+    #[test]
+    fn test_search_terms_and_filters() {
+        let mut posts: Vec<Document> = (1..=5)
+            .map(|i| doc! { "_id": i as i64, "title": format!("Post {}", i), "views": i * 10 })
+            .collect();
+        posts.push(doc! { "_id": 6_i64, "title": "Rust", "body": "Async (a+)+ post", "views": 0 });
+        let service =
+            MongoService::with_data_source(MockCollection::with_documents("posts", posts), None);
+        let search = |query: SearchQuery| -> Vec<ID> {
+            hit_ids(
+                service
+                    .search_with(query, Some(doc! { "_id": 1 }), None, None, None, None)
+                    .unwrap(),
+            )
+        };
+        let fields = vec!["title".to_owned(), "body".to_owned()];
+
+        // terms are literal unless regex matching is asked for
+        assert!(search(SearchQuery::new("post.*", fields.clone())).is_empty());
+        assert_eq!(
+            search(SearchQuery::new("(A+)+", fields.clone())),
+            vec![ID::I64(6)]
+        );
+        assert_eq!(
+            search(SearchQuery::new("^post [12]$", fields.clone()).with_matching(TermMatch::Regex)),
+            vec![ID::I64(1), ID::I64(2)]
+        );
+
+        assert!(search(SearchQuery::new("3 post", fields.clone())).is_empty());
+        assert_eq!(
+            search(SearchQuery::new("3 post", fields.clone()).with_matching(TermMatch::AllWords)),
+            vec![ID::I64(3)]
+        );
+        assert_eq!(
+            search(
+                SearchQuery::new("rust async", fields.clone()).with_matching(TermMatch::AllWords)
+            ),
+            vec![ID::I64(6)]
+        );
+
+        let query = SearchQuery::new("post", fields).with_filter(Filter::gte("views", 40));
+        assert_eq!(search(query), vec![ID::I64(4), ID::I64(5)]);
+
+        // there is nothing to match the term in without fields
+        assert!(search(SearchQuery::new("post", vec![])).is_empty());
+        assert!(
+            search(SearchQuery::new("3 post", vec![]).with_matching(TermMatch::AllWords))
+                .is_empty()
+        );
+    }
+
+    fn posts() -> Vec<Document> {
+        (1..=5)
+            .map(|i| doc! { "_id": i as i64, "title": format!("Post {}", i), "views": i * 10 })
+            .collect()
+    }
+
+    fn page_ids(page: &FindResult<Document>) -> Vec<i64> {
+        page.items
+            .iter()
+            .filter_map(|item| item.get_i64("_id").ok())
+            .collect()
+    }
+
+    #[test]
+    fn test_count_and_count_by() {
+        let mut posts = posts();
+        for (i, post) in posts.iter_mut().take(4).enumerate() {
+            post.insert("category", if i % 2 == 0 { "odd" } else { "even" });
+        }
+        let service =
+            MongoService::with_data_source(MockCollection::with_documents("posts", posts), None)
+                .with_soft_delete();
+
+        assert_eq!(service.count(None).unwrap(), 5);
+        assert_eq!(
+            service
+                .count(Some(doc! { "views": { "$gt": 20 } }))
+                .unwrap(),
+            3
+        );
+        assert_eq!(
+            service.count_by("category", None).unwrap(),
+            vec![
+                GroupCount {
+                    value: Bson::String("even".to_owned()),
+                    count: 2
+                },
+                GroupCount {
+                    value: Bson::String("odd".to_owned()),
+                    count: 2
+                },
+                GroupCount {
+                    value: Bson::Null,
+                    count: 1
+                },
+            ]
+        );
+
+        // trashed items are not counted
+        service.delete_one_by_id(ID::I64(2)).unwrap();
+        assert_eq!(service.count(None).unwrap(), 4);
+        let counts = service
+            .count_by("category", Some(doc! { "views": { "$lt": 40 } }))
+            .unwrap();
+        let counts: Vec<(Bson, i64)> = counts.into_iter().map(|c| (c.value, c.count)).collect();
+        assert_eq!(counts, vec![(Bson::String("odd".to_owned()), 2)]);
+    }
+
+    #[test]
+    fn test_estimated_counts_page_like_exact_ones() {
+        let collection = MockCollection::with_documents("posts", posts());
+        let service = MongoService::with_data_source(collection, None).with_estimated_count();
+        let first: FindResult<Document> =
+            service.find(None, None, Some(2), None, None, None).unwrap();
+        assert_eq!(first.total_count, 5);
+        let second: FindResult<Document> = service
+            .find(None, None, Some(2), first.page_info.next_cursor, None, None)
+            .unwrap();
+        assert_eq!(page_ids(&second), vec![3, 4]);
+        assert!(second.page_info.has_next_page && second.page_info.has_previous_page);
+        let skipped: FindResult<Document> = service
+            .find(None, None, Some(2), None, None, Some(4))
+            .unwrap();
+        assert_eq!(skipped.items.len(), 1);
+        assert!(!skipped.page_info.has_next_page);
+
+        // soft delete filters every page, so the count stays exact
+        let service = service.with_soft_delete();
+        service.delete_one_by_id(ID::I64(2)).unwrap();
+        let page: FindResult<Document> =
+            service.find(None, None, Some(2), None, None, None).unwrap();
+        assert_eq!(page.total_count, 4);
+    }
+
+    struct PublishedPosts {
+        data_source: MockCollection,
+        published: Document,
+    }
+
+    impl BaseService<'_> for PublishedPosts {
+        fn new(_: &mongodb::Collection, _: Option<Document>) -> Self {
+            unimplemented!()
+        }
+        fn data_source(&self) -> &dyn DataSource {
+            &self.data_source
+        }
+        fn default_filter(&self) -> Option<&Document> {
+            Some(&self.published)
+        }
+    }
+
+    #[test]
+    fn test_watch_change_events() {
+        let collection = MockCollection::with_documents("posts", posts());
+        let service = MongoService::with_data_source(collection.clone(), None);
+        let published = PublishedPosts {
+            data_source: collection,
+            published: doc! { "views": { "$gte": 20 } },
+        };
+        let mut all = service.watch::<Document>(vec![], None).unwrap();
+        let mut popular = published.watch::<Document>(vec![], None).unwrap();
+        let mut inserts = service
+            .watch::<Document>(vec![doc! { "$match": { "operationType": "insert" } }], None)
+            .unwrap();
+        assert!(all.try_next().unwrap().is_none());
+
+        service
+            .insert_one(doc! { "_id": 6_i64, "title": "Post 6", "views": 5 }, None)
+            .unwrap();
+        let _: Item = service
+            .update_one(ID::I64(6), doc! { "views": 60 }, None)
+            .unwrap();
+        service.delete_one_by_id(ID::I64(1)).unwrap();
+
+        let mut events = Vec::new();
+        while let Some(event) = all.try_next().unwrap() {
+            events.push(event);
+        }
+        let operations: Vec<ChangeOperation> = events.iter().map(|e| e.operation.clone()).collect();
+        assert_eq!(
+            operations,
+            vec![
+                ChangeOperation::Insert,
+                ChangeOperation::Update,
+                ChangeOperation::Delete
+            ]
+        );
+        assert_eq!(
+            events[1].document.as_ref().unwrap().get_i32("views"),
+            Ok(60)
+        );
+        assert_eq!(events[2].id, Some(ID::I64(1)));
+        assert!(events[2].document.is_none());
+
+        // the insert had too few views for the default filter
+        let event = popular.try_next().unwrap().unwrap();
+        assert_eq!(event.operation, ChangeOperation::Update);
+        assert_eq!(
+            popular.try_next().unwrap().unwrap().operation,
+            ChangeOperation::Delete
+        );
+        let event = inserts.try_next().unwrap().unwrap();
+        assert_eq!(event.id, Some(ID::I64(6)));
+        assert!(inserts.try_next().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_watch_resumes_after_a_token() {
+        let collection = MockCollection::with_documents("posts", posts());
+        let service = MongoService::with_data_source(collection, None);
+        let mut all = service.watch::<Document>(vec![], None).unwrap();
+        service
+            .insert_one(doc! { "_id": 6_i64, "title": "Post 6" }, None)
+            .unwrap();
+        service.delete_one_by_id(ID::I64(6)).unwrap();
+        let inserted = all.try_next().unwrap().unwrap();
+
+        // resuming after the insert replays what came after it
+        let token = inserted.resume_token.clone();
+        let mut resumed = service
+            .watch::<Document>(vec![], Some(token.clone()))
+            .unwrap();
+        assert_eq!(resumed.resume_token(), Some(&token));
+        let event = resumed.try_next().unwrap().unwrap();
+        assert_eq!(event.operation, ChangeOperation::Delete);
+        assert_eq!(resumed.resume_token(), Some(&event.resume_token));
+        assert!(resumed.try_next().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_cache_is_invalidated_by_writes() {
+        let collection = MockCollection::with_documents("posts", posts());
+        let service = MongoService::with_data_source(collection.clone(), None).with_cache(10, None);
+        let views = |id: i64| -> Option<i32> {
+            let post: Option<Document> = service.find_one_by_id(ID::I64(id)).unwrap();
+            post.and_then(|post| post.get_i32("views").ok())
+        };
+        assert_eq!(views(1), Some(10));
+        assert_eq!(views(1), Some(10));
+        assert_eq!(
+            service.cache_stats(),
+            Some(CacheStats {
+                hits: 1,
+                misses: 1,
+                evictions: 0,
+                size: 1,
+            })
+        );
+
+        // writes that bypass the service aren't seen until the item is invalidated
+        collection
+            .update_one(
+                doc! { "_id": 1_i64 },
+                doc! { "$set": { "views": 11 } },
+                None,
+            )
+            .unwrap();
+        assert_eq!(views(1), Some(10));
+        let _: Item = service
+            .update_one(ID::I64(1), doc! { "views": 12 }, None)
+            .unwrap();
+        assert_eq!(views(1), Some(12));
+
+        service
+            .insert_embedded(ID::I64(1), "comments", vec![doc! { "_id": "a" }], None)
+            .unwrap();
+        let post: Option<Document> = service.find_one_by_id(ID::I64(1)).unwrap();
+        assert_eq!(comments(&post.unwrap()), vec!["a"]);
+
+        assert_eq!(views(2), Some(20));
+        service
+            .update_many(Some(doc! { "_id": 2_i64 }), doc! { "views": 21 }, None)
+            .unwrap();
+        assert_eq!(views(2), Some(21));
+
+        service.delete_one_by_id(ID::I64(1)).unwrap();
+        assert_eq!(views(1), None);
+        assert_eq!(service.cache_stats().unwrap().size, 1);
+    }
+
+    fn titles(page: &FindResult<Document>) -> Vec<&str> {
+        page.items
+            .iter()
+            .filter_map(|item| item.get_str("title").ok())
+            .collect()
+    }
+
+    #[test]
+    fn test_tenant_scoping() {
+        let collection = MockCollection::new("posts");
+        let posts = MongoService::with_data_source(collection.clone(), None)
+            .with_tenant_field("tenant_id")
+            .with_cache(10, None);
+        let acme = posts.for_tenant("acme");
+        let globex = posts.for_tenant("globex");
+
+        // the tenant is stamped over whatever the item claims
+        acme.insert_one(
+            doc! { "_id": 1_i64, "title": "Acme", "views": 1, "tenant_id": "globex" },
+            None,
+        )
+        .unwrap();
+        globex
+            .insert_many(
+                vec![doc! { "_id": 2_i64, "title": "Globex", "views": 2 }],
+                None,
+            )
+            .unwrap();
+        assert_eq!(collection.documents()[0].get_str("tenant_id"), Ok("acme"));
+        assert!(posts
+            .insert_one(doc! { "title": "Nobody's" }, None)
+            .is_err());
+
+        let page: FindResult<Document> = acme.find(None, None, None, None, None, None).unwrap();
+        assert_eq!(titles(&page), vec!["Acme"]);
+        let unscoped: FindResult<Document> =
+            posts.find(None, None, None, None, None, None).unwrap();
+        assert!(unscoped.items.is_empty());
+        assert_eq!(globex.count(Some(doc! { "tenant_id": "acme" })).unwrap(), 0);
+
+        // the shared cache doesn't hand one tenant's item to another
+        let found: Option<Document> = acme.find_one_by_id(ID::I64(1)).unwrap();
+        assert!(found.is_some());
+        let found: Option<Document> = globex.find_one_by_id(ID::I64(1)).unwrap();
+        assert!(found.is_none());
+
+        let updated: Result<Item, ServiceError> =
+            globex.update_one(ID::I64(1), doc! { "views": 100 }, None);
+        assert!(updated.is_err());
+        globex.delete_one_by_id(ID::I64(1)).unwrap();
+        globex
+            .upsert_embedded(
+                ID::I64(1),
+                "comments",
+                vec![doc! { "_id": "c1", "text": "hijacked" }],
+                None,
+                Some(doc! { "title": "Hijack", "views": 0 }),
+            )
+            .unwrap_err();
+        let post: Document = acme.find_one_by_id(ID::I64(1)).unwrap().unwrap();
+        assert_eq!(post.get_i32("views"), Ok(1));
+        assert!(post.get("comments").is_none());
+        assert_eq!(collection.documents().len(), 2);
+
+        let scoped = {
+            let mut sources = crate::DataSources::new();
+            sources.add_mongo_service("posts", posts.clone());
+            sources.for_tenant("globex")
+        };
+        let page: FindResult<Document> = scoped
+            .get_mongo_service("posts")
+            .unwrap()
+            .find(None, None, None, None, None, None)
+            .unwrap();
+        assert_eq!(titles(&page), vec!["Globex"]);
+    }
+
+    #[test]
+    fn test_tenant_cannot_be_moved() {
+        let collection = MockCollection::new("posts");
+        let posts = MongoService::with_data_source(collection.clone(), None)
+            .with_tenant_field("tenant_id")
+            .with_soft_delete();
+        let acme = posts.for_tenant("acme");
+        let globex = posts.for_tenant("globex");
+        acme.insert_one(doc! { "_id": 1_i64, "title": "Acme", "views": 1 }, None)
+            .unwrap();
+        let tenant = || {
+            collection.documents()[0]
+                .get_str("tenant_id")
+                .unwrap()
+                .to_owned()
+        };
+
+        // none of the update paths can hand the item over to another tenant
+        let _: Item = acme
+            .update_one(ID::I64(1), doc! { "tenant_id": "globex" }, None)
+            .unwrap();
+        let _: Item = acme
+            .update_one_with_doc(
+                ID::I64(1),
+                doc! { "$set": { "tenant_id": "globex", "views": 2 } },
+            )
+            .unwrap();
+        let _: Item = acme
+            .update_one_with_doc(ID::I64(1), doc! { "$unset": { "tenant_id": "" } })
+            .unwrap();
+        acme.update_many(None, doc! { "tenant_id": "globex" }, None)
+            .unwrap();
+        acme.update_many_with_doc(
+            None,
+            doc! { "$set": { "tenant_id": "globex" }, "$inc": { "views": 1 } },
+            None,
+        )
+        .unwrap();
+        assert_eq!(tenant(), "acme");
+        let post: Document = acme.find_one_by_id(ID::I64(1)).unwrap().unwrap();
+        assert_eq!(post.get_i32("views"), Ok(3));
+        assert_eq!(globex.count(None).unwrap(), 0);
+
+        // a filter on the tenant field doesn't hide the trash from the scope
+        acme.delete_one_by_id(ID::I64(1)).unwrap();
+        let tenant_filter = Some(doc! { "tenant_id": "acme" });
+        let deleted: FindResult<Document> = acme
+            .find_deleted(tenant_filter.clone(), None, None, None, None, None)
+            .unwrap();
+        assert_eq!(deleted.items.len(), 1);
+        let deleted: FindResult<Document> = globex
+            .find_deleted(tenant_filter, None, None, None, None, None)
+            .unwrap();
+        assert!(deleted.items.is_empty());
+    }
+
+    #[test]
+    fn test_nested_tenant_fields_stay_with_the_tenant() {
+        let collection = MockCollection::new("posts");
+        let acme = MongoService::with_data_source(collection.clone(), None)
+            .with_tenant_field("org.id")
+            .for_tenant("acme");
+        acme.insert_one(doc! { "_id": 1_i64, "org": { "id": "globex" } }, None)
+            .unwrap();
+        let _: Item = acme
+            .update_one_with_doc(
+                ID::I64(1),
+                doc! { "$set": { "org": { "id": "globex", "name": "Globex" } } },
+            )
+            .unwrap();
+        let org = collection.documents()[0]
+            .get_document("org")
+            .unwrap()
+            .clone();
+        assert_eq!(org, doc! { "name": "Globex", "id": "acme" });
+        assert_eq!(acme.count(None).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_upsert_embedded_stamps_the_tenant() {
+        let collection = MockCollection::new("posts");
+        let posts =
+            MongoService::with_data_source(collection.clone(), None).with_tenant_field("tenant_id");
+        let acme = posts.for_tenant("acme");
+        acme.upsert_embedded(
+            ID::I64(1),
+            "comments",
+            vec![doc! { "_id": "a" }],
+            None,
+            None::<Document>,
+        )
+        .unwrap();
+        assert_eq!(collection.documents()[0].get_str("tenant_id"), Ok("acme"));
+        assert_eq!(acme.count(None).unwrap(), 1);
+
+        let upserted = posts.upsert_embedded(
+            ID::I64(2),
+            "comments",
+            vec![doc! { "_id": "a" }],
+            None,
+            None::<Document>,
+        );
+        assert!(upserted.is_err());
+        assert_eq!(collection.documents().len(), 1);
+    }
+
+    #[test]
+    fn test_watch_skips_other_tenants_deletes() {
+        let collection = MockCollection::new("posts");
+        let posts = MongoService::with_data_source(collection, None).with_tenant_field("tenant_id");
+        let acme = posts.for_tenant("acme");
+        let globex = posts.for_tenant("globex");
+        let mut events = acme.watch::<Document>(vec![], None).unwrap();
+        globex.insert_one(doc! { "_id": 1_i64 }, None).unwrap();
+        globex.delete_one_by_id(ID::I64(1)).unwrap();
+        acme.insert_one(doc! { "_id": 2_i64 }, None).unwrap();
+
+        let event = events.try_next().unwrap().unwrap();
+        assert_eq!(event.operation, ChangeOperation::Insert);
+        assert_eq!(event.id, Some(ID::I64(2)));
+        // the mock's delete events carry no shard key, so the tenant's own deletes are skipped
+        // as well
+        acme.delete_one_by_id(ID::I64(2)).unwrap();
+        assert!(events.try_next().unwrap().is_none());
+    }
+    #[test]
+    fn test_projections() {
+        let collection = MockCollection::with_documents(
+            "users",
+            (1..=3)
+                .map(|i| {
+                    doc! {
+                        "_id": i as i64,
+                        "name": format!("User {}", i),
+                        "password": "hash",
+                        "views": i * 10,
+                        "comments": [{ "text": "a" }, { "text": "b" }, { "text": "c" }],
+                    }
+                })
+                .collect(),
+        );
+        let service = MongoService::with_data_source(collection, None)
+            .with_hidden_fields(&["password"])
+            .with_cache(10, None);
+        let keys = |item: &Document| -> Vec<String> { item.keys().cloned().collect() };
+
+        let page: FindResult<Document> = service.find(None, None, None, None, None, None).unwrap();
+        assert_eq!(
+            keys(&page.items[0]),
+            vec!["_id", "name", "views", "comments"]
+        );
+
+        // the sort field is kept for the cursors
+        let projection = Projection::include(&["name"]).with_slice("comments", -1);
+        let first: FindResult<Document> = service
+            .find_projected(
+                None,
+                Some(projection.clone()),
+                Some(doc! { "views": -1 }),
+                Some(1),
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+        assert_eq!(
+            keys(&first.items[0]),
+            vec!["_id", "name", "views", "comments"]
+        );
+        assert_eq!(
+            first.items[0].get_array("comments").unwrap(),
+            &vec![Bson::Document(doc! { "text": "c" })]
+        );
+        let second: FindResult<Document> = service
+            .find_projected(
+                None,
+                Some(projection),
+                Some(doc! { "views": -1 }),
+                Some(1),
+                first.page_info.next_cursor,
+                None,
+                None,
+            )
+            .unwrap();
+        assert_eq!(second.items[0].get_str("name").unwrap(), "User 2");
+
+        // the cursors of a sort by a hidden field would give it away
+        let by_password: Result<FindResult<Document>, ServiceError> =
+            service.find(None, Some(doc! { "password": 1 }), None, None, None, None);
+        assert!(by_password.is_err());
+        let by_password: FindResult<Document> = service
+            .find_projected(
+                None,
+                Some(Projection::all().revealing(&["password"])),
+                Some(doc! { "password": 1 }),
+                None,
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+        assert_eq!(by_password.items.len(), 3);
+
+        let user: Document = service.find_one_by_id(ID::I64(1)).unwrap().unwrap();
+        assert!(!user.contains_key("password"));
+        // a second read comes from the cache, which still hides the password
+        let user: Document = service.find_one_by_id(ID::I64(1)).unwrap().unwrap();
+        assert!(!user.contains_key("password"));
+        let user: Document = service
+            .find_one_by_id_projected(
+                ID::I64(1),
+                Some(Projection::exclude(&["comments"]).revealing(&["password"])),
+            )
+            .unwrap()
+            .unwrap();
+        assert_eq!(keys(&user), vec!["_id", "name", "password", "views"]);
+        let user: Document = service
+            .find_one_projected(
+                doc! { "name": "User 2" },
+                Some(Projection::include(&["password"])),
+            )
+            .unwrap()
+            .unwrap();
+        assert_eq!(keys(&user), vec!["_id", "password"]);
+
+        let query = SearchQuery::new("user 3", vec!["name".to_owned()])
+            .with_projection(Projection::include(&["name"]));
+        let found: FindResult<Document> = service
+            .search_with(query.clone(), None, None, None, None, None)
+            .unwrap();
+        assert_eq!(keys(&found.items[0]), vec!["_id", "name"]);
+
+        let service = service.with_text_search(None);
+        let query = SearchQuery::new("user", vec![])
+            .with_projection(Projection::exclude(&["name"]).with_slice_range("comments", 1, 1));
+        let found: FindResult<Document> = service
+            .search_with(query, None, Some(1), None, None, None)
+            .unwrap();
+        assert_eq!(found.total_count, 3);
+        assert_eq!(keys(&found.items[0]), vec!["_id", "views", "comments"]);
+        assert_eq!(
+            found.items[0].get_array("comments").unwrap(),
+            &vec![Bson::Document(doc! { "text": "b" })]
+        );
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct User {
+        password: Option<String>,
+        #[serde(default)]
+        node: NodeDetails,
+    }
+
+    impl Node for User {
+        fn node(&self) -> &NodeDetails {
+            &self.node
+        }
+    }
+
+    #[test]
+    fn test_hidden_fields_stay_hidden_on_every_read() {
+        let collection = MockCollection::with_documents(
+            "users",
+            vec![doc! {
+                "_id": 1_i64,
+                "name": "User 1",
+                "password": "hash",
+                "comments": [{ "_id": "a", "text": "a", "email": "a@example.com" }],
+            }],
+        );
+        let service = MongoService::with_data_source(collection, None)
+            .with_hidden_fields(&["password", "comments.email"]);
+        let mut changes = service.watch::<Document>(vec![], None).unwrap();
+        let a = ID::with_string("a");
+
+        let page: FindResult<Document> = service
+            .aggregate(vec![], None, None, None, None, None)
+            .unwrap();
+        assert!(!page.items[0].contains_key("password"));
+        let grouped: FindResult<Document> = service
+            .aggregate(
+                vec![doc! { "$group": { "_id": "$password" } }],
+                None,
+                None,
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+        assert_eq!(grouped.items[0].get("_id"), Some(&Bson::Null));
+
+        let comments: FindResult<Document> = service
+            .find_embedded(ID::I64(1), "comments", None, None, None, None, None, None)
+            .unwrap();
+        assert_eq!(comments.items, vec![doc! { "_id": "a", "text": "a" }]);
+        let comments: Vec<Document> = service
+            .get_embedded_by_id(ID::I64(1), "comments", None, None)
+            .unwrap();
+        assert_eq!(comments, vec![doc! { "_id": "a", "text": "a" }]);
+
+        let user: User = service
+            .update_one(ID::I64(1), doc! { "name": "User" }, None)
+            .unwrap();
+        assert!(user.password.is_none());
+        let user: Document = service
+            .update_embedded(
+                ID::I64(1),
+                "comments",
+                a.clone(),
+                doc! { "text": "b" },
+                None,
+            )
+            .unwrap();
+        assert!(!user.contains_key("password"));
+        let comment: Document = service
+            .find_and_update_embedded_item(ID::I64(1), "comments", a, doc! { "text": "c" }, None)
+            .unwrap();
+        assert!(!comment.contains_key("email"));
+
+        let event = changes.try_next().unwrap().unwrap();
+        let user = event.document.unwrap();
+        assert!(!user.contains_key("password"));
+        let comment = user.get_array("comments").unwrap()[0]
+            .as_document()
+            .unwrap();
+        assert!(!comment.contains_key("email"));
+    }
+
+    #[test]
+    fn test_find_embedded() {
+        let comments: Vec<Bson> = (1..=6)
+            .map(|i| {
+                Bson::Document(doc! {
+                    "_id": i as i64,
+                    "text": format!("Comment {}", i),
+                    "author_id": if i % 2 == 0 { "ann" } else { "bob" },
+                    "node": { "date_created": i * 100 },
+                })
+            })
+            .collect();
+        let collection = MockCollection::with_documents(
+            "posts",
+            vec![
+                doc! { "_id": 1_i64, "title": "Post 1", "views": 10, "comments": comments },
+                doc! { "_id": 2_i64, "title": "Post 2", "views": 20, "comments": [] },
+            ],
+        );
+        let service = MongoService::with_data_source(collection, None).with_soft_delete();
+        service
+            .soft_delete_embedded(ID::I64(1), "comments", ID::I64(4), None)
+            .unwrap();
+        let texts = |page: &FindResult<Document>| -> Vec<String> {
+            page.items
+                .iter()
+                .map(|comment| comment.get_str("text").unwrap().to_owned())
+                .collect()
+        };
+
+        let newest_first = Some(doc! { "node.date_created": -1 });
+        let first: FindResult<Document> = service
+            .find_embedded(
+                ID::I64(1),
+                "comments",
+                Some(doc! { "author_id": "ann" }),
+                newest_first.clone(),
+                Some(1),
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+        assert_eq!(first.total_count, 2);
+        assert_eq!(texts(&first), vec!["Comment 6"]);
+        assert!(first.page_info.has_next_page);
+        let second: FindResult<Document> = service
+            .find_embedded(
+                ID::I64(1),
+                "comments",
+                Some(doc! { "author_id": "ann" }),
+                newest_first,
+                Some(1),
+                first.page_info.next_cursor,
+                None,
+                None,
+            )
+            .unwrap();
+        assert_eq!(texts(&second), vec!["Comment 2"]);
+        assert!(!second.page_info.has_next_page);
+        let back: FindResult<Document> = service
+            .find_embedded(
+                ID::I64(1),
+                "comments",
+                Some(doc! { "author_id": "ann" }),
+                Some(doc! { "node.date_created": -1 }),
+                Some(1),
+                None,
+                second.page_info.start_cursor,
+                None,
+            )
+            .unwrap();
+        assert_eq!(texts(&back), vec!["Comment 6"]);
+
+        let all: FindResult<Document> = service
+            .find_embedded(
+                ID::I64(1),
+                "comments",
+                None,
+                Some(doc! { "_id": 1 }),
+                None,
+                None,
+                None,
+                Some(3),
+            )
+            .unwrap();
+        assert_eq!(all.total_count, 5);
+        assert_eq!(texts(&all), vec!["Comment 5", "Comment 6"]);
+        let none: FindResult<Document> = service
+            .find_embedded(ID::I64(2), "comments", None, None, None, None, None, None)
+            .unwrap();
+        assert_eq!(none.total_count, 0);
+        assert!(none.items.is_empty());
+    }
+
+    #[test]
+    fn test_nested_embedded() {
+        let collection = MockCollection::with_documents(
+            "boards",
+            vec![doc! {
+                "_id": 1_i64,
+                "sections": [
+                    { "_id": "s1", "items": [{ "_id": "i1", "text": "a" }, { "_id": "i2", "text": "b" }] },
+                    { "_id": "s2", "items": [{ "_id": "i1", "text": "other section" }] },
+                ],
+            }],
+        );
+        let service = MongoService::with_data_source(collection.clone(), None);
+        let stored = || collection.documents().remove(0);
+        let section = |board: &Document, index: usize| -> Document {
+            match &board.get_array("sections").unwrap()[index] {
+                Bson::Document(section) => section.clone(),
+                _ => panic!("not a section"),
+            }
+        };
+        let texts = |section: &Document| -> Vec<String> {
+            section
+                .get_array("items")
+                .unwrap()
+                .iter()
+                .map(|item| match item {
+                    Bson::Document(item) => item.get_str("text").unwrap().to_owned(),
+                    _ => panic!("not an item"),
+                })
+                .collect()
+        };
+        let version = |item: &Document| -> i64 {
+            item.get_document("node")
+                .and_then(|node| node.get_i64("version"))
+                .unwrap_or(0)
+        };
+
+        let s1 = ("sections", ID::String("s1".into()));
+        let ids = service
+            .insert_nested_embedded(
+                ID::I64(1),
+                std::slice::from_ref(&s1),
+                "items",
+                vec![doc! { "text": "c" }],
+                Some(ID::String("ann".into())),
+            )
+            .unwrap();
+        let board = stored();
+        assert_eq!(texts(&section(&board, 0)), vec!["a", "b", "c"]);
+        assert_eq!(texts(&section(&board, 1)), vec!["other section"]);
+        assert!(board
+            .get_document("node")
+            .unwrap()
+            .contains_key("date_modified"));
+        assert!(section(&board, 0).get_document("node").is_ok());
+        assert!(section(&board, 1).get_document("node").is_err());
+        assert_eq!((version(&board), version(&section(&board, 0))), (1, 0));
+
+        let updated: Document = service
+            .update_nested_embedded(
+                ID::I64(1),
+                &[s1.clone(), ("items", ID::String("i1".into()))],
+                doc! { "text": "a2" },
+                None,
+            )
+            .unwrap();
+        assert_eq!(texts(&section(&updated, 0)), vec!["a2", "b", "c"]);
+        assert_eq!(texts(&section(&updated, 1)), vec!["other section"]);
+        assert_eq!((version(&updated), version(&section(&updated, 0))), (2, 0));
+        let item = match &section(&updated, 0).get_array("items").unwrap()[0] {
+            Bson::Document(item) => item.clone(),
+            _ => panic!("not an item"),
+        };
+        assert_eq!(version(&item), 1);
+
+        service
+            .delete_nested_embedded(ID::I64(1), &[s1.clone(), ("items", ids[0].clone())], None)
+            .unwrap();
+        let board = stored();
+        assert_eq!(texts(&section(&board, 0)), vec!["a2", "b"]);
+        assert_eq!((version(&board), version(&section(&board, 0))), (3, 0));
+
+        let missing = service.update_nested_embedded::<Document, Document>(
+            ID::I64(1),
+            &[
+                ("sections", ID::String("s2".into())),
+                ("items", ID::String("i2".into())),
+            ],
+            doc! { "text": "x" },
+            None,
+        );
+        assert!(matches!(missing, Err(ServiceError::NotFound(_))));
+        let missing = service.insert_nested_embedded(
+            ID::I64(2),
+            &[s1],
+            "items",
+            vec![doc! { "text": "x" }],
+            None,
+        );
+        assert!(matches!(missing, Err(ServiceError::NotFound(_))));
+    }
+
+    #[test]
+    fn test_find_and_update_embedded() {
+        let collection = MockCollection::with_documents(
+            "posts",
+            vec![doc! {
+                "_id": 1_i64,
+                "title": "Post 1",
+                "views": 10,
+                "comments": [{ "_id": "c1", "text": "first" }, { "_id": "c2", "text": "second" }],
+            }],
+        );
+        let service = MongoService::with_data_source(collection.clone(), None);
+
+        let comment: Document = service
+            .find_and_update_embedded_item(
+                ID::I64(1),
+                "comments",
+                ID::String("c2".into()),
+                doc! { "text": "edited" },
+                Some(ID::String("ann".into())),
+            )
+            .unwrap();
+        assert_eq!(comment.get_str("_id").unwrap(), "c2");
+        assert_eq!(comment.get_str("text").unwrap(), "edited");
+        let node = comment.get_document("node").unwrap();
+        assert_eq!(node.get_i64("version").unwrap(), 1);
+        assert_eq!(node.get_str("updated_by_id").unwrap(), "ann");
+
+        let post: Document = service
+            .find_and_update_embedded(
+                ID::I64(1),
+                "comments",
+                ID::String("c1".into()),
+                doc! { "text": "also edited" },
+                None,
+            )
+            .unwrap();
+        let texts: Vec<&str> = post
+            .get_array("comments")
+            .unwrap()
+            .iter()
+            .filter_map(|comment| comment.as_document())
+            .map(|comment| comment.get_str("text").unwrap())
+            .collect();
+        assert_eq!(texts, vec!["also edited", "edited"]);
+
+        let before = collection.documents();
+        let missing = service.find_and_update_embedded_item::<Document, Document>(
+            ID::I64(1),
+            "comments",
+            ID::String("c3".into()),
+            doc! { "text": "nope" },
+            None,
+        );
+        assert!(matches!(missing, Err(ServiceError::NotFound(_))));
+        assert_eq!(collection.documents(), before);
+    }
+
+    fn posts_service() -> (MongoService, MockCollection) {
+        let collection = MockCollection::with_documents("posts", posts());
+        let service = MongoService::with_data_source(collection.clone(), None);
+        (service, collection)
+    }
+
+    #[test]
+    fn test_upsert_one_inserts_then_updates() {
+        let (service, collection) = posts_service();
+        let created = service
+            .upsert_one(
+                UpsertKey::Id(ID::I64(6)),
+                doc! { "title": "Post 6", "views": 60 },
+                Some(ID::with_string("ann")),
+            )
+            .unwrap();
+        assert_eq!((created.id, created.inserted), (ID::I64(6), true));
+        let updated = service
+            .upsert_one(
+                UpsertKey::Id(ID::I64(6)),
+                doc! { "_id": 6_i64, "title": "Post six", "views": 61 },
+                Some(ID::with_string("bob")),
+            )
+            .unwrap();
+        assert!(!updated.inserted);
+
+        let post = collection.documents().remove(5);
+        assert_eq!(post.get_str("title"), Ok("Post six"));
+        let node = post.get_document("node").unwrap();
+        assert_eq!(node.get_str("created_by_id"), Ok("ann"));
+        assert_eq!(node.get_str("updated_by_id"), Ok("bob"));
+        assert_eq!(node.get_i64("version"), Ok(2));
+        assert!(node.contains_key("date_created") && node.contains_key("date_modified"));
+        assert_eq!(collection.documents().len(), 6);
+    }
+
+    #[test]
+    fn test_upsert_many_keeps_going_past_failures() {
+        let (service, collection) = posts_service();
+        let service = service.with_soft_delete();
+        service.delete_one_by_id(ID::I64(2)).unwrap();
+        let results = service.upsert_many(
+            vec![
+                (
+                    UpsertKey::Filter(doc! { "title": "Post 2" }),
+                    doc! { "title": "Post 2", "views": 21 },
+                ),
+                (
+                    UpsertKey::Filter(doc! { "title": "Post 1" }),
+                    doc! { "title": "Post 1", "views": 11 },
+                ),
+                (
+                    UpsertKey::Filter(doc! { "title": "Post 7" }),
+                    doc! { "title": "Post 7", "views": 70 },
+                ),
+            ],
+            None,
+        );
+        assert!(matches!(results[0], Err(ServiceError::Trashed(ID::I64(2)))));
+        let updated = results[1].as_ref().unwrap();
+        assert_eq!((&updated.id, updated.inserted), (&ID::I64(1), false));
+        assert!(results[2].as_ref().unwrap().inserted);
+        assert_eq!(collection.documents()[0].get_i32("views"), Ok(11));
+        assert_eq!(service.count(Some(doc! { "title": "Post 7" })).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_upsert_leaves_trashed_items_in_the_trash() {
+        let (service, collection) = trash_service(vec![doc! { "_id": 1_i64, "title": "kept" }]);
+        service.delete_one_by_id(ID::I64(1)).unwrap();
+        for key in [
+            UpsertKey::Id(ID::I64(1)),
+            UpsertKey::Filter(doc! { "title": "kept" }),
+        ] {
+            let upserted = service.upsert_one(key, doc! { "title": "kept" }, None);
+            assert!(matches!(upserted, Err(ServiceError::Trashed(ID::I64(1)))));
+        }
+        assert_eq!(collection.documents().len(), 1);
+
+        assert!(service.restore_by_id(ID::I64(1)).unwrap());
+        let restored = service
+            .upsert_one(UpsertKey::Id(ID::I64(1)), doc! { "title": "back" }, None)
+            .unwrap();
+        assert!(!restored.inserted);
+    }
+
+    #[test]
+    fn test_upsert_validates_before_running_hooks() {
+        use std::sync::{Arc, Mutex};
+
+        struct Titled;
+        impl Validator for Titled {
+            fn validate(
+                &self,
+                document: &Document,
+                _scope: ValidationScope,
+                _field_path: Option<&str>,
+            ) -> Vec<FieldError> {
+                match document.get_str("title") {
+                    Ok("") | Err(_) => vec![FieldError::new("title", "is required")],
+                    Ok(_) => Vec::new(),
+                }
+            }
+        }
+
+        #[derive(Clone, Default)]
+        struct Calls(Arc<Mutex<Vec<String>>>);
+        impl ServiceHooks for Calls {
+            fn before_insert(
+                &self,
+                document: &mut Document,
+                _user_id: Option<&ID>,
+            ) -> Result<(), ServiceError> {
+                self.0.lock().unwrap().push("before insert".to_owned());
+                document.insert("views", 0);
+                Ok(())
+            }
+
+            fn after_insert(
+                &self,
+                id: &ID,
+                _document: &Document,
+                _user_id: Option<&ID>,
+            ) -> Result<(), ServiceError> {
+                self.0.lock().unwrap().push(format!("insert {}", id));
+                Ok(())
+            }
+
+            fn before_update(
+                &self,
+                id: &ID,
+                _update: &mut Document,
+                _user_id: Option<&ID>,
+            ) -> Result<(), ServiceError> {
+                match id {
+                    ID::I64(1) => Err("Post 1 is pinned".into()),
+                    _ => Ok(()),
+                }
+            }
+
+            fn after_update(
+                &self,
+                id: &ID,
+                document: &Document,
+                _user_id: Option<&ID>,
+            ) -> Result<(), ServiceError> {
+                let title = document.get_str("title").unwrap_or_default();
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push(format!("update {} {}", id, title));
+                Ok(())
+            }
+        }
+
+        let (service, collection) = posts_service();
+        let calls = Calls::default();
+        let service = service.with_validator(Titled).with_hooks(calls.clone());
+
+        let invalid = service.upsert_one(UpsertKey::Id(ID::I64(9)), doc! { "views": 1 }, None);
+        assert!(matches!(invalid, Err(ServiceError::ValidationError(_))));
+        assert!(calls.0.lock().unwrap().is_empty());
+
+        let vetoed = service.upsert_one(
+            UpsertKey::Filter(doc! { "title": "Post 1" }),
+            doc! { "title": "Post 1", "views": 11 },
+            None,
+        );
+        assert!(vetoed.is_err());
+        assert_eq!(collection.documents()[0].get_i32("views"), Ok(10));
+
+        service
+            .upsert_one(
+                UpsertKey::Id(ID::I64(10)),
+                doc! { "title": "Hooked", "views": 99 },
+                None,
+            )
+            .unwrap();
+        assert_eq!(collection.documents()[5].get_i32("views"), Ok(0));
+        service
+            .upsert_one(
+                UpsertKey::Id(ID::I64(10)),
+                doc! { "title": "Renamed", "views": 5 },
+                None,
+            )
+            .unwrap();
+        assert_eq!(
+            *calls.0.lock().unwrap(),
+            vec!["before insert", "insert 10", "update 10 Renamed"]
+        );
+    }
+
+    #[test]
+    fn test_upsert_stays_in_the_tenant() {
+        let (service, _) = posts_service();
+        let tenants = service.with_tenant_field("tenant_id");
+        let acme = tenants.for_tenant("acme");
+        let upserted = acme
+            .upsert_one(
+                UpsertKey::Filter(doc! { "title": "Post 1" }),
+                doc! { "title": "Post 1", "views": 1, "tenant_id": "other" },
+                None,
+            )
+            .unwrap();
+        // the unscoped post 1 isn't acme's, so acme gets its own
+        assert!(upserted.inserted);
+        assert_eq!(acme.count(Some(doc! { "title": "Post 1" })).unwrap(), 1);
+        let refused = tenants.upsert_one(
+            UpsertKey::Id(ID::I64(8)),
+            doc! { "title": "Post 8", "views": 80 },
+            None,
+        );
+        assert!(refused.is_err());
     }
 }
@@ -0,0 +1,217 @@
+use bson::Document;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::id::ID;
+
+/// Counters of an `ItemCache`, for monitoring.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Items dropped to make room for others.
+    pub evictions: u64,
+    /// Items currently cached.
+    pub size: usize,
+}
+
+struct Entry {
+    document: Document,
+    stored_at: Instant,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct CacheState {
+    entries: HashMap<ID, Entry>,
+    /// The ids of the entries by when they were last used, oldest first.
+    recency: BTreeMap<u64, ID>,
+    clock: u64,
+    generation: u64,
+    stats: CacheStats,
+}
+
+impl CacheState {
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    fn remove(&mut self, id: &ID) -> Option<Entry> {
+        let entry = self.entries.remove(id)?;
+        self.recency.remove(&entry.last_used);
+        Some(entry)
+    }
+
+    fn evict_least_recently_used(&mut self) {
+        if let Some((_, id)) = self.recency.pop_first() {
+            self.entries.remove(&id);
+            self.stats.evictions += 1;
+        }
+    }
+}
+
+/// A bounded read-through cache of items by `ID`, used by `find_one_by_id`.
+///
+/// Once `capacity` items are cached the least recently used one makes room, and items older
+/// than `ttl` are read again. The service invalidates an item whenever it writes to it, and
+/// clears the whole cache on writes that don't say which items they touched, like
+/// `update_many`. Writes made outside of the service are only picked up once the `ttl` expires.
+pub struct ItemCache {
+    capacity: usize,
+    ttl: Option<Duration>,
+    state: Mutex<CacheState>,
+}
+
+impl ItemCache {
+    pub fn new(capacity: usize, ttl: Option<Duration>) -> Self {
+        ItemCache {
+            capacity,
+            ttl,
+            state: Mutex::new(CacheState::default()),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, CacheState> {
+        self.state.lock().expect("Item cache lock poisoned")
+    }
+
+    /// Changes with every invalidation. Take it before reading an item from the collection and
+    /// hand it to `insert`, so that an item read before a concurrent write isn't cached.
+    pub fn generation(&self) -> u64 {
+        self.state().generation
+    }
+
+    pub fn get(&self, id: &ID) -> Option<Document> {
+        let mut state = self.state();
+        let clock = state.tick();
+        let ttl = self.ttl;
+        let found = match state.entries.get_mut(id) {
+            Some(entry) if ttl.map_or(true, |ttl| entry.stored_at.elapsed() < ttl) => {
+                let last_used = std::mem::replace(&mut entry.last_used, clock);
+                let document = entry.document.clone();
+                state.recency.remove(&last_used);
+                state.recency.insert(clock, id.clone());
+                Some(document)
+            }
+            Some(_) => {
+                state.remove(id);
+                None
+            }
+            None => None,
+        };
+        match found {
+            Some(_) => state.stats.hits += 1,
+            None => state.stats.misses += 1,
+        }
+        found
+    }
+
+    /// Caches an item read at `generation`, unless it has been invalidated since.
+    pub fn insert(&self, id: ID, document: Document, generation: u64) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut state = self.state();
+        if state.generation != generation {
+            return;
+        }
+        if state.remove(&id).is_none() && state.entries.len() >= self.capacity {
+            state.evict_least_recently_used();
+        }
+        let last_used = state.tick();
+        state.recency.insert(last_used, id.clone());
+        state.entries.insert(
+            id,
+            Entry {
+                document,
+                stored_at: Instant::now(),
+                last_used,
+            },
+        );
+    }
+
+    pub fn invalidate(&self, id: &ID) {
+        let mut state = self.state();
+        state.generation += 1;
+        state.remove(id);
+    }
+
+    pub fn clear(&self) {
+        let mut state = self.state();
+        state.generation += 1;
+        state.entries.clear();
+        state.recency.clear();
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let state = self.state();
+        CacheStats {
+            size: state.entries.len(),
+            ..state.stats
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bson::doc;
+    use std::thread::sleep;
+
+    #[test]
+    fn test_lru_eviction_and_ttl() {
+        let cache = ItemCache::new(2, None);
+        let generation = cache.generation();
+        cache.insert(ID::I64(1), doc! { "_id": 1_i64 }, generation);
+        cache.insert(ID::I64(2), doc! { "_id": 2_i64 }, generation);
+        assert!(cache.get(&ID::I64(1)).is_some());
+        // 2 is the least recently used now
+        cache.insert(ID::I64(3), doc! { "_id": 3_i64 }, generation);
+        assert!(cache.get(&ID::I64(2)).is_none());
+        assert!(cache.get(&ID::I64(1)).is_some());
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                hits: 2,
+                misses: 1,
+                evictions: 1,
+                size: 2,
+            }
+        );
+
+        // an item read before an invalidation isn't cached
+        cache.invalidate(&ID::I64(1));
+        cache.insert(ID::I64(1), doc! { "_id": 1_i64 }, generation);
+        assert!(cache.get(&ID::I64(1)).is_none());
+
+        let cache = ItemCache::new(10, Some(Duration::from_millis(20)));
+        cache.insert(ID::I64(1), doc! { "_id": 1_i64 }, cache.generation());
+        assert!(cache.get(&ID::I64(1)).is_some());
+        sleep(Duration::from_millis(40));
+        assert!(cache.get(&ID::I64(1)).is_none());
+        assert_eq!(cache.stats().size, 0);
+    }
+
+    #[test]
+    fn test_reinserting_an_item_keeps_one_entry() {
+        let cache = ItemCache::new(2, None);
+        let generation = cache.generation();
+        cache.insert(ID::I64(1), doc! { "_id": 1_i64 }, generation);
+        cache.insert(ID::I64(2), doc! { "_id": 2_i64 }, generation);
+        cache.insert(ID::I64(1), doc! { "_id": 1_i64, "v": 2 }, generation);
+        assert_eq!(cache.stats().evictions, 0);
+        // 1 was refreshed by the second insert, so 2 makes room
+        cache.insert(ID::I64(3), doc! { "_id": 3_i64 }, generation);
+        assert!(cache.get(&ID::I64(2)).is_none());
+        assert_eq!(cache.get(&ID::I64(1)), Some(doc! { "_id": 1_i64, "v": 2 }));
+        assert_eq!(cache.stats().size, 2);
+
+        cache.clear();
+        cache.insert(ID::I64(4), doc! { "_id": 4_i64 }, cache.generation());
+        cache.insert(ID::I64(5), doc! { "_id": 5_i64 }, cache.generation());
+        assert_eq!(cache.stats().evictions, 1);
+        assert_eq!(cache.stats().size, 2);
+    }
+}
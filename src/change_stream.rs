@@ -0,0 +1,148 @@
+use bson::{Bson, Document};
+use serde::de::DeserializeOwned;
+use std::marker::PhantomData;
+
+use crate::error::ServiceError;
+use crate::id::ID;
+use crate::projection::hide;
+
+/// The raw change events of a collection, as returned by `DataSource::watch`. Like a tailable
+/// cursor it yields `None` whenever no change arrived in time and can be polled again after.
+pub type ChangeEvents = Box<dyn Iterator<Item = Result<Document, ServiceError>> + Send>;
+
+/// What kind of change a `ChangeEvent` is about.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ChangeOperation {
+    Insert,
+    Update,
+    Replace,
+    Delete,
+    /// Events about the collection itself, like `drop`, `rename` or `invalidate`.
+    Other(String),
+}
+
+/// A change made to an item of a watched collection.
+#[derive(Clone, Debug)]
+pub struct ChangeEvent<T> {
+    pub operation: ChangeOperation,
+    /// The `_id` of the changed item.
+    pub id: Option<ID>,
+    /// The item as it is after the change, `None` for deletes.
+    pub document: Option<T>,
+    /// Pass it to `watch` to resume right after this event.
+    pub resume_token: Document,
+}
+
+/// A change stream opened by `BaseService::watch`.
+pub struct ChangeStream<T> {
+    events: ChangeEvents,
+    resume_token: Option<Document>,
+    hidden: Vec<String>,
+    item: PhantomData<T>,
+}
+
+impl<T> ChangeStream<T>
+where
+    T: DeserializeOwned,
+{
+    pub fn new(events: ChangeEvents, resume_token: Option<Document>) -> Self {
+        ChangeStream {
+            events,
+            resume_token,
+            hidden: Vec::new(),
+            item: PhantomData,
+        }
+    }
+
+    /// Leaves `hidden` fields out of the changed items, see `BaseService::hidden_fields`.
+    pub(crate) fn hiding(mut self, hidden: Vec<String>) -> Self {
+        self.hidden = hidden;
+        self
+    }
+
+    /// Waits for the next change. It returns `None` when nothing changed within the server's
+    /// await time, so subscribers can poll it in a loop.
+    pub fn try_next(&mut self) -> Result<Option<ChangeEvent<T>>, ServiceError> {
+        let event = match self.events.next() {
+            Some(event) => event?,
+            None => return Ok(None),
+        };
+        let resume_token = match event.get("_id") {
+            Some(Bson::Document(token)) => token.clone(),
+            _ => return Err("Change event without a resume token".into()),
+        };
+        let operation = match event.get_str("operationType").unwrap_or_default() {
+            "insert" => ChangeOperation::Insert,
+            "update" => ChangeOperation::Update,
+            "replace" => ChangeOperation::Replace,
+            "delete" => ChangeOperation::Delete,
+            other => ChangeOperation::Other(other.to_owned()),
+        };
+        let id = event
+            .get_document("documentKey")
+            .ok()
+            .and_then(|key| key.get("_id"))
+            .and_then(ID::try_with_bson);
+        let document = match event.get("fullDocument") {
+            Some(Bson::Document(document)) => {
+                let mut document = document.clone();
+                hide(&mut document, &self.hidden);
+                Some(bson::from_bson(Bson::Document(document))?)
+            }
+            _ => None,
+        };
+        self.resume_token = Some(resume_token.clone());
+        Ok(Some(ChangeEvent {
+            operation,
+            id,
+            document,
+            resume_token,
+        }))
+    }
+
+    /// The token of the last event seen (or the one the stream was resumed from), to resume
+    /// after a reconnect without missing changes.
+    pub fn resume_token(&self) -> Option<&Document> {
+        self.resume_token.as_ref()
+    }
+}
+
+/// Rewrites a filter on items into one on the `fullDocument` of their change events.
+pub fn on_full_document(filter: &Document) -> Document {
+    on_event_field(filter, "fullDocument")
+}
+
+/// Rewrites a filter on items into one on the `documentKey` of their change events, the only
+/// part of the item a delete event carries.
+pub fn on_document_key(filter: &Document) -> Document {
+    on_event_field(filter, "documentKey")
+}
+
+fn on_event_field(filter: &Document, event_field: &str) -> Document {
+    let mut rewritten = Document::new();
+    for (key, value) in filter.iter() {
+        match (key.as_str(), value) {
+            ("$and", Bson::Array(clauses))
+            | ("$or", Bson::Array(clauses))
+            | ("$nor", Bson::Array(clauses)) => {
+                let clauses = clauses
+                    .iter()
+                    .map(|clause| match clause {
+                        Bson::Document(clause) => {
+                            Bson::Document(on_event_field(clause, event_field))
+                        }
+                        other => other.clone(),
+                    })
+                    .collect();
+                rewritten.insert(key.clone(), Bson::Array(clauses));
+            }
+            (operator, _) if operator.starts_with('$') => {
+                rewritten.insert(key.clone(), value.clone());
+            }
+            (path, _) => {
+                rewritten.insert(format!("{}.{}", event_field, path), value.clone());
+            }
+        }
+    }
+    rewritten
+}
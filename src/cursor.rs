@@ -0,0 +1,226 @@
+use bson::{doc, Bson, Document};
+use mongodb_cursor_pagination::{CursorDirections, Edge, FindResult, PageInfo};
+use std::io::Cursor;
+
+use crate::error::ServiceError;
+
+const DEFAULT_LIMIT: i64 = 25;
+
+/// A page of a sorted result set, following the contract of `PaginatedCursor::find` so that
+/// its cursors, `page_info` and `total_count` behave the same as those of `find`.
+///
+/// The items are read with `filter`, `query_sort`, `skip` and `limit` and then handed to `page`.
+pub struct PageQuery {
+    cursor_doc: Document,
+    has_cursor: bool,
+    direction: CursorDirections,
+    sort: Document,
+    limit: i64,
+    skip: i64,
+}
+
+impl PageQuery {
+    pub fn new(
+        sort: Option<Document>,
+        limit: Option<i64>,
+        skip: Option<i64>,
+        cursor: Option<String>,
+        direction: CursorDirections,
+    ) -> Result<Self, ServiceError> {
+        let has_cursor = cursor.is_some();
+        let cursor_doc = match cursor {
+            Some(encoded) => decode_cursor(&encoded)?,
+            None => Document::new(),
+        };
+        let mut sort = sort.unwrap_or_default();
+        if !sort.contains_key("_id") {
+            sort.insert("_id", -1);
+        }
+        Ok(PageQuery {
+            cursor_doc,
+            has_cursor,
+            direction,
+            sort,
+            // ask for one more than the limit to find out if there is another page
+            limit: limit.unwrap_or(DEFAULT_LIMIT) + 1,
+            skip: skip.unwrap_or(0),
+        })
+    }
+
+    fn has_skip(&self) -> bool {
+        !self.has_cursor && self.skip != 0
+    }
+
+    fn is_previous_query(&self) -> bool {
+        self.has_cursor && self.direction == CursorDirections::Previous
+    }
+
+    /// Narrows `filter` down to the items past the cursor.
+    pub fn filter(&self, filter: Option<Document>) -> Document {
+        let mut query_doc = filter.unwrap_or_default();
+        if self.cursor_doc.is_empty() {
+            return query_doc;
+        }
+        if self.sort.len() > 1 {
+            let keys: Vec<&String> = self.sort.keys().collect();
+            let mut queries: Vec<Bson> = Vec::new();
+            for (i, key) in keys.iter().enumerate() {
+                let mut query = query_doc.clone();
+                for previous in keys.iter().take(i) {
+                    let value = self.cursor_doc.get(previous).cloned().unwrap_or(Bson::Null);
+                    query.insert(previous.as_str(), value);
+                }
+                let value = self.cursor_doc.get(key).cloned().unwrap_or(Bson::Null);
+                query.insert(key.as_str(), doc! { self.direction_operator(key): value });
+                queries.push(Bson::Document(query));
+            }
+            query_doc = doc! { "$or": queries };
+        } else {
+            let value = self.cursor_doc.get("_id").cloned().unwrap_or(Bson::Null);
+            query_doc.insert("_id", doc! { self.direction_operator("_id"): value });
+        }
+        query_doc
+    }
+
+    /// The sort to read with, which is reversed when paging backwards.
+    pub fn query_sort(&self) -> Document {
+        if !self.is_previous_query() {
+            return self.sort.clone();
+        }
+        let mut reversed = Document::new();
+        for (key, value) in self.sort.iter() {
+            match value {
+                Bson::I32(v) => {
+                    reversed.insert(key.clone(), Bson::I32(-v));
+                }
+                Bson::I64(v) => {
+                    reversed.insert(key.clone(), Bson::I64(-v));
+                }
+                _ => {}
+            }
+        }
+        reversed
+    }
+
+    /// The number of items to skip, which is ignored once there is a cursor.
+    pub fn skip(&self) -> i64 {
+        if self.has_skip() {
+            self.skip
+        } else {
+            0
+        }
+    }
+
+    /// One more than the page size.
+    pub fn limit(&self) -> i64 {
+        self.limit
+    }
+
+    /// Builds the page out of the items that were read and the size of the whole result set.
+    pub fn page(
+        &self,
+        found: Vec<Document>,
+        total_count: i64,
+    ) -> Result<FindResult<Document>, ServiceError> {
+        let mut items: Vec<Document> = vec![];
+        let mut edges: Vec<Edge> = vec![];
+        let mut has_next_page = false;
+        let mut has_previous_page = false;
+        let mut start_cursor: Option<String> = None;
+        let mut next_cursor: Option<String> = None;
+
+        if total_count > 0 {
+            let is_previous_query = self.is_previous_query();
+            for item in found {
+                edges.push(Edge {
+                    cursor: encode_cursor(&item, &self.sort)?,
+                });
+                items.push(item);
+            }
+
+            let has_more = if self.has_skip() {
+                has_previous_page = true;
+                let has_more = (items.len() as i64 + self.skip) < total_count;
+                has_next_page = has_more;
+                has_more
+            } else {
+                let has_more = items.len() as i64 > self.limit - 1;
+                has_previous_page = (self.has_cursor && self.direction == CursorDirections::Next)
+                    || (is_previous_query && has_more);
+                has_next_page = (self.direction == CursorDirections::Next && has_more)
+                    || (is_previous_query && self.has_cursor);
+                has_more
+            };
+
+            if is_previous_query {
+                items.reverse();
+                edges.reverse();
+            }
+            if has_more && !is_previous_query {
+                items.pop();
+                edges.pop();
+            } else if has_more {
+                items.remove(0);
+                edges.remove(0);
+            }
+
+            if !items.is_empty() && edges.len() == items.len() {
+                start_cursor = Some(edges[0].cursor.to_owned());
+                next_cursor = Some(edges[items.len() - 1].cursor.to_owned());
+            }
+        }
+
+        Ok(FindResult {
+            page_info: PageInfo {
+                has_next_page,
+                has_previous_page,
+                start_cursor,
+                next_cursor,
+            },
+            edges,
+            total_count,
+            items,
+        })
+    }
+
+    fn direction_operator(&self, key: &str) -> &'static str {
+        let ascending = match self.sort.get(key) {
+            Some(Bson::I32(v)) => *v >= 0,
+            Some(Bson::I64(v)) => *v >= 0,
+            Some(Bson::FloatingPoint(v)) => *v >= 0.0,
+            _ => true,
+        };
+        match (&self.direction, ascending) {
+            (CursorDirections::Next, true) | (CursorDirections::Previous, false) => "$gt",
+            _ => "$lt",
+        }
+    }
+}
+
+fn cursor_value(document: &Document, key: &str) -> Option<Bson> {
+    let mut parts = key.splitn(2, '.');
+    let value = document.get(parts.next()?)?;
+    match (value, parts.next()) {
+        (Bson::Document(d), Some(rest)) => cursor_value(d, rest),
+        _ => Some(value.clone()),
+    }
+}
+
+fn encode_cursor(document: &Document, sort: &Document) -> Result<String, ServiceError> {
+    let mut only_sort_keys = Document::new();
+    for key in sort.keys() {
+        if let Some(value) = cursor_value(document, key) {
+            only_sort_keys.insert(key.clone(), value);
+        }
+    }
+    let mut buf = Vec::new();
+    bson::encode_document(&mut buf, &only_sort_keys)?;
+    Ok(base64::encode(&buf))
+}
+
+fn decode_cursor(encoded: &str) -> Result<Document, ServiceError> {
+    let decoded =
+        base64::decode(encoded).map_err(|e| ServiceError::InvalidCursor(e.to_string()))?;
+    bson::decode_document(&mut Cursor::new(&decoded))
+        .map_err(|e| ServiceError::InvalidCursor(e.to_string()))
+}
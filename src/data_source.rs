@@ -0,0 +1,261 @@
+use bson::{doc, Document};
+use mongodb::options::{
+    AggregateOptions, CountOptions, DeleteOptions, FindOneAndUpdateOptions, FindOneOptions,
+    FindOptions, InsertManyOptions, InsertOneOptions, UpdateOptions,
+};
+use mongodb::results::{DeleteResult, InsertManyResult, InsertOneResult, UpdateResult};
+use mongodb::Collection;
+use mongodb_cursor_pagination::{CursorDirections, FindResult, PaginatedCursor};
+use std::time::Duration;
+
+use crate::change_stream::ChangeEvents;
+use crate::error::ServiceError;
+
+/// How long a change stream waits for a change before the poll comes back empty.
+const CHANGE_STREAM_AWAIT: Duration = Duration::from_secs(1);
+
+/// The storage operations a `BaseService` is built on top of.
+///
+/// `mongodb::Collection` is the real implementation, but anything that can evaluate MongoDB
+/// filter, sort, projection and update documents can stand in for it (see `MockCollection`
+/// behind the `test` feature).
+pub trait DataSource: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn find(
+        &self,
+        filter: Option<Document>,
+        options: Option<FindOptions>,
+    ) -> Result<Vec<Document>, ServiceError>;
+
+    fn find_one(
+        &self,
+        filter: Option<Document>,
+        options: Option<FindOneOptions>,
+    ) -> Result<Option<Document>, ServiceError>;
+
+    /// Runs a cursor paginated find, following the contract of `PaginatedCursor::find`.
+    fn find_page(
+        &self,
+        filter: Option<Document>,
+        options: FindOptions,
+        cursor: Option<String>,
+        direction: CursorDirections,
+    ) -> Result<FindResult<Document>, ServiceError>;
+
+    fn count_documents(
+        &self,
+        filter: Option<Document>,
+        options: Option<CountOptions>,
+    ) -> Result<i64, ServiceError>;
+
+    /// The number of documents according to the collection's metadata, which is fast but can't
+    /// take a filter.
+    fn estimated_document_count(&self) -> Result<i64, ServiceError>;
+
+    fn aggregate(
+        &self,
+        pipeline: Vec<Document>,
+        options: Option<AggregateOptions>,
+    ) -> Result<Vec<Document>, ServiceError>;
+
+    /// Opens a change stream on the collection with `pipeline` applied to its events, starting
+    /// now or right after the event of the `resume_after` token. Updates carry the current
+    /// version of the item as `fullDocument`.
+    fn watch(
+        &self,
+        pipeline: Vec<Document>,
+        resume_after: Option<Document>,
+    ) -> Result<ChangeEvents, ServiceError>;
+
+    fn insert_one(
+        &self,
+        document: Document,
+        options: Option<InsertOneOptions>,
+    ) -> Result<InsertOneResult, ServiceError>;
+
+    fn insert_many(
+        &self,
+        documents: Vec<Document>,
+        options: Option<InsertManyOptions>,
+    ) -> Result<InsertManyResult, ServiceError>;
+
+    fn update_one(
+        &self,
+        query: Document,
+        update: Document,
+        options: Option<UpdateOptions>,
+    ) -> Result<UpdateResult, ServiceError>;
+
+    fn update_many(
+        &self,
+        query: Document,
+        update: Document,
+        options: Option<UpdateOptions>,
+    ) -> Result<UpdateResult, ServiceError>;
+
+    /// Updates the first document matching `query` in one atomic step and returns it, as it was
+    /// before the update unless the options ask for `ReturnDocument::After`.
+    fn find_one_and_update(
+        &self,
+        query: Document,
+        update: Document,
+        options: Option<FindOneAndUpdateOptions>,
+    ) -> Result<Option<Document>, ServiceError>;
+
+    fn delete_one(
+        &self,
+        query: Document,
+        options: Option<DeleteOptions>,
+    ) -> Result<DeleteResult, ServiceError>;
+
+    fn delete_many(
+        &self,
+        query: Document,
+        options: Option<DeleteOptions>,
+    ) -> Result<DeleteResult, ServiceError>;
+}
+
+impl DataSource for Collection {
+    fn name(&self) -> &str {
+        Collection::name(self)
+    }
+
+    fn find(
+        &self,
+        filter: Option<Document>,
+        options: Option<FindOptions>,
+    ) -> Result<Vec<Document>, ServiceError> {
+        let cursor = Collection::find(self, filter, options)?;
+        let mut documents = Vec::new();
+        for result in cursor {
+            documents.push(result?);
+        }
+        Ok(documents)
+    }
+
+    fn find_one(
+        &self,
+        filter: Option<Document>,
+        options: Option<FindOneOptions>,
+    ) -> Result<Option<Document>, ServiceError> {
+        Ok(Collection::find_one(self, filter, options)?)
+    }
+
+    fn find_page(
+        &self,
+        filter: Option<Document>,
+        options: FindOptions,
+        cursor: Option<String>,
+        direction: CursorDirections,
+    ) -> Result<FindResult<Document>, ServiceError> {
+        let query_cursor = PaginatedCursor::new(Some(options), cursor, Some(direction));
+        Ok(query_cursor.find(self, filter.as_ref())?)
+    }
+
+    fn count_documents(
+        &self,
+        filter: Option<Document>,
+        options: Option<CountOptions>,
+    ) -> Result<i64, ServiceError> {
+        Ok(Collection::count_documents(self, filter, options)?)
+    }
+
+    fn estimated_document_count(&self) -> Result<i64, ServiceError> {
+        Ok(Collection::estimated_document_count(self, None)?)
+    }
+
+    fn aggregate(
+        &self,
+        pipeline: Vec<Document>,
+        options: Option<AggregateOptions>,
+    ) -> Result<Vec<Document>, ServiceError> {
+        let cursor = Collection::aggregate(self, pipeline, options)?;
+        let mut documents = Vec::new();
+        for result in cursor {
+            documents.push(result?);
+        }
+        Ok(documents)
+    }
+
+    fn watch(
+        &self,
+        pipeline: Vec<Document>,
+        resume_after: Option<Document>,
+    ) -> Result<ChangeEvents, ServiceError> {
+        // the driver has no change stream helper yet, but a $changeStream aggregation returns
+        // the same tailable cursor
+        let mut change_stream = doc! { "fullDocument": "updateLookup" };
+        if let Some(token) = resume_after {
+            change_stream.insert("resumeAfter", token);
+        }
+        let mut stages = vec![doc! { "$changeStream": change_stream }];
+        stages.extend(pipeline);
+        let options = AggregateOptions::builder()
+            .max_await_time(Some(CHANGE_STREAM_AWAIT))
+            .build();
+        let cursor = Collection::aggregate(self, stages, options)?;
+        Ok(Box::new(cursor.map(|event| Ok(event?))))
+    }
+
+    fn insert_one(
+        &self,
+        document: Document,
+        options: Option<InsertOneOptions>,
+    ) -> Result<InsertOneResult, ServiceError> {
+        Ok(Collection::insert_one(self, document, options)?)
+    }
+
+    fn insert_many(
+        &self,
+        documents: Vec<Document>,
+        options: Option<InsertManyOptions>,
+    ) -> Result<InsertManyResult, ServiceError> {
+        Ok(Collection::insert_many(self, documents, options)?)
+    }
+
+    fn update_one(
+        &self,
+        query: Document,
+        update: Document,
+        options: Option<UpdateOptions>,
+    ) -> Result<UpdateResult, ServiceError> {
+        Ok(Collection::update_one(self, query, update, options)?)
+    }
+
+    fn update_many(
+        &self,
+        query: Document,
+        update: Document,
+        options: Option<UpdateOptions>,
+    ) -> Result<UpdateResult, ServiceError> {
+        Ok(Collection::update_many(self, query, update, options)?)
+    }
+
+    fn find_one_and_update(
+        &self,
+        query: Document,
+        update: Document,
+        options: Option<FindOneAndUpdateOptions>,
+    ) -> Result<Option<Document>, ServiceError> {
+        Ok(Collection::find_one_and_update(
+            self, query, update, options,
+        )?)
+    }
+
+    fn delete_one(
+        &self,
+        query: Document,
+        options: Option<DeleteOptions>,
+    ) -> Result<DeleteResult, ServiceError> {
+        Ok(Collection::delete_one(self, query, options)?)
+    }
+
+    fn delete_many(
+        &self,
+        query: Document,
+        options: Option<DeleteOptions>,
+    ) -> Result<DeleteResult, ServiceError> {
+        Ok(Collection::delete_many(self, query, options)?)
+    }
+}
@@ -1,6 +1,9 @@
 use mongodb_cursor_pagination::error::CursorError;
 use std::{error, fmt, io};
 
+use crate::id::ID;
+use crate::validation::FieldError;
+
 /// Possible errors that can arise during parsing and creating a cursor.
 #[derive(Debug)]
 pub enum ServiceError {
@@ -10,6 +13,12 @@ pub enum ServiceError {
     ConnectionError(String),
     InvalidCursor(String),
     NotFound(String),
+    /// A versioned update lost the race, carries the version the item is at now.
+    Conflict(i64),
+    /// The item is soft deleted and has to be restored before it can be written to.
+    Trashed(ID),
+    /// A validator rejected the item, carries every field that failed.
+    ValidationError(Vec<FieldError>),
     Unknown(String),
 }
 
@@ -68,6 +77,23 @@ impl fmt::Display for ServiceError {
             ServiceError::InvalidCursor(ref cursor) => {
                 write!(fmt, "Invalid cursor - unable to parse: {:?}", cursor)
             }
+            ServiceError::Conflict(version) => {
+                write!(
+                    fmt,
+                    "Version conflict - the item is now at version {}",
+                    version
+                )
+            }
+            ServiceError::Trashed(ref id) => {
+                write!(fmt, "Item {} is in the trash - restore it first", id)
+            }
+            ServiceError::ValidationError(ref errors) => {
+                let fields: Vec<String> = errors
+                    .iter()
+                    .map(|e| format!("{}: {}", e.field, e.message))
+                    .collect();
+                write!(fmt, "Validation failed - {}", fields.join(", "))
+            }
             ServiceError::ConnectionError(ref inner)
             | ServiceError::ParseError(ref inner)
             | ServiceError::NotFound(ref inner)
@@ -83,6 +109,9 @@ impl error::Error for ServiceError {
             ServiceError::IoError(ref inner) => inner.description(),
             ServiceError::MongoError(ref inner) => inner.description(),
             ServiceError::InvalidCursor(_) => "Invalid cursor value",
+            ServiceError::Conflict(_) => "Version conflict",
+            ServiceError::Trashed(_) => "Item is in the trash",
+            ServiceError::ValidationError(_) => "Validation failed",
             ServiceError::Unknown(ref inner)
             | ServiceError::ParseError(ref inner)
             | ServiceError::ConnectionError(ref inner)
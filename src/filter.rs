@@ -0,0 +1,384 @@
+use bson::{doc, Bson, Document};
+use std::ops::{Bound, RangeBounds};
+
+#[derive(Clone, Debug, PartialEq)]
+enum Clause {
+    /// A field with the value it has to equal, or a document of query operators.
+    Field(String, Bson),
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+    Nor(Vec<Filter>),
+}
+
+/// A query filter that compiles to the `Document` taken by `find`, `find_one` and friends.
+///
+/// Filters are built from field conditions and combined with `and`, `or` and `not`:
+///
+/// ```
+/// use mongodb_base_service::Filter;
+///
+/// let filter = Filter::eq("status", "published")
+///     .and(Filter::range("views", 10..100))
+///     .and(Filter::exists("node.date_deleted", false).or(Filter::eq("pinned", true)));
+/// let query = filter.to_document();
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct Filter {
+    clause: Clause,
+}
+
+fn field<V: Into<Bson>>(path: &str, operator: &str, value: V) -> Filter {
+    let mut condition = Document::new();
+    condition.insert(operator, value.into());
+    Filter {
+        clause: Clause::Field(path.to_owned(), Bson::Document(condition)),
+    }
+}
+
+fn is_operator_document(value: &Bson) -> bool {
+    match value {
+        Bson::Document(d) => !d.is_empty() && d.keys().all(|key| key.starts_with('$')),
+        _ => false,
+    }
+}
+
+fn to_array<I, V>(values: I) -> Bson
+where
+    I: IntoIterator<Item = V>,
+    V: Into<Bson>,
+{
+    Bson::Array(values.into_iter().map(Into::into).collect())
+}
+
+impl Filter {
+    /// Matches every item.
+    pub fn all() -> Self {
+        Filter {
+            clause: Clause::And(Vec::new()),
+        }
+    }
+
+    pub fn eq<V: Into<Bson>>(path: &str, value: V) -> Self {
+        let value = value.into();
+        if is_operator_document(&value) {
+            // a document made of operators would be read as a condition
+            return field(path, "$eq", value);
+        }
+        Filter {
+            clause: Clause::Field(path.to_owned(), value),
+        }
+    }
+
+    pub fn ne<V: Into<Bson>>(path: &str, value: V) -> Self {
+        field(path, "$ne", value)
+    }
+
+    pub fn gt<V: Into<Bson>>(path: &str, value: V) -> Self {
+        field(path, "$gt", value)
+    }
+
+    pub fn gte<V: Into<Bson>>(path: &str, value: V) -> Self {
+        field(path, "$gte", value)
+    }
+
+    pub fn lt<V: Into<Bson>>(path: &str, value: V) -> Self {
+        field(path, "$lt", value)
+    }
+
+    pub fn lte<V: Into<Bson>>(path: &str, value: V) -> Self {
+        field(path, "$lte", value)
+    }
+
+    pub fn is_in<I, V>(path: &str, values: I) -> Self
+    where
+        I: IntoIterator<Item = V>,
+        V: Into<Bson>,
+    {
+        field(path, "$in", to_array(values))
+    }
+
+    pub fn not_in<I, V>(path: &str, values: I) -> Self
+    where
+        I: IntoIterator<Item = V>,
+        V: Into<Bson>,
+    {
+        field(path, "$nin", to_array(values))
+    }
+
+    /// Matches values within a range, eg. `10..20`, `10..=20` or `10..`.
+    pub fn range<V, R>(path: &str, range: R) -> Self
+    where
+        V: Into<Bson> + Clone,
+        R: RangeBounds<V>,
+    {
+        let mut condition = Document::new();
+        match range.start_bound() {
+            Bound::Included(start) => {
+                condition.insert("$gte", start.clone().into());
+            }
+            Bound::Excluded(start) => {
+                condition.insert("$gt", start.clone().into());
+            }
+            Bound::Unbounded => {}
+        }
+        match range.end_bound() {
+            Bound::Included(end) => {
+                condition.insert("$lte", end.clone().into());
+            }
+            Bound::Excluded(end) => {
+                condition.insert("$lt", end.clone().into());
+            }
+            Bound::Unbounded => {}
+        }
+        if condition.is_empty() {
+            return Filter::all();
+        }
+        Filter {
+            clause: Clause::Field(path.to_owned(), Bson::Document(condition)),
+        }
+    }
+
+    pub fn exists(path: &str, exists: bool) -> Self {
+        field(path, "$exists", exists)
+    }
+
+    /// Matches strings against a regular expression, eg. `regex("title", "^intro", "i")`.
+    pub fn regex(path: &str, pattern: &str, options: &str) -> Self {
+        Filter {
+            clause: Clause::Field(
+                path.to_owned(),
+                Bson::RegExp(pattern.to_owned(), options.to_owned()),
+            ),
+        }
+    }
+
+    /// Matches arrays with at least one element matching `filter`, whose paths are relative to
+    /// the element.
+    pub fn elem_match(path: &str, filter: Filter) -> Self {
+        field(path, "$elemMatch", filter.to_document())
+    }
+
+    /// Moves every field path of `filter` below `path`, eg. to reuse a filter written for an
+    /// embedded document.
+    pub fn nested(path: &str, filter: Filter) -> Self {
+        let prefix = |filters: Vec<Filter>| {
+            filters
+                .into_iter()
+                .map(|filter| Filter::nested(path, filter))
+                .collect()
+        };
+        let clause = match filter.clause {
+            Clause::Field(field, condition) => {
+                Clause::Field(format!("{}.{}", path, field), condition)
+            }
+            Clause::And(filters) => Clause::And(prefix(filters)),
+            Clause::Or(filters) => Clause::Or(prefix(filters)),
+            Clause::Nor(filters) => Clause::Nor(prefix(filters)),
+        };
+        Filter { clause }
+    }
+
+    /// Matches the items every one of `filters` matches, which is every item when there are none.
+    pub fn all_of<I: IntoIterator<Item = Filter>>(filters: I) -> Self {
+        Filter {
+            clause: Clause::And(filters.into_iter().collect()),
+        }
+    }
+
+    /// Matches the items any one of `filters` matches, which is no item when there are none.
+    pub fn any_of<I: IntoIterator<Item = Filter>>(filters: I) -> Self {
+        Filter {
+            clause: Clause::Or(filters.into_iter().collect()),
+        }
+    }
+
+    pub fn and(self, other: Filter) -> Self {
+        match self.clause {
+            Clause::And(mut filters) => {
+                filters.push(other);
+                Filter {
+                    clause: Clause::And(filters),
+                }
+            }
+            clause => Filter::all_of(vec![Filter { clause }, other]),
+        }
+    }
+
+    pub fn or(self, other: Filter) -> Self {
+        match self.clause {
+            Clause::Or(mut filters) => {
+                filters.push(other);
+                Filter {
+                    clause: Clause::Or(filters),
+                }
+            }
+            clause => Filter::any_of(vec![Filter { clause }, other]),
+        }
+    }
+
+    /// Matches the items this filter doesn't match. Note that, as in MongoDB, negated field
+    /// conditions also match items that don't have the field.
+    #[allow(clippy::should_implement_trait)]
+    pub fn not(self) -> Self {
+        let clause = match self.clause {
+            Clause::Field(path, Bson::Document(condition))
+                if is_operator_document(&Bson::Document(condition.clone())) =>
+            {
+                let mut negated = Document::new();
+                negated.insert("$not", condition);
+                Clause::Field(path, Bson::Document(negated))
+            }
+            Clause::Field(path, Bson::RegExp(pattern, options)) => {
+                let mut negated = Document::new();
+                negated.insert("$not", Bson::RegExp(pattern, options));
+                Clause::Field(path, Bson::Document(negated))
+            }
+            Clause::Field(path, value) => return field(&path, "$ne", value),
+            Clause::Nor(filters) if filters.len() == 1 => {
+                return filters.into_iter().next().unwrap_or_else(Filter::all)
+            }
+            clause => Clause::Nor(vec![Filter { clause }]),
+        };
+        Filter { clause }
+    }
+
+    /// Compiles the filter. Conditions are kept in a single document where their paths and
+    /// operators don't overlap and fall back to `$and` where they do.
+    pub fn to_document(&self) -> Document {
+        match &self.clause {
+            Clause::Field(path, condition) => {
+                let mut document = Document::new();
+                document.insert(path.clone(), condition.clone());
+                document
+            }
+            Clause::And(filters) => {
+                let documents: Vec<Document> = filters.iter().map(Filter::to_document).collect();
+                merge(&documents).unwrap_or_else(|| {
+                    let mut document = Document::new();
+                    document.insert("$and", to_array(documents));
+                    document
+                })
+            }
+            // MongoDB refuses an empty `$or`
+            Clause::Or(filters) if filters.is_empty() => match_nothing(),
+            Clause::Or(filters) | Clause::Nor(filters) => {
+                let operator = match self.clause {
+                    Clause::Or(_) => "$or",
+                    _ => "$nor",
+                };
+                let mut document = Document::new();
+                document.insert(operator, to_array(filters.iter().map(Filter::to_document)));
+                document
+            }
+        }
+    }
+}
+
+/// A filter document no item matches.
+pub(crate) fn match_nothing() -> Document {
+    let mut document = Document::new();
+    document.insert("_id", doc! { "$in": [] });
+    document
+}
+
+/// Combines the documents of an `and` into one, if none of them overlap.
+fn merge(documents: &[Document]) -> Option<Document> {
+    let mut merged = Document::new();
+    for document in documents {
+        for (key, value) in document.iter() {
+            match (merged.get_mut(key), value) {
+                (None, value) => {
+                    merged.insert(key.clone(), value.clone());
+                }
+                (Some(Bson::Document(existing)), Bson::Document(condition))
+                    if is_operator_document(&Bson::Document(existing.clone()))
+                        && is_operator_document(value)
+                        && condition.keys().all(|op| !existing.contains_key(op)) =>
+                {
+                    for (operator, argument) in condition.iter() {
+                        existing.insert(operator.clone(), argument.clone());
+                    }
+                }
+                _ => return None,
+            }
+        }
+    }
+    Some(merged)
+}
+
+impl From<Filter> for Document {
+    fn from(filter: Filter) -> Document {
+        filter.to_document()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filters_compile_to_documents() {
+        let filter = Filter::eq("status", "published")
+            .and(Filter::range("views", 10..=100))
+            .and(Filter::gt("views", 0))
+            .and(Filter::is_in("tags", vec!["rust", "mongo"]));
+        assert_eq!(
+            filter.to_document(),
+            doc! {
+                "status": "published",
+                "views": { "$gte": 10, "$lte": 100, "$gt": 0 },
+                "tags": { "$in": ["rust", "mongo"] },
+            }
+        );
+
+        // overlapping conditions can't share a document
+        let overlapping = Filter::gt("views", 1).and(Filter::gt("views", 5));
+        assert_eq!(
+            overlapping.to_document(),
+            doc! { "$and": [{ "views": { "$gt": 1 } }, { "views": { "$gt": 5 } }] }
+        );
+
+        let either = Filter::exists("node.date_deleted", false).or(Filter::eq("pinned", true));
+        assert_eq!(
+            either.clone().not().to_document(),
+            doc! { "$nor": [{ "$or": [{ "node.date_deleted": { "$exists": false } }, { "pinned": true }] }] }
+        );
+        assert_eq!(either.clone().not().not(), either);
+        assert_eq!(
+            Filter::eq("title", "x").not().to_document(),
+            doc! { "title": { "$ne": "x" } }
+        );
+        assert_eq!(
+            Filter::regex("title", "^intro", "i").not().to_document(),
+            doc! { "title": { "$not": Bson::RegExp("^intro".to_owned(), "i".to_owned()) } }
+        );
+
+        let comment = Filter::eq("author", "jane").and(Filter::gte("likes", 3));
+        assert_eq!(
+            Filter::elem_match("comments", comment.clone()).to_document(),
+            doc! { "comments": { "$elemMatch": { "author": "jane", "likes": { "$gte": 3 } } } }
+        );
+        assert_eq!(
+            Filter::nested("featured", comment).to_document(),
+            doc! { "featured.author": "jane", "featured.likes": { "$gte": 3 } }
+        );
+        assert_eq!(Filter::all().to_document(), doc! {});
+    }
+
+    #[test]
+    fn test_empty_alternatives_match_nothing() {
+        let nothing = doc! { "_id": { "$in": [] } };
+        assert_eq!(Filter::any_of(vec![]).to_document(), nothing);
+        assert_eq!(Filter::all_of(vec![]).to_document(), doc! {});
+        assert_eq!(
+            Filter::eq("status", "published")
+                .and(Filter::any_of(vec![]))
+                .to_document(),
+            doc! { "status": "published", "_id": { "$in": [] } }
+        );
+        assert_eq!(
+            Filter::any_of(vec![]).not().to_document(),
+            doc! { "$nor": [{ "_id": { "$in": [] } }] }
+        );
+    }
+}
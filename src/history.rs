@@ -0,0 +1,392 @@
+use bson::{oid::ObjectId, Bson, Document};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
+
+use crate::error::ServiceError;
+use crate::id::ID;
+use crate::projection::{hidden_within, hide, is_hidden};
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HistoryOperation {
+    Insert,
+    Update,
+    Delete,
+    SoftDelete,
+    Restore,
+}
+
+/// A single field that changed, addressed by its dotted path within the item.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct FieldChange {
+    pub field: String,
+    pub before: Option<Bson>,
+    pub after: Option<Bson>,
+}
+
+/// An entry in a service's history collection.
+///
+/// Changes to embedded items are recorded against the parent document, with `field_path` and
+/// `embedded_id` pointing at the item that changed.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct HistoryRecord {
+    #[serde(rename = "_id")]
+    pub id: Option<ID>,
+    pub target_id: ID,
+    pub operation: HistoryOperation,
+    pub field_path: Option<String>,
+    pub embedded_id: Option<ID>,
+    pub changes: Vec<FieldChange>,
+    pub user_id: Option<ID>,
+    /// The tenant of the item, see `TenantScope`.
+    pub tenant: Option<Bson>,
+    pub timestamp: i64,
+}
+
+static RECORD_COUNTER: AtomicUsize = AtomicUsize::new(0);
+static PROCESS_BYTES: OnceLock<[u8; 5]> = OnceLock::new();
+
+/// `ObjectId::new` fills the middle bytes with fresh random values on every call, so its ids
+/// don't sort in creation order within a second. History is listed newest first, so records use
+/// the timestamp, a per process random value and a counter instead.
+fn record_id(timestamp: i64) -> ObjectId {
+    let process = PROCESS_BYTES.get_or_init(|| {
+        let mut process = [0; 5];
+        process.copy_from_slice(&uuid::Uuid::new_v4().as_bytes()[..5]);
+        process
+    });
+    let count = RECORD_COUNTER.fetch_add(1, Ordering::SeqCst) as u32;
+    let mut bytes = [0; 12];
+    bytes[..4].copy_from_slice(&(timestamp as u32).to_be_bytes());
+    bytes[4..9].copy_from_slice(process);
+    bytes[9..].copy_from_slice(&count.to_be_bytes()[1..]);
+    ObjectId::with_bytes(bytes)
+}
+
+impl HistoryRecord {
+    pub fn new(target_id: ID, operation: HistoryOperation, timestamp: i64) -> Self {
+        HistoryRecord {
+            id: Some(ID::ObjectId(record_id(timestamp))),
+            target_id,
+            operation,
+            field_path: None,
+            embedded_id: None,
+            changes: Vec::new(),
+            user_id: None,
+            tenant: None,
+            timestamp,
+        }
+    }
+
+    pub fn embedded(mut self, field_path: &str, embedded_id: ID) -> Self {
+        self.field_path = Some(field_path.to_owned());
+        self.embedded_id = Some(embedded_id);
+        self
+    }
+
+    pub fn diff(mut self, before: Option<&Document>, after: Option<&Document>) -> Self {
+        self.changes = diff(before, after);
+        self
+    }
+
+    pub fn user(mut self, user_id: Option<ID>) -> Self {
+        self.user_id = user_id;
+        self
+    }
+
+    pub fn tenant(mut self, tenant: Option<Bson>) -> Self {
+        self.tenant = tenant;
+        self
+    }
+
+    /// Leaves the `hidden` fields of the item out of the changes, see
+    /// `BaseService::hidden_fields`.
+    pub(crate) fn hiding(mut self, hidden: &[String]) -> Self {
+        let prefix = self
+            .field_path
+            .as_ref()
+            .map_or(String::new(), |path| format!("{}.", path));
+        let path = |change: &FieldChange| format!("{}{}", prefix, change.field);
+        // changes to a hidden field, or to a field within one, are dropped
+        self.changes
+            .retain(|change| !is_hidden(hidden, &path(change)));
+        // while hidden fields within a changed value are removed from it
+        for change in self.changes.iter_mut() {
+            let within = hidden_within(hidden, &path(change));
+            if within.is_empty() {
+                continue;
+            }
+            for value in change.before.iter_mut().chain(change.after.iter_mut()) {
+                match value {
+                    Bson::Document(document) => hide(document, &within),
+                    Bson::Array(items) => {
+                        for item in items.iter_mut() {
+                            if let Bson::Document(document) = item {
+                                hide(document, &within);
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        self
+    }
+
+    pub fn to_document(&self) -> Result<Document, ServiceError> {
+        match bson::to_bson(self)? {
+            Bson::Document(document) => Ok(document),
+            _ => Err(ServiceError::ParseError(
+                "Unable to serialize history record".to_owned(),
+            )),
+        }
+    }
+}
+
+/// Lists the fields that differ between two versions of an item.
+///
+/// Embedded documents are compared field by field while arrays are compared as a whole. The
+/// `_id` and the bookkeeping in `node` are left out since every write touches them.
+pub fn diff(before: Option<&Document>, after: Option<&Document>) -> Vec<FieldChange> {
+    let empty = Document::new();
+    let mut changes = Vec::new();
+    diff_into(
+        "",
+        before.unwrap_or(&empty),
+        after.unwrap_or(&empty),
+        &mut changes,
+    );
+    changes
+}
+
+fn diff_into(prefix: &str, before: &Document, after: &Document, changes: &mut Vec<FieldChange>) {
+    let mut keys: Vec<&String> = before.keys().collect();
+    keys.extend(
+        after
+            .keys()
+            .filter(|key| !before.contains_key(key.as_str())),
+    );
+    for key in keys {
+        if prefix.is_empty() && (key == "_id" || key == "node") {
+            continue;
+        }
+        let field = format!("{}{}", prefix, key);
+        match (before.get(key), after.get(key)) {
+            (Some(Bson::Document(b)), Some(Bson::Document(a))) => {
+                diff_into(&format!("{}.", field), b, a, changes)
+            }
+            (b, a) if b != a => changes.push(FieldChange {
+                field,
+                before: b.cloned(),
+                after: a.cloned(),
+            }),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::base::BaseService;
+    use crate::mock::MockCollection;
+    use crate::mongo::MongoService;
+    use bson::doc;
+
+    fn tracked(documents: Vec<Document>) -> (MongoService, MockCollection) {
+        let history = MockCollection::new("items_history");
+        let service = MongoService::with_data_source(
+            MockCollection::with_documents("items", documents),
+            None,
+        )
+        .with_history(history.clone());
+        (service, history)
+    }
+
+    #[test]
+    fn test_diff_nested_fields() {
+        let before = doc! {
+            "_id": 1,
+            "title": "Old",
+            "meta": { "tags": ["a"], "views": 1 },
+            "node": { "version": 1 },
+        };
+        let after = doc! {
+            "_id": 1,
+            "title": "New",
+            "meta": { "tags": ["a", "b"], "views": 1 },
+            "summary": "added",
+            "node": { "version": 2 },
+        };
+        let changes = diff(Some(&before), Some(&after));
+        let fields: Vec<&str> = changes.iter().map(|c| c.field.as_str()).collect();
+        assert_eq!(fields, vec!["title", "meta.tags", "summary"]);
+        assert_eq!(changes[2].before, None);
+        assert_eq!(changes[2].after, Some(Bson::String("added".to_owned())));
+
+        let inserted = diff(None, Some(&after));
+        assert_eq!(inserted.len(), 3);
+        assert!(diff(Some(&before), Some(&before)).is_empty());
+    }
+
+    #[test]
+    fn test_hidden_fields_are_left_out() {
+        let hidden = vec![
+            "password".to_owned(),
+            "profile.ssn".to_owned(),
+            "comments.ip".to_owned(),
+        ];
+        let before = doc! { "password": "old", "profile": { "ssn": "1" }, "title": "Old" };
+        let after = doc! {
+            "password": "new",
+            "profile": { "ssn": "2" },
+            "title": "New",
+            "comments": [{ "text": "hi", "ip": "127.0.0.1" }],
+        };
+        let record = HistoryRecord::new(ID::I64(1), HistoryOperation::Update, 0)
+            .diff(Some(&before), Some(&after))
+            .hiding(&hidden);
+        let fields: Vec<&str> = record.changes.iter().map(|c| c.field.as_str()).collect();
+        assert_eq!(fields, vec!["title", "comments"]);
+        assert_eq!(
+            record.changes[1].after,
+            Some(Bson::Array(vec![Bson::Document(doc! { "text": "hi" })]))
+        );
+
+        // changes to embedded items are addressed from the parent
+        let record = HistoryRecord::new(ID::I64(1), HistoryOperation::Update, 0)
+            .embedded("comments", ID::I64(2))
+            .diff(
+                Some(&doc! { "text": "hi", "ip": "1" }),
+                Some(&doc! { "text": "ho", "ip": "2" }),
+            )
+            .hiding(&hidden);
+        let fields: Vec<&str> = record.changes.iter().map(|c| c.field.as_str()).collect();
+        assert_eq!(fields, vec!["text"]);
+    }
+
+    #[test]
+    fn test_writes_are_recorded_with_their_changes() {
+        let (service, history) = tracked(Vec::new());
+        let admin = Some(ID::with_string("admin"));
+        let id = service
+            .insert_one(doc! { "_id": 1_i64, "title": "Tracked" }, admin.clone())
+            .unwrap();
+        let _: Document = service
+            .update_one_with_doc(id.clone(), doc! { "$set": { "title": "Renamed" } })
+            .unwrap();
+        service
+            .delete_one_by_id_with_user(id, admin.clone())
+            .unwrap();
+
+        let records: Vec<HistoryRecord> = history
+            .documents()
+            .into_iter()
+            .map(|record| bson::from_bson(Bson::Document(record)).unwrap())
+            .collect();
+        let operations: Vec<HistoryOperation> = records.iter().map(|r| r.operation).collect();
+        assert_eq!(
+            operations,
+            vec![
+                HistoryOperation::Insert,
+                HistoryOperation::Update,
+                HistoryOperation::Delete
+            ]
+        );
+        assert_eq!(records[0].user_id, admin);
+        assert_eq!(
+            records[1].changes,
+            vec![FieldChange {
+                field: "title".to_owned(),
+                before: Some(Bson::String("Tracked".to_owned())),
+                after: Some(Bson::String("Renamed".to_owned())),
+            }]
+        );
+        assert_eq!(records[2].user_id, admin);
+        assert_eq!(records[2].changes[0].after, None);
+    }
+
+    #[test]
+    fn test_embedded_changes_are_recorded_against_the_parent() {
+        let (service, history) = tracked(vec![doc! { "_id": 1_i64 }]);
+        let ids = service
+            .insert_embedded(ID::I64(1), "comments", vec![doc! { "text": "a" }], None)
+            .unwrap();
+        let _: Document = service
+            .update_embedded(
+                ID::I64(1),
+                "comments",
+                ids[0].clone(),
+                doc! { "text": "b" },
+                None,
+            )
+            .unwrap();
+
+        let records = history.documents();
+        assert_eq!(records.len(), 2);
+        let update: HistoryRecord = bson::from_bson(Bson::Document(records[1].clone())).unwrap();
+        assert_eq!(update.target_id, ID::I64(1));
+        assert_eq!(update.field_path, Some("comments".to_owned()));
+        assert_eq!(update.embedded_id, Some(ids[0].clone()));
+        assert_eq!(update.changes[0].field, "text");
+    }
+
+    #[test]
+    fn test_find_history_pages_newest_first() {
+        let (service, _) = tracked(vec![doc! { "_id": 1_i64, "views": 0 }]);
+        for views in 1..=4 {
+            let _: Document = service
+                .update_one_with_doc(ID::I64(1), doc! { "$set": { "views": views } })
+                .unwrap();
+        }
+        let page = service
+            .find_history(ID::I64(1), Some(3), None, None, None)
+            .unwrap();
+        assert_eq!(page.total_count, 4);
+        assert!(page.page_info.has_next_page);
+        let rest = service
+            .find_history(ID::I64(1), Some(3), page.page_info.next_cursor, None, None)
+            .unwrap();
+        let views: Vec<Option<Bson>> = page
+            .items
+            .iter()
+            .chain(rest.items.iter())
+            .map(|record| record.changes[0].after.clone())
+            .collect();
+        assert_eq!(
+            views,
+            vec![
+                Some(Bson::I32(4)),
+                Some(Bson::I32(3)),
+                Some(Bson::I32(2)),
+                Some(Bson::I32(1))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_history_is_kept_per_tenant() {
+        let history = MockCollection::new("items_history");
+        let service = MongoService::with_data_source(MockCollection::new("items"), None)
+            .with_tenant_field("org")
+            .with_history(history.clone());
+        let acme = service.for_tenant("acme");
+        let id = acme.insert_one(doc! { "_id": 1_i64 }, None).unwrap();
+        acme.delete_one_by_id(id.clone()).unwrap();
+        assert_eq!(history.documents()[0].get_str("tenant"), Ok("acme"));
+
+        // the history outlives the item for its own tenant, and no one else sees it
+        let own = acme
+            .find_history(id.clone(), None, None, None, None)
+            .unwrap();
+        assert_eq!(own.total_count, 2);
+        let other = service
+            .for_tenant("globex")
+            .find_history(id.clone(), None, None, None, None)
+            .unwrap();
+        assert_eq!(other.total_count, 0);
+        let unscoped = service.find_history(id, None, None, None, None).unwrap();
+        assert_eq!(unscoped.total_count, 0);
+    }
+}
@@ -0,0 +1,305 @@
+use bson::Document;
+
+use crate::error::ServiceError;
+use crate::id::ID;
+
+/// Callbacks around every write a `BaseService` makes.
+///
+/// `before_*` hooks run before anything is written. They can change the serialized document (or
+/// the update document with its operators, eg. `{ "$set": { .. } }`) and returning an error
+/// vetoes the operation. `after_*` hooks run once the write succeeded, with the item as stored
+/// for inserts and the parent document as stored for updates; an error they return is passed on
+/// to the caller. Soft deletes run the delete hooks and restores run the update hooks.
+///
+/// Every method does nothing by default, so implementors only pick the ones they need.
+#[allow(unused_variables)]
+pub trait ServiceHooks: Send + Sync {
+    /// The document carries the `_id` when the caller set one.
+    fn before_insert(
+        &self,
+        document: &mut Document,
+        user_id: Option<&ID>,
+    ) -> Result<(), ServiceError> {
+        Ok(())
+    }
+
+    fn after_insert(
+        &self,
+        id: &ID,
+        document: &Document,
+        user_id: Option<&ID>,
+    ) -> Result<(), ServiceError> {
+        Ok(())
+    }
+
+    fn before_update(
+        &self,
+        id: &ID,
+        update: &mut Document,
+        user_id: Option<&ID>,
+    ) -> Result<(), ServiceError> {
+        Ok(())
+    }
+
+    fn after_update(
+        &self,
+        id: &ID,
+        document: &Document,
+        user_id: Option<&ID>,
+    ) -> Result<(), ServiceError> {
+        Ok(())
+    }
+
+    fn before_delete(&self, id: &ID, user_id: Option<&ID>) -> Result<(), ServiceError> {
+        Ok(())
+    }
+
+    fn after_delete(&self, id: &ID, user_id: Option<&ID>) -> Result<(), ServiceError> {
+        Ok(())
+    }
+
+    fn before_insert_embedded(
+        &self,
+        id: &ID,
+        field_path: &str,
+        item: &mut Document,
+        user_id: Option<&ID>,
+    ) -> Result<(), ServiceError> {
+        Ok(())
+    }
+
+    fn after_insert_embedded(
+        &self,
+        id: &ID,
+        field_path: &str,
+        embedded_id: &ID,
+        item: &Document,
+        user_id: Option<&ID>,
+    ) -> Result<(), ServiceError> {
+        Ok(())
+    }
+
+    fn before_update_embedded(
+        &self,
+        id: &ID,
+        field_path: &str,
+        embedded_id: &ID,
+        update: &mut Document,
+        user_id: Option<&ID>,
+    ) -> Result<(), ServiceError> {
+        Ok(())
+    }
+
+    fn after_update_embedded(
+        &self,
+        id: &ID,
+        field_path: &str,
+        embedded_id: &ID,
+        document: &Document,
+        user_id: Option<&ID>,
+    ) -> Result<(), ServiceError> {
+        Ok(())
+    }
+
+    fn before_delete_embedded(
+        &self,
+        id: &ID,
+        field_path: &str,
+        embedded_id: &ID,
+        user_id: Option<&ID>,
+    ) -> Result<(), ServiceError> {
+        Ok(())
+    }
+
+    fn after_delete_embedded(
+        &self,
+        id: &ID,
+        field_path: &str,
+        embedded_id: &ID,
+        user_id: Option<&ID>,
+    ) -> Result<(), ServiceError> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::base::BaseService;
+    use crate::mock::MockCollection;
+    use crate::mongo::MongoService;
+    use bson::doc;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_before_insert_can_change_the_document() {
+        struct Defaults;
+        impl ServiceHooks for Defaults {
+            fn before_insert(
+                &self,
+                document: &mut Document,
+                _user_id: Option<&ID>,
+            ) -> Result<(), ServiceError> {
+                document.insert("views", 0);
+                Ok(())
+            }
+        }
+
+        let collection = MockCollection::new("items");
+        let service = MongoService::with_data_source(collection.clone(), None).with_hooks(Defaults);
+        service
+            .insert_one(doc! { "_id": 1_i64, "views": 99 }, None)
+            .unwrap();
+        assert_eq!(collection.documents()[0].get_i32("views"), Ok(0));
+    }
+
+    #[test]
+    fn test_before_hooks_can_veto_writes() {
+        struct Pinned;
+        impl ServiceHooks for Pinned {
+            fn before_update(
+                &self,
+                _id: &ID,
+                _update: &mut Document,
+                _user_id: Option<&ID>,
+            ) -> Result<(), ServiceError> {
+                Err("The item is pinned".into())
+            }
+
+            fn before_delete(&self, _id: &ID, _user_id: Option<&ID>) -> Result<(), ServiceError> {
+                Err("The item is pinned".into())
+            }
+        }
+
+        let collection =
+            MockCollection::with_documents("items", vec![doc! { "_id": 1_i64, "title": "a" }]);
+        let service = MongoService::with_data_source(collection.clone(), None).with_hooks(Pinned);
+        let updated: Result<Document, ServiceError> =
+            service.update_one_with_doc(ID::I64(1), doc! { "$set": { "title": "b" } });
+        assert!(matches!(updated, Err(ServiceError::Unknown(_))));
+        match service.delete_one_by_id(ID::I64(1)) {
+            Err(ServiceError::Unknown(message)) => assert_eq!(message, "The item is pinned"),
+            _ => panic!("expected the delete to be vetoed"),
+        }
+        assert!(service.delete_one_by_query(doc! { "title": "a" }).is_err());
+        assert_eq!(
+            collection.documents(),
+            vec![doc! { "_id": 1_i64, "title": "a" }]
+        );
+    }
+
+    #[test]
+    fn test_after_hooks_run_once_the_write_succeeded() {
+        #[derive(Clone, Default)]
+        struct Calls(Arc<Mutex<Vec<String>>>);
+        impl ServiceHooks for Calls {
+            fn after_insert(
+                &self,
+                id: &ID,
+                _document: &Document,
+                user_id: Option<&ID>,
+            ) -> Result<(), ServiceError> {
+                let user = user_id.map_or(String::new(), ID::to_string);
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push(format!("insert {} {}", id, user));
+                Ok(())
+            }
+
+            fn after_update(
+                &self,
+                id: &ID,
+                document: &Document,
+                _user_id: Option<&ID>,
+            ) -> Result<(), ServiceError> {
+                let title = document.get_str("title").unwrap_or_default();
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push(format!("update {} {}", id, title));
+                Ok(())
+            }
+
+            fn after_delete(&self, id: &ID, _user_id: Option<&ID>) -> Result<(), ServiceError> {
+                self.0.lock().unwrap().push(format!("delete {}", id));
+                Ok(())
+            }
+        }
+
+        let calls = Calls::default();
+        let service = MongoService::with_data_source(MockCollection::new("items"), None)
+            .with_hooks(calls.clone());
+        let id = service
+            .insert_one(doc! { "_id": 1_i64 }, Some(ID::with_string("ann")))
+            .unwrap();
+        let _: Document = service
+            .update_one_with_doc(id.clone(), doc! { "$set": { "title": "Renamed" } })
+            .unwrap();
+        service.delete_one_by_id(id.clone()).unwrap();
+        // nothing was deleted, so there is nothing to tell the hooks
+        service.delete_one_by_id(id).unwrap();
+        assert_eq!(
+            *calls.0.lock().unwrap(),
+            vec!["insert 1 ann", "update 1 Renamed", "delete 1"]
+        );
+    }
+
+    #[test]
+    fn test_embedded_hooks_run_around_embedded_writes() {
+        #[derive(Clone, Default)]
+        struct Calls(Arc<Mutex<Vec<String>>>);
+        impl ServiceHooks for Calls {
+            fn before_insert_embedded(
+                &self,
+                _id: &ID,
+                _field_path: &str,
+                item: &mut Document,
+                _user_id: Option<&ID>,
+            ) -> Result<(), ServiceError> {
+                item.insert("_id", "fixed");
+                Ok(())
+            }
+
+            fn after_insert_embedded(
+                &self,
+                id: &ID,
+                field_path: &str,
+                embedded_id: &ID,
+                _item: &Document,
+                _user_id: Option<&ID>,
+            ) -> Result<(), ServiceError> {
+                let call = format!("insert {} {} {}", id, field_path, embedded_id);
+                self.0.lock().unwrap().push(call);
+                Ok(())
+            }
+
+            fn after_delete_embedded(
+                &self,
+                id: &ID,
+                field_path: &str,
+                embedded_id: &ID,
+                _user_id: Option<&ID>,
+            ) -> Result<(), ServiceError> {
+                let call = format!("delete {} {} {}", id, field_path, embedded_id);
+                self.0.lock().unwrap().push(call);
+                Ok(())
+            }
+        }
+
+        let calls = Calls::default();
+        let collection = MockCollection::with_documents("items", vec![doc! { "_id": 1_i64 }]);
+        let service = MongoService::with_data_source(collection, None).with_hooks(calls.clone());
+        let ids = service
+            .insert_embedded(ID::I64(1), "comments", vec![doc! { "text": "a" }], None)
+            .unwrap();
+        assert_eq!(ids, vec![ID::with_string("fixed")]);
+        service
+            .delete_embedded(ID::I64(1), "comments", ids[0].clone())
+            .unwrap();
+        assert_eq!(
+            *calls.0.lock().unwrap(),
+            vec!["insert 1 comments fixed", "delete 1 comments fixed"]
+        );
+    }
+}
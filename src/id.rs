@@ -149,11 +149,20 @@ impl ID {
     }
 
     pub fn with_bson(value: &Bson) -> Self {
-        match value.into() {
-            Bson::String(s) => ID::String(s),
-            Bson::ObjectId(o) => ID::ObjectId(o),
-            Bson::I64(i) => ID::I64(i),
-            _ => panic!("Invalid id type used {:?}", value),
+        match ID::try_with_bson(value) {
+            Some(id) => id,
+            None => panic!("Invalid id type used {:?}", value),
+        }
+    }
+
+    /// Like `with_bson`, but gives `None` for a value that can't be an ID, eg. a double `_id`
+    /// that was stored by another application.
+    pub fn try_with_bson(value: &Bson) -> Option<Self> {
+        match value {
+            Bson::String(s) => Some(ID::String(s.clone())),
+            Bson::ObjectId(o) => Some(ID::ObjectId(o.clone())),
+            Bson::I64(i) => Some(ID::I64(*i)),
+            _ => None,
         }
     }
 
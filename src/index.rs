@@ -0,0 +1,563 @@
+use bson::{doc, Bson, Document};
+use mongodb::error::ErrorKind;
+use mongodb::Database;
+use std::time::Duration;
+
+use crate::error::ServiceError;
+
+const NAMESPACE_NOT_FOUND: i32 = 26;
+const ID_INDEX: &str = "_id_";
+
+/// An index a service needs, see `BaseService::indexes`.
+///
+/// ```
+/// use bson::doc;
+/// use mongodb_base_service::IndexSpec;
+/// use std::time::Duration;
+///
+/// let indexes = vec![
+///     IndexSpec::new(doc! { "email": 1 }).with_unique(),
+///     IndexSpec::new(doc! { "author_id": 1, "node.date_created": -1 }),
+///     IndexSpec::new(doc! { "expires_at": 1 }).with_expire_after(Duration::from_secs(0)),
+///     IndexSpec::text(&["title", "body"]).with_default_language("english"),
+/// ];
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct IndexSpec {
+    keys: Document,
+    name: Option<String>,
+    unique: bool,
+    sparse: bool,
+    expire_after: Option<Duration>,
+    partial_filter: Option<Document>,
+    weights: Option<Document>,
+    default_language: Option<String>,
+}
+
+impl IndexSpec {
+    /// An index on `keys`, eg. `{ "email": 1 }`, `{ "a": 1, "b": -1 }` or `{ "loc": "2dsphere" }`.
+    pub fn new(keys: Document) -> Self {
+        IndexSpec {
+            keys,
+            name: None,
+            unique: false,
+            sparse: false,
+            expire_after: None,
+            partial_filter: None,
+            weights: None,
+            default_language: None,
+        }
+    }
+
+    /// A text index on `fields`, as used by `SearchMode::Text`.
+    pub fn text(fields: &[&str]) -> Self {
+        let mut keys = Document::new();
+        for field in fields {
+            keys.insert(*field, "text");
+        }
+        IndexSpec::new(keys)
+    }
+
+    /// Names the index, instead of MongoDB's default name built from the keys.
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_owned());
+        self
+    }
+
+    pub fn with_unique(mut self) -> Self {
+        self.unique = true;
+        self
+    }
+
+    /// Leaves items without the indexed fields out of the index.
+    pub fn with_sparse(mut self) -> Self {
+        self.sparse = true;
+        self
+    }
+
+    /// Makes it a TTL index, removing items once the date in the indexed field is `expire_after`
+    /// in the past.
+    pub fn with_expire_after(mut self, expire_after: Duration) -> Self {
+        self.expire_after = Some(expire_after);
+        self
+    }
+
+    /// Only indexes the items matching `filter`, either a `Document` or a `Filter`.
+    pub fn with_partial_filter<F: Into<Document>>(mut self, filter: F) -> Self {
+        self.partial_filter = Some(filter.into());
+        self
+    }
+
+    /// The relevance of each field of a text index, fields that aren't listed weigh 1.
+    pub fn with_weights(mut self, weights: Document) -> Self {
+        self.weights = Some(weights);
+        self
+    }
+
+    /// The language of a text index, which picks its stemming and stop words.
+    pub fn with_default_language(mut self, language: &str) -> Self {
+        self.default_language = Some(language.to_owned());
+        self
+    }
+
+    /// The given name, or the one MongoDB would generate, eg. `a_1_b_-1`.
+    pub fn name(&self) -> String {
+        if let Some(name) = &self.name {
+            return name.clone();
+        }
+        self.keys
+            .iter()
+            .map(|(key, value)| match value {
+                Bson::String(s) => format!("{}_{}", key, s),
+                Bson::FloatingPoint(f) => format!("{}_{}", key, f),
+                Bson::I32(i) => format!("{}_{}", key, i),
+                Bson::I64(i) => format!("{}_{}", key, i),
+                other => format!("{}_{}", key, other),
+            })
+            .collect::<Vec<String>>()
+            .join("_")
+    }
+
+    /// The index as `createIndexes` takes it.
+    pub fn to_document(&self) -> Document {
+        let mut spec = doc! { "key": self.keys.clone(), "name": self.name() };
+        if self.unique {
+            spec.insert("unique", true);
+        }
+        if self.sparse {
+            spec.insert("sparse", true);
+        }
+        if let Some(expire_after) = self.expire_after {
+            spec.insert("expireAfterSeconds", expire_after.as_secs() as i64);
+        }
+        if let Some(filter) = &self.partial_filter {
+            spec.insert("partialFilterExpression", filter.clone());
+        }
+        if let Some(weights) = &self.weights {
+            spec.insert("weights", weights.clone());
+        }
+        if let Some(language) = &self.default_language {
+            spec.insert("default_language", language.clone());
+        }
+        spec
+    }
+}
+
+/// An existing index whose keys or options differ from its declaration.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IndexDrift {
+    pub collection: String,
+    pub name: String,
+    pub declared: Document,
+    pub existing: Document,
+}
+
+/// What `DataSources::ensure_indexes` found and did. Indexes are named `collection.index`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct IndexReport {
+    /// Declared indexes that were missing and have been created.
+    pub created: Vec<String>,
+    /// Indexes that differ from their declaration. They are left as they are, since fixing them
+    /// means dropping and rebuilding them.
+    pub drifted: Vec<IndexDrift>,
+    /// Indexes that exist but aren't declared by the service.
+    pub undeclared: Vec<String>,
+    /// Undeclared indexes that have been dropped, when asked to.
+    pub dropped: Vec<String>,
+}
+
+/// Where `DataSources::ensure_indexes` reads and changes index definitions, which is the
+/// `mongodb::Database` the collections live in.
+pub trait IndexStore {
+    /// The indexes of `collection` as `listIndexes` returns them, none if it doesn't exist yet.
+    fn list_indexes(&self, collection: &str) -> Result<Vec<Document>, ServiceError>;
+
+    fn create_index(&self, collection: &str, index: Document) -> Result<(), ServiceError>;
+
+    fn drop_index(&self, collection: &str, name: &str) -> Result<(), ServiceError>;
+}
+
+impl IndexStore for Database {
+    fn list_indexes(&self, collection: &str) -> Result<Vec<Document>, ServiceError> {
+        let result = match self.run_command(doc! { "listIndexes": collection }, None) {
+            Ok(result) => result,
+            Err(e) => match e.kind.as_ref() {
+                ErrorKind::CommandError(c) if c.code == NAMESPACE_NOT_FOUND => {
+                    return Ok(Vec::new())
+                }
+                _ => return Err(e.into()),
+            },
+        };
+        let batch = result
+            .get_document("cursor")
+            .and_then(|cursor| cursor.get_array("firstBatch"))
+            .map_err(|e| ServiceError::ParseError(e.to_string()))?;
+        Ok(batch
+            .iter()
+            .filter_map(|index| match index {
+                Bson::Document(index) => Some(index.clone()),
+                _ => None,
+            })
+            .collect())
+    }
+
+    fn create_index(&self, collection: &str, index: Document) -> Result<(), ServiceError> {
+        self.run_command(
+            doc! { "createIndexes": collection, "indexes": [index] },
+            None,
+        )?;
+        Ok(())
+    }
+
+    fn drop_index(&self, collection: &str, name: &str) -> Result<(), ServiceError> {
+        self.run_command(doc! { "dropIndexes": collection, "index": name }, None)?;
+        Ok(())
+    }
+}
+
+/// Adds `spec` to the indexes declared for `collection`, unless it is already there. An index
+/// declared again under the same name with other keys or options is an error.
+pub(crate) fn declare_index(
+    declared: &mut Vec<IndexSpec>,
+    collection: &str,
+    spec: IndexSpec,
+) -> Result<(), ServiceError> {
+    match declared.iter().find(|other| other.name() == spec.name()) {
+        Some(other) if comparable(&other.to_document()) == comparable(&spec.to_document()) => {
+            Ok(())
+        }
+        Some(_) => Err(format!(
+            "Index {}.{} is declared differently by two services",
+            collection,
+            spec.name()
+        )
+        .into()),
+        None => {
+            declared.push(spec);
+            Ok(())
+        }
+    }
+}
+
+/// Brings the indexes of one collection in line with `declared`, adding to `report`.
+pub fn ensure_collection_indexes<S: IndexStore + ?Sized>(
+    store: &S,
+    collection: &str,
+    declared: &[IndexSpec],
+    drop_undeclared: bool,
+    report: &mut IndexReport,
+) -> Result<(), ServiceError> {
+    let existing = store.list_indexes(collection)?;
+    let mut matched: Vec<String> = Vec::new();
+    for spec in declared {
+        let wanted = comparable(&spec.to_document());
+        // look for it by name first, then by keys in case it was created under another name
+        let found = existing
+            .iter()
+            .find(|index| index.get_str("name").ok() == Some(spec.name().as_str()))
+            .or_else(|| {
+                existing
+                    .iter()
+                    .find(|index| comparable(index).get("key") == wanted.get("key"))
+            });
+        match found {
+            Some(index) => {
+                let name = index.get_str("name").unwrap_or_default().to_owned();
+                if comparable(index) != wanted {
+                    report.drifted.push(IndexDrift {
+                        collection: collection.to_owned(),
+                        name: name.clone(),
+                        declared: spec.to_document(),
+                        existing: index.clone(),
+                    });
+                }
+                matched.push(name);
+            }
+            None => {
+                store.create_index(collection, spec.to_document())?;
+                report
+                    .created
+                    .push(format!("{}.{}", collection, spec.name()));
+                matched.push(spec.name());
+            }
+        }
+    }
+    for index in existing.iter() {
+        let name = index.get_str("name").unwrap_or_default();
+        if name == ID_INDEX || matched.iter().any(|m| m == name) {
+            continue;
+        }
+        report.undeclared.push(format!("{}.{}", collection, name));
+        if drop_undeclared {
+            store.drop_index(collection, name)?;
+            report.dropped.push(format!("{}.{}", collection, name));
+        }
+    }
+    Ok(())
+}
+
+fn as_i64(value: &Bson) -> Option<i64> {
+    match value {
+        Bson::I32(i) => Some(i64::from(*i)),
+        Bson::I64(i) => Some(*i),
+        Bson::FloatingPoint(f) => Some(*f as i64),
+        _ => None,
+    }
+}
+
+/// The parts of an index definition that matter, normalized so that a declaration can be
+/// compared with what `listIndexes` returns (number types, defaults and text index keys).
+fn comparable(index: &Document) -> Document {
+    let empty = Document::new();
+    let key = index.get_document("key").unwrap_or(&empty);
+    let is_text = key.contains_key("_fts") || key.values().any(|v| v.as_str() == Some("text"));
+    let normalize = |value: &Bson| {
+        as_i64(value)
+            .map(Bson::I64)
+            .unwrap_or_else(|| value.clone())
+    };
+
+    let mut comparable = Document::new();
+    let mut keys = Document::new();
+    for (field, value) in key.iter() {
+        let is_text_key = value.as_str() == Some("text") || field == "_fts" || field == "_ftsx";
+        if !is_text || !is_text_key {
+            keys.insert(field.clone(), normalize(value));
+        }
+    }
+    comparable.insert("key", keys);
+    if is_text {
+        // text indexes list their fields as weights
+        let mut weights: Vec<(String, Bson)> = key
+            .iter()
+            .filter(|(field, value)| value.as_str() == Some("text") && *field != "_fts")
+            .map(|(field, _)| (field.clone(), Bson::I64(1)))
+            .collect();
+        if let Ok(declared) = index.get_document("weights") {
+            for (field, weight) in declared.iter() {
+                match weights.iter_mut().find(|(f, _)| f == field) {
+                    Some(entry) => entry.1 = normalize(weight),
+                    None => weights.push((field.clone(), normalize(weight))),
+                }
+            }
+        }
+        weights.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut text = Document::new();
+        for (field, weight) in weights {
+            text.insert(field, weight);
+        }
+        comparable.insert("weights", text);
+        let language = index.get_str("default_language").unwrap_or("english");
+        comparable.insert("default_language", language);
+    }
+    comparable.insert("unique", index.get_bool("unique").unwrap_or(false));
+    comparable.insert("sparse", index.get_bool("sparse").unwrap_or(false));
+    if let Some(expire_after) = index.get("expireAfterSeconds").and_then(as_i64) {
+        comparable.insert("expireAfterSeconds", expire_after);
+    }
+    if let Ok(filter) = index.get_document("partialFilterExpression") {
+        comparable.insert("partialFilterExpression", filter.clone());
+    }
+    comparable
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockCollection;
+    use crate::mongo::MongoService;
+    use crate::DataSources;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct MemoryStore {
+        indexes: Mutex<HashMap<String, Vec<Document>>>,
+    }
+
+    impl IndexStore for MemoryStore {
+        fn list_indexes(&self, collection: &str) -> Result<Vec<Document>, ServiceError> {
+            let indexes = self.indexes.lock().unwrap();
+            Ok(indexes.get(collection).cloned().unwrap_or_default())
+        }
+
+        fn create_index(&self, collection: &str, index: Document) -> Result<(), ServiceError> {
+            let mut indexes = self.indexes.lock().unwrap();
+            indexes
+                .entry(collection.to_owned())
+                .or_default()
+                .push(index);
+            Ok(())
+        }
+
+        fn drop_index(&self, collection: &str, name: &str) -> Result<(), ServiceError> {
+            let mut indexes = self.indexes.lock().unwrap();
+            if let Some(indexes) = indexes.get_mut(collection) {
+                indexes.retain(|index| index.get_str("name").ok() != Some(name));
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_ensure_indexes() {
+        let store = MemoryStore::default();
+        store
+            .create_index("posts", doc! { "key": { "_id": 1 }, "name": "_id_" })
+            .unwrap();
+        // what listIndexes returns for an existing text index
+        store
+            .create_index(
+                "posts",
+                doc! {
+                    "key": { "_fts": "text", "_ftsx": 1 },
+                    "name": "title_text",
+                    "weights": { "title": 1 },
+                    "default_language": "english",
+                },
+            )
+            .unwrap();
+        store
+            .create_index(
+                "posts",
+                doc! { "key": { "slug": 1.0 }, "name": "slug_1", "expireAfterSeconds": 60 },
+            )
+            .unwrap();
+        store
+            .create_index("posts", doc! { "key": { "legacy": 1 }, "name": "legacy_1" })
+            .unwrap();
+
+        let mut data_sources = DataSources::new();
+        data_sources.add_mongo_service(
+            "posts",
+            MongoService::with_data_source(MockCollection::new("posts"), None).with_indexes(vec![
+                IndexSpec::text(&["title"]),
+                IndexSpec::new(doc! { "slug": 1 }).with_unique(),
+                IndexSpec::new(doc! { "author_id": 1, "views": -1 })
+                    .with_partial_filter(doc! { "views": { "$gt": 0 } }),
+            ]),
+        );
+        data_sources.add_mongo_service(
+            "users",
+            MongoService::with_data_source(MockCollection::new("users"), None).with_indexes(vec![
+                IndexSpec::new(doc! { "email": 1 })
+                    .with_name("unique_email")
+                    .with_unique()
+                    .with_sparse(),
+            ]),
+        );
+
+        let report = data_sources.ensure_indexes(&store, false).unwrap();
+        assert_eq!(
+            report.created,
+            vec!["posts.author_id_1_views_-1", "users.unique_email"]
+        );
+        assert_eq!(report.drifted.len(), 1);
+        assert_eq!(report.drifted[0].name, "slug_1");
+        assert_eq!(report.undeclared, vec!["posts.legacy_1"]);
+        assert!(report.dropped.is_empty());
+        let users = store.list_indexes("users").unwrap();
+        assert_eq!(
+            users,
+            vec![doc! {
+                "key": { "email": 1 },
+                "name": "unique_email",
+                "unique": true,
+                "sparse": true,
+            }]
+        );
+
+        let report = data_sources.ensure_indexes(&store, true).unwrap();
+        assert!(report.created.is_empty());
+        assert_eq!(report.dropped, vec!["posts.legacy_1"]);
+        assert_eq!(store.list_indexes("posts").unwrap().len(), 4);
+    }
+
+    fn service(collection: &str, indexes: Vec<IndexSpec>) -> MongoService {
+        MongoService::with_data_source(MockCollection::new(collection), None).with_indexes(indexes)
+    }
+
+    #[test]
+    fn test_services_sharing_a_collection_declare_indexes_together() {
+        let store = MemoryStore::default();
+        store
+            .create_index("posts", doc! { "key": { "legacy": 1 }, "name": "legacy_1" })
+            .unwrap();
+        let mut data_sources = DataSources::new();
+        data_sources.add_mongo_service(
+            "posts",
+            service(
+                "posts",
+                vec![
+                    IndexSpec::new(doc! { "slug": 1 }).with_unique(),
+                    IndexSpec::new(doc! { "author_id": 1 }),
+                ],
+            ),
+        );
+        data_sources.add_mongo_service(
+            "drafts",
+            service(
+                "posts",
+                vec![
+                    IndexSpec::new(doc! { "author_id": 1 }),
+                    IndexSpec::new(doc! { "status": 1 }),
+                ],
+            ),
+        );
+
+        let report = data_sources.ensure_indexes(&store, true).unwrap();
+        assert_eq!(
+            report.created,
+            vec!["posts.author_id_1", "posts.status_1", "posts.slug_1"]
+        );
+        assert_eq!(report.dropped, vec!["posts.legacy_1"]);
+        assert_eq!(store.list_indexes("posts").unwrap().len(), 3);
+
+        let report = data_sources.ensure_indexes(&store, true).unwrap();
+        assert_eq!(report, IndexReport::default());
+    }
+
+    #[test]
+    fn test_conflicting_declarations_are_refused() {
+        let store = MemoryStore::default();
+        let mut data_sources = DataSources::new();
+        data_sources.add_mongo_service(
+            "posts",
+            service(
+                "posts",
+                vec![IndexSpec::new(doc! { "slug": 1 }).with_unique()],
+            ),
+        );
+        data_sources.add_mongo_service(
+            "drafts",
+            service("posts", vec![IndexSpec::new(doc! { "slug": 1 })]),
+        );
+        assert!(data_sources.ensure_indexes(&store, false).is_err());
+        assert!(store.list_indexes("posts").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_declarations_compare_like_listed_indexes() {
+        let declared = IndexSpec::text(&["title", "body"])
+            .with_weights(doc! { "title": 10 })
+            .to_document();
+        let listed = doc! {
+            "v": 2,
+            "key": { "_fts": "text", "_ftsx": 1 },
+            "name": "title_text_body_text",
+            "weights": { "body": 1, "title": 10 },
+            "default_language": "english",
+            "language_override": "language",
+        };
+        assert_eq!(comparable(&declared), comparable(&listed));
+
+        let declared = IndexSpec::new(doc! { "expires_at": 1 })
+            .with_expire_after(Duration::from_secs(60))
+            .to_document();
+        let listed = doc! { "key": { "expires_at": 1.0 }, "name": "expires_at_1", "expireAfterSeconds": 60.0 };
+        assert_eq!(comparable(&declared), comparable(&listed));
+        let stale =
+            doc! { "key": { "expires_at": 1 }, "name": "expires_at_1", "expireAfterSeconds": 30 };
+        assert_ne!(comparable(&declared), comparable(&stale));
+    }
+}
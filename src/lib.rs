@@ -5,31 +5,82 @@ extern crate juniper;
 #[macro_use]
 extern crate lazy_static;
 
-use bson::Document;
+use bson::{Bson, Document};
+use mongodb_cursor_pagination::FindResult;
+use serde::de::DeserializeOwned;
 
+#[cfg(feature = "async")]
+mod async_base;
 mod base;
+mod cache;
+mod change_stream;
+mod cursor;
+mod data_source;
 mod error;
+mod filter;
+mod history;
+mod hooks;
 mod id;
+mod index;
+mod loader;
+#[cfg(any(test, feature = "test"))]
+mod mock;
 mod mongo;
 mod node;
+mod projection;
+mod relation;
+mod search;
+mod tenant;
+mod validation;
 
+pub use crate::cache::{CacheStats, ItemCache};
+pub use crate::change_stream::{ChangeEvent, ChangeEvents, ChangeOperation, ChangeStream};
+pub use crate::data_source::DataSource;
 pub use crate::error::ServiceError;
-use crate::mongo::MongoService;
+pub use crate::filter::Filter;
+pub use crate::history::{FieldChange, HistoryOperation, HistoryRecord};
+pub use crate::hooks::ServiceHooks;
+pub use crate::index::{IndexDrift, IndexReport, IndexSpec, IndexStore};
+pub use crate::loader::Loader;
+pub use crate::mongo::MongoService;
+pub use crate::projection::Projection;
+pub use crate::relation::{Relation, RelationKind};
+pub use crate::search::{SearchMode, SearchQuery, TermMatch, TEXT_SCORE_FIELD};
+pub use crate::tenant::TenantScope;
+pub use crate::validation::{FieldError, ValidationScope, Validator};
+
+#[cfg(feature = "async")]
+pub use crate::async_base::{run_blocking, AsyncBaseService};
+#[cfg(feature = "async")]
+pub use crate::loader::AsyncLoader;
+#[cfg(feature = "async")]
+pub use crate::mongo::AsyncMongoService;
 
 use mongodb::Collection;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::index::{declare_index, ensure_collection_indexes};
+use crate::relation::populate;
 
-pub use base::{BaseService, DeleteResponse};
+pub use base::{
+    BaseService, CountMode, DeleteManyResponse, DeleteResponse, GroupCount, UpdateManyResponse,
+    UpsertKey, UpsertResponse,
+};
 pub use id::ID;
 pub use node::Node;
 pub use node::NodeDetails;
 
 #[cfg(feature = "graphql")]
-pub use base::DeleteResponseGQL;
+pub use base::{
+    DeleteManyResponseGQL, DeleteResponseGQL, UpdateManyResponseGQL, UpsertResponseGQL,
+};
 
 #[cfg(feature = "test")]
 pub use base::mock_time;
 
+#[cfg(any(test, feature = "test"))]
+pub use mock::MockCollection;
+
 #[derive(Clone)]
 pub struct DataSources {
     collections: HashMap<String, MongoService>,
@@ -54,6 +105,126 @@ impl DataSources {
         );
     }
 
+    /// Registers an already configured service, eg. one built with `with_soft_delete`,
+    /// `with_history`, `with_hooks`, `with_validator`, `with_text_search`, `with_estimated_count`,
+    /// `with_indexes`, `with_cache`, `with_tenant_field`, `with_hidden_fields` or `with_relations`.
+    pub fn add_mongo_service(&mut self, name: &str, service: MongoService) {
+        self.collections.insert(name.to_string(), service);
+    }
+
+    /// Registers a service backed by an in-memory `MockCollection` and returns the collection so
+    /// that tests can seed and inspect it.
+    #[cfg(any(test, feature = "test"))]
+    pub fn create_mock_service(
+        &mut self,
+        name: &str,
+        default_sort: Option<Document>,
+    ) -> MockCollection {
+        let collection = MockCollection::new(name);
+        self.collections.insert(
+            name.to_string(),
+            MongoService::with_data_source(collection.clone(), default_sort),
+        );
+        collection
+    }
+
+    /// Creates the indexes the services declare (see `BaseService::indexes`) that are missing
+    /// from their collections in `store`, usually the `mongodb::Database` they live in. Indexes
+    /// whose options drifted from their declaration are only reported, and indexes nobody
+    /// declared are reported and, with `drop_undeclared`, dropped. Services sharing a collection
+    /// declare its indexes together, so declaring the same index twice is fine but declaring it
+    /// differently is an error. Call it on startup.
+    pub fn ensure_indexes<S: IndexStore + ?Sized>(
+        &self,
+        store: &S,
+        drop_undeclared: bool,
+    ) -> Result<IndexReport, ServiceError> {
+        let mut services: Vec<(&String, &MongoService)> = self.collections.iter().collect();
+        services.sort_by_key(|(key, _)| *key);
+        let mut declared: BTreeMap<&str, Vec<IndexSpec>> = BTreeMap::new();
+        for (_, service) in services {
+            let collection = service.data_source().name();
+            let specs = declared.entry(collection).or_default();
+            for spec in service.indexes() {
+                declare_index(specs, collection, spec)?;
+            }
+        }
+        let mut report = IndexReport::default();
+        for (collection, specs) in declared {
+            ensure_collection_indexes(store, collection, &specs, drop_undeclared, &mut report)?;
+        }
+        Ok(report)
+    }
+
+    /// A copy of the data sources whose tenant scoped services (see
+    /// `MongoService::with_tenant_field`) only see the items of `tenant`, eg. for the context of
+    /// a request. Services without a tenant field are shared.
+    pub fn for_tenant<B: Into<Bson>>(&self, tenant: B) -> Self {
+        let tenant = tenant.into();
+        DataSources {
+            collections: self
+                .collections
+                .iter()
+                .map(|(name, service)| (name.clone(), service.for_tenant(tenant.clone())))
+                .collect(),
+        }
+    }
+
+    /// Fills in the `relations` (see `MongoService::with_relations`) of items read from the
+    /// service registered as `key`, making one batched query per relation to the related
+    /// services, and deserializes the items with their related items.
+    pub fn populate<T: DeserializeOwned>(
+        &self,
+        key: &str,
+        items: Vec<Document>,
+        relations: &[&str],
+    ) -> Result<Vec<T>, ServiceError> {
+        let service = self.get_mongo_service(key)?;
+        populate(&self.collections, service, items, relations)?
+            .into_iter()
+            .map(|item| Ok(bson::from_bson(Bson::Document(item))?))
+            .collect()
+    }
+
+    /// `find` on the service registered as `key`, with the `relations` of the page's items
+    /// filled in.
+    #[allow(clippy::too_many_arguments)]
+    pub fn find_populated<T: DeserializeOwned>(
+        &self,
+        key: &str,
+        relations: &[&str],
+        filter: Option<Document>,
+        sort: Option<Document>,
+        limit: Option<i32>,
+        after: Option<String>,
+        before: Option<String>,
+        skip: Option<i32>,
+    ) -> Result<FindResult<T>, ServiceError> {
+        let page: FindResult<Document> = self
+            .get_mongo_service(key)?
+            .find(filter, sort, limit, after, before, skip)?;
+        Ok(FindResult {
+            page_info: page.page_info,
+            edges: page.edges,
+            total_count: page.total_count,
+            items: self.populate(key, page.items, relations)?,
+        })
+    }
+
+    /// `find_one` on the service registered as `key`, with the `relations` of the item filled in.
+    pub fn find_one_populated<T: DeserializeOwned>(
+        &self,
+        key: &str,
+        relations: &[&str],
+        filter: Document,
+    ) -> Result<Option<T>, ServiceError> {
+        let item: Option<Document> = self.get_mongo_service(key)?.find_one(filter)?;
+        match item {
+            Some(item) => Ok(self.populate(key, vec![item], relations)?.pop()),
+            None => Ok(None),
+        }
+    }
+
     pub fn get_mongo_service(&self, key: &str) -> Result<&MongoService, ServiceError> {
         let service = self.collections.get(&key.to_string());
         match service {
@@ -65,3 +236,115 @@ impl DataSources {
         }
     }
 }
+
+/// The async counterpart of `DataSources`, handing out `AsyncMongoService`s.
+#[cfg(feature = "async")]
+#[derive(Clone, Default)]
+pub struct AsyncDataSources {
+    collections: HashMap<String, AsyncMongoService>,
+}
+
+#[cfg(feature = "async")]
+impl AsyncDataSources {
+    pub fn new() -> Self {
+        AsyncDataSources {
+            collections: HashMap::new(),
+        }
+    }
+
+    pub fn create_mongo_service(
+        &mut self,
+        name: &str,
+        collection: &Collection,
+        default_sort: Option<Document>,
+    ) {
+        self.collections.insert(
+            name.to_string(),
+            MongoService::new(collection, default_sort).into(),
+        );
+    }
+
+    /// Registers an already configured service, eg. one built with `with_soft_delete`,
+    /// `with_history`, `with_hooks`, `with_validator`, `with_text_search`, `with_estimated_count`,
+    /// `with_indexes`, `with_cache`, `with_tenant_field`, `with_hidden_fields` or `with_relations`.
+    pub fn add_mongo_service(&mut self, name: &str, service: MongoService) {
+        self.collections.insert(name.to_string(), service.into());
+    }
+
+    /// Registers a service backed by an in-memory `MockCollection` and returns the collection so
+    /// that tests can seed and inspect it.
+    #[cfg(any(test, feature = "test"))]
+    pub fn create_mock_service(
+        &mut self,
+        name: &str,
+        default_sort: Option<Document>,
+    ) -> MockCollection {
+        let collection = MockCollection::new(name);
+        self.collections.insert(
+            name.to_string(),
+            MongoService::with_data_source(collection.clone(), default_sort).into(),
+        );
+        collection
+    }
+
+    /// See `DataSources::for_tenant`.
+    pub fn for_tenant<B: Into<Bson>>(&self, tenant: B) -> Self {
+        let tenant = tenant.into();
+        AsyncDataSources {
+            collections: self
+                .collections
+                .iter()
+                .map(|(name, service)| (name.clone(), service.for_tenant(tenant.clone())))
+                .collect(),
+        }
+    }
+
+    /// See `DataSources::populate`, eg. for the items of an async `find`.
+    pub async fn populate<T>(
+        &self,
+        key: &str,
+        items: Vec<Document>,
+        relations: &[&str],
+    ) -> Result<Vec<T>, ServiceError>
+    where
+        T: DeserializeOwned + Send + 'static,
+    {
+        let data_sources = DataSources {
+            collections: self
+                .collections
+                .iter()
+                .map(|(name, service)| (name.clone(), service.service().clone()))
+                .collect(),
+        };
+        let key = key.to_owned();
+        let relations: Vec<String> = relations.iter().map(|name| (*name).to_owned()).collect();
+        run_blocking(move || {
+            let relations: Vec<&str> = relations.iter().map(String::as_str).collect();
+            data_sources.populate(&key, items, &relations)
+        })
+        .await
+    }
+
+    pub fn get_mongo_service(&self, key: &str) -> Result<&AsyncMongoService, ServiceError> {
+        match self.collections.get(key) {
+            Some(s) => Ok(s),
+            None => Err(ServiceError::ConnectionError(format!(
+                "Unable to connect to collection {}",
+                key
+            ))),
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl From<DataSources> for AsyncDataSources {
+    fn from(data_sources: DataSources) -> AsyncDataSources {
+        AsyncDataSources {
+            collections: data_sources
+                .collections
+                .into_iter()
+                .map(|(name, service)| (name, service.into()))
+                .collect(),
+        }
+    }
+}
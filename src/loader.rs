@@ -0,0 +1,333 @@
+use serde::de::DeserializeOwned;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Condvar, Mutex, MutexGuard};
+#[cfg(feature = "async")]
+use std::time::Duration;
+
+#[cfg(feature = "async")]
+use crate::async_base::{run_blocking, AsyncBaseService};
+use crate::base::BaseService;
+use crate::error::ServiceError;
+use crate::id::ID;
+
+struct LoaderState<T> {
+    loaded: HashMap<ID, Option<T>>,
+    pending: Vec<ID>,
+    in_flight: HashSet<ID>,
+    batches: usize,
+}
+
+impl<T: Clone> LoaderState<T> {
+    fn new() -> Self {
+        LoaderState {
+            loaded: HashMap::new(),
+            pending: Vec::new(),
+            in_flight: HashSet::new(),
+            batches: 0,
+        }
+    }
+
+    fn queue(&mut self, ids: &[ID]) {
+        for id in ids.iter() {
+            if !self.loaded.contains_key(id)
+                && !self.in_flight.contains(id)
+                && !self.pending.contains(id)
+            {
+                self.pending.push(id.clone());
+            }
+        }
+    }
+
+    /// Takes the pending ids that `ids` need, marking them in flight.
+    fn take_batch(&mut self, ids: &[ID]) -> Option<Vec<ID>> {
+        if !ids.iter().any(|id| self.pending.contains(id)) {
+            return None;
+        }
+        let batch = std::mem::take(&mut self.pending);
+        self.in_flight.extend(batch.iter().cloned());
+        Some(batch)
+    }
+
+    fn finish(&mut self, batch: Vec<ID>, items: Option<Vec<Option<T>>>) {
+        for id in batch.iter() {
+            self.in_flight.remove(id);
+        }
+        if let Some(items) = items {
+            self.batches += 1;
+            for (id, item) in batch.into_iter().zip(items) {
+                self.loaded.insert(id, item);
+            }
+        }
+    }
+
+    fn is_loaded(&self, ids: &[ID]) -> bool {
+        ids.iter().all(|id| self.loaded.contains_key(id))
+    }
+
+    fn is_in_flight(&self, ids: &[ID]) -> bool {
+        ids.iter().any(|id| self.in_flight.contains(id))
+    }
+
+    fn items(&self, ids: &[ID]) -> Vec<Option<T>> {
+        ids.iter()
+            .map(|id| self.loaded.get(id).cloned().flatten())
+            .collect()
+    }
+}
+
+/// Batches the lookups by id of a request into `find_many_by_ids` queries, DataLoader style.
+///
+/// Make one per request, eg. in the GraphQL context, since it remembers every item it loaded.
+/// Resolvers `queue` the ids they are going to need, and the first `load` reads all of them in
+/// a single query. The async flavour, `AsyncLoader`, doesn't need the ids queued up front.
+pub struct Loader<S, T> {
+    service: S,
+    state: Mutex<LoaderState<T>>,
+    loaded: Condvar,
+}
+
+impl<S, T> Loader<S, T>
+where
+    S: for<'a> BaseService<'a>,
+    T: DeserializeOwned + Clone,
+{
+    pub fn new(service: S) -> Self {
+        Loader {
+            service,
+            state: Mutex::new(LoaderState::new()),
+            loaded: Condvar::new(),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, LoaderState<T>> {
+        self.state.lock().expect("Loader lock poisoned")
+    }
+
+    /// Announces ids that will be loaded, so that they are read along with the next batch.
+    pub fn queue(&self, ids: &[ID]) {
+        self.state().queue(ids);
+    }
+
+    pub fn load(&self, id: ID) -> Result<Option<T>, ServiceError> {
+        Ok(self.load_many(vec![id])?.pop().flatten())
+    }
+
+    /// Loads `ids`, along with every queued id that hasn't been loaded yet, in one query.
+    ///
+    /// The loader isn't locked during the query, so other threads can queue ids meanwhile. The
+    /// ones that need ids of a batch in flight wait for it rather than reading them again.
+    pub fn load_many(&self, ids: Vec<ID>) -> Result<Vec<Option<T>>, ServiceError> {
+        let mut state = self.state();
+        state.queue(&ids);
+        loop {
+            if state.is_loaded(&ids) {
+                return Ok(state.items(&ids));
+            }
+            if let Some(batch) = state.take_batch(&ids) {
+                drop(state);
+                let result = self.service.find_many_by_ids(batch.clone());
+                state = self.state();
+                state.finish(batch, result.as_ref().ok().cloned());
+                self.loaded.notify_all();
+                result?;
+            } else if state.is_in_flight(&ids) {
+                state = self.loaded.wait(state).expect("Loader lock poisoned");
+            } else {
+                // the batch these ids were in failed, try them again
+                state.queue(&ids);
+            }
+        }
+    }
+
+    /// How many queries the loader has made so far.
+    pub fn batches(&self) -> usize {
+        self.state().batches
+    }
+}
+
+/// The async flavour of `Loader`, which batches the lookups of concurrent resolvers.
+///
+/// A `load` waits for a short delay, 1ms by default, before it queries, so that the lookups
+/// made meanwhile by the other resolvers of the request (eg. the fields of a list) share the
+/// query.
+#[cfg(feature = "async")]
+pub struct AsyncLoader<S, T> {
+    service: S,
+    state: Mutex<LoaderState<T>>,
+    loaded: tokio::sync::Notify,
+    delay: Duration,
+}
+
+#[cfg(feature = "async")]
+impl<S, T> AsyncLoader<S, T>
+where
+    S: for<'a> BaseService<'a> + Clone + Send + Sync + 'static,
+    T: DeserializeOwned + Clone + Send + 'static,
+{
+    pub fn new<A>(service: &A) -> Self
+    where
+        A: AsyncBaseService<Service = S>,
+    {
+        AsyncLoader {
+            service: service.service().clone(),
+            state: Mutex::new(LoaderState::new()),
+            loaded: tokio::sync::Notify::new(),
+            delay: Duration::from_millis(1),
+        }
+    }
+
+    /// Sets how long a `load` waits for the other lookups before it queries.
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    fn state(&self) -> MutexGuard<'_, LoaderState<T>> {
+        self.state.lock().expect("Loader lock poisoned")
+    }
+
+    pub async fn load(&self, id: ID) -> Result<Option<T>, ServiceError> {
+        Ok(self.load_many(vec![id]).await?.pop().flatten())
+    }
+
+    pub async fn load_many(&self, ids: Vec<ID>) -> Result<Vec<Option<T>>, ServiceError> {
+        self.state().queue(&ids);
+        // let the other lookups of the request queue their ids first
+        tokio::time::sleep(self.delay).await;
+        loop {
+            // created before looking, so that a batch finishing in between isn't missed
+            let notified = self.loaded.notified();
+            let batch = {
+                let mut state = self.state();
+                if state.is_loaded(&ids) {
+                    return Ok(state.items(&ids));
+                }
+                let batch = state.take_batch(&ids);
+                if batch.is_none() && !state.is_in_flight(&ids) {
+                    // the batch these ids were in failed, try them again
+                    state.queue(&ids);
+                    continue;
+                }
+                batch
+            };
+            let batch = match batch {
+                Some(batch) => batch,
+                None => {
+                    notified.await;
+                    continue;
+                }
+            };
+            let service = self.service.clone();
+            let requested = batch.clone();
+            let result = run_blocking(move || service.find_many_by_ids(requested)).await;
+            self.state().finish(batch, result.as_ref().ok().cloned());
+            self.loaded.notify_waiters();
+            result?;
+        }
+    }
+
+    /// How many queries the loader has made so far.
+    pub fn batches(&self) -> usize {
+        self.state().batches
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockCollection;
+    use crate::mongo::MongoService;
+    use bson::{doc, Document};
+
+    fn users() -> MongoService {
+        let collection = MockCollection::with_documents(
+            "users",
+            (1..=3)
+                .map(|i| doc! { "_id": i as i64, "name": format!("User {}", i) })
+                .collect(),
+        );
+        MongoService::with_data_source(collection, None)
+    }
+
+    fn names(items: &[Option<Document>]) -> Vec<Option<&str>> {
+        items
+            .iter()
+            .map(|item| item.as_ref().map(|item| item.get_str("name").unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn test_loader_batches_queued_ids() {
+        let loader: Loader<MongoService, Document> = Loader::new(users());
+        loader.queue(&[ID::I64(3), ID::I64(9)]);
+        let first = loader.load(ID::I64(1)).unwrap();
+        assert_eq!(first.unwrap().get_str("name").unwrap(), "User 1");
+        let items = loader
+            .load_many(vec![ID::I64(3), ID::I64(9), ID::I64(1)])
+            .unwrap();
+        assert_eq!(names(&items), vec![Some("User 3"), None, Some("User 1")]);
+        assert_eq!(loader.batches(), 1);
+
+        assert!(loader.load(ID::I64(2)).unwrap().is_some());
+        assert_eq!(loader.batches(), 2);
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn test_async_loader_coalesces_concurrent_loads() {
+        let service = crate::AsyncMongoService::from(users());
+        let loader: AsyncLoader<MongoService, Document> = AsyncLoader::new(&service);
+        let (a, b, c) = tokio::join!(
+            loader.load(ID::I64(2)),
+            loader.load_many(vec![ID::I64(1), ID::I64(7)]),
+            loader.load(ID::I64(2)),
+        );
+        assert_eq!(a.unwrap().unwrap().get_str("name").unwrap(), "User 2");
+        assert_eq!(names(&b.unwrap()), vec![Some("User 1"), None]);
+        assert!(c.unwrap().is_some());
+        assert_eq!(loader.batches(), 1);
+
+        assert!(loader.load(ID::I64(1)).await.unwrap().is_some());
+        assert_eq!(loader.batches(), 1);
+    }
+
+    #[test]
+    fn test_loader_is_shared_between_threads() {
+        let loader: Loader<MongoService, Document> = Loader::new(users());
+        std::thread::scope(|scope| {
+            let loads: Vec<_> = (1..=3)
+                .map(|i| {
+                    let loader = &loader;
+                    scope.spawn(move || loader.load_many(vec![ID::I64(i), ID::I64(2)]))
+                })
+                .collect();
+            for (i, load) in (1..=3).zip(loads) {
+                let items = load.join().unwrap().unwrap();
+                let name = format!("User {}", i);
+                assert_eq!(names(&items), vec![Some(name.as_str()), Some("User 2")]);
+            }
+        });
+        let batches = loader.batches();
+        assert!((1..=3).contains(&batches));
+        assert!(loader.load(ID::I64(3)).unwrap().is_some());
+        assert_eq!(loader.batches(), batches);
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn test_async_loader_waits_for_lookups_that_come_a_little_later() {
+        let service = crate::AsyncMongoService::from(users());
+        let loader: AsyncLoader<MongoService, Document> =
+            AsyncLoader::new(&service).with_delay(Duration::from_millis(20));
+        let later = async {
+            for _ in 0..3 {
+                tokio::task::yield_now().await;
+            }
+            loader.load(ID::I64(3)).await
+        };
+        let (a, b) = tokio::join!(loader.load(ID::I64(1)), later);
+        assert!(a.unwrap().is_some());
+        assert!(b.unwrap().is_some());
+        assert_eq!(loader.batches(), 1);
+    }
+}
@@ -0,0 +1,548 @@
+use bson::{Bson, Document};
+use std::cmp::Ordering;
+
+use crate::error::ServiceError;
+use crate::mock::bad_value;
+use crate::mock::filter::{as_f64, compare, matches, sort_documents, values_equal};
+use crate::mock::projection::{project, slice};
+use crate::mock::text::{TextQuery, TEXT_SCORE_KEY};
+use crate::mock::update::arithmetic;
+
+/// Runs an aggregation pipeline over `documents`.
+///
+/// Supports `$match`, `$sort`, `$skip`, `$limit`, `$project`, `$addFields`/`$set`, `$unset`,
+/// `$unwind`, `$group`, `$count`, `$replaceRoot` and `$facet`, and `$text` in a `$match` along
+/// with `{ "$meta": "textScore" }`. `$lookup` needs other collections and is rejected.
+pub fn run_pipeline(
+    mut documents: Vec<Document>,
+    pipeline: &[Document],
+) -> Result<Vec<Document>, ServiceError> {
+    for stage in pipeline {
+        let (name, argument) = match stage.iter().next() {
+            Some(entry) if stage.len() == 1 => entry,
+            _ => {
+                return Err(bad_value(
+                    "A pipeline stage specification object must contain exactly one field.",
+                ))
+            }
+        };
+        documents = run_stage(documents, name, argument)?;
+    }
+    for document in documents.iter_mut() {
+        document.remove(TEXT_SCORE_KEY);
+    }
+    Ok(documents)
+}
+
+fn run_stage(
+    documents: Vec<Document>,
+    name: &str,
+    argument: &Bson,
+) -> Result<Vec<Document>, ServiceError> {
+    match (name, argument) {
+        ("$match", Bson::Document(filter)) => {
+            let text = match filter.get("$text") {
+                Some(argument) => Some(TextQuery::parse(argument)?),
+                None => None,
+            };
+            let mut matched = Vec::new();
+            for mut document in documents {
+                if matches(&document, filter)? {
+                    if let Some(score) = text.as_ref().and_then(|text| text.score(&document)) {
+                        document.insert(TEXT_SCORE_KEY, score);
+                    }
+                    matched.push(document);
+                }
+            }
+            Ok(matched)
+        }
+        ("$sort", Bson::Document(sort)) => {
+            let mut documents = documents;
+            sort_documents(&mut documents, sort);
+            Ok(documents)
+        }
+        ("$skip", _) => {
+            let skip = as_f64(argument).ok_or_else(|| bad_value("$skip must be a number"))?;
+            Ok(documents.into_iter().skip(skip.max(0.0) as usize).collect())
+        }
+        ("$limit", _) => {
+            let limit = as_f64(argument).ok_or_else(|| bad_value("$limit must be a number"))?;
+            Ok(documents
+                .into_iter()
+                .take(limit.max(0.0) as usize)
+                .collect())
+        }
+        ("$project", Bson::Document(projection)) => documents
+            .iter()
+            .map(|document| project_stage(document, projection))
+            .collect(),
+        ("$addFields", Bson::Document(fields)) | ("$set", Bson::Document(fields)) => documents
+            .into_iter()
+            .map(|mut document| {
+                for (key, expression) in fields.iter() {
+                    match evaluate(expression, &document)? {
+                        Some(value) => set_path(&mut document, key, value),
+                        None => remove_path(&mut document, key),
+                    }
+                }
+                Ok(document)
+            })
+            .collect(),
+        ("$unset", _) => {
+            let fields: Vec<Bson> = match argument {
+                Bson::Array(fields) => fields.clone(),
+                field => vec![field.clone()],
+            };
+            Ok(documents
+                .into_iter()
+                .map(|mut document| {
+                    for field in fields.iter() {
+                        if let Bson::String(field) = field {
+                            remove_path(&mut document, field);
+                        }
+                    }
+                    document
+                })
+                .collect())
+        }
+        ("$unwind", _) => unwind(documents, argument),
+        ("$group", Bson::Document(group)) => group_stage(&documents, group),
+        ("$count", Bson::String(field)) => {
+            if documents.is_empty() {
+                return Ok(Vec::new());
+            }
+            let mut counted = Document::new();
+            counted.insert(field.clone(), documents.len() as i32);
+            Ok(vec![counted])
+        }
+        ("$replaceRoot", Bson::Document(options)) => documents
+            .iter()
+            .map(|document| {
+                let new_root = options.get("newRoot").unwrap_or(&Bson::Null);
+                match evaluate(new_root, document)? {
+                    Some(Bson::Document(root)) => Ok(root),
+                    _ => Err(bad_value("'newRoot' expression must evaluate to an object")),
+                }
+            })
+            .collect(),
+        ("$facet", Bson::Document(facets)) => {
+            let mut faceted = Document::new();
+            for (key, pipeline) in facets.iter() {
+                let pipeline: Vec<Document> = match pipeline {
+                    Bson::Array(stages) => stages
+                        .iter()
+                        .map(|stage| match stage {
+                            Bson::Document(stage) => Ok(stage.clone()),
+                            _ => Err(bad_value("$facet stages must be objects")),
+                        })
+                        .collect::<Result<_, _>>()?,
+                    _ => return Err(bad_value("$facet pipelines must be arrays")),
+                };
+                let results = run_pipeline(documents.clone(), &pipeline)?;
+                faceted.insert(
+                    key.clone(),
+                    Bson::Array(results.into_iter().map(Bson::Document).collect()),
+                );
+            }
+            Ok(vec![faceted])
+        }
+        ("$lookup", _) => Err(bad_value(
+            "$lookup is not supported by MockCollection, it only knows its own documents",
+        )),
+        _ => Err(bad_value(&format!(
+            "Unrecognized pipeline stage name: '{}'",
+            name
+        ))),
+    }
+}
+
+fn project_stage(document: &Document, projection: &Document) -> Result<Document, ServiceError> {
+    let is_plain = projection
+        .values()
+        .all(|value| matches!(value, Bson::Boolean(_) | Bson::I32(_) | Bson::I64(_)));
+    if is_plain {
+        return project(document, projection);
+    }
+    // computed fields, only inclusions can be mixed in
+    let mut projected = Document::new();
+    if !matches!(
+        projection.get("_id"),
+        Some(Bson::Boolean(false)) | Some(Bson::I32(0)) | Some(Bson::I64(0))
+    ) {
+        if let Some(id) = document.get("_id") {
+            projected.insert("_id", id.clone());
+        }
+    }
+    for (key, expression) in projection.iter() {
+        let value = match expression {
+            Bson::Boolean(true) | Bson::I32(1) | Bson::I64(1) => get_path(document, key),
+            Bson::Boolean(false) | Bson::I32(0) | Bson::I64(0) => continue,
+            expression => evaluate(expression, document)?,
+        };
+        if let Some(value) = value {
+            set_path(&mut projected, key, value);
+        }
+    }
+    Ok(projected)
+}
+
+fn unwind(documents: Vec<Document>, argument: &Bson) -> Result<Vec<Document>, ServiceError> {
+    let (path, preserve) = match argument {
+        Bson::String(path) => (path.clone(), false),
+        Bson::Document(options) => (
+            options.get_str("path").unwrap_or_default().to_owned(),
+            options
+                .get_bool("preserveNullAndEmptyArrays")
+                .unwrap_or(false),
+        ),
+        _ => return Err(bad_value("$unwind requires a path")),
+    };
+    let path = match path.strip_prefix('$') {
+        Some(path) => path.to_owned(),
+        None => return Err(bad_value("$unwind path must be prefixed with '$'")),
+    };
+    let mut unwound = Vec::new();
+    for document in documents {
+        match get_path(&document, &path) {
+            Some(Bson::Array(items)) if !items.is_empty() => {
+                for item in items {
+                    let mut copy = document.clone();
+                    set_path(&mut copy, &path, item);
+                    unwound.push(copy);
+                }
+            }
+            Some(Bson::Array(_)) | Some(Bson::Null) | None => {
+                if preserve {
+                    unwound.push(document);
+                }
+            }
+            Some(_) => unwound.push(document),
+        }
+    }
+    Ok(unwound)
+}
+
+enum Accumulator {
+    Sum(Bson),
+    Avg(f64, usize),
+    Min(Option<Bson>),
+    Max(Option<Bson>),
+    First(Option<Bson>),
+    Last(Option<Bson>),
+    Push(Vec<Bson>),
+    AddToSet(Vec<Bson>),
+}
+
+impl Accumulator {
+    fn new(operator: &str) -> Result<Self, ServiceError> {
+        Ok(match operator {
+            "$sum" => Accumulator::Sum(Bson::I32(0)),
+            "$avg" => Accumulator::Avg(0.0, 0),
+            "$min" => Accumulator::Min(None),
+            "$max" => Accumulator::Max(None),
+            "$first" => Accumulator::First(None),
+            "$last" => Accumulator::Last(None),
+            "$push" => Accumulator::Push(Vec::new()),
+            "$addToSet" => Accumulator::AddToSet(Vec::new()),
+            _ => return Err(bad_value(&format!("unknown group operator '{}'", operator))),
+        })
+    }
+
+    fn add(&mut self, value: Option<Bson>, is_first: bool) -> Result<(), ServiceError> {
+        match self {
+            Accumulator::Sum(total) => {
+                if let Some(value) = value.filter(|v| as_f64(v).is_some()) {
+                    *total = arithmetic(Some(total), &value, false)?;
+                }
+            }
+            Accumulator::Avg(total, count) => {
+                if let Some(value) = value.as_ref().and_then(as_f64) {
+                    *total += value;
+                    *count += 1;
+                }
+            }
+            Accumulator::Min(current) => keep_extreme(current, value, Ordering::Less),
+            Accumulator::Max(current) => keep_extreme(current, value, Ordering::Greater),
+            Accumulator::First(first) => {
+                if is_first {
+                    *first = value;
+                }
+            }
+            Accumulator::Last(last) => *last = value,
+            Accumulator::Push(items) => {
+                if let Some(value) = value {
+                    items.push(value);
+                }
+            }
+            Accumulator::AddToSet(items) => {
+                if let Some(value) = value {
+                    if !items.iter().any(|item| values_equal(item, &value)) {
+                        items.push(value);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn result(self) -> Bson {
+        match self {
+            Accumulator::Sum(total) => total,
+            Accumulator::Avg(_, 0) => Bson::Null,
+            Accumulator::Avg(total, count) => Bson::FloatingPoint(total / count as f64),
+            Accumulator::Min(value)
+            | Accumulator::Max(value)
+            | Accumulator::First(value)
+            | Accumulator::Last(value) => value.unwrap_or(Bson::Null),
+            Accumulator::Push(items) | Accumulator::AddToSet(items) => Bson::Array(items),
+        }
+    }
+}
+
+/// Keeps whichever of the two values sorts towards `wanted`, ignoring nulls.
+fn keep_extreme(current: &mut Option<Bson>, value: Option<Bson>, wanted: Ordering) {
+    if let Some(value) = value.filter(|v| *v != Bson::Null) {
+        let replace = match current {
+            Some(existing) => compare(&value, existing) == wanted,
+            None => true,
+        };
+        if replace {
+            *current = Some(value);
+        }
+    }
+}
+
+fn group_stage(documents: &[Document], group: &Document) -> Result<Vec<Document>, ServiceError> {
+    let key_expression = group
+        .get("_id")
+        .ok_or_else(|| bad_value("a group specification must include an _id"))?;
+    let mut fields: Vec<(&String, &str, &Bson)> = Vec::new();
+    for (field, accumulator) in group.iter().filter(|(field, _)| *field != "_id") {
+        match accumulator {
+            Bson::Document(spec) if spec.len() == 1 => {
+                let (operator, expression) = spec.iter().next().unwrap();
+                fields.push((field, operator, expression));
+            }
+            _ => {
+                return Err(bad_value(&format!(
+                    "The field '{}' must be an accumulator object",
+                    field
+                )))
+            }
+        }
+    }
+
+    // groups keep the order in which their key was first seen
+    let mut groups: Vec<(Bson, Vec<Accumulator>)> = Vec::new();
+    for document in documents {
+        let key = evaluate(key_expression, document)?.unwrap_or(Bson::Null);
+        let position = groups
+            .iter()
+            .position(|(existing, _)| values_equal(existing, &key));
+        let (position, is_first) = match position {
+            Some(position) => (position, false),
+            None => {
+                let accumulators = fields
+                    .iter()
+                    .map(|(_, operator, _)| Accumulator::new(operator))
+                    .collect::<Result<_, _>>()?;
+                groups.push((key, accumulators));
+                (groups.len() - 1, true)
+            }
+        };
+        for ((_, _, expression), accumulator) in fields.iter().zip(groups[position].1.iter_mut()) {
+            accumulator.add(evaluate(expression, document)?, is_first)?;
+        }
+    }
+
+    Ok(groups
+        .into_iter()
+        .map(|(key, accumulators)| {
+            let mut grouped = Document::new();
+            grouped.insert("_id", key);
+            for ((field, _, _), accumulator) in fields.iter().zip(accumulators) {
+                grouped.insert((*field).clone(), accumulator.result());
+            }
+            grouped
+        })
+        .collect())
+}
+
+/// Evaluates an aggregation expression against a document, `None` standing for a missing
+/// value.
+fn evaluate(expression: &Bson, document: &Document) -> Result<Option<Bson>, ServiceError> {
+    match expression {
+        Bson::String(path) if path == "$$ROOT" => Ok(Some(Bson::Document(document.clone()))),
+        Bson::String(path) if path.starts_with('$') => Ok(get_path(document, &path[1..])),
+        Bson::Document(operator)
+            if operator.len() == 1 && operator.keys().all(|key| key.starts_with('$')) =>
+        {
+            let (name, argument) = operator.iter().next().unwrap();
+            evaluate_operator(name, argument, document)
+        }
+        Bson::Document(fields) => {
+            let mut evaluated = Document::new();
+            for (key, value) in fields.iter() {
+                if let Some(value) = evaluate(value, document)? {
+                    evaluated.insert(key.clone(), value);
+                }
+            }
+            Ok(Some(Bson::Document(evaluated)))
+        }
+        Bson::Array(items) => {
+            let mut evaluated = Vec::new();
+            for item in items {
+                evaluated.push(evaluate(item, document)?.unwrap_or(Bson::Null));
+            }
+            Ok(Some(Bson::Array(evaluated)))
+        }
+        literal => Ok(Some(literal.clone())),
+    }
+}
+
+fn evaluate_arguments(argument: &Bson, document: &Document) -> Result<Vec<Bson>, ServiceError> {
+    let arguments = match argument {
+        Bson::Array(items) => items.clone(),
+        item => vec![item.clone()],
+    };
+    arguments
+        .iter()
+        .map(|argument| Ok(evaluate(argument, document)?.unwrap_or(Bson::Null)))
+        .collect()
+}
+
+fn evaluate_operator(
+    name: &str,
+    argument: &Bson,
+    document: &Document,
+) -> Result<Option<Bson>, ServiceError> {
+    if name == "$literal" {
+        return Ok(Some(argument.clone()));
+    }
+    if name == "$meta" {
+        return match (argument, document.get(TEXT_SCORE_KEY)) {
+            (Bson::String(meta), Some(score)) if meta == "textScore" => Ok(Some(score.clone())),
+            (Bson::String(meta), None) if meta == "textScore" => Err(bad_value(
+                "query requires text score metadata, but it is not available",
+            )),
+            _ => Err(bad_value(&format!(
+                "Unsupported argument to $meta: {}",
+                argument
+            ))),
+        };
+    }
+    let arguments = evaluate_arguments(argument, document)?;
+    if arguments.contains(&Bson::Null) && name != "$ifNull" {
+        return Ok(Some(Bson::Null));
+    }
+    let value = match name {
+        "$add" | "$multiply" => {
+            let mut total = if name == "$add" {
+                Bson::I32(0)
+            } else {
+                Bson::I32(1)
+            };
+            for argument in arguments.iter() {
+                total = arithmetic(Some(&total), argument, name == "$multiply")?;
+            }
+            total
+        }
+        "$subtract" | "$divide" => match (arguments.first(), arguments.get(1)) {
+            (Some(a), Some(b)) if name == "$subtract" => {
+                let negated = arithmetic(Some(b), &Bson::I32(-1), true)?;
+                arithmetic(Some(a), &negated, false)?
+            }
+            (Some(a), Some(b)) => match (as_f64(a), as_f64(b)) {
+                (Some(_), Some(d)) if d.abs() < f64::EPSILON => {
+                    return Err(bad_value("can't $divide by zero"))
+                }
+                (Some(n), Some(d)) => Bson::FloatingPoint(n / d),
+                _ => return Err(bad_value("$divide only supports numeric types")),
+            },
+            _ => return Err(bad_value(&format!("{} takes exactly 2 arguments", name))),
+        },
+        "$concat" => {
+            let mut concatenated = String::new();
+            for argument in arguments.iter() {
+                match argument {
+                    Bson::String(s) => concatenated.push_str(s),
+                    _ => return Err(bad_value("$concat only supports strings")),
+                }
+            }
+            Bson::String(concatenated)
+        }
+        "$toUpper" | "$toLower" => match arguments.first() {
+            Some(Bson::String(s)) if name == "$toUpper" => Bson::String(s.to_uppercase()),
+            Some(Bson::String(s)) => Bson::String(s.to_lowercase()),
+            _ => Bson::String(String::new()),
+        },
+        "$slice" => match arguments.as_slice() {
+            [items, count] => slice(items, count),
+            [items, skip, limit] => slice(items, &Bson::Array(vec![skip.clone(), limit.clone()])),
+            _ => return Err(bad_value("$slice takes 2 or 3 arguments")),
+        },
+        "$size" => match arguments.first() {
+            Some(Bson::Array(items)) => Bson::I32(items.len() as i32),
+            _ => return Err(bad_value("The argument to $size must be an array")),
+        },
+        "$ifNull" => arguments
+            .iter()
+            .find(|a| **a != Bson::Null)
+            .cloned()
+            .unwrap_or(Bson::Null),
+        _ => return Err(bad_value(&format!("Unrecognized expression '{}'", name))),
+    };
+    Ok(Some(value))
+}
+
+fn get_path(document: &Document, path: &str) -> Option<Bson> {
+    let mut parts = path.splitn(2, '.');
+    let value = document.get(parts.next()?)?;
+    match (value, parts.next()) {
+        (value, None) => Some(value.clone()),
+        (Bson::Document(d), Some(rest)) => get_path(d, rest),
+        (Bson::Array(items), Some(rest)) => Some(Bson::Array(
+            items
+                .iter()
+                .filter_map(|item| match item {
+                    Bson::Document(d) => get_path(d, rest),
+                    _ => None,
+                })
+                .collect(),
+        )),
+        _ => None,
+    }
+}
+
+fn set_path(document: &mut Document, path: &str, value: Bson) {
+    match path.split_once('.') {
+        // existing fields keep their position, as in MongoDB
+        None => match document.get_mut(path) {
+            Some(existing) => *existing = value,
+            None => {
+                document.insert(path, value);
+            }
+        },
+        Some((head, rest)) => {
+            if !matches!(document.get(head), Some(Bson::Document(_))) {
+                document.insert(head, Document::new());
+            }
+            if let Ok(child) = document.get_document_mut(head) {
+                set_path(child, rest, value);
+            }
+        }
+    }
+}
+
+fn remove_path(document: &mut Document, path: &str) {
+    match path.split_once('.') {
+        None => {
+            document.remove(path);
+        }
+        Some((head, rest)) => {
+            if let Ok(child) = document.get_document_mut(head) {
+                remove_path(child, rest);
+            }
+        }
+    }
+}
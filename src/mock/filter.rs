@@ -0,0 +1,421 @@
+use bson::{Bson, Document};
+use regex::Regex;
+use std::cmp::Ordering;
+
+use crate::error::ServiceError;
+use crate::mock::bad_value;
+use crate::mock::text::TextQuery;
+
+/// Collects every value reachable through a dotted `path`, descending into arrays of
+/// sub-documents the same way a MongoDB query does.
+pub fn values_at<'a>(document: &'a Document, path: &str) -> Vec<&'a Bson> {
+    let parts: Vec<&str> = path.split('.').collect();
+    let mut values = Vec::new();
+    if let Some(value) = document.get(parts[0]) {
+        collect_values(value, &parts[1..], &mut values);
+    }
+    values
+}
+
+fn collect_values<'a>(value: &'a Bson, path: &[&str], values: &mut Vec<&'a Bson>) {
+    if path.is_empty() {
+        values.push(value);
+        return;
+    }
+    match value {
+        Bson::Document(d) => {
+            if let Some(child) = d.get(path[0]) {
+                collect_values(child, &path[1..], values);
+            }
+        }
+        Bson::Array(items) => {
+            if let Ok(index) = path[0].parse::<usize>() {
+                if let Some(child) = items.get(index) {
+                    collect_values(child, &path[1..], values);
+                }
+            }
+            for item in items {
+                if let Bson::Document(_) = item {
+                    collect_values(item, path, values);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// The canonical ordering of bson types used when comparing values of different types.
+fn type_order(value: &Bson) -> u8 {
+    match value {
+        Bson::Null => 1,
+        Bson::FloatingPoint(_) | Bson::I32(_) | Bson::I64(_) => 2,
+        Bson::Symbol(_) | Bson::String(_) => 3,
+        Bson::Document(_) => 4,
+        Bson::Array(_) => 5,
+        Bson::Binary(_, _) => 6,
+        Bson::ObjectId(_) => 7,
+        Bson::Boolean(_) => 8,
+        Bson::UtcDatetime(_) => 9,
+        Bson::TimeStamp(_) => 10,
+        Bson::RegExp(_, _) => 11,
+        _ => 12,
+    }
+}
+
+pub fn as_f64(value: &Bson) -> Option<f64> {
+    match value {
+        Bson::FloatingPoint(f) => Some(*f),
+        Bson::I32(i) => Some(f64::from(*i)),
+        Bson::I64(i) => Some(*i as f64),
+        _ => None,
+    }
+}
+
+/// Compares two values using MongoDB's comparison order.
+pub fn compare(a: &Bson, b: &Bson) -> Ordering {
+    let type_ordering = type_order(a).cmp(&type_order(b));
+    if type_ordering != Ordering::Equal {
+        return type_ordering;
+    }
+    match (a, b) {
+        (Bson::I64(x), Bson::I64(y)) => x.cmp(y),
+        (Bson::I32(x), Bson::I32(y)) => x.cmp(y),
+        (Bson::String(x), Bson::String(y))
+        | (Bson::Symbol(x), Bson::Symbol(y))
+        | (Bson::String(x), Bson::Symbol(y))
+        | (Bson::Symbol(x), Bson::String(y)) => x.cmp(y),
+        (Bson::Document(x), Bson::Document(y)) => {
+            for ((x_key, x_value), (y_key, y_value)) in x.iter().zip(y.iter()) {
+                let ordering = x_key.cmp(y_key).then_with(|| compare(x_value, y_value));
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            }
+            x.len().cmp(&y.len())
+        }
+        (Bson::Array(x), Bson::Array(y)) => {
+            for (x_value, y_value) in x.iter().zip(y.iter()) {
+                let ordering = compare(x_value, y_value);
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            }
+            x.len().cmp(&y.len())
+        }
+        (Bson::Binary(_, x), Bson::Binary(_, y)) => x.cmp(y),
+        (Bson::ObjectId(x), Bson::ObjectId(y)) => x.bytes().cmp(&y.bytes()),
+        (Bson::Boolean(x), Bson::Boolean(y)) => x.cmp(y),
+        (Bson::UtcDatetime(x), Bson::UtcDatetime(y)) => x.cmp(y),
+        (Bson::TimeStamp(x), Bson::TimeStamp(y)) => x.cmp(y),
+        (Bson::RegExp(x, x_options), Bson::RegExp(y, y_options)) => {
+            x.cmp(y).then_with(|| x_options.cmp(y_options))
+        }
+        _ => match (as_f64(a), as_f64(b)) {
+            (Some(x), Some(y)) => x.partial_cmp(&y).unwrap_or(Ordering::Equal),
+            _ => Ordering::Equal,
+        },
+    }
+}
+
+pub fn values_equal(a: &Bson, b: &Bson) -> bool {
+    compare(a, b) == Ordering::Equal
+}
+
+/// A document is treated as an operator expression when its first key starts with `$`.
+pub fn is_operator_document(document: &Document) -> bool {
+    document
+        .keys()
+        .next()
+        .map(|key| key.starts_with('$'))
+        .unwrap_or(false)
+}
+
+pub fn build_regex(pattern: &str, options: &str) -> Result<Regex, ServiceError> {
+    let flags: String = options
+        .chars()
+        .filter(|c| ['i', 'm', 's', 'x'].contains(c))
+        .collect();
+    let expression = if flags.is_empty() {
+        pattern.to_string()
+    } else {
+        format!("(?{}){}", flags, pattern)
+    };
+    Regex::new(&expression).map_err(|e| bad_value(&format!("Invalid regular expression: {}", e)))
+}
+
+fn regex_matches(values: &[&Bson], regex: &Regex) -> bool {
+    values.iter().any(|value| match value {
+        Bson::String(s) | Bson::Symbol(s) => regex.is_match(s),
+        Bson::Array(items) => items.iter().any(|item| match item {
+            Bson::String(s) | Bson::Symbol(s) => regex.is_match(s),
+            _ => false,
+        }),
+        _ => false,
+    })
+}
+
+/// Returns true when `document` satisfies the MongoDB query `filter`.
+pub fn matches(document: &Document, filter: &Document) -> Result<bool, ServiceError> {
+    for (key, condition) in filter.iter() {
+        let is_match = match key.as_str() {
+            "$and" | "$or" | "$nor" => {
+                let clauses = match condition {
+                    Bson::Array(clauses) => clauses,
+                    _ => return Err(bad_value(&format!("{} must be an array", key))),
+                };
+                let mut results = Vec::with_capacity(clauses.len());
+                for clause in clauses {
+                    match clause {
+                        Bson::Document(clause) => results.push(matches(document, clause)?),
+                        _ => return Err(bad_value(&format!("{} entries must be objects", key))),
+                    }
+                }
+                match key.as_str() {
+                    "$and" => results.iter().all(|r| *r),
+                    "$or" => results.iter().any(|r| *r),
+                    _ => !results.iter().any(|r| *r),
+                }
+            }
+            "$text" => TextQuery::parse(condition)?.score(document).is_some(),
+            operator if operator.starts_with('$') => {
+                return Err(bad_value(&format!(
+                    "unknown top level operator: {}",
+                    operator
+                )))
+            }
+            path => matches_condition(&values_at(document, path), condition)?,
+        };
+        if !is_match {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Evaluates the condition for a single field against the values found at its path.
+pub fn matches_condition(values: &[&Bson], condition: &Bson) -> Result<bool, ServiceError> {
+    match condition {
+        Bson::Document(operators) if is_operator_document(operators) => {
+            for (operator, argument) in operators.iter() {
+                if !matches_operator(values, operator, argument, operators)? {
+                    return Ok(false);
+                }
+            }
+            Ok(true)
+        }
+        Bson::RegExp(pattern, options) => {
+            Ok(regex_matches(values, &build_regex(pattern, options)?))
+        }
+        _ => Ok(matches_equality(values, condition)),
+    }
+}
+
+fn matches_equality(values: &[&Bson], target: &Bson) -> bool {
+    if values.is_empty() {
+        return target == &Bson::Null;
+    }
+    values.iter().any(|value| {
+        values_equal(value, target)
+            || match value {
+                Bson::Array(items) => items.iter().any(|item| values_equal(item, target)),
+                _ => false,
+            }
+    })
+}
+
+fn matches_comparison(values: &[&Bson], target: &Bson, accept: fn(Ordering) -> bool) -> bool {
+    let compare_one =
+        |value: &Bson| type_order(value) == type_order(target) && accept(compare(value, target));
+    values.iter().any(|value| {
+        compare_one(value)
+            || match value {
+                Bson::Array(items) => items.iter().any(compare_one),
+                _ => false,
+            }
+    })
+}
+
+fn matches_in(values: &[&Bson], targets: &Bson) -> Result<bool, ServiceError> {
+    let targets = match targets {
+        Bson::Array(targets) => targets,
+        _ => return Err(bad_value("$in needs an array")),
+    };
+    for target in targets {
+        let is_match = match target {
+            Bson::RegExp(pattern, options) => {
+                regex_matches(values, &build_regex(pattern, options)?)
+            }
+            _ => matches_equality(values, target),
+        };
+        if is_match {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn is_truthy(value: &Bson) -> bool {
+    match value {
+        Bson::Boolean(b) => *b,
+        Bson::Null => false,
+        _ => as_f64(value).map(|n| n != 0.0).unwrap_or(true),
+    }
+}
+
+fn matches_type(value: &Bson, type_name: &Bson) -> bool {
+    let name = match type_name {
+        Bson::String(name) => name.as_str(),
+        _ => match as_f64(type_name).map(|n| n as i64) {
+            Some(1) => "double",
+            Some(2) => "string",
+            Some(3) => "object",
+            Some(4) => "array",
+            Some(7) => "objectId",
+            Some(8) => "bool",
+            Some(9) => "date",
+            Some(10) => "null",
+            Some(16) => "int",
+            Some(18) => "long",
+            _ => "",
+        },
+    };
+    match (name, value) {
+        ("double", Bson::FloatingPoint(_))
+        | ("string", Bson::String(_))
+        | ("object", Bson::Document(_))
+        | ("array", Bson::Array(_))
+        | ("objectId", Bson::ObjectId(_))
+        | ("bool", Bson::Boolean(_))
+        | ("date", Bson::UtcDatetime(_))
+        | ("null", Bson::Null)
+        | ("int", Bson::I32(_))
+        | ("long", Bson::I64(_)) => true,
+        ("number", v) => as_f64(v).is_some(),
+        _ => false,
+    }
+}
+
+/// Returns true when `element` satisfies an `$elemMatch` (or `$pull`) condition.
+pub fn element_matches(element: &Bson, condition: &Bson) -> Result<bool, ServiceError> {
+    match condition {
+        Bson::Document(query) if is_operator_document(query) => {
+            matches_condition(&[element], condition)
+        }
+        Bson::Document(query) => match element {
+            Bson::Document(element_doc) => matches(element_doc, query),
+            _ => Ok(false),
+        },
+        _ => matches_condition(&[element], condition),
+    }
+}
+
+fn matches_operator(
+    values: &[&Bson],
+    operator: &str,
+    argument: &Bson,
+    operators: &Document,
+) -> Result<bool, ServiceError> {
+    match operator {
+        "$eq" => Ok(matches_equality(values, argument)),
+        "$ne" => Ok(!matches_equality(values, argument)),
+        "$gt" => Ok(matches_comparison(values, argument, |o| {
+            o == Ordering::Greater
+        })),
+        "$gte" => Ok(matches_comparison(values, argument, |o| {
+            o != Ordering::Less
+        })),
+        "$lt" => Ok(matches_comparison(values, argument, |o| {
+            o == Ordering::Less
+        })),
+        "$lte" => Ok(matches_comparison(values, argument, |o| {
+            o != Ordering::Greater
+        })),
+        "$in" => matches_in(values, argument),
+        "$nin" => Ok(!matches_in(values, argument)?),
+        "$exists" => Ok(values.is_empty() != is_truthy(argument)),
+        "$type" => Ok(values.iter().any(|value| matches_type(value, argument))),
+        "$regex" => {
+            let regex = match argument {
+                Bson::String(pattern) => {
+                    let options = operators.get_str("$options").unwrap_or("");
+                    build_regex(pattern, options)?
+                }
+                Bson::RegExp(pattern, options) => build_regex(pattern, options)?,
+                _ => return Err(bad_value("$regex has to be a string")),
+            };
+            Ok(regex_matches(values, &regex))
+        }
+        "$options" => Ok(true),
+        "$not" => match argument {
+            Bson::Document(_) | Bson::RegExp(_, _) => Ok(!matches_condition(values, argument)?),
+            _ => Err(bad_value("$not needs a regex or a document")),
+        },
+        "$size" => {
+            let size = as_f64(argument).ok_or_else(|| bad_value("$size needs a number"))?;
+            Ok(values.iter().any(|value| match value {
+                Bson::Array(items) => items.len() as f64 == size,
+                _ => false,
+            }))
+        }
+        "$all" => match argument {
+            Bson::Array(targets) => Ok(!targets.is_empty()
+                && targets
+                    .iter()
+                    .all(|target| matches_equality(values, target))),
+            _ => Err(bad_value("$all needs an array")),
+        },
+        "$elemMatch" => {
+            for value in values {
+                if let Bson::Array(items) = value {
+                    for item in items {
+                        if element_matches(item, argument)? {
+                            return Ok(true);
+                        }
+                    }
+                }
+            }
+            Ok(false)
+        }
+        _ => Err(bad_value(&format!("unknown operator: {}", operator))),
+    }
+}
+
+fn sort_value(document: &Document, path: &str, ascending: bool) -> Bson {
+    let mut candidates: Vec<&Bson> = Vec::new();
+    for value in values_at(document, path) {
+        match value {
+            Bson::Array(items) if !items.is_empty() => candidates.extend(items.iter()),
+            _ => candidates.push(value),
+        }
+    }
+    let best = if ascending {
+        candidates.into_iter().min_by(|a, b| compare(a, b))
+    } else {
+        candidates.into_iter().max_by(|a, b| compare(a, b))
+    };
+    best.cloned().unwrap_or(Bson::Null)
+}
+
+/// Compares two documents according to a MongoDB sort specification.
+pub fn compare_documents(a: &Document, b: &Document, sort: &Document) -> Ordering {
+    for (path, direction) in sort.iter() {
+        let ascending = as_f64(direction).map(|d| d >= 0.0).unwrap_or(true);
+        let ordering = compare(
+            &sort_value(a, path, ascending),
+            &sort_value(b, path, ascending),
+        );
+        let ordering = if ascending {
+            ordering
+        } else {
+            ordering.reverse()
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    Ordering::Equal
+}
+
+pub fn sort_documents(documents: &mut [Document], sort: &Document) {
+    documents.sort_by(|a, b| compare_documents(a, b, sort));
+}
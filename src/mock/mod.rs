@@ -0,0 +1,966 @@
+mod aggregate;
+mod filter;
+mod pagination;
+mod projection;
+mod text;
+mod update;
+
+use bson::{doc, oid::ObjectId, Bson, Document};
+use mongodb::error::{
+    BulkWriteError, BulkWriteFailure, CommandError, ErrorKind, WriteError, WriteFailure,
+};
+use mongodb::options::{
+    AggregateOptions, CountOptions, DeleteOptions, FindOneAndUpdateOptions, FindOneOptions,
+    FindOptions, InsertManyOptions, InsertOneOptions, ReturnDocument, UpdateOptions,
+};
+use mongodb::results::{DeleteResult, InsertManyResult, InsertOneResult, UpdateResult};
+use mongodb_cursor_pagination::{CursorDirections, FindResult};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::change_stream::ChangeEvents;
+use crate::data_source::DataSource;
+use crate::error::ServiceError;
+
+use self::aggregate::run_pipeline;
+use self::filter::{matches, sort_documents};
+use self::projection::project;
+use self::update::{apply_update, upsert_seed, UpdateContext};
+
+/// An in-memory stand-in for a `mongodb::Collection`.
+///
+/// Filter, sort, projection and update documents are evaluated locally, so a service backed by
+/// a `MockCollection` behaves like one backed by MongoDB without needing a running mongod.
+/// Clones share the same documents, which makes it easy to seed or inspect a collection that
+/// has been handed to a service.
+#[derive(Clone, Debug)]
+pub struct MockCollection {
+    name: String,
+    documents: Arc<RwLock<Vec<Document>>>,
+    events: Arc<RwLock<Vec<Document>>>,
+}
+
+fn bad_value(message: &str) -> ServiceError {
+    ServiceError::MongoError(
+        ErrorKind::CommandError(CommandError {
+            code: 2,
+            code_name: "BadValue".to_owned(),
+            message: message.to_owned(),
+            labels: vec![],
+        })
+        .into(),
+    )
+}
+
+fn duplicate_key_message(id: &Bson) -> String {
+    format!("E11000 duplicate key error dup key: {{ _id: {} }}", id)
+}
+
+fn duplicate_key(id: &Bson) -> ServiceError {
+    ServiceError::MongoError(
+        ErrorKind::WriteError(WriteFailure::WriteError(WriteError {
+            code: 11000,
+            code_name: Some("DuplicateKey".to_owned()),
+            message: duplicate_key_message(id),
+        }))
+        .into(),
+    )
+}
+
+impl MockCollection {
+    pub fn new(name: &str) -> Self {
+        MockCollection {
+            name: name.to_owned(),
+            documents: Arc::new(RwLock::new(Vec::new())),
+            events: Arc::new(RwLock::new(Vec::new())),
+        }
+    }
+
+    /// Creates a collection seeded with `documents`, generating an `_id` where one is missing.
+    pub fn with_documents(name: &str, documents: Vec<Document>) -> Self {
+        let collection = MockCollection::new(name);
+        {
+            let mut stored = collection.write();
+            for document in documents {
+                stored.push(with_object_id(document));
+            }
+        }
+        collection
+    }
+
+    /// A snapshot of every stored document in insertion order.
+    pub fn documents(&self) -> Vec<Document> {
+        self.read().clone()
+    }
+
+    pub fn clear(&self) {
+        self.write().clear();
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, Vec<Document>> {
+        self.documents
+            .read()
+            .expect("Mock collection lock poisoned")
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, Vec<Document>> {
+        self.documents
+            .write()
+            .expect("Mock collection lock poisoned")
+    }
+
+    /// Appends a change event for `watch`, numbering the resume tokens.
+    fn record(&self, operation: &str, document: &Document) {
+        let mut events = self.events.write().expect("Mock collection lock poisoned");
+        let mut event = doc! {
+            "_id": { "_data": (events.len() + 1).to_string() },
+            "operationType": operation,
+            "ns": { "db": "mock", "coll": self.name.clone() },
+            "documentKey": { "_id": document.get("_id").cloned().unwrap_or(Bson::Null) },
+        };
+        if operation != "delete" {
+            event.insert("fullDocument", document.clone());
+        }
+        events.push(event);
+    }
+
+    fn count(&self, filter: Option<&Document>) -> Result<i64, ServiceError> {
+        let mut count = 0;
+        for document in self.read().iter() {
+            if filter.map_or(Ok(true), |f| matches(document, f))? {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    fn select(
+        &self,
+        filter: Option<&Document>,
+        sort: Option<&Document>,
+        skip: i64,
+        limit: i64,
+        projection: Option<&Document>,
+    ) -> Result<Vec<Document>, ServiceError> {
+        let mut selected = Vec::new();
+        for document in self.read().iter() {
+            if filter.map_or(Ok(true), |f| matches(document, f))? {
+                selected.push(document.clone());
+            }
+        }
+        if let Some(sort) = sort {
+            sort_documents(&mut selected, sort);
+        }
+        let selected = selected.into_iter().skip(skip.max(0) as usize);
+        let selected: Vec<Document> = if limit != 0 {
+            selected.take(limit.unsigned_abs() as usize).collect()
+        } else {
+            selected.collect()
+        };
+        match projection {
+            Some(projection) => selected.iter().map(|d| project(d, projection)).collect(),
+            None => Ok(selected),
+        }
+    }
+
+    fn position(documents: &[Document], filter: &Document) -> Result<Option<usize>, ServiceError> {
+        for (i, document) in documents.iter().enumerate() {
+            if matches(document, filter)? {
+                return Ok(Some(i));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The position of the first document matching `filter` in the order of `sort`.
+    fn sorted_position(
+        documents: &[Document],
+        filter: &Document,
+        sort: &Document,
+    ) -> Result<Option<usize>, ServiceError> {
+        let mut matching = Vec::new();
+        for document in documents.iter() {
+            if matches(document, filter)? {
+                matching.push(document.clone());
+            }
+        }
+        sort_documents(&mut matching, sort);
+        match matching.first().and_then(|first| first.get("_id")) {
+            Some(id) => MockCollection::position(documents, &doc! { "_id": id.clone() }),
+            None => Ok(None),
+        }
+    }
+
+    /// Inserts the document an upsert that matched nothing creates, returning its `_id`.
+    fn upsert(
+        &self,
+        stored: &mut Vec<Document>,
+        query: &Document,
+        update: &Document,
+        array_filters: &[Document],
+    ) -> Result<Bson, ServiceError> {
+        let mut inserted = upsert_seed(query)?;
+        let context = UpdateContext {
+            filter: query,
+            array_filters,
+            is_insert: true,
+        };
+        apply_update(&mut inserted, update, &context)?;
+        let upserted_id = insert_into(stored, inserted)?;
+        self.record("insert", stored.last().unwrap());
+        Ok(upserted_id)
+    }
+}
+
+fn with_object_id(document: Document) -> Document {
+    if document.contains_key("_id") {
+        return document;
+    }
+    // mongod puts a generated _id first
+    let mut with_id = Document::new();
+    with_id.insert(
+        "_id",
+        ObjectId::new().expect("Unable to generate an ObjectId"),
+    );
+    for (key, value) in document {
+        with_id.insert(key, value);
+    }
+    with_id
+}
+
+fn insert_into(stored: &mut Vec<Document>, document: Document) -> Result<Bson, ServiceError> {
+    let document = with_object_id(document);
+    let id = document.get("_id").cloned().unwrap_or(Bson::Null);
+    if stored
+        .iter()
+        .any(|existing| existing.get("_id") == Some(&id))
+    {
+        return Err(duplicate_key(&id));
+    }
+    stored.push(document);
+    Ok(id)
+}
+
+/// Applies an update to a stored document, returning whether it changed.
+fn update_at(
+    stored: &mut Document,
+    query: &Document,
+    update: &Document,
+    array_filters: &[Document],
+) -> Result<bool, ServiceError> {
+    let mut updated = stored.clone();
+    let context = UpdateContext {
+        filter: query,
+        array_filters,
+        is_insert: false,
+    };
+    apply_update(&mut updated, update, &context)?;
+    if updated.get("_id") != stored.get("_id") {
+        return Err(bad_value(
+            "Performing an update on the path '_id' would modify the immutable field '_id'",
+        ));
+    }
+    let modified = updated != *stored;
+    *stored = updated;
+    Ok(modified)
+}
+
+/// The change events of a `MockCollection` after a position, running out instead of waiting
+/// whenever it has caught up.
+struct MockChangeEvents {
+    events: Arc<RwLock<Vec<Document>>>,
+    position: usize,
+    pipeline: Vec<Document>,
+}
+
+impl Iterator for MockChangeEvents {
+    type Item = Result<Document, ServiceError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let event = {
+                let events = self.events.read().expect("Mock collection lock poisoned");
+                events.get(self.position)?.clone()
+            };
+            self.position += 1;
+            match run_pipeline(vec![event], &self.pipeline) {
+                Ok(mut passed) if !passed.is_empty() => return Some(Ok(passed.remove(0))),
+                Ok(_) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+impl DataSource for MockCollection {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn find(
+        &self,
+        filter: Option<Document>,
+        options: Option<FindOptions>,
+    ) -> Result<Vec<Document>, ServiceError> {
+        let options = options.unwrap_or_default();
+        self.select(
+            filter.as_ref(),
+            options.sort.as_ref(),
+            options.skip.unwrap_or(0),
+            options.limit.unwrap_or(0),
+            options.projection.as_ref(),
+        )
+    }
+
+    fn find_one(
+        &self,
+        filter: Option<Document>,
+        options: Option<FindOneOptions>,
+    ) -> Result<Option<Document>, ServiceError> {
+        let options = options.unwrap_or_default();
+        let found = self.select(
+            filter.as_ref(),
+            options.sort.as_ref(),
+            options.skip.unwrap_or(0),
+            1,
+            options.projection.as_ref(),
+        )?;
+        Ok(found.into_iter().next())
+    }
+
+    fn find_page(
+        &self,
+        filter: Option<Document>,
+        options: FindOptions,
+        cursor: Option<String>,
+        direction: CursorDirections,
+    ) -> Result<FindResult<Document>, ServiceError> {
+        pagination::find_page(self, filter, options, cursor, direction)
+    }
+
+    fn count_documents(
+        &self,
+        filter: Option<Document>,
+        options: Option<CountOptions>,
+    ) -> Result<i64, ServiceError> {
+        let options = options.unwrap_or_default();
+        let count = self.count(filter.as_ref())? - options.skip.unwrap_or(0);
+        let count = count.max(0);
+        Ok(match options.limit {
+            Some(limit) if limit > 0 => count.min(limit),
+            _ => count,
+        })
+    }
+
+    fn estimated_document_count(&self) -> Result<i64, ServiceError> {
+        self.count(None)
+    }
+
+    fn aggregate(
+        &self,
+        pipeline: Vec<Document>,
+        _options: Option<AggregateOptions>,
+    ) -> Result<Vec<Document>, ServiceError> {
+        run_pipeline(self.documents(), &pipeline)
+    }
+
+    fn watch(
+        &self,
+        pipeline: Vec<Document>,
+        resume_after: Option<Document>,
+    ) -> Result<ChangeEvents, ServiceError> {
+        let position = match resume_after {
+            Some(token) => token
+                .get_str("_data")
+                .ok()
+                .and_then(|data| data.parse().ok())
+                .ok_or_else(|| bad_value("Invalid resume token"))?,
+            None => self
+                .events
+                .read()
+                .expect("Mock collection lock poisoned")
+                .len(),
+        };
+        Ok(Box::new(MockChangeEvents {
+            events: self.events.clone(),
+            position,
+            pipeline,
+        }))
+    }
+
+    fn insert_one(
+        &self,
+        document: Document,
+        _options: Option<InsertOneOptions>,
+    ) -> Result<InsertOneResult, ServiceError> {
+        let mut stored = self.write();
+        let inserted_id = insert_into(&mut stored, document)?;
+        self.record("insert", stored.last().unwrap());
+        Ok(InsertOneResult { inserted_id })
+    }
+
+    fn insert_many(
+        &self,
+        documents: Vec<Document>,
+        options: Option<InsertManyOptions>,
+    ) -> Result<InsertManyResult, ServiceError> {
+        let ordered = options.and_then(|o| o.ordered).unwrap_or(true);
+        let mut stored = self.write();
+        let mut inserted_ids = HashMap::new();
+        let mut write_errors = Vec::new();
+        for (index, document) in documents.into_iter().enumerate() {
+            let id = with_object_id(document.clone())
+                .get("_id")
+                .cloned()
+                .unwrap_or(Bson::Null);
+            match insert_into(&mut stored, document) {
+                Ok(id) => {
+                    self.record("insert", stored.last().unwrap());
+                    inserted_ids.insert(index, id);
+                }
+                Err(_) => {
+                    write_errors.push(BulkWriteError {
+                        index,
+                        code: 11000,
+                        code_name: Some("DuplicateKey".to_owned()),
+                        message: duplicate_key_message(&id),
+                    });
+                    if ordered {
+                        break;
+                    }
+                }
+            }
+        }
+        if write_errors.is_empty() {
+            Ok(InsertManyResult { inserted_ids })
+        } else {
+            Err(ServiceError::MongoError(
+                ErrorKind::BulkWriteError(BulkWriteFailure {
+                    write_errors: Some(write_errors),
+                    write_concern_error: None,
+                })
+                .into(),
+            ))
+        }
+    }
+
+    fn update_one(
+        &self,
+        query: Document,
+        update: Document,
+        options: Option<UpdateOptions>,
+    ) -> Result<UpdateResult, ServiceError> {
+        let options = options.unwrap_or_default();
+        let array_filters = options.array_filters.unwrap_or_default();
+        let mut stored = self.write();
+        match MockCollection::position(&stored, &query)? {
+            Some(index) => {
+                let modified = update_at(&mut stored[index], &query, &update, &array_filters)?;
+                if modified {
+                    self.record("update", &stored[index]);
+                }
+                Ok(UpdateResult {
+                    matched_count: 1,
+                    modified_count: if modified { 1 } else { 0 },
+                    upserted_id: None,
+                })
+            }
+            None if options.upsert == Some(true) => {
+                let upserted_id = self.upsert(&mut stored, &query, &update, &array_filters)?;
+                Ok(UpdateResult {
+                    matched_count: 0,
+                    modified_count: 0,
+                    upserted_id: Some(upserted_id),
+                })
+            }
+            None => Ok(UpdateResult {
+                matched_count: 0,
+                modified_count: 0,
+                upserted_id: None,
+            }),
+        }
+    }
+
+    fn update_many(
+        &self,
+        query: Document,
+        update: Document,
+        options: Option<UpdateOptions>,
+    ) -> Result<UpdateResult, ServiceError> {
+        let options = options.unwrap_or_default();
+        let array_filters = options.array_filters.unwrap_or_default();
+        let mut stored = self.write();
+        let mut matched_count = 0;
+        let mut modified_count = 0;
+        // validate every update before writing any of them back
+        let mut updated = stored.clone();
+        let mut modified = Vec::new();
+        for (index, document) in updated.iter_mut().enumerate() {
+            if matches(document, &query)? {
+                matched_count += 1;
+                if update_at(document, &query, &update, &array_filters)? {
+                    modified_count += 1;
+                    modified.push(index);
+                }
+            }
+        }
+        if matched_count == 0 && options.upsert == Some(true) {
+            drop(stored);
+            return self.update_one(
+                query,
+                update,
+                Some(UpdateOptions {
+                    array_filters: Some(array_filters),
+                    upsert: Some(true),
+                    ..Default::default()
+                }),
+            );
+        }
+        *stored = updated;
+        for index in modified {
+            self.record("update", &stored[index]);
+        }
+        Ok(UpdateResult {
+            matched_count,
+            modified_count,
+            upserted_id: None,
+        })
+    }
+
+    fn find_one_and_update(
+        &self,
+        query: Document,
+        update: Document,
+        options: Option<FindOneAndUpdateOptions>,
+    ) -> Result<Option<Document>, ServiceError> {
+        let options = options.unwrap_or_default();
+        let array_filters = options.array_filters.unwrap_or_default();
+        let return_after = matches!(options.return_document, Some(ReturnDocument::After));
+        let mut stored = self.write();
+        let position = match &options.sort {
+            Some(sort) => MockCollection::sorted_position(&stored, &query, sort)?,
+            None => MockCollection::position(&stored, &query)?,
+        };
+        let returned = match position {
+            Some(index) => {
+                let before = stored[index].clone();
+                if update_at(&mut stored[index], &query, &update, &array_filters)? {
+                    self.record("update", &stored[index]);
+                }
+                Some(if return_after {
+                    stored[index].clone()
+                } else {
+                    before
+                })
+            }
+            None if options.upsert == Some(true) => {
+                self.upsert(&mut stored, &query, &update, &array_filters)?;
+                if return_after {
+                    stored.last().cloned()
+                } else {
+                    None
+                }
+            }
+            None => None,
+        };
+        match (returned, options.projection) {
+            (Some(document), Some(projection)) => Ok(Some(project(&document, &projection)?)),
+            (returned, _) => Ok(returned),
+        }
+    }
+
+    fn delete_one(
+        &self,
+        query: Document,
+        _options: Option<DeleteOptions>,
+    ) -> Result<DeleteResult, ServiceError> {
+        let mut stored = self.write();
+        match MockCollection::position(&stored, &query)? {
+            Some(index) => {
+                let removed = stored.remove(index);
+                self.record("delete", &removed);
+                Ok(DeleteResult { deleted_count: 1 })
+            }
+            None => Ok(DeleteResult { deleted_count: 0 }),
+        }
+    }
+
+    fn delete_many(
+        &self,
+        query: Document,
+        _options: Option<DeleteOptions>,
+    ) -> Result<DeleteResult, ServiceError> {
+        let mut stored = self.write();
+        let mut deleted = Vec::with_capacity(stored.len());
+        for document in stored.iter() {
+            deleted.push(matches(document, &query)?);
+        }
+        for (document, _) in stored.iter().zip(deleted.iter()).filter(|(_, d)| **d) {
+            self.record("delete", document);
+        }
+        let mut flags = deleted.iter();
+        stored.retain(|_| !flags.next().unwrap_or(&false));
+        Ok(DeleteResult {
+            deleted_count: deleted.iter().filter(|d| **d).count() as i64,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::base::BaseService;
+    use crate::id::ID;
+    use crate::mongo::MongoService;
+    use crate::node::{Node, NodeDetails};
+    use bson::doc;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Deserialize, Serialize)]
+    struct Comment {
+        #[serde(rename = "_id")]
+        id: Option<ID>,
+        text: String,
+    }
+
+    #[derive(Debug, Deserialize, Serialize)]
+    struct Post {
+        #[serde(rename = "_id")]
+        id: ID,
+        title: String,
+        views: i32,
+        #[serde(default)]
+        comments: Vec<Comment>,
+        #[serde(default)]
+        node: NodeDetails,
+    }
+
+    impl Node for Post {
+        fn node(&self) -> &NodeDetails {
+            &self.node
+        }
+    }
+
+    #[test]
+    fn test_filter_operators() {
+        let document = doc! {
+            "name": "rust",
+            "tags": ["a", "b"],
+            "stats": { "stars": 10 },
+            "items": [{ "qty": 1 }, { "qty": 5 }],
+        };
+        let cases = vec![
+            (doc! { "name": "rust" }, true),
+            (doc! { "tags": "b" }, true),
+            (doc! { "stats.stars": { "$gte": 10, "$lt": 11 } }, true),
+            (doc! { "items.qty": { "$gt": 4 } }, true),
+            (
+                doc! { "items": { "$elemMatch": { "qty": { "$gt": 5 } } } },
+                false,
+            ),
+            (doc! { "missing": { "$exists": false } }, true),
+            (doc! { "missing": Bson::Null }, true),
+            (doc! { "name": { "$in": ["go", "rust"] } }, true),
+            (doc! { "name": { "$regex": "^RU", "$options": "i" } }, true),
+            (
+                doc! { "$or": [{ "name": "go" }, { "tags": { "$size": 2 } }] },
+                true,
+            ),
+            (doc! { "name": { "$not": { "$eq": "rust" } } }, false),
+        ];
+        for (filter, expected) in cases {
+            assert_eq!(matches(&document, &filter).unwrap(), expected, "{}", filter);
+        }
+    }
+
+    #[test]
+    fn test_update_operators() {
+        let collection = MockCollection::with_documents(
+            "docs",
+            vec![
+                doc! { "_id": 1, "count": 1, "list": [{ "_id": "a", "n": 1 }, { "_id": "b", "n": 2 }] },
+            ],
+        );
+        collection
+            .update_one(
+                doc! { "_id": 1, "list._id": "b" },
+                doc! {
+                    "$inc": { "count": 2 },
+                    "$set": { "list.$.n": 20, "nested.value": true },
+                    "$push": { "tags": { "$each": ["x", "y"] } },
+                },
+                None,
+            )
+            .unwrap();
+        collection
+            .update_one(
+                doc! { "_id": 1 },
+                doc! { "$pull": { "list": { "_id": "a" } } },
+                None,
+            )
+            .unwrap();
+        assert_eq!(
+            collection.documents()[0],
+            doc! {
+                "_id": 1,
+                "count": 3,
+                "list": [{ "_id": "b", "n": 20 }],
+                "nested": { "value": true },
+                "tags": ["x", "y"],
+            }
+        );
+    }
+
+    #[test]
+    fn test_positional_operator_is_resolved_before_updating() {
+        let collection = MockCollection::with_documents(
+            "docs",
+            vec![
+                doc! { "_id": 1, "list": [{ "_id": "a", "done": false }, { "_id": "b", "done": false }] },
+            ],
+        );
+        collection
+            .update_one(
+                doc! { "_id": 1, "list": { "$elemMatch": { "_id": "b", "done": false } } },
+                doc! { "$set": { "list.$.done": true, "list.$.by": "me" } },
+                None,
+            )
+            .unwrap();
+        assert_eq!(
+            collection.documents()[0].get_array("list").unwrap()[1],
+            Bson::Document(doc! { "_id": "b", "done": true, "by": "me" })
+        );
+    }
+
+    fn views(items: &[Document]) -> Vec<i32> {
+        items.iter().map(|d| d.get_i32("views").unwrap()).collect()
+    }
+
+    #[test]
+    fn test_find_paginates_with_cursors() {
+        let collection = MockCollection::with_documents(
+            "posts",
+            (1..=5)
+                .map(|i| doc! { "_id": i as i64, "views": i * 10 })
+                .collect(),
+        );
+        let service = MongoService::with_data_source(collection, None);
+        let sort = Some(doc! { "views": -1 });
+        let first: FindResult<Document> = service
+            .find(None, sort.clone(), Some(2), None, None, None)
+            .unwrap();
+        assert_eq!(first.total_count, 5);
+        assert_eq!(views(&first.items), vec![50, 40]);
+        assert!(first.page_info.has_next_page);
+        assert!(!first.page_info.has_previous_page);
+
+        let second: FindResult<Document> = service
+            .find(
+                None,
+                sort.clone(),
+                Some(2),
+                first.page_info.next_cursor.clone(),
+                None,
+                None,
+            )
+            .unwrap();
+        assert_eq!(views(&second.items), vec![30, 20]);
+        assert!(second.page_info.has_previous_page);
+
+        let back: FindResult<Document> = service
+            .find(
+                None,
+                sort,
+                Some(2),
+                None,
+                second.page_info.start_cursor.clone(),
+                None,
+            )
+            .unwrap();
+        assert_eq!(views(&back.items), vec![50, 40]);
+        assert!(!back.page_info.has_previous_page);
+    }
+
+    #[test]
+    fn test_search_and_find_one() {
+        let collection = MockCollection::with_documents(
+            "posts",
+            vec![
+                doc! { "_id": 3i64, "title": "Post 3", "views": 30 },
+                doc! { "_id": 4i64, "title": "Post 4", "views": 40 },
+            ],
+        );
+        let service = MongoService::with_data_source(collection, None);
+        let results: FindResult<Document> = service
+            .search(
+                "post 3".to_owned(),
+                vec!["title".to_owned()],
+                None,
+                None,
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+        assert_eq!(views(&results.items), vec![30]);
+        let post: Option<Document> = service.find_one_by_id(ID::I64(4)).unwrap();
+        assert_eq!(post.unwrap().get_str("title"), Ok("Post 4"));
+        let missing: Option<Document> = service.find_one(doc! { "views": { "$gt": 100 } }).unwrap();
+        assert!(missing.is_none());
+    }
+
+    #[test]
+    fn test_insert_update_and_delete() {
+        let collection = MockCollection::new("posts");
+        let service = MongoService::with_data_source(collection.clone(), None);
+        let id = service
+            .insert_one(
+                doc! { "_id": "p1", "title": "Hello", "views": 0 },
+                Some(ID::with_string("user")),
+            )
+            .unwrap();
+        assert_eq!(id, ID::with_string("p1"));
+
+        let updated: Post = service
+            .update_one(
+                id.clone(),
+                doc! { "views": 7 },
+                Some(ID::with_string("editor")),
+            )
+            .unwrap();
+        assert_eq!(updated.views, 7);
+        assert_eq!(
+            updated.node().updated_by_id(),
+            &Some(ID::with_string("editor"))
+        );
+        assert!(updated.node().date_modified().is_some());
+
+        service.delete_one_by_id(id).unwrap();
+        assert!(collection.documents().is_empty());
+    }
+
+    #[test]
+    fn test_duplicate_ids_are_rejected() {
+        let collection = MockCollection::with_documents("posts", vec![doc! { "_id": 1_i64 }]);
+        let service = MongoService::with_data_source(collection, None);
+        let result = service.insert_one(doc! { "_id": 1_i64, "title": "dupe" }, None);
+        match result {
+            Err(ServiceError::MongoError(_)) => {}
+            other => panic!("expected a duplicate key error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_embedded_operations() {
+        let collection = MockCollection::with_documents(
+            "posts",
+            vec![doc! { "_id": 1_i64, "title": "Post 1", "views": 10 }],
+        );
+        let service = MongoService::with_data_source(collection.clone(), None);
+        let ids = service
+            .insert_embedded(
+                ID::I64(1),
+                "comments",
+                vec![
+                    Comment {
+                        id: None,
+                        text: "first".to_owned(),
+                    },
+                    Comment {
+                        id: None,
+                        text: "second".to_owned(),
+                    },
+                ],
+                None,
+            )
+            .unwrap();
+        assert_eq!(ids.len(), 2);
+
+        let post: Post = service
+            .update_embedded(
+                ID::I64(1),
+                "comments",
+                ids[1].clone(),
+                doc! { "text": "edited" },
+                None,
+            )
+            .unwrap();
+        assert_eq!(post.comments[1].text, "edited");
+
+        let page: Vec<Comment> = service
+            .get_embedded_by_id(ID::I64(1), "comments", Some(1), Some(1))
+            .unwrap();
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].text, "edited");
+
+        service
+            .delete_embedded(ID::I64(1), "comments", ids[0].clone())
+            .unwrap();
+        let stored = &collection.documents()[0];
+        assert_eq!(stored.get_array("comments").unwrap().len(), 1);
+
+        service
+            .upsert_embedded(
+                ID::I64(9),
+                "comments",
+                vec![Comment {
+                    id: Some(ID::with_string("c1")),
+                    text: "new".to_owned(),
+                }],
+                None,
+                Some(doc! { "title": "Created", "views": 0 }),
+            )
+            .unwrap();
+        let created: Option<Post> = service.find_one_by_id(ID::I64(9)).unwrap();
+        assert_eq!(created.unwrap().comments[0].text, "new");
+    }
+
+    #[test]
+    fn test_aggregate_stages() {
+        let collection = MockCollection::with_documents(
+            "posts",
+            (0..6)
+                .map(|i| doc! { "title": format!("Post {}", i), "views": i * 10 })
+                .collect(),
+        );
+        let faceted = collection
+            .aggregate(
+                vec![doc! {
+                    "$facet": {
+                        "popular": [
+                            { "$match": { "views": { "$gte": 30 } } },
+                            { "$project": { "_id": 0, "title": 1, "double": { "$multiply": ["$views", 2] } } },
+                        ],
+                        "count": [{ "$count": "posts" }],
+                    }
+                }],
+                None,
+            )
+            .unwrap();
+        let popular = faceted[0].get_array("popular").unwrap();
+        assert_eq!(popular.len(), 3);
+        assert_eq!(
+            popular[0],
+            Bson::Document(doc! { "title": "Post 3", "double": 60 })
+        );
+        assert_eq!(
+            faceted[0].get_array("count").unwrap()[0],
+            Bson::Document(doc! { "posts": 6 })
+        );
+        assert!(collection
+            .aggregate(vec![doc! { "$lookup": { "from": "users" } }], None)
+            .is_err());
+    }
+
+    #[test]
+    fn test_find_one_and_update_returns_the_previous_document() {
+        let collection =
+            MockCollection::with_documents("posts", vec![doc! { "_id": 1_i64, "views": 10 }]);
+        let previous = collection
+            .find_one_and_update(doc! { "_id": 1_i64 }, doc! { "$inc": { "views": 1 } }, None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(previous.get_i32("views").unwrap(), 10);
+        assert_eq!(collection.documents()[0].get_i32("views").unwrap(), 11);
+    }
+}
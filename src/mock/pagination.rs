@@ -0,0 +1,37 @@
+use bson::Document;
+use mongodb::options::FindOptions;
+use mongodb_cursor_pagination::{CursorDirections, FindResult};
+
+use crate::cursor::PageQuery;
+use crate::error::ServiceError;
+use crate::mock::MockCollection;
+
+/// An in-memory port of `PaginatedCursor::find`, producing the same page shape and cursors.
+pub fn find_page(
+    collection: &MockCollection,
+    filter: Option<Document>,
+    options: FindOptions,
+    cursor: Option<String>,
+    direction: CursorDirections,
+) -> Result<FindResult<Document>, ServiceError> {
+    let page = PageQuery::new(
+        options.sort.clone(),
+        options.limit,
+        options.skip,
+        cursor,
+        direction,
+    )?;
+    let total_count = collection.count(filter.as_ref())?;
+    let found = if total_count > 0 {
+        collection.select(
+            Some(&page.filter(filter)),
+            Some(&page.query_sort()),
+            page.skip(),
+            page.limit(),
+            options.projection.as_ref(),
+        )?
+    } else {
+        Vec::new()
+    };
+    page.page(found, total_count)
+}
@@ -0,0 +1,200 @@
+use bson::{Bson, Document};
+
+use crate::error::ServiceError;
+use crate::mock::bad_value;
+use crate::mock::filter::{as_f64, element_matches};
+
+enum Action {
+    Include,
+    Exclude,
+    Slice(Bson),
+    ElemMatch(Bson),
+}
+
+#[derive(Default)]
+struct ProjectionNode {
+    action: Option<Action>,
+    children: Vec<(String, ProjectionNode)>,
+}
+
+impl ProjectionNode {
+    fn child(&self, key: &str) -> Option<&ProjectionNode> {
+        self.children
+            .iter()
+            .find(|(name, _)| name == key)
+            .map(|(_, node)| node)
+    }
+
+    fn child_mut(&mut self, key: &str) -> &mut ProjectionNode {
+        let position = match self.children.iter().position(|(name, _)| name == key) {
+            Some(position) => position,
+            None => {
+                self.children
+                    .push((key.to_string(), ProjectionNode::default()));
+                self.children.len() - 1
+            }
+        };
+        &mut self.children[position].1
+    }
+}
+
+/// Applies a MongoDB projection (inclusion, exclusion, `$slice` and `$elemMatch`) to a document.
+pub fn project(document: &Document, projection: &Document) -> Result<Document, ServiceError> {
+    let mut root = ProjectionNode::default();
+    let mut inclusion = false;
+    let mut exclusion = false;
+    for (path, value) in projection.iter() {
+        let action = match value {
+            Bson::Document(d) if d.contains_key("$slice") => {
+                Action::Slice(d.get("$slice").cloned().unwrap_or(Bson::Null))
+            }
+            Bson::Document(d) if d.contains_key("$elemMatch") => {
+                Action::ElemMatch(d.get("$elemMatch").cloned().unwrap_or(Bson::Null))
+            }
+            Bson::Document(_) => {
+                return Err(bad_value(&format!(
+                    "Unsupported projection option: {}",
+                    path
+                )))
+            }
+            Bson::Boolean(false) => Action::Exclude,
+            v if as_f64(v) == Some(0.0) => Action::Exclude,
+            _ => Action::Include,
+        };
+        if path != "_id" {
+            match action {
+                Action::Include => inclusion = true,
+                Action::Exclude => exclusion = true,
+                _ => {}
+            }
+        }
+        let mut node = &mut root;
+        for part in path.split('.') {
+            node = node.child_mut(part);
+        }
+        node.action = Some(action);
+    }
+    if inclusion && exclusion {
+        return Err(bad_value(
+            "Projection cannot have a mix of inclusion and exclusion.",
+        ));
+    }
+    if inclusion {
+        if root.child("_id").is_none() {
+            root.child_mut("_id").action = Some(Action::Include);
+        }
+        Ok(include_document(document, &root))
+    } else {
+        Ok(exclude_document(document, &root))
+    }
+}
+
+fn include_document(document: &Document, node: &ProjectionNode) -> Document {
+    let mut projected = Document::new();
+    for (key, value) in document.iter() {
+        if let Some(child) = node.child(key) {
+            if let Some(value) = include_value(value, child) {
+                projected.insert(key.clone(), value);
+            }
+        }
+    }
+    projected
+}
+
+fn include_value(value: &Bson, node: &ProjectionNode) -> Option<Bson> {
+    match &node.action {
+        Some(Action::Include) => Some(value.clone()),
+        Some(Action::Exclude) => None,
+        Some(Action::Slice(argument)) => Some(slice(value, argument)),
+        Some(Action::ElemMatch(condition)) => elem_match(value, condition),
+        None => match value {
+            Bson::Document(d) => Some(Bson::Document(include_document(d, node))),
+            Bson::Array(items) => Some(Bson::Array(
+                items
+                    .iter()
+                    .filter_map(|item| match item {
+                        Bson::Document(d) => Some(Bson::Document(include_document(d, node))),
+                        _ => None,
+                    })
+                    .collect(),
+            )),
+            _ => None,
+        },
+    }
+}
+
+fn exclude_document(document: &Document, node: &ProjectionNode) -> Document {
+    let mut projected = Document::new();
+    for (key, value) in document.iter() {
+        let value = match node.child(key) {
+            Some(child) => exclude_value(value, child),
+            None => Some(value.clone()),
+        };
+        if let Some(value) = value {
+            projected.insert(key.clone(), value);
+        }
+    }
+    projected
+}
+
+fn exclude_value(value: &Bson, node: &ProjectionNode) -> Option<Bson> {
+    match &node.action {
+        Some(Action::Include) => Some(value.clone()),
+        Some(Action::Exclude) => None,
+        Some(Action::Slice(argument)) => Some(slice(value, argument)),
+        Some(Action::ElemMatch(condition)) => elem_match(value, condition),
+        None => match value {
+            Bson::Document(d) => Some(Bson::Document(exclude_document(d, node))),
+            Bson::Array(items) => Some(Bson::Array(
+                items
+                    .iter()
+                    .map(|item| match item {
+                        Bson::Document(d) => Bson::Document(exclude_document(d, node)),
+                        other => other.clone(),
+                    })
+                    .collect(),
+            )),
+            other => Some(other.clone()),
+        },
+    }
+}
+
+/// Implements `$slice` with either a count (negative counts from the end) or `[skip, limit]`.
+pub fn slice(value: &Bson, argument: &Bson) -> Bson {
+    let items = match value {
+        Bson::Array(items) => items,
+        _ => return value.clone(),
+    };
+    let len = items.len() as i64;
+    let (skip, limit) = match argument {
+        Bson::Array(range) if range.len() == 2 => (
+            as_f64(&range[0]).unwrap_or(0.0) as i64,
+            as_f64(&range[1]).unwrap_or(0.0) as i64,
+        ),
+        _ => {
+            let count = as_f64(argument).unwrap_or(0.0) as i64;
+            if count < 0 {
+                (count, -count)
+            } else {
+                (0, count)
+            }
+        }
+    };
+    let start = if skip < 0 {
+        (len + skip).max(0)
+    } else {
+        skip.min(len)
+    };
+    let end = (start + limit.max(0)).min(len);
+    Bson::Array(items[start as usize..end as usize].to_vec())
+}
+
+fn elem_match(value: &Bson, condition: &Bson) -> Option<Bson> {
+    match value {
+        Bson::Array(items) => items
+            .iter()
+            .find(|item| element_matches(item, condition).unwrap_or(false))
+            .map(|item| Bson::Array(vec![item.clone()])),
+        _ => None,
+    }
+}
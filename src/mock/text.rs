@@ -0,0 +1,125 @@
+use bson::{Bson, Document};
+
+use crate::error::ServiceError;
+use crate::mock::bad_value;
+
+/// Where a `$match` on `$text` keeps each document's score for `{ "$meta": "textScore" }`.
+pub const TEXT_SCORE_KEY: &str = "$textScore";
+
+/// A `$text` query.
+///
+/// There are no index definitions in memory, so every string of a document is searched as if it
+/// had a wildcard text index. Words are matched exactly, without stemming or stop words.
+pub struct TextQuery {
+    terms: Vec<String>,
+    phrases: Vec<String>,
+    negated: Vec<String>,
+    case_sensitive: bool,
+}
+
+impl TextQuery {
+    pub fn parse(argument: &Bson) -> Result<Self, ServiceError> {
+        let options = match argument {
+            Bson::Document(options) => options,
+            _ => return Err(bad_value("$text expects an object")),
+        };
+        let search = match options.get("$search") {
+            Some(Bson::String(search)) => search,
+            _ => return Err(bad_value("$search needs a String")),
+        };
+        let case_sensitive = match options.get("$caseSensitive") {
+            Some(Bson::Boolean(case_sensitive)) => *case_sensitive,
+            _ => false,
+        };
+        let normalize = |text: &str| {
+            if case_sensitive {
+                text.to_owned()
+            } else {
+                text.to_lowercase()
+            }
+        };
+
+        let mut query = TextQuery {
+            terms: Vec::new(),
+            phrases: Vec::new(),
+            negated: Vec::new(),
+            case_sensitive,
+        };
+        for (i, part) in search.split('"').enumerate() {
+            if i % 2 == 1 {
+                if !part.trim().is_empty() {
+                    query.phrases.push(normalize(part.trim()));
+                }
+                continue;
+            }
+            for token in part.split_whitespace() {
+                let (negated, token) = match token.strip_prefix('-') {
+                    Some(token) => (true, token),
+                    None => (false, token),
+                };
+                for word in words(token) {
+                    if negated {
+                        query.negated.push(normalize(word));
+                    } else {
+                        query.terms.push(normalize(word));
+                    }
+                }
+            }
+        }
+        Ok(query)
+    }
+
+    /// The relevance of `document`, or `None` when it doesn't match.
+    pub fn score(&self, document: &Document) -> Option<f64> {
+        let mut texts = Vec::new();
+        collect_strings(&Bson::Document(document.clone()), &mut texts);
+        if !self.case_sensitive {
+            texts = texts.iter().map(|text| text.to_lowercase()).collect();
+        }
+        let document_words: Vec<&str> = texts.iter().flat_map(|text| words(text)).collect();
+
+        if self
+            .negated
+            .iter()
+            .any(|negated| document_words.contains(&negated.as_str()))
+        {
+            return None;
+        }
+        let mut score = 0.0;
+        for phrase in self.phrases.iter() {
+            let found: usize = texts
+                .iter()
+                .map(|text| text.matches(phrase.as_str()).count())
+                .sum();
+            if found == 0 {
+                return None;
+            }
+            score += found as f64;
+        }
+        for term in self.terms.iter() {
+            score += document_words.iter().filter(|word| *word == term).count() as f64;
+        }
+        if score > 0.0 {
+            Some(score)
+        } else {
+            None
+        }
+    }
+}
+
+fn words(text: &str) -> impl Iterator<Item = &str> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+}
+
+fn collect_strings(value: &Bson, texts: &mut Vec<String>) {
+    match value {
+        Bson::String(text) => texts.push(text.clone()),
+        Bson::Array(items) => items.iter().for_each(|item| collect_strings(item, texts)),
+        Bson::Document(document) => document
+            .iter()
+            .filter(|(key, _)| key.as_str() != TEXT_SCORE_KEY)
+            .for_each(|(_, value)| collect_strings(value, texts)),
+        _ => {}
+    }
+}
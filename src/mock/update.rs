@@ -0,0 +1,640 @@
+use bson::{Bson, Document};
+use chrono::Utc;
+use std::cmp::Ordering;
+
+use crate::error::ServiceError;
+use crate::mock::bad_value;
+use crate::mock::filter::{
+    as_f64, compare, compare_documents, element_matches, is_operator_document, matches,
+    matches_condition, values_at, values_equal,
+};
+
+/// What an update needs to know besides the update document itself.
+pub struct UpdateContext<'a> {
+    /// The query that selected the document, used to resolve the positional `$` operator.
+    pub filter: &'a Document,
+    /// The `arrayFilters` used to resolve `$[identifier]` operators.
+    pub array_filters: &'a [Document],
+    /// Whether the update is creating a new document through an upsert.
+    pub is_insert: bool,
+}
+
+/// Applies a MongoDB update document (`$set`, `$push`, `$pull`, ...) to `document` in place.
+pub fn apply_update(
+    document: &mut Document,
+    update: &Document,
+    context: &UpdateContext,
+) -> Result<(), ServiceError> {
+    if update.is_empty() || update.keys().any(|key| !key.starts_with('$')) {
+        return Err(bad_value(
+            "update document must contain only atomic operators",
+        ));
+    }
+    let mut root = Bson::Document(std::mem::replace(document, Document::new()));
+    let result = resolve_positional(&root, update, context.filter)
+        .and_then(|update| apply_operators(&mut root, &update, context));
+    if let Bson::Document(updated) = root {
+        *document = updated;
+    }
+    result
+}
+
+/// Replaces the positional `$` of every path with the index it refers to before anything is
+/// applied. MongoDB resolves it once from the query, so a `$set` of a field the query matched
+/// on doesn't move the position for the rest of the update.
+fn resolve_positional(
+    root: &Bson,
+    update: &Document,
+    filter: &Document,
+) -> Result<Document, ServiceError> {
+    let mut resolved = Document::new();
+    for (operator, fields) in update.iter() {
+        let fields = match fields {
+            Bson::Document(fields) => fields,
+            other => {
+                resolved.insert(operator.clone(), other.clone());
+                continue;
+            }
+        };
+        let mut resolved_fields = Document::new();
+        for (path, argument) in fields.iter() {
+            resolved_fields.insert(positional_path(root, path, filter)?, argument.clone());
+        }
+        resolved.insert(operator.clone(), resolved_fields);
+    }
+    Ok(resolved)
+}
+
+fn positional_path(root: &Bson, path: &str, filter: &Document) -> Result<String, ServiceError> {
+    let mut parts: Vec<String> = path.split('.').map(String::from).collect();
+    let position = match parts.iter().position(|part| part == "$") {
+        Some(position) => position,
+        None => return Ok(path.to_string()),
+    };
+    let prefix = &parts[..position];
+    if prefix.iter().any(|part| part.starts_with('$')) {
+        return Ok(path.to_string());
+    }
+    let items = match prefix
+        .iter()
+        .try_fold(root, |container, key| get_child(container, key))
+    {
+        Some(Bson::Array(items)) => items,
+        _ => return Ok(path.to_string()),
+    };
+    match positional_index(items, &prefix.join("."), filter)? {
+        Some(index) => {
+            parts[position] = index.to_string();
+            Ok(parts.join("."))
+        }
+        None => Ok(path.to_string()),
+    }
+}
+
+fn apply_operators(
+    root: &mut Bson,
+    update: &Document,
+    context: &UpdateContext,
+) -> Result<(), ServiceError> {
+    for (operator, fields) in update.iter() {
+        let fields = match fields {
+            Bson::Document(fields) => fields,
+            _ => {
+                return Err(bad_value(&format!(
+                    "Modifiers operate on fields but we found a non-object for {}",
+                    operator
+                )))
+            }
+        };
+        for (path, argument) in fields.iter() {
+            let parts: Vec<&str> = path.split('.').collect();
+            match operator.as_str() {
+                "$set" => walk(root, &parts, "", context, true, &mut |parent, key| {
+                    set_child(parent, key, argument.clone())
+                })?,
+                "$setOnInsert" => {
+                    if context.is_insert {
+                        walk(root, &parts, "", context, true, &mut |parent, key| {
+                            set_child(parent, key, argument.clone())
+                        })?
+                    }
+                }
+                "$unset" => walk(root, &parts, "", context, false, &mut |parent, key| {
+                    remove_child(parent, key);
+                    Ok(())
+                })?,
+                "$inc" | "$mul" => {
+                    let multiply = operator == "$mul";
+                    walk(root, &parts, "", context, true, &mut |parent, key| {
+                        let value = arithmetic(get_child(parent, key), argument, multiply)?;
+                        set_child(parent, key, value)
+                    })?
+                }
+                "$min" | "$max" => {
+                    let wanted = if operator == "$min" {
+                        Ordering::Less
+                    } else {
+                        Ordering::Greater
+                    };
+                    walk(root, &parts, "", context, true, &mut |parent, key| {
+                        let replace = match get_child(parent, key) {
+                            Some(current) => compare(argument, current) == wanted,
+                            None => true,
+                        };
+                        if replace {
+                            set_child(parent, key, argument.clone())?;
+                        }
+                        Ok(())
+                    })?
+                }
+                "$currentDate" => walk(root, &parts, "", context, true, &mut |parent, key| {
+                    set_child(parent, key, Bson::UtcDatetime(Utc::now()))
+                })?,
+                "$push" | "$addToSet" => {
+                    let add_to_set = operator == "$addToSet";
+                    walk(root, &parts, "", context, true, &mut |parent, key| {
+                        let mut items = existing_array(parent, key, path)?;
+                        push(&mut items, argument, add_to_set)?;
+                        set_child(parent, key, Bson::Array(items))
+                    })?
+                }
+                "$pull" | "$pullAll" | "$pop" => {
+                    walk(root, &parts, "", context, false, &mut |parent, key| {
+                        if get_child(parent, key).is_none() {
+                            return Ok(());
+                        }
+                        let mut items = existing_array(parent, key, path)?;
+                        match operator.as_str() {
+                            "$pull" => {
+                                let mut kept = Vec::with_capacity(items.len());
+                                for item in items {
+                                    if !element_matches(&item, argument)? {
+                                        kept.push(item);
+                                    }
+                                }
+                                items = kept;
+                            }
+                            "$pullAll" => {
+                                let removed = match argument {
+                                    Bson::Array(removed) => removed,
+                                    _ => return Err(bad_value("$pullAll requires an array")),
+                                };
+                                items.retain(|item| !removed.iter().any(|r| values_equal(item, r)));
+                            }
+                            _ => {
+                                if as_f64(argument).unwrap_or(1.0) < 0.0 {
+                                    if !items.is_empty() {
+                                        items.remove(0);
+                                    }
+                                } else {
+                                    items.pop();
+                                }
+                            }
+                        }
+                        set_child(parent, key, Bson::Array(items))
+                    })?
+                }
+                "$rename" => {
+                    let target = match argument {
+                        Bson::String(target) => target,
+                        _ => return Err(bad_value("$rename target must be a string")),
+                    };
+                    let mut moved: Option<Bson> = None;
+                    walk(root, &parts, "", context, false, &mut |parent, key| {
+                        if let Bson::Document(d) = parent {
+                            moved = d.remove(key);
+                        }
+                        Ok(())
+                    })?;
+                    if let Some(value) = moved {
+                        let target_parts: Vec<&str> = target.split('.').collect();
+                        walk(
+                            root,
+                            &target_parts,
+                            "",
+                            context,
+                            true,
+                            &mut |parent, key| set_child(parent, key, value.clone()),
+                        )?;
+                    }
+                }
+                _ => {
+                    return Err(bad_value(&format!(
+                        "Unknown modifier: {}. Expected a valid update modifier",
+                        operator
+                    )))
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Builds the document an upsert starts from out of the equality conditions in `filter`.
+pub fn upsert_seed(filter: &Document) -> Result<Document, ServiceError> {
+    let mut root = Bson::Document(Document::new());
+    seed_from_filter(&mut root, filter)?;
+    match root {
+        Bson::Document(seed) => Ok(seed),
+        _ => Ok(Document::new()),
+    }
+}
+
+fn seed_from_filter(root: &mut Bson, filter: &Document) -> Result<(), ServiceError> {
+    let empty = Document::new();
+    let context = UpdateContext {
+        filter: &empty,
+        array_filters: &[],
+        is_insert: true,
+    };
+    for (key, condition) in filter.iter() {
+        if key == "$and" {
+            if let Bson::Array(clauses) = condition {
+                for clause in clauses {
+                    if let Bson::Document(clause) = clause {
+                        seed_from_filter(root, clause)?;
+                    }
+                }
+            }
+            continue;
+        }
+        if key.starts_with('$') {
+            continue;
+        }
+        let value = match condition {
+            Bson::Document(operators) if is_operator_document(operators) => {
+                operators.get("$eq").cloned()
+            }
+            Bson::RegExp(_, _) => None,
+            value => Some(value.clone()),
+        };
+        if let Some(value) = value {
+            let parts: Vec<&str> = key.split('.').collect();
+            walk(root, &parts, "", &context, true, &mut |parent, key| {
+                set_child(parent, key, value.clone())
+            })?;
+        }
+    }
+    Ok(())
+}
+
+/// Resolves `path` below `container`, creating intermediate documents when `create` is set, and
+/// calls `apply` with every parent container and final key the path resolves to.
+fn walk<F>(
+    container: &mut Bson,
+    path: &[&str],
+    prefix: &str,
+    context: &UpdateContext,
+    create: bool,
+    apply: &mut F,
+) -> Result<(), ServiceError>
+where
+    F: FnMut(&mut Bson, &str) -> Result<(), ServiceError>,
+{
+    let segment = path[0];
+    let keys = resolve_segment(container, segment, prefix, context)?;
+    if path.len() == 1 {
+        for key in keys {
+            apply(container, &key)?;
+        }
+        return Ok(());
+    }
+    let next_prefix = if segment.starts_with('$') {
+        prefix.to_string()
+    } else if prefix.is_empty() {
+        segment.to_string()
+    } else {
+        format!("{}.{}", prefix, segment)
+    };
+    for key in keys {
+        if get_child(container, &key).is_none() {
+            if !create {
+                continue;
+            }
+            set_child(container, &key, Bson::Document(Document::new()))?;
+        }
+        if let Some(child) = get_child_mut(container, &key) {
+            match child {
+                Bson::Document(_) | Bson::Array(_) => {
+                    walk(child, &path[1..], &next_prefix, context, create, apply)?
+                }
+                _ if create => {
+                    return Err(bad_value(&format!(
+                        "Cannot create field '{}' in element {{{}: {}}}",
+                        path[1], key, child
+                    )))
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+fn resolve_segment(
+    container: &Bson,
+    segment: &str,
+    prefix: &str,
+    context: &UpdateContext,
+) -> Result<Vec<String>, ServiceError> {
+    if !segment.starts_with('$') {
+        return Ok(vec![segment.to_string()]);
+    }
+    let items = match container {
+        Bson::Array(items) => items,
+        _ => {
+            return Err(bad_value(&format!(
+                "Cannot apply array updates to non-array element at {}",
+                prefix
+            )))
+        }
+    };
+    if segment == "$" {
+        let index = positional_index(items, prefix, context.filter)?;
+        Ok(index.into_iter().map(|i| i.to_string()).collect())
+    } else if segment == "$[]" {
+        Ok((0..items.len()).map(|i| i.to_string()).collect())
+    } else if segment.starts_with("$[") && segment.ends_with(']') {
+        let identifier = &segment[2..segment.len() - 1];
+        let mut indexes = Vec::new();
+        for (i, item) in items.iter().enumerate() {
+            if matches_array_filters(item, identifier, context.array_filters)? {
+                indexes.push(i.to_string());
+            }
+        }
+        Ok(indexes)
+    } else {
+        Ok(vec![segment.to_string()])
+    }
+}
+
+fn positional_conditions(filter: &Document, prefix: &str, conditions: &mut Vec<(String, Bson)>) {
+    let nested = format!("{}.", prefix);
+    for (key, condition) in filter.iter() {
+        if key == "$and" {
+            if let Bson::Array(clauses) = condition {
+                for clause in clauses {
+                    if let Bson::Document(clause) = clause {
+                        positional_conditions(clause, prefix, conditions);
+                    }
+                }
+            }
+        } else if key == prefix {
+            conditions.push((String::new(), condition.clone()));
+        } else if key.starts_with(&nested) {
+            conditions.push((key[nested.len()..].to_string(), condition.clone()));
+        }
+    }
+}
+
+/// Finds the array element the positional `$` operator refers to, using the query conditions
+/// on the array field.
+fn positional_index(
+    items: &[Bson],
+    prefix: &str,
+    filter: &Document,
+) -> Result<Option<usize>, ServiceError> {
+    let mut conditions = Vec::new();
+    positional_conditions(filter, prefix, &mut conditions);
+    if conditions.is_empty() {
+        return Err(bad_value(
+            "The positional operator did not find the match needed from the query.",
+        ));
+    }
+    for (i, item) in items.iter().enumerate() {
+        let mut is_match = true;
+        for (path, condition) in conditions.iter() {
+            let element_match = if path.is_empty() {
+                match condition {
+                    Bson::Document(d) if d.contains_key("$elemMatch") => {
+                        element_matches(item, d.get("$elemMatch").unwrap_or(&Bson::Null))?
+                    }
+                    _ => matches_condition(&[item], condition)?,
+                }
+            } else {
+                match item {
+                    Bson::Document(d) => matches_condition(&values_at(d, path), condition)?,
+                    _ => false,
+                }
+            };
+            if !element_match {
+                is_match = false;
+                break;
+            }
+        }
+        if is_match {
+            return Ok(Some(i));
+        }
+    }
+    Ok(None)
+}
+
+fn matches_array_filters(
+    item: &Bson,
+    identifier: &str,
+    array_filters: &[Document],
+) -> Result<bool, ServiceError> {
+    let nested = format!("{}.", identifier);
+    let mut wrapped = Document::new();
+    wrapped.insert(identifier, item.clone());
+    let mut found = false;
+    for array_filter in array_filters {
+        if array_filter
+            .keys()
+            .any(|key| key == identifier || key.starts_with(&nested))
+        {
+            found = true;
+            if !matches(&wrapped, array_filter)? {
+                return Ok(false);
+            }
+        }
+    }
+    if !found {
+        return Err(bad_value(&format!(
+            "No array filter found for identifier '{}'",
+            identifier
+        )));
+    }
+    Ok(true)
+}
+
+fn get_child<'a>(container: &'a Bson, key: &str) -> Option<&'a Bson> {
+    match container {
+        Bson::Document(d) => d.get(key),
+        Bson::Array(items) => key.parse::<usize>().ok().and_then(|i| items.get(i)),
+        _ => None,
+    }
+}
+
+fn get_child_mut<'a>(container: &'a mut Bson, key: &str) -> Option<&'a mut Bson> {
+    match container {
+        Bson::Document(d) => d.get_mut(key),
+        Bson::Array(items) => match key.parse::<usize>() {
+            Ok(i) => items.get_mut(i),
+            Err(_) => None,
+        },
+        _ => None,
+    }
+}
+
+fn set_child(container: &mut Bson, key: &str, value: Bson) -> Result<(), ServiceError> {
+    match container {
+        Bson::Document(d) => {
+            match d.get_mut(key) {
+                Some(existing) => *existing = value,
+                None => {
+                    d.insert(key, value);
+                }
+            }
+            Ok(())
+        }
+        Bson::Array(items) => {
+            let index = key
+                .parse::<usize>()
+                .map_err(|_| bad_value(&format!("Cannot create field '{}' in an array", key)))?;
+            while items.len() <= index {
+                items.push(Bson::Null);
+            }
+            items[index] = value;
+            Ok(())
+        }
+        _ => Err(bad_value(&format!("Cannot create field '{}'", key))),
+    }
+}
+
+fn remove_child(container: &mut Bson, key: &str) {
+    match container {
+        Bson::Document(d) => {
+            d.remove(key);
+        }
+        Bson::Array(items) => {
+            if let Ok(index) = key.parse::<usize>() {
+                if let Some(item) = items.get_mut(index) {
+                    *item = Bson::Null;
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+fn existing_array(parent: &Bson, key: &str, path: &str) -> Result<Vec<Bson>, ServiceError> {
+    match get_child(parent, key) {
+        None => Ok(Vec::new()),
+        Some(Bson::Array(items)) => Ok(items.clone()),
+        Some(_) => Err(bad_value(&format!("The field '{}' must be an array", path))),
+    }
+}
+
+fn push(items: &mut Vec<Bson>, argument: &Bson, add_to_set: bool) -> Result<(), ServiceError> {
+    let modifiers = match argument {
+        Bson::Document(d) if d.contains_key("$each") => Some(d),
+        _ => None,
+    };
+    let new_items = match modifiers {
+        Some(d) => match d.get("$each") {
+            Some(Bson::Array(each)) => each.clone(),
+            _ => return Err(bad_value("The argument to $each must be an array")),
+        },
+        None => vec![argument.clone()],
+    };
+    if add_to_set {
+        for item in new_items {
+            if !items.iter().any(|existing| values_equal(existing, &item)) {
+                items.push(item);
+            }
+        }
+        return Ok(());
+    }
+    let position = modifiers
+        .and_then(|d| d.get("$position"))
+        .and_then(as_f64)
+        .map(|p| p as i64);
+    match position {
+        Some(p) => {
+            let len = items.len() as i64;
+            let index = if p < 0 { (len + p).max(0) } else { p.min(len) } as usize;
+            for (offset, item) in new_items.into_iter().enumerate() {
+                items.insert(index + offset, item);
+            }
+        }
+        None => items.extend(new_items),
+    }
+    if let Some(sort) = modifiers.and_then(|d| d.get("$sort")) {
+        match sort {
+            Bson::Document(sort) => items.sort_by(|a, b| match (a, b) {
+                (Bson::Document(a), Bson::Document(b)) => compare_documents(a, b, sort),
+                _ => compare(a, b),
+            }),
+            direction => {
+                let descending = as_f64(direction).unwrap_or(1.0) < 0.0;
+                items.sort_by(|a, b| {
+                    let ordering = compare(a, b);
+                    if descending {
+                        ordering.reverse()
+                    } else {
+                        ordering
+                    }
+                });
+            }
+        }
+    }
+    if let Some(count) = modifiers
+        .and_then(|d| d.get("$slice"))
+        .and_then(as_f64)
+        .map(|s| s as i64)
+    {
+        if count >= 0 {
+            items.truncate(count as usize);
+        } else {
+            let keep = (-count) as usize;
+            if items.len() > keep {
+                items.drain(0..items.len() - keep);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn as_i64(value: &Bson) -> Option<i64> {
+    match value {
+        Bson::I32(i) => Some(i64::from(*i)),
+        Bson::I64(i) => Some(*i),
+        _ => None,
+    }
+}
+
+pub fn arithmetic(
+    current: Option<&Bson>,
+    argument: &Bson,
+    multiply: bool,
+) -> Result<Bson, ServiceError> {
+    let current = current.cloned().unwrap_or(Bson::I32(0));
+    let result = match (&current, argument) {
+        (Bson::I32(a), Bson::I32(b)) => {
+            let checked = if multiply {
+                a.checked_mul(*b)
+            } else {
+                a.checked_add(*b)
+            };
+            match checked {
+                Some(value) => Some(Bson::I32(value)),
+                None if multiply => Some(Bson::I64(i64::from(*a) * i64::from(*b))),
+                None => Some(Bson::I64(i64::from(*a) + i64::from(*b))),
+            }
+        }
+        (Bson::I32(_), Bson::I64(_))
+        | (Bson::I64(_), Bson::I32(_))
+        | (Bson::I64(_), Bson::I64(_)) => match (as_i64(&current), as_i64(argument)) {
+            (Some(a), Some(b)) if multiply => Some(Bson::I64(a.wrapping_mul(b))),
+            (Some(a), Some(b)) => Some(Bson::I64(a.wrapping_add(b))),
+            _ => None,
+        },
+        _ => match (as_f64(&current), as_f64(argument)) {
+            (Some(a), Some(b)) if multiply => Some(Bson::FloatingPoint(a * b)),
+            (Some(a), Some(b)) => Some(Bson::FloatingPoint(a + b)),
+            _ => None,
+        },
+    };
+    result.ok_or_else(|| bad_value("Cannot apply an arithmetic update to a non-numeric value"))
+}
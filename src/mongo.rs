@@ -1,23 +1,154 @@
-use bson::{doc, Document};
+use bson::{doc, Bson, Document};
 use mongodb::Collection;
+use std::sync::Arc;
+use std::time::Duration;
 
-use crate::base::BaseService;
+#[cfg(feature = "async")]
+use crate::async_base::AsyncBaseService;
+use crate::base::{BaseService, CountMode};
+use crate::cache::ItemCache;
+use crate::data_source::DataSource;
+use crate::hooks::ServiceHooks;
+use crate::index::IndexSpec;
+use crate::relation::Relation;
+use crate::search::SearchMode;
+use crate::tenant::TenantScope;
+use crate::validation::Validator;
 
 #[derive(Clone)]
 pub struct MongoService {
-    data_source: Collection,
+    data_source: Arc<dyn DataSource>,
     default_sort: Option<Document>,
+    soft_delete: bool,
+    history: Option<Arc<dyn DataSource>>,
+    hooks: Option<Arc<dyn ServiceHooks>>,
+    validator: Option<Arc<dyn Validator>>,
+    search_mode: SearchMode,
+    count_mode: CountMode,
+    indexes: Vec<IndexSpec>,
+    cache: Option<Arc<ItemCache>>,
+    tenant: Option<TenantScope>,
+    hidden_fields: Vec<String>,
+    relations: Vec<Relation>,
 }
 
-impl BaseService<'_> for MongoService {
-    fn new(collection: &Collection, default_sort: Option<Document>) -> Self {
+impl MongoService {
+    /// Creates a service backed by any `DataSource`, eg. a `MockCollection` in unit tests.
+    pub fn with_data_source<D>(data_source: D, default_sort: Option<Document>) -> Self
+    where
+        D: DataSource + 'static,
+    {
         MongoService {
-            data_source: collection.clone(),
+            data_source: Arc::new(data_source),
             default_sort,
+            soft_delete: false,
+            history: None,
+            hooks: None,
+            validator: None,
+            search_mode: SearchMode::Regex,
+            count_mode: CountMode::Exact,
+            indexes: Vec::new(),
+            cache: None,
+            tenant: None,
+            hidden_fields: Vec::new(),
+            relations: Vec::new(),
         }
     }
-    fn data_source(&self) -> &Collection {
-        &self.data_source
+
+    /// Turns deletes into soft deletes, see `BaseService::uses_soft_delete`.
+    pub fn with_soft_delete(mut self) -> Self {
+        self.soft_delete = true;
+        self
+    }
+
+    /// Records every change made through this service in a companion history collection.
+    pub fn with_history<D>(mut self, history: D) -> Self
+    where
+        D: DataSource + 'static,
+    {
+        self.history = Some(Arc::new(history));
+        self
+    }
+
+    /// Runs the given hooks around every write made through this service.
+    pub fn with_hooks<H>(mut self, hooks: H) -> Self
+    where
+        H: ServiceHooks + 'static,
+    {
+        self.hooks = Some(Arc::new(hooks));
+        self
+    }
+
+    /// Checks every item with `validator` before it is written.
+    pub fn with_validator<V>(mut self, validator: V) -> Self
+    where
+        V: Validator + 'static,
+    {
+        self.validator = Some(Arc::new(validator));
+        self
+    }
+
+    /// Declares the indexes of the collection, see `DataSources::ensure_indexes`.
+    pub fn with_indexes(mut self, indexes: Vec<IndexSpec>) -> Self {
+        self.indexes = indexes;
+        self
+    }
+
+    /// Shares the collection between tenants, each item holding its tenant in `field`. Until
+    /// `for_tenant` picks a tenant the service finds nothing and inserts fail.
+    pub fn with_tenant_field(mut self, field: &str) -> Self {
+        self.tenant = Some(TenantScope::new(field));
+        self
+    }
+
+    /// A copy of the service that only reads and writes the items of `tenant`, to be made per
+    /// request or per call. Services without a tenant field are shared and returned as they are.
+    pub fn for_tenant<B: Into<Bson>>(&self, tenant: B) -> Self {
+        let mut service = self.clone();
+        service.tenant = service.tenant.map(|scope| scope.with_tenant(tenant.into()));
+        service
+    }
+
+    /// Leaves `fields` out of every read unless a `Projection` asks for them.
+    pub fn with_hidden_fields(mut self, fields: &[&str]) -> Self {
+        self.hidden_fields = fields.iter().map(|field| (*field).to_owned()).collect();
+        self
+    }
+
+    /// Caches up to `capacity` items read by `find_one_by_id`, each for at most `ttl`. Clones of
+    /// the service share the cache.
+    pub fn with_cache(mut self, capacity: usize, ttl: Option<Duration>) -> Self {
+        self.cache = Some(Arc::new(ItemCache::new(capacity, ttl)));
+        self
+    }
+
+    /// Declares the relations to other services, see `DataSources::populate`.
+    pub fn with_relations(mut self, relations: Vec<Relation>) -> Self {
+        self.relations = relations;
+        self
+    }
+
+    /// Estimates the `total_count` of unfiltered pages, see `CountMode::Estimated`.
+    pub fn with_estimated_count(mut self) -> Self {
+        self.count_mode = CountMode::Estimated;
+        self
+    }
+
+    /// Makes `search` use the collection's text index, see `SearchMode::Text`.
+    pub fn with_text_search(mut self, language: Option<&str>) -> Self {
+        self.search_mode = SearchMode::Text {
+            language: language.map(str::to_owned),
+        };
+        self
+    }
+}
+
+impl BaseService<'_> for MongoService {
+    fn new(collection: &Collection, default_sort: Option<Document>) -> Self {
+        MongoService::with_data_source(collection.clone(), default_sort)
+    }
+    fn data_source(&self) -> &dyn DataSource {
+        self.data_source.as_ref()
     }
     fn default_sort(&self) -> Document {
         match &self.default_sort {
@@ -25,4 +156,68 @@ impl BaseService<'_> for MongoService {
             None => doc! { "_id": 1 },
         }
     }
+    fn uses_soft_delete(&self) -> bool {
+        self.soft_delete
+    }
+    fn indexes(&self) -> Vec<IndexSpec> {
+        self.indexes.clone()
+    }
+    fn relations(&self) -> Vec<Relation> {
+        self.relations.clone()
+    }
+    fn count_mode(&self) -> CountMode {
+        self.count_mode
+    }
+    fn search_mode(&self) -> SearchMode {
+        self.search_mode.clone()
+    }
+    fn hooks(&self) -> Option<&dyn ServiceHooks> {
+        self.hooks.as_deref()
+    }
+    fn validator(&self) -> Option<&dyn Validator> {
+        self.validator.as_deref()
+    }
+    fn history_data_source(&self) -> Option<&dyn DataSource> {
+        self.history.as_deref()
+    }
+    fn cache(&self) -> Option<&ItemCache> {
+        self.cache.as_deref()
+    }
+    fn tenant_scope(&self) -> Option<&TenantScope> {
+        self.tenant.as_ref()
+    }
+    fn hidden_fields(&self) -> Vec<String> {
+        self.hidden_fields.clone()
+    }
+}
+
+/// A `MongoService` that can be awaited, running each blocking call on tokio's blocking pool.
+#[cfg(feature = "async")]
+#[derive(Clone)]
+pub struct AsyncMongoService {
+    service: MongoService,
+}
+
+#[cfg(feature = "async")]
+impl AsyncMongoService {
+    /// See `MongoService::for_tenant`.
+    pub fn for_tenant<B: Into<Bson>>(&self, tenant: B) -> Self {
+        self.service.for_tenant(tenant).into()
+    }
+}
+
+#[cfg(feature = "async")]
+impl From<MongoService> for AsyncMongoService {
+    fn from(service: MongoService) -> AsyncMongoService {
+        AsyncMongoService { service }
+    }
+}
+
+#[cfg(feature = "async")]
+impl AsyncBaseService for AsyncMongoService {
+    type Service = MongoService;
+
+    fn service(&self) -> &MongoService {
+        &self.service
+    }
 }
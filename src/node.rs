@@ -8,6 +8,9 @@ pub struct NodeDetails {
     date_modified: Option<i64>,
     created_by_id: Option<ID>,
     updated_by_id: Option<ID>,
+    date_deleted: Option<i64>,
+    deleted_by_id: Option<ID>,
+    version: Option<i64>,
 }
 
 fn get_timestamp(timestamp: Option<i64>) -> Option<DateTime<Utc>> {
@@ -33,6 +36,24 @@ impl NodeDetails {
     pub fn updated_by_id(&self) -> &Option<ID> {
         &self.updated_by_id
     }
+
+    pub fn date_deleted(&self) -> Option<DateTime<Utc>> {
+        get_timestamp(self.date_deleted)
+    }
+
+    pub fn deleted_by_id(&self) -> &Option<ID> {
+        &self.deleted_by_id
+    }
+
+    /// Incremented on every write, items written before versioning was added are at version 0.
+    pub fn version(&self) -> i64 {
+        self.version.unwrap_or(0)
+    }
+
+    /// Whether the item has been soft deleted and is waiting in the trash.
+    pub fn is_deleted(&self) -> bool {
+        self.date_deleted.is_some()
+    }
 }
 
 pub trait Node {
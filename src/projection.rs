@@ -0,0 +1,301 @@
+use bson::{doc, Bson, Document};
+
+use crate::error::ServiceError;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Fields {
+    All,
+    Include(Vec<String>),
+    Exclude(Vec<String>),
+}
+
+/// Which fields of the items a read returns.
+///
+/// Fields are given by their (dotted) path. Embedded arrays can be cut down with `with_slice`,
+/// eg. to list posts with only their latest comments. The fields of the sort are always returned
+/// since the page cursors are made from them, which is why sorting by a hidden field is refused
+/// unless the projection returns it. Items are deserialized from what is left, so the
+/// type read has to default whatever the projection leaves out.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Projection {
+    fields: Fields,
+    slices: Vec<(String, Bson)>,
+    revealed: Vec<String>,
+}
+
+impl Default for Projection {
+    fn default() -> Self {
+        Projection::all()
+    }
+}
+
+fn paths(fields: &[&str]) -> Vec<String> {
+    fields.iter().map(|field| (*field).to_owned()).collect()
+}
+
+/// Whether one path is the other or lies within it, which MongoDB refuses to project together.
+fn overlaps(a: &str, b: &str) -> bool {
+    let (shorter, longer) = if a.len() <= b.len() { (a, b) } else { (b, a) };
+    longer == shorter || (longer.starts_with(shorter) && longer[shorter.len()..].starts_with('.'))
+}
+
+impl Projection {
+    /// Every field, except the service's hidden fields.
+    pub fn all() -> Self {
+        Projection {
+            fields: Fields::All,
+            slices: Vec::new(),
+            revealed: Vec::new(),
+        }
+    }
+
+    /// Only `fields` (and `_id`). Hidden fields are returned when they are listed.
+    pub fn include(fields: &[&str]) -> Self {
+        Projection {
+            fields: Fields::Include(paths(fields)),
+            ..Projection::all()
+        }
+    }
+
+    /// Every field but `fields` and the service's hidden fields.
+    pub fn exclude(fields: &[&str]) -> Self {
+        Projection {
+            fields: Fields::Exclude(paths(fields)),
+            ..Projection::all()
+        }
+    }
+
+    /// Returns hidden fields along with the others, see `BaseService::hidden_fields`.
+    pub fn revealing(mut self, fields: &[&str]) -> Self {
+        self.revealed.extend(paths(fields));
+        self
+    }
+
+    /// Only the first `limit` items of an embedded array, or the last ones when it is negative.
+    pub fn with_slice(mut self, field: &str, limit: i64) -> Self {
+        self.slices.push((field.to_owned(), Bson::I64(limit)));
+        self
+    }
+
+    /// `limit` items of an embedded array, after skipping `skip` of them.
+    pub fn with_slice_range(mut self, field: &str, skip: i64, limit: i64) -> Self {
+        self.slices.push((
+            field.to_owned(),
+            Bson::Array(vec![Bson::I64(skip), Bson::I64(limit)]),
+        ));
+        self
+    }
+
+    /// The projection document of a read, leaving out the `hidden` fields that aren't asked
+    /// for and keeping the fields of `sort`. `None` returns whole items.
+    pub(crate) fn resolve(
+        &self,
+        hidden: &[String],
+        sort: Option<&Document>,
+    ) -> Result<Option<Document>, ServiceError> {
+        let sort_keys: Vec<&String> = sort.map(|sort| sort.keys().collect()).unwrap_or_default();
+        // the page cursors would give the hidden values away
+        let asked_for = |field: &String| match &self.fields {
+            Fields::Include(fields) => fields.contains(field) || self.revealed.contains(field),
+            _ => self.revealed.contains(field),
+        };
+        for key in sort_keys.iter() {
+            if let Some(field) = hidden
+                .iter()
+                .find(|field| !asked_for(field) && overlaps(field, key))
+            {
+                return Err(format!("Unable to sort by the hidden field {}", field).into());
+            }
+        }
+        let mut projection = Document::new();
+        match &self.fields {
+            Fields::Include(fields) => {
+                for field in fields.iter() {
+                    projection.insert(field.clone(), 1);
+                }
+                for key in sort_keys {
+                    if !fields.iter().any(|field| overlaps(field, key)) {
+                        projection.insert(key.clone(), 1);
+                    }
+                }
+            }
+            Fields::Exclude(_) | Fields::All => {
+                let excluded = match &self.fields {
+                    Fields::Exclude(fields) => fields.iter(),
+                    _ => [].iter(),
+                };
+                let hidden = hidden
+                    .iter()
+                    .filter(|field| !self.revealed.iter().any(|revealed| revealed == *field));
+                for field in excluded.chain(hidden) {
+                    if !sort_keys.iter().any(|key| overlaps(field, key)) {
+                        projection.insert(field.clone(), 0);
+                    }
+                }
+            }
+        }
+        for (field, slice) in self.slices.iter() {
+            projection.insert(field.clone(), doc! { "$slice": slice.clone() });
+        }
+        if projection.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(projection))
+        }
+    }
+
+    /// The stages applying the projection at the end of an aggregation pipeline, where
+    /// `$slice` takes the form of an expression.
+    pub(crate) fn stages(
+        &self,
+        hidden: &[String],
+        sort: Option<&Document>,
+    ) -> Result<Vec<Document>, ServiceError> {
+        let mut projection = match self.resolve(hidden, sort)? {
+            Some(projection) => projection,
+            None => return Ok(Vec::new()),
+        };
+        let mut sliced = Document::new();
+        for (field, slice) in self.slices.iter() {
+            let mut arguments = vec![Bson::String(format!("${}", field))];
+            match slice {
+                Bson::Array(range) => arguments.extend(range.iter().cloned()),
+                limit => arguments.push(limit.clone()),
+            }
+            sliced.insert(field.clone(), doc! { "$slice": arguments });
+            if let Fields::Include(_) = self.fields {
+                projection.insert(field.clone(), 1);
+            } else {
+                projection.remove(field);
+            }
+        }
+        let mut stages = Vec::new();
+        if !projection.is_empty() {
+            stages.push(doc! { "$project": projection });
+        }
+        if !sliced.is_empty() {
+            stages.push(doc! { "$addFields": sliced });
+        }
+        Ok(stages)
+    }
+}
+
+impl From<Projection> for Document {
+    fn from(projection: Projection) -> Document {
+        // nothing is hidden, so nothing can be refused
+        projection
+            .resolve(&[], None)
+            .ok()
+            .flatten()
+            .unwrap_or_default()
+    }
+}
+
+/// Removes `hidden` fields from an item that was read whole.
+pub(crate) fn hide(document: &mut Document, hidden: &[String]) {
+    for path in hidden.iter() {
+        remove_path(document, path);
+    }
+}
+
+/// The `hidden` fields that lie within `path`, relative to it, eg. `email` for `author.email`
+/// within `author`.
+pub(crate) fn hidden_within(hidden: &[String], path: &str) -> Vec<String> {
+    let within = format!("{}.", path);
+    hidden
+        .iter()
+        .filter_map(|field| field.strip_prefix(&within))
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Whether `path` is a hidden field or lies within one.
+pub(crate) fn is_hidden(hidden: &[String], path: &str) -> bool {
+    hidden
+        .iter()
+        .any(|field| path == field || path.starts_with(&format!("{}.", field)))
+}
+
+fn remove_path(document: &mut Document, path: &str) {
+    match path.split_once('.') {
+        None => {
+            document.remove(path);
+        }
+        Some((head, rest)) => match document.get_mut(head) {
+            Some(Bson::Document(nested)) => remove_path(nested, rest),
+            Some(Bson::Array(items)) => {
+                for item in items.iter_mut() {
+                    if let Bson::Document(nested) = item {
+                        remove_path(nested, rest);
+                    }
+                }
+            }
+            _ => {}
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_projection() {
+        let hidden = vec!["password".to_owned(), "profile.ssn".to_owned()];
+        let sort = doc! { "node.date_created": -1, "_id": 1 };
+        assert_eq!(
+            Projection::all().resolve(&hidden, Some(&sort)).unwrap(),
+            Some(doc! { "password": 0, "profile.ssn": 0 })
+        );
+        assert_eq!(Projection::all().resolve(&[], Some(&sort)).unwrap(), None);
+        assert_eq!(
+            Projection::include(&["title", "node", "password"])
+                .resolve(&hidden, Some(&sort))
+                .unwrap(),
+            Some(doc! { "title": 1, "node": 1, "password": 1, "_id": 1 })
+        );
+        assert_eq!(
+            Projection::exclude(&["body", "node"])
+                .revealing(&["password"])
+                .with_slice("comments", -3)
+                .resolve(&hidden, Some(&sort))
+                .unwrap(),
+            Some(doc! { "body": 0, "profile.ssn": 0, "comments": { "$slice": -3_i64 } })
+        );
+
+        // sorting by a hidden field would leak it through the cursors
+        let by_password = doc! { "password": 1, "_id": 1 };
+        assert!(Projection::all()
+            .resolve(&hidden, Some(&by_password))
+            .is_err());
+        assert!(Projection::include(&["title"])
+            .resolve(&hidden, Some(&doc! { "profile": 1 }))
+            .is_err());
+        assert_eq!(
+            Projection::all()
+                .revealing(&["password"])
+                .resolve(&hidden, Some(&by_password))
+                .unwrap(),
+            Some(doc! { "profile.ssn": 0 })
+        );
+
+        assert_eq!(
+            Projection::include(&["title"])
+                .with_slice_range("comments", 1, 2)
+                .stages(&[], None)
+                .unwrap(),
+            vec![
+                doc! { "$project": { "title": 1, "comments": 1 } },
+                doc! { "$addFields": { "comments": { "$slice": ["$comments", 1_i64, 2_i64] } } },
+            ]
+        );
+
+        let mut item = doc! {
+            "name": "a",
+            "password": "secret",
+            "profile": { "ssn": "123", "city": "Paris" },
+        };
+        hide(&mut item, &hidden);
+        assert_eq!(item, doc! { "name": "a", "profile": { "city": "Paris" } });
+    }
+}
@@ -0,0 +1,408 @@
+use bson::{doc, Bson, Document};
+use std::collections::HashMap;
+
+use crate::base::BaseService;
+use crate::error::ServiceError;
+use crate::id::ID;
+use crate::mongo::MongoService;
+use crate::projection::{hide, Projection};
+
+/// How the items of a `Relation` are found.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum RelationKind {
+    /// The item's `local_field` holds the id of one related item.
+    One { local_field: String },
+    /// The related items hold the item's id in their `foreign_field`.
+    Many { foreign_field: String },
+    /// The item's `local_field` holds an array with the ids of the related items.
+    ManyByIds { local_field: String },
+}
+
+/// A relation from the items of a service to those of another service registered in the same
+/// `DataSources`, filled in on request by `DataSources::populate`.
+///
+/// The related items are read through the other service, so its soft delete, tenant and hidden
+/// fields apply. They are stored in the field named after the relation: the item (or null) for
+/// `One`, an array for the others.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Relation {
+    name: String,
+    service: String,
+    kind: RelationKind,
+}
+
+impl Relation {
+    /// The item whose id is in `local_field`, eg. `Relation::one("author", "users", "author_id")`
+    /// or `Relation::one("created_by", "users", "node.created_by_id")`.
+    pub fn one(name: &str, service: &str, local_field: &str) -> Self {
+        Relation::new(
+            name,
+            service,
+            RelationKind::One {
+                local_field: local_field.to_owned(),
+            },
+        )
+    }
+
+    /// The items that have the item's id in `foreign_field`, eg.
+    /// `Relation::many("comments", "comments", "post_id")`.
+    pub fn many(name: &str, service: &str, foreign_field: &str) -> Self {
+        Relation::new(
+            name,
+            service,
+            RelationKind::Many {
+                foreign_field: foreign_field.to_owned(),
+            },
+        )
+    }
+
+    /// The items whose ids are in the array of `local_field`, eg.
+    /// `Relation::many_by_ids("tags", "tags", "tag_ids")`.
+    pub fn many_by_ids(name: &str, service: &str, local_field: &str) -> Self {
+        Relation::new(
+            name,
+            service,
+            RelationKind::ManyByIds {
+                local_field: local_field.to_owned(),
+            },
+        )
+    }
+
+    pub fn new(name: &str, service: &str, kind: RelationKind) -> Self {
+        Relation {
+            name: name.to_owned(),
+            service: service.to_owned(),
+            kind,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn service(&self) -> &str {
+        &self.service
+    }
+
+    pub fn kind(&self) -> &RelationKind {
+        &self.kind
+    }
+}
+
+fn get_path<'d>(document: &'d Document, path: &str) -> Option<&'d Bson> {
+    match path.split_once('.') {
+        None => document.get(path),
+        Some((head, rest)) => match document.get(head) {
+            Some(Bson::Document(nested)) => get_path(nested, rest),
+            _ => None,
+        },
+    }
+}
+
+/// The ids a relation can follow, other values are ignored.
+fn as_id(value: &Bson) -> Option<ID> {
+    match value {
+        Bson::String(_) | Bson::ObjectId(_) | Bson::I64(_) => Some(ID::with_bson(value)),
+        _ => None,
+    }
+}
+
+fn local_ids(item: &Document, kind: &RelationKind) -> Vec<ID> {
+    match kind {
+        RelationKind::One { local_field } => get_path(item, local_field)
+            .and_then(as_id)
+            .into_iter()
+            .collect(),
+        RelationKind::ManyByIds { local_field } => match get_path(item, local_field) {
+            Some(Bson::Array(values)) => values.iter().filter_map(as_id).collect(),
+            _ => Vec::new(),
+        },
+        RelationKind::Many { .. } => Vec::new(),
+    }
+}
+
+/// Whether a foreign field holds `id`, either as its value or in its array.
+fn holds(key: &Bson, id: &Bson) -> bool {
+    match key {
+        Bson::Array(values) => values.contains(id),
+        _ => key == id,
+    }
+}
+
+/// Fills in `relations` on `items` of `service`, with one query per relation.
+pub(crate) fn populate(
+    services: &HashMap<String, MongoService>,
+    service: &MongoService,
+    mut items: Vec<Document>,
+    relations: &[&str],
+) -> Result<Vec<Document>, ServiceError> {
+    let declared = service.relations();
+    for name in relations.iter() {
+        let relation = declared
+            .iter()
+            .find(|relation| relation.name == *name)
+            .ok_or_else(|| {
+                ServiceError::NotFound(format!(
+                    "No relation {} on collection {}",
+                    name,
+                    service.data_source().name()
+                ))
+            })?;
+        let target = services.get(&relation.service).ok_or_else(|| {
+            ServiceError::ConnectionError(format!(
+                "Unable to connect to collection {}",
+                relation.service
+            ))
+        })?;
+        match &relation.kind {
+            RelationKind::One { .. } | RelationKind::ManyByIds { .. } => {
+                let ids: Vec<ID> = items
+                    .iter()
+                    .flat_map(|item| local_ids(item, &relation.kind))
+                    .collect();
+                let found: Vec<Option<Document>> = target.find_many_by_ids(ids.clone())?;
+                let related: HashMap<ID, Document> = ids
+                    .into_iter()
+                    .zip(found)
+                    .filter_map(|(id, item)| Some((id, item?)))
+                    .collect();
+                for item in items.iter_mut() {
+                    let mut linked = local_ids(item, &relation.kind)
+                        .into_iter()
+                        .filter_map(|id| related.get(&id).cloned().map(Bson::Document));
+                    let value = match relation.kind {
+                        RelationKind::One { .. } => linked.next().unwrap_or(Bson::Null),
+                        _ => Bson::Array(linked.collect()),
+                    };
+                    item.insert(relation.name.clone(), value);
+                }
+            }
+            RelationKind::Many { foreign_field } => {
+                let ids: Vec<Bson> = items
+                    .iter()
+                    .filter_map(|item| item.get(service.id_parameter()).cloned())
+                    .collect();
+                // the foreign field is read even when it is hidden so that the items can be
+                // matched, and hidden again afterwards
+                let projection = Projection::all().revealing(&[foreign_field.as_str()]);
+                let mut related: Vec<Document> = target
+                    .find_projected(
+                        Some(doc! { foreign_field: { "$in": ids } }),
+                        Some(projection),
+                        None,
+                        None,
+                        None,
+                        None,
+                        None,
+                    )?
+                    .items;
+                let keys: Vec<Option<Bson>> = related
+                    .iter()
+                    .map(|related| get_path(related, foreign_field).cloned())
+                    .collect();
+                let hidden = target.hidden_fields();
+                for related in related.iter_mut() {
+                    hide(related, &hidden);
+                }
+                for item in items.iter_mut() {
+                    let id = item.get(service.id_parameter()).cloned();
+                    let linked: Vec<Bson> = related
+                        .iter()
+                        .zip(keys.iter())
+                        .filter(|(_, key)| match (&id, key) {
+                            (Some(id), Some(key)) => holds(key, id),
+                            _ => false,
+                        })
+                        .map(|(related, _)| Bson::Document(related.clone()))
+                        .collect();
+                    item.insert(relation.name.clone(), Bson::Array(linked));
+                }
+            }
+        }
+    }
+    Ok(items)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockCollection;
+    use crate::DataSources;
+    use mongodb_cursor_pagination::FindResult;
+    use serde::Deserialize;
+
+    fn comments(documents: Vec<Document>) -> MongoService {
+        MongoService::with_data_source(MockCollection::with_documents("comments", documents), None)
+    }
+
+    fn with_posts(comments: MongoService, relation: Relation) -> DataSources {
+        let mut data_sources = DataSources::new();
+        data_sources.add_mongo_service("comments", comments);
+        let posts = MockCollection::with_documents(
+            "posts",
+            vec![doc! { "_id": 1_i64 }, doc! { "_id": 2_i64 }],
+        );
+        data_sources.add_mongo_service(
+            "posts",
+            MongoService::with_data_source(posts, Some(doc! { "_id": 1 }))
+                .with_relations(vec![relation]),
+        );
+        data_sources
+    }
+
+    fn texts(post: &Document, field: &str) -> Vec<String> {
+        post.get_array(field)
+            .unwrap()
+            .iter()
+            .map(|comment| match comment {
+                Bson::Document(comment) => comment.get_str("text").unwrap().to_owned(),
+                _ => panic!("not a document"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_populate_relations() {
+        #[derive(Debug, Deserialize)]
+        struct Tag {
+            name: String,
+        }
+
+        #[derive(Debug, Deserialize)]
+        struct User {
+            name: String,
+        }
+
+        #[derive(Debug, Deserialize)]
+        struct PopulatedPost {
+            title: String,
+            author: Option<User>,
+            created_by: Option<User>,
+            comments: Vec<Document>,
+            tags: Vec<Tag>,
+        }
+
+        let mut data_sources = crate::DataSources::new();
+        let users = MockCollection::with_documents(
+            "users",
+            vec![
+                doc! { "_id": 1_i64, "name": "Ann", "password": "secret" },
+                doc! { "_id": 2_i64, "name": "Bob", "password": "secret" },
+            ],
+        );
+        data_sources.add_mongo_service(
+            "users",
+            MongoService::with_data_source(users, None).with_hidden_fields(&["password"]),
+        );
+        let tags = MockCollection::with_documents(
+            "tags",
+            vec![
+                doc! { "_id": "rust", "name": "Rust" },
+                doc! { "_id": "db", "name": "Databases" },
+            ],
+        );
+        data_sources.add_mongo_service("tags", MongoService::with_data_source(tags, None));
+        let comments = MockCollection::with_documents(
+            "comments",
+            vec![
+                doc! { "_id": 1_i64, "post_id": 2_i64, "text": "first" },
+                doc! { "_id": 2_i64, "post_id": 1_i64, "text": "second" },
+                doc! { "_id": 3_i64, "post_id": 2_i64, "text": "third" },
+            ],
+        );
+        data_sources.add_mongo_service("comments", MongoService::with_data_source(comments, None));
+        let posts = MockCollection::with_documents(
+            "posts",
+            vec![
+                doc! {
+                    "_id": 1_i64,
+                    "title": "Post 1",
+                    "author_id": 2_i64,
+                    "tag_ids": ["db", "gone", "rust"],
+                    "node": { "created_by_id": 1_i64 },
+                },
+                doc! { "_id": 2_i64, "title": "Post 2", "author_id": 9_i64, "tag_ids": [] },
+            ],
+        );
+        data_sources.add_mongo_service(
+            "posts",
+            MongoService::with_data_source(posts, Some(doc! { "_id": 1 })).with_relations(vec![
+                Relation::one("author", "users", "author_id"),
+                Relation::one("created_by", "users", "node.created_by_id"),
+                Relation::many("comments", "comments", "post_id"),
+                Relation::many_by_ids("tags", "tags", "tag_ids"),
+            ]),
+        );
+
+        let relations = ["author", "created_by", "comments", "tags"];
+        let found: FindResult<PopulatedPost> = data_sources
+            .find_populated("posts", &relations, None, None, None, None, None, None)
+            .unwrap();
+        assert_eq!(found.total_count, 2);
+        let first = &found.items[0];
+        assert_eq!(first.title, "Post 1");
+        assert_eq!(first.author.as_ref().unwrap().name, "Bob");
+        assert_eq!(first.created_by.as_ref().unwrap().name, "Ann");
+        assert_eq!(first.comments.len(), 1);
+        let tag_names: Vec<&str> = first.tags.iter().map(|tag| tag.name.as_str()).collect();
+        assert_eq!(tag_names, vec!["Databases", "Rust"]);
+        let second = &found.items[1];
+        assert!(second.author.is_none() && second.created_by.is_none());
+        let texts: Vec<&str> = second
+            .comments
+            .iter()
+            .map(|comment| comment.get_str("text").unwrap())
+            .collect();
+        assert_eq!(texts, vec!["first", "third"]);
+        assert!(second.tags.is_empty());
+
+        let post: Document = data_sources
+            .find_one_populated("posts", &["author"], doc! { "_id": 1_i64 })
+            .unwrap()
+            .unwrap();
+        let author = post.get_document("author").unwrap();
+        assert_eq!(author.get_str("name").unwrap(), "Bob");
+        assert!(!author.contains_key("password"));
+        assert!(!post.contains_key("tags"));
+
+        assert!(matches!(
+            data_sources.populate::<Document>("posts", vec![], &["editor"]),
+            Err(ServiceError::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_many_matches_foreign_arrays() {
+        let comments = comments(vec![
+            doc! { "_id": 1_i64, "post_ids": [1_i64, 2_i64], "text": "both" },
+            doc! { "_id": 2_i64, "post_ids": [2_i64], "text": "second" },
+            doc! { "_id": 3_i64, "post_ids": [], "text": "none" },
+        ]);
+        let data_sources = with_posts(comments, Relation::many("comments", "comments", "post_ids"));
+        let found: FindResult<Document> = data_sources
+            .find_populated("posts", &["comments"], None, None, None, None, None, None)
+            .unwrap();
+        assert_eq!(texts(&found.items[0], "comments"), vec!["both"]);
+        assert_eq!(texts(&found.items[1], "comments"), vec!["both", "second"]);
+    }
+
+    #[test]
+    fn test_many_reads_through_the_target_service() {
+        let comments = comments(vec![
+            doc! { "_id": 1_i64, "post_id": 1_i64, "text": "live" },
+            doc! {
+                "_id": 2_i64,
+                "post_id": 1_i64,
+                "text": "trashed",
+                "node": { "date_deleted": bson::Bson::UtcDatetime(chrono::Utc::now()) },
+            },
+        ])
+        .with_soft_delete();
+        let data_sources = with_posts(comments, Relation::many("comments", "comments", "post_id"));
+        let found: FindResult<Document> = data_sources
+            .find_populated("posts", &["comments"], None, None, None, None, None, None)
+            .unwrap();
+        assert_eq!(texts(&found.items[0], "comments"), vec!["live"]);
+        assert!(texts(&found.items[1], "comments").is_empty());
+    }
+}
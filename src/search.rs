@@ -0,0 +1,149 @@
+use bson::{doc, Bson, Document};
+use voca_rs::case::snake_case;
+
+use crate::filter::match_nothing;
+use crate::projection::Projection;
+
+/// The field that `text_search` keeps each item's relevance in while sorting and paging. It is
+/// removed from the items returned, but can be named in a `sort`.
+pub const TEXT_SCORE_FIELD: &str = "_textScore";
+
+/// How `BaseService::search` matches its search term.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub enum SearchMode {
+    /// Case-insensitive regexes on each of the given fields. Works without any index but can't
+    /// use one either.
+    #[default]
+    Regex,
+    /// MongoDB's `$text` operator, which needs a text index on the collection and ignores the
+    /// fields passed to `search`. The term supports `"exact phrases"` and `-negated` words, and
+    /// `language` picks the stemming and stop words (the index' default when `None`). Only
+    /// `TermMatch::Literal` queries can be run this way.
+    Text { language: Option<String> },
+}
+
+/// How `SearchMode::Regex` matches the term of a `SearchQuery`, always ignoring case.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum TermMatch {
+    /// The whole term has to appear in one of the fields, taken literally.
+    #[default]
+    Literal,
+    /// Every word of the term has to appear in at least one of the fields, in any order.
+    AllWords,
+    /// The term is used as a regular expression as is, so only use it with trusted input.
+    Regex,
+}
+
+/// What `BaseService::search_with` looks for.
+///
+/// ```
+/// use mongodb_base_service::{Filter, SearchQuery, TermMatch};
+///
+/// let query = SearchQuery::new("rust async", vec!["title".into(), "body".into()])
+///     .with_matching(TermMatch::AllWords)
+///     .with_filter(Filter::eq("status", "published"));
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct SearchQuery {
+    pub(crate) term: String,
+    fields: Vec<String>,
+    pub(crate) matching: TermMatch,
+    pub(crate) filter: Option<Document>,
+    pub(crate) projection: Option<Projection>,
+}
+
+impl SearchQuery {
+    /// Searches `fields` (converted to snake case) for the literal `term`. With
+    /// `SearchMode::Text` the fields are ignored, the text index decides what is searched.
+    pub fn new(term: &str, fields: Vec<String>) -> Self {
+        SearchQuery {
+            term: term.to_owned(),
+            fields,
+            matching: TermMatch::Literal,
+            filter: None,
+            projection: None,
+        }
+    }
+
+    /// How the term is matched in `SearchMode::Regex`, `SearchMode::Text` refuses anything but
+    /// `TermMatch::Literal`.
+    pub fn with_matching(mut self, matching: TermMatch) -> Self {
+        self.matching = matching;
+        self
+    }
+
+    /// Only returns items that also match `filter`, either a `Document` or a `Filter`.
+    pub fn with_filter<F: Into<Document>>(mut self, filter: F) -> Self {
+        self.filter = Some(filter.into());
+        self
+    }
+
+    /// Only returns the fields of `projection` for each item found.
+    pub fn with_projection(mut self, projection: Projection) -> Self {
+        self.projection = Some(projection);
+        self
+    }
+
+    /// The filter matching the term in `SearchMode::Regex`.
+    pub(crate) fn term_filter(&self) -> Document {
+        match self.matching {
+            TermMatch::Literal => self.any_field(regex::escape(&self.term)),
+            TermMatch::Regex => self.any_field(self.term.clone()),
+            TermMatch::AllWords => all_of(
+                self.term
+                    .split_whitespace()
+                    .map(|word| self.any_field(regex::escape(word)))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Matches `pattern` in any of the fields, and nothing when there are no fields.
+    fn any_field(&self, pattern: String) -> Document {
+        if self.fields.is_empty() {
+            return match_nothing();
+        }
+        let clauses: Vec<Bson> = self
+            .fields
+            .iter()
+            .map(|field| {
+                let mut clause = Document::new();
+                clause.insert(
+                    snake_case(field),
+                    Bson::RegExp(pattern.clone(), "i".to_owned()),
+                );
+                Bson::Document(clause)
+            })
+            .collect();
+        doc! { "$or": clauses }
+    }
+}
+
+/// ANDs the filters together, leaving out empty ones.
+pub fn all_of(filters: Vec<Document>) -> Document {
+    let mut filters: Vec<Document> = filters.into_iter().filter(|f| !f.is_empty()).collect();
+    match filters.len() {
+        0 => Document::new(),
+        1 => filters.remove(0),
+        _ => doc! { "$and": filters.into_iter().map(Bson::Document).collect::<Vec<Bson>>() },
+    }
+}
+
+/// The pipeline matching `search_term` with `$text` along with `filter` and adding each item's
+/// relevance as `TEXT_SCORE_FIELD`. `$text` has to be in the first stage.
+pub fn text_search_pipeline(
+    search_term: &str,
+    language: Option<&str>,
+    filter: Document,
+) -> Vec<Document> {
+    let mut text = doc! { "$search": search_term };
+    if let Some(language) = language {
+        text.insert("$language", language);
+    }
+    let mut filter = filter;
+    filter.insert("$text", text);
+    vec![
+        doc! { "$match": filter },
+        doc! { "$addFields": { TEXT_SCORE_FIELD: { "$meta": "textScore" } } },
+    ]
+}
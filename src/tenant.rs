@@ -0,0 +1,281 @@
+use bson::{doc, Bson, Document};
+
+use crate::error::ServiceError;
+
+/// Keeps a service to the items of one tenant of a shared collection.
+///
+/// The tenant is stored in `field` of every item. A scope without a tenant, as declared by
+/// `MongoService::with_tenant_field` before `for_tenant` picks one, matches nothing and refuses
+/// inserts, so a forgotten tenant can't leak every tenant's items.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TenantScope {
+    field: String,
+    tenant: Option<Bson>,
+}
+
+impl TenantScope {
+    pub fn new(field: &str) -> Self {
+        TenantScope {
+            field: field.to_owned(),
+            tenant: None,
+        }
+    }
+
+    pub fn with_tenant<B: Into<Bson>>(mut self, tenant: B) -> Self {
+        self.tenant = Some(tenant.into());
+        self
+    }
+
+    pub fn field(&self) -> &str {
+        &self.field
+    }
+
+    pub fn tenant(&self) -> Option<&Bson> {
+        self.tenant.as_ref()
+    }
+
+    /// Restricts `filter` to the tenant's items. A filter that already says something about the
+    /// tenant field is ANDed with the scope rather than trusted, leaving its other conditions
+    /// where they were.
+    pub fn apply(&self, mut filter: Document) -> Document {
+        let condition = match &self.tenant {
+            Some(tenant) => tenant.clone(),
+            None => Bson::Document(doc! { "$in": [] }),
+        };
+        match filter.remove(&self.field) {
+            Some(claimed) => {
+                let conditions = vec![
+                    Bson::Document(doc! { self.field.clone(): claimed }),
+                    Bson::Document(doc! { self.field.clone(): condition }),
+                ];
+                match filter.get_array_mut("$and") {
+                    Ok(and) => and.extend(conditions),
+                    Err(_) => {
+                        filter.insert("$and", conditions);
+                    }
+                }
+            }
+            None => {
+                filter.insert(self.field.clone(), condition);
+            }
+        }
+        filter
+    }
+
+    /// Keeps an update from moving items to another tenant: update operators can't touch the
+    /// tenant field, except that setting a document on a parent of it (eg. `org` for `org.id`)
+    /// keeps the tenant inside, and a replacement document keeps the tenant. An update that
+    /// only touched the tenant field has nothing left to do and is refused.
+    pub fn restrict(&self, mut update: Document) -> Result<Document, ServiceError> {
+        if !update.keys().all(|key| key.starts_with('$')) {
+            match &self.tenant {
+                Some(tenant) => insert_at(&mut update, &self.field, tenant.clone()),
+                None => remove_at(&mut update, &self.field),
+            };
+            return Ok(update);
+        }
+        let operators: Vec<String> = update.keys().cloned().collect();
+        for operator in operators {
+            let fields = match update.get_document_mut(&operator) {
+                Ok(fields) => fields,
+                Err(_) => continue,
+            };
+            let paths: Vec<String> = fields.keys().cloned().collect();
+            for path in paths {
+                // a rename can move another field onto the tenant field as well
+                let renamed_onto = operator == "$rename"
+                    && fields
+                        .get_str(&path)
+                        .is_ok_and(|target| self.covers(target) || self.is_parent(target));
+                if self.covers(&path) || renamed_onto {
+                    fields.remove(&path);
+                } else if self.is_parent(&path) {
+                    let inner = &self.field[path.len() + 1..];
+                    match (operator.as_str(), fields.get_mut(&path), &self.tenant) {
+                        ("$set", Some(Bson::Document(value)), Some(tenant))
+                        | ("$setOnInsert", Some(Bson::Document(value)), Some(tenant)) => {
+                            insert_at(value, inner, tenant.clone())
+                        }
+                        _ => {
+                            fields.remove(&path);
+                        }
+                    }
+                }
+            }
+            if fields.is_empty() {
+                update.remove(&operator);
+            }
+        }
+        if update.is_empty() {
+            return Err(format!("The update only changed the tenant field {}", self.field).into());
+        }
+        Ok(update)
+    }
+
+    /// Whether writing to `path` would change the tenant field.
+    fn covers(&self, path: &str) -> bool {
+        path == self.field || path.starts_with(&format!("{}.", self.field))
+    }
+
+    /// Whether `path` holds the tenant field, eg. `org` for `org.id`.
+    fn is_parent(&self, path: &str) -> bool {
+        self.field.starts_with(&format!("{}.", path))
+    }
+
+    /// Sets the tenant of an item that is about to be inserted, replacing whatever it claimed.
+    pub fn stamp(&self, document: &mut Document) -> Result<(), ServiceError> {
+        match &self.tenant {
+            Some(tenant) => {
+                insert_at(document, &self.field, tenant.clone());
+                Ok(())
+            }
+            None => Err(ServiceError::Unknown(format!(
+                "No tenant was given for items scoped by {}",
+                self.field
+            ))),
+        }
+    }
+
+    /// Whether `document` belongs to the tenant.
+    pub fn owns(&self, document: &Document) -> bool {
+        self.tenant.is_some() && get_at(document, &self.field) == self.tenant.as_ref()
+    }
+}
+
+fn get_at<'d>(document: &'d Document, path: &str) -> Option<&'d Bson> {
+    match path.split_once('.') {
+        Some((head, rest)) => match document.get(head) {
+            Some(Bson::Document(inner)) => get_at(inner, rest),
+            _ => None,
+        },
+        None => document.get(path),
+    }
+}
+
+/// Sets the value at a dotted `path`, creating (or replacing) the documents on the way.
+fn insert_at(document: &mut Document, path: &str, value: Bson) {
+    match path.split_once('.') {
+        Some((head, rest)) => {
+            if !matches!(document.get(head), Some(Bson::Document(_))) {
+                document.insert(head, Document::new());
+            }
+            if let Some(Bson::Document(inner)) = document.get_mut(head) {
+                insert_at(inner, rest, value);
+            }
+        }
+        None => {
+            document.insert(path, value);
+        }
+    }
+}
+
+fn remove_at(document: &mut Document, path: &str) {
+    match path.split_once('.') {
+        Some((head, rest)) => {
+            if let Some(Bson::Document(inner)) = document.get_mut(head) {
+                remove_at(inner, rest);
+            }
+        }
+        None => {
+            document.remove(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scope_filters_and_stamps() {
+        let scope = TenantScope::new("tenant_id").with_tenant("acme");
+        assert_eq!(
+            scope.apply(doc! { "title": "a" }),
+            doc! { "title": "a", "tenant_id": "acme" }
+        );
+        assert_eq!(
+            scope.apply(doc! { "tenant_id": "other" }),
+            doc! { "$and": [{ "tenant_id": "other" }, { "tenant_id": "acme" }] }
+        );
+
+        assert_eq!(
+            scope.apply(doc! { "tenant_id": "other", "node.date_deleted": Bson::Null }),
+            doc! {
+                "node.date_deleted": Bson::Null,
+                "$and": [{ "tenant_id": "other" }, { "tenant_id": "acme" }],
+            }
+        );
+        assert_eq!(
+            scope
+                .restrict(doc! {
+                    "$set": { "title": "b", "tenant_id": "other" },
+                    "$unset": { "tenant_id": "" },
+                    "$rename": { "owner": "tenant_id" },
+                })
+                .unwrap(),
+            doc! { "$set": { "title": "b" } }
+        );
+        assert_eq!(
+            scope
+                .restrict(doc! { "title": "b", "tenant_id": "other" })
+                .unwrap(),
+            doc! { "title": "b", "tenant_id": "acme" }
+        );
+
+        let mut item = doc! { "title": "a", "tenant_id": "other" };
+        scope.stamp(&mut item).unwrap();
+        assert_eq!(item, doc! { "title": "a", "tenant_id": "acme" });
+        assert!(scope.owns(&item));
+
+        let unscoped = TenantScope::new("tenant_id");
+        assert_eq!(
+            unscoped.apply(Document::new()),
+            doc! { "tenant_id": { "$in": [] } }
+        );
+        assert!(unscoped.stamp(&mut item).is_err());
+        assert!(!unscoped.owns(&item));
+    }
+
+    #[test]
+    fn test_updates_that_only_move_the_tenant_are_refused() {
+        let scope = TenantScope::new("tenant_id").with_tenant("acme");
+        assert!(scope
+            .restrict(doc! { "$set": { "tenant_id": "other" } })
+            .is_err());
+        assert!(scope
+            .restrict(doc! { "$rename": { "owner": "tenant_id" } })
+            .is_err());
+    }
+
+    #[test]
+    fn test_nested_tenant_fields() {
+        let scope = TenantScope::new("org.id").with_tenant("acme");
+        assert_eq!(
+            scope
+                .restrict(doc! {
+                    "$set": { "org": { "id": "other", "name": "Other" }, "title": "b" },
+                    "$unset": { "org": "" },
+                    "$rename": { "owner": "org" },
+                })
+                .unwrap(),
+            doc! { "$set": { "org": { "name": "Other", "id": "acme" }, "title": "b" } }
+        );
+        assert_eq!(
+            scope
+                .restrict(doc! { "$set": { "org": "other", "title": "b" } })
+                .unwrap(),
+            doc! { "$set": { "title": "b" } }
+        );
+        assert_eq!(
+            scope
+                .restrict(doc! { "title": "b", "org": { "id": "other" } })
+                .unwrap(),
+            doc! { "title": "b", "org": { "id": "acme" } }
+        );
+
+        let mut item = doc! { "title": "a" };
+        scope.stamp(&mut item).unwrap();
+        assert_eq!(item, doc! { "title": "a", "org": { "id": "acme" } });
+        assert!(scope.owns(&item));
+    }
+}
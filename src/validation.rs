@@ -0,0 +1,146 @@
+use bson::Document;
+use serde::{Deserialize, Serialize};
+
+/// A field that failed validation, addressed by its dotted path within the item.
+#[cfg_attr(feature = "graphql", derive(juniper::GraphQLObject))]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(field: &str, message: &str) -> Self {
+        FieldError {
+            field: field.to_owned(),
+            message: message.to_owned(),
+        }
+    }
+}
+
+/// How much of an item the document handed to a `Validator` carries.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ValidationScope {
+    /// The complete item, on inserts and when an item is replaced as a whole.
+    Full,
+    /// Only the fields being updated, so fields that are missing shouldn't be reported.
+    Partial,
+}
+
+/// Checks items before a `BaseService` writes them.
+///
+/// It gets the serialized item on inserts, the serialized update on partial updates (or the
+/// `$set` document of `update_one_with_doc`) and the replacement document on full updates.
+/// Embedded items are validated on their own, with `field_path` saying which array they live
+/// in. Every problem found should be returned, they are reported together as a
+/// `ServiceError::ValidationError`. Validation runs before any `ServiceHooks`.
+pub trait Validator: Send + Sync {
+    fn validate(
+        &self,
+        document: &Document,
+        scope: ValidationScope,
+        field_path: Option<&str>,
+    ) -> Vec<FieldError>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::base::BaseService;
+    use crate::error::ServiceError;
+    use crate::id::ID;
+    use crate::mock::MockCollection;
+    use crate::mongo::MongoService;
+    use bson::doc;
+
+    struct TitleValidator;
+
+    impl Validator for TitleValidator {
+        fn validate(
+            &self,
+            document: &Document,
+            scope: ValidationScope,
+            field_path: Option<&str>,
+        ) -> Vec<FieldError> {
+            let field = if field_path.is_some() {
+                "text"
+            } else {
+                "title"
+            };
+            let mut errors = Vec::new();
+            match document.get_str(field) {
+                Ok("") => errors.push(FieldError::new(field, "must not be empty")),
+                Err(_) if scope == ValidationScope::Full => {
+                    errors.push(FieldError::new(field, "is required"))
+                }
+                _ => {}
+            }
+            if document.get_i32("views").is_ok_and(|views| views < 0) {
+                errors.push(FieldError::new("views", "must not be negative"));
+            }
+            errors
+        }
+    }
+
+    fn validated(documents: Vec<Document>) -> (MongoService, MockCollection) {
+        let collection = MockCollection::with_documents("items", documents);
+        let service =
+            MongoService::with_data_source(collection.clone(), None).with_validator(TitleValidator);
+        (service, collection)
+    }
+
+    #[test]
+    fn test_inserts_report_every_failing_field() {
+        let (service, collection) = validated(Vec::new());
+        match service.insert_one(doc! { "_id": 1_i64, "views": -1 }, None) {
+            Err(ServiceError::ValidationError(errors)) => assert_eq!(
+                errors,
+                vec![
+                    FieldError::new("title", "is required"),
+                    FieldError::new("views", "must not be negative"),
+                ]
+            ),
+            other => panic!("expected a validation error, got {:?}", other),
+        }
+        // errors of a batch are prefixed with the index of the item
+        match service.insert_many(vec![doc! { "title": "Fine" }, doc! { "title": "" }], None) {
+            Err(ServiceError::ValidationError(errors)) => {
+                assert_eq!(
+                    errors,
+                    vec![FieldError::new("1.title", "must not be empty")]
+                )
+            }
+            other => panic!("expected a validation error, got {:?}", other),
+        }
+        assert!(collection.documents().is_empty());
+    }
+
+    #[test]
+    fn test_partial_updates_only_check_the_fields_they_carry() {
+        let (service, collection) = validated(vec![doc! { "_id": 1_i64, "title": "a" }]);
+        let _: Document = service
+            .update_one_with_doc(ID::I64(1), doc! { "$set": { "views": 3 } })
+            .unwrap();
+        let result: Result<Document, ServiceError> =
+            service.update_one_with_doc(ID::I64(1), doc! { "$set": { "title": "" } });
+        assert!(matches!(result, Err(ServiceError::ValidationError(_))));
+        let result: Result<Document, ServiceError> =
+            service.update_one_with_doc(ID::I64(1), doc! { "$set": { "views": -5 } });
+        assert!(matches!(result, Err(ServiceError::ValidationError(_))));
+        assert_eq!(collection.documents()[0].get_i32("views"), Ok(3));
+    }
+
+    #[test]
+    fn test_embedded_items_are_validated_with_their_field_path() {
+        let (service, _) = validated(vec![doc! { "_id": 1_i64, "title": "a" }]);
+        let result =
+            service.insert_embedded(ID::I64(1), "comments", vec![doc! { "text": "" }], None);
+        match result {
+            Err(ServiceError::ValidationError(errors)) => assert_eq!(
+                ServiceError::ValidationError(errors).to_string(),
+                "Validation failed - 0.text: must not be empty"
+            ),
+            other => panic!("expected a validation error, got {:?}", other),
+        }
+    }
+}